@@ -1,10 +1,19 @@
 use arboard::Clipboard;
 use eframe::egui;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
-/// スナップする角度の許容範囲（度）
-const SNAP_ANGLE_TOLERANCE_DEG: f32 = 5.0;
+/// 多角形の始点をクリックしたとみなす距離（スクリーン座標, px）
+const POLYGON_CLOSE_DISTANCE: f32 = 8.0;
+/// オブジェクトスナップが効く距離（画面ピクセル）
+const OBJECT_SNAP_DISTANCE: f32 = 10.0;
+/// 選択した測定の端点・角をつかめる距離（スクリーン座標, px）
+const HANDLE_HIT_DISTANCE: f32 = 8.0;
+/// 測定をクリックで選択できる線からの距離（スクリーン座標, px）
+const SELECT_HIT_DISTANCE: f32 = 6.0;
+/// エッジスナップでエッジとみなす最小の勾配の大きさ（Sobel フィルタの出力）
+const EDGE_SNAP_MIN_GRADIENT: f32 = 20.0;
 
 /// 測定状態のステートマシン
 #[derive(Default)]
@@ -12,6 +21,10 @@ enum MeasurementState {
     #[default]
     Idle,
     FirstPointSelected(egui::Pos2),
+    /// 3点で指定する測定で、2点目まで選択済み
+    SecondPointSelected(egui::Pos2, egui::Pos2),
+    /// 頂点を順に追加中（折れ線・多角形）
+    Vertices(Vec<egui::Pos2>),
 }
 
 /// キャリブレーション状態
@@ -33,23 +46,224 @@ enum MeasurementMode {
     #[default]
     Line,
     Rectangle,
+    Angle,
+    Polyline,
+    Polygon,
+    Circle,
+    Ellipse,
+    RotatedRectangle,
+    PointToLine,
+    ParallelGap,
+    Marker,
+    MagicWand,
+    /// 既存の測定を選択して編集
+    Select,
+}
+
+/// 編集のために選択した測定（測定ID）
+/// ドラッグでの移動・変形と名前・メモは線分・矩形のみ。ほかの測定はスタイル・レイヤーの変更と削除
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Selection {
+    Line(u64),
+    Rect(u64),
+    Angle(u64),
+    Polyline(u64),
+    Polygon(u64),
+    Circle(u64),
+    Ellipse(u64),
+    RotatedRect(u64),
+    PointToLine(u64),
+    ParallelGap(u64),
+    Marker(u64),
+    Region(u64),
+    Blob(u64),
+}
+
+impl Selection {
+    /// 選択した測定を削除する操作
+    fn remove_action(self) -> Action {
+        match self {
+            Selection::Line(id) => Action::RemoveLine(id),
+            Selection::Rect(id) => Action::RemoveRect(id),
+            Selection::Angle(id) => Action::RemoveAngle(id),
+            Selection::Polyline(id) => Action::RemovePolyline(id),
+            Selection::Polygon(id) => Action::RemovePolygon(id),
+            Selection::Circle(id) => Action::RemoveCircle(id),
+            Selection::Ellipse(id) => Action::RemoveEllipse(id),
+            Selection::RotatedRect(id) => Action::RemoveRotatedRect(id),
+            Selection::PointToLine(id) => Action::RemovePointToLine(id),
+            Selection::ParallelGap(id) => Action::RemoveParallelGap(id),
+            Selection::Marker(id) => Action::RemoveMarker(id),
+            Selection::Region(id) => Action::RemoveRegion(id),
+            Selection::Blob(id) => Action::RemoveBlob(id),
+        }
+    }
+}
+
+/// 測定結果一覧で編集する文字列の項目
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum TextField {
+    Name,
+    Note,
+}
+
+impl TextField {
+    fn of<'a>(self, name: &'a str, note: &'a str) -> &'a str {
+        match self {
+            TextField::Name => name,
+            TextField::Note => note,
+        }
+    }
+
+    fn of_mut<'a>(self, name: &'a mut String, note: &'a mut String) -> &'a mut String {
+        match self {
+            TextField::Name => name,
+            TextField::Note => note,
+        }
+    }
+}
+
+/// ドラッグでつかんでいる部分
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DragHandle {
+    LineStart,
+    LineEnd,
+    /// 矩形の角（RectangleMeasurement::corners の番号）
+    RectCorner(usize),
+    /// 図形全体の移動
+    Body,
+}
+
+/// ドラッグ中の編集
+struct DragEdit {
+    handle: DragHandle,
+    /// ドラッグを始めた位置（画像座標）
+    grab_pos: egui::Pos2,
+    /// ドラッグ前の線分
+    original_lines: Vec<Measurement>,
+    /// ドラッグ前の矩形
+    original_rects: Vec<RectangleMeasurement>,
+}
+
+/// 画素値から読み取るチャンネル
+#[derive(Default, PartialEq, Clone, Copy)]
+enum ImageChannel {
+    /// 輝度（ITU-R BT.601 の重み付け）
+    #[default]
+    Luminance,
+    Red,
+    Green,
+    Blue,
+    Alpha,
+}
+
+impl ImageChannel {
+    const ALL: [ImageChannel; 5] = [
+        ImageChannel::Luminance,
+        ImageChannel::Red,
+        ImageChannel::Green,
+        ImageChannel::Blue,
+        ImageChannel::Alpha,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            ImageChannel::Luminance => "輝度",
+            ImageChannel::Red => "R",
+            ImageChannel::Green => "G",
+            ImageChannel::Blue => "B",
+            ImageChannel::Alpha => "A",
+        }
+    }
+
+    /// CSV/JSON 出力用の名前
+    fn key(self) -> &'static str {
+        match self {
+            ImageChannel::Luminance => "luminance",
+            ImageChannel::Red => "red",
+            ImageChannel::Green => "green",
+            ImageChannel::Blue => "blue",
+            ImageChannel::Alpha => "alpha",
+        }
+    }
+
+    fn value(self, rgba: [f32; 4]) -> f32 {
+        match self {
+            ImageChannel::Luminance => 0.299 * rgba[0] + 0.587 * rgba[1] + 0.114 * rgba[2],
+            ImageChannel::Red => rgba[0],
+            ImageChannel::Green => rgba[1],
+            ImageChannel::Blue => rgba[2],
+            ImageChannel::Alpha => rgba[3],
+        }
+    }
+}
+
+/// 円の指定方法
+#[derive(Default, PartialEq, Clone, Copy)]
+enum CircleMethod {
+    /// 中心と円周上の1点
+    #[default]
+    CenterRadius,
+    /// 円周上の3点
+    ThreePoint,
 }
 
 /// 測定結果
 #[derive(Clone, Serialize, Deserialize)]
 struct Measurement {
+    /// 測定ID（履歴に追加したときに割り当てる一意の番号。削除しても他の測定の番号は変わらない）
+    #[serde(default)]
+    id: u64,
     start: (f32, f32),
     end: (f32, f32),
     distance_px: f32,
+    /// 名前（空なら「線#N」と表示）
+    #[serde(default)]
+    name: String,
+    /// メモ
+    #[serde(default)]
+    note: String,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
 }
 
 impl Measurement {
     fn new(start: egui::Pos2, end: egui::Pos2) -> Self {
         let distance_px = start.distance(end);
         Self {
+            id: 0,
             start: (start.x, start.y),
             end: (end.x, end.y),
             distance_px,
+            name: String::new(),
+            note: String::new(),
+            layer: 0,
+            style: MeasurementStyle::default(),
+        }
+    }
+
+    /// 名前・メモ・レイヤー・スタイルを保ったまま端点を置き換える
+    fn with_points(&self, start: egui::Pos2, end: egui::Pos2) -> Self {
+        Self {
+            id: self.id,
+            name: self.name.clone(),
+            note: self.note.clone(),
+            layer: self.layer,
+            style: self.style.clone(),
+            ..Self::new(start, end)
+        }
+    }
+
+    /// 表示名（名前がなければ測定ID）
+    fn display_name(&self) -> String {
+        if self.name.is_empty() {
+            format!("線#{}", self.id)
+        } else {
+            self.name.clone()
         }
     }
 
@@ -70,16 +284,215 @@ impl Measurement {
             None => (self.distance_px, "px".to_string()),
         }
     }
+
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![vec![self.start_pos(), self.end_pos()]]
+    }
+}
+
+/// 測定ごとの表示スタイル（None の項目はレイヤーの色・既定の値を使う）
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct MeasurementStyle {
+    /// 線の色（RGBA）
+    stroke_color: Option<[u8; 4]>,
+    /// 線の太さ（px, 既定 2.0。粒子の輪郭は 1.5）
+    stroke_width: Option<f32>,
+    /// 端点・角の点の半径（px, 既定 5.0。マーカーは 6.0）
+    point_radius: Option<f32>,
+    /// ラベルの文字サイズ（既定は標準の文字サイズ。粒子の番号は 11.0）
+    label_font_size: Option<f32>,
+    /// ラベルを既定の位置からずらす量（スクリーン座標, px）
+    label_offset: (f32, f32),
+}
+
+impl MeasurementStyle {
+    fn stroke(&self, layer_color: egui::Color32) -> egui::Stroke {
+        self.stroke_or(layer_color, 2.0)
+    }
+
+    /// 線の太さの既定値が 2.0 でない測定の線
+    fn stroke_or(&self, layer_color: egui::Color32, default_width: f32) -> egui::Stroke {
+        egui::Stroke::new(
+            self.stroke_width.unwrap_or(default_width),
+            self.color_or(layer_color),
+        )
+    }
+
+    fn color_or(&self, layer_color: egui::Color32) -> egui::Color32 {
+        self.stroke_color.map_or(layer_color, |[r, g, b, a]| {
+            egui::Color32::from_rgba_unmultiplied(r, g, b, a)
+        })
+    }
+
+    fn font_id(&self) -> egui::FontId {
+        self.label_font_size
+            .map_or_else(egui::FontId::default, egui::FontId::proportional)
+    }
+
+    fn label_shift(&self) -> egui::Vec2 {
+        egui::vec2(self.label_offset.0, self.label_offset.1)
+    }
+
+    fn label_style(&self, color: egui::Color32) -> LabelStyle {
+        LabelStyle {
+            color,
+            font_id: self.font_id(),
+            shift: self.label_shift(),
+        }
+    }
+
+    /// before から after で値が変わった項目だけを適用する（ほかの項目はそのまま）
+    fn apply_changes(&mut self, before: &MeasurementStyle, after: &MeasurementStyle) {
+        if before.stroke_color != after.stroke_color {
+            self.stroke_color = after.stroke_color;
+        }
+        if before.stroke_width != after.stroke_width {
+            self.stroke_width = after.stroke_width;
+        }
+        if before.point_radius != after.point_radius {
+            self.point_radius = after.point_radius;
+        }
+        if before.label_font_size != after.label_font_size {
+            self.label_font_size = after.label_font_size;
+        }
+        if before.label_offset.0 != after.label_offset.0 {
+            self.label_offset.0 = after.label_offset.0;
+        }
+        if before.label_offset.1 != after.label_offset.1 {
+            self.label_offset.1 = after.label_offset.1;
+        }
+    }
+}
+
+/// 寸法ラベルの描き方（文字色・文字の大きさ・既定の位置からのずれ）
+struct LabelStyle {
+    color: egui::Color32,
+    font_id: egui::FontId,
+    shift: egui::Vec2,
+}
+
+impl LabelStyle {
+    /// 個別のスタイルを持たないラベル（プレビューなど）
+    fn plain(color: egui::Color32) -> Self {
+        MeasurementStyle::default().label_style(color)
+    }
+
+    fn text(&self, painter: &egui::Painter, pos: egui::Pos2, anchor: egui::Align2, text: String) {
+        painter.text(
+            pos + self.shift,
+            anchor,
+            text,
+            self.font_id.clone(),
+            self.color,
+        );
+    }
+}
+
+/// 種類によらず選択・レイヤー・表示スタイルを扱うための測定の共通部分
+trait MeasurementItem {
+    fn id(&self) -> u64;
+    fn layer_id(&self) -> u64;
+    fn set_layer_id(&mut self, layer: u64);
+    fn style(&self) -> &MeasurementStyle;
+    fn style_mut(&mut self) -> &mut MeasurementStyle;
+    /// 選択したときの参照
+    fn selection(&self) -> Selection;
+    /// 同じ測定IDの測定をこの測定で置き換える操作
+    fn replace_action(&self) -> Action;
+    /// クリックでの選択・範囲選択・選択中の強調表示に使う輪郭（画像座標の折れ線。閉じた図形は始点に戻る）
+    fn outline(&self) -> Vec<Vec<egui::Pos2>>;
+}
+
+/// 測定の型と、選択・置き換えの操作の対応（共通部分の実装）
+macro_rules! impl_measurement_item {
+    ($($item:ty => $selection:ident, $replace:ident;)*) => {
+        $(
+            impl MeasurementItem for $item {
+                fn id(&self) -> u64 {
+                    self.id
+                }
+
+                fn layer_id(&self) -> u64 {
+                    self.layer
+                }
+
+                fn set_layer_id(&mut self, layer: u64) {
+                    self.layer = layer;
+                }
+
+                fn style(&self) -> &MeasurementStyle {
+                    &self.style
+                }
+
+                fn style_mut(&mut self) -> &mut MeasurementStyle {
+                    &mut self.style
+                }
+
+                fn selection(&self) -> Selection {
+                    Selection::$selection(self.id)
+                }
+
+                fn replace_action(&self) -> Action {
+                    Action::$replace(self.clone())
+                }
+
+                fn outline(&self) -> Vec<Vec<egui::Pos2>> {
+                    self.selection_outline()
+                }
+            }
+        )*
+    };
+}
+
+impl_measurement_item! {
+    Measurement => Line, ReplaceLine;
+    RectangleMeasurement => Rect, ReplaceRect;
+    AngleMeasurement => Angle, ReplaceAngle;
+    PolylineMeasurement => Polyline, ReplacePolyline;
+    PolygonMeasurement => Polygon, ReplacePolygon;
+    CircleMeasurement => Circle, ReplaceCircle;
+    EllipseMeasurement => Ellipse, ReplaceEllipse;
+    RotatedRectangleMeasurement => RotatedRect, ReplaceRotatedRect;
+    PointToLineMeasurement => PointToLine, ReplacePointToLine;
+    ParallelGapMeasurement => ParallelGap, ReplaceParallelGap;
+    Marker => Marker, ReplaceMarker;
+    RegionMeasurement => Region, ReplaceRegion;
+    BlobMeasurement => Blob, ReplaceBlob;
+}
+
+/// 始点に戻って閉じた折れ線
+fn closed_outline(points: impl IntoIterator<Item = egui::Pos2>) -> Vec<egui::Pos2> {
+    let mut points: Vec<egui::Pos2> = points.into_iter().collect();
+    if let Some(&first) = points.first() {
+        points.push(first);
+    }
+    points
 }
 
 /// 矩形測定結果
 #[derive(Clone, Serialize, Deserialize)]
 struct RectangleMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
     corner1: (f32, f32),
     corner2: (f32, f32),
     width_px: f32,
     height_px: f32,
     area_px: f32,
+    /// 名前（空なら「矩#N」と表示）
+    #[serde(default)]
+    name: String,
+    /// メモ
+    #[serde(default)]
+    note: String,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
 }
 
 impl RectangleMeasurement {
@@ -87,11 +500,28 @@ impl RectangleMeasurement {
         let width_px = (corner2.x - corner1.x).abs();
         let height_px = (corner2.y - corner1.y).abs();
         Self {
+            id: 0,
             corner1: (corner1.x, corner1.y),
             corner2: (corner2.x, corner2.y),
             width_px,
             height_px,
             area_px: width_px * height_px,
+            name: String::new(),
+            note: String::new(),
+            layer: 0,
+            style: MeasurementStyle::default(),
+        }
+    }
+
+    /// 名前・メモ・レイヤー・スタイルを保ったまま角を置き換える
+    fn with_corners(&self, corner1: egui::Pos2, corner2: egui::Pos2) -> Self {
+        Self {
+            id: self.id,
+            name: self.name.clone(),
+            note: self.note.clone(),
+            layer: self.layer,
+            style: self.style.clone(),
+            ..Self::new(corner1, corner2)
         }
     }
 
@@ -109,6 +539,12 @@ impl RectangleMeasurement {
         )
     }
 
+    /// 4つの角（左上から時計回り）
+    fn corners(&self) -> [egui::Pos2; 4] {
+        let (min, max) = (self.min_corner(), self.max_corner());
+        [min, egui::pos2(max.x, min.y), max, egui::pos2(min.x, max.y)]
+    }
+
     fn dimensions_with_calibration(
         &self,
         calibration: Option<&Calibration>,
@@ -128,2058 +564,10825 @@ impl RectangleMeasurement {
             ),
         }
     }
-}
-
-/// キャリブレーション設定
-#[derive(Clone, Serialize, Deserialize)]
-struct Calibration {
-    pixels_per_unit: f32,
-    unit_name: String,
-}
 
-/// Undo/Redo 用の操作ログ
-#[derive(Clone)]
-enum Action {
-    AddLine(Measurement),
-    AddRect(RectangleMeasurement),
-    RemoveLine(usize),
-    RemoveRect(usize),
-    SetCalibration(Option<Calibration>),
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![closed_outline(self.corners())]
+    }
 }
 
-/// ログベースの履歴管理
-#[derive(Default)]
-struct History {
-    actions: Vec<Action>,
-    cursor: usize,
+/// 角度測定結果（頂点と2本の辺上の点）
+#[derive(Clone, Serialize, Deserialize)]
+struct AngleMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    vertex: (f32, f32),
+    arm1: (f32, f32),
+    arm2: (f32, f32),
+    angle_deg: f32,
 }
 
-impl History {
-    fn push_action(&mut self, action: Action) {
-        if self.cursor < self.actions.len() {
-            self.actions.truncate(self.cursor);
+impl AngleMeasurement {
+    fn new(vertex: egui::Pos2, arm1: egui::Pos2, arm2: egui::Pos2) -> Self {
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            vertex: (vertex.x, vertex.y),
+            arm1: (arm1.x, arm1.y),
+            arm2: (arm2.x, arm2.y),
+            angle_deg: included_angle_deg(vertex, arm1, arm2),
         }
-        self.actions.push(action);
-        self.cursor = self.actions.len();
     }
 
-    fn can_undo(&self) -> bool {
-        self.cursor > 0
+    fn vertex_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.vertex.0, self.vertex.1)
     }
 
-    fn can_redo(&self) -> bool {
-        self.cursor < self.actions.len()
+    fn arm1_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.arm1.0, self.arm1.1)
     }
 
-    fn undo(&mut self) -> bool {
-        if self.can_undo() {
-            self.cursor -= 1;
-            true
-        } else {
-            false
-        }
+    fn arm2_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.arm2.0, self.arm2.1)
     }
 
-    fn redo(&mut self) -> bool {
-        if self.can_redo() {
-            self.cursor += 1;
-            true
-        } else {
-            false
-        }
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![vec![self.arm1_pos(), self.vertex_pos(), self.arm2_pos()]]
     }
+}
 
-    fn rebuild_state(
-        &self,
-    ) -> (
-        Vec<Measurement>,
-        Vec<RectangleMeasurement>,
-        Option<Calibration>,
-    ) {
-        let mut measurements = Vec::new();
-        let mut rectangle_measurements = Vec::new();
-        let mut calibration = None;
-
-        for action in self.actions.iter().take(self.cursor) {
-            match action {
-                Action::AddLine(m) => measurements.push(m.clone()),
-                Action::AddRect(r) => rectangle_measurements.push(r.clone()),
-                Action::RemoveLine(index) => {
-                    if *index < measurements.len() {
-                        measurements.remove(*index);
-                    }
-                }
-                Action::RemoveRect(index) => {
-                    if *index < rectangle_measurements.len() {
-                        rectangle_measurements.remove(*index);
-                    }
-                }
-                Action::SetCalibration(cal) => {
-                    calibration = cal.clone();
-                }
-            }
+/// 折れ線測定結果
+#[derive(Clone, Serialize, Deserialize)]
+struct PolylineMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    points: Vec<(f32, f32)>,
+    total_length_px: f32,
+}
+
+impl PolylineMeasurement {
+    fn new(points: &[egui::Pos2]) -> Self {
+        let total_length_px = points.windows(2).map(|w| w[0].distance(w[1])).sum();
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            points: points.iter().map(|p| (p.x, p.y)).collect(),
+            total_length_px,
         }
+    }
 
-        (measurements, rectangle_measurements, calibration)
+    fn point_positions(&self) -> Vec<egui::Pos2> {
+        self.points.iter().map(|&(x, y)| egui::pos2(x, y)).collect()
     }
 
-    fn reset_with_calibration(&mut self, calibration: Option<Calibration>) {
-        self.actions.clear();
-        self.cursor = 0;
-        if let Some(cal) = calibration {
-            self.actions.push(Action::SetCalibration(Some(cal)));
-            self.cursor = self.actions.len();
-        }
+    /// 各区間を線分測定として取り出す
+    fn segments(&self) -> Vec<Measurement> {
+        self.point_positions()
+            .windows(2)
+            .map(|w| Measurement::new(w[0], w[1]))
+            .collect()
     }
-}
 
-/// エクスポート用のデータ構造
-#[derive(Serialize)]
-struct ExportData {
-    calibration: Option<Calibration>,
-    measurements: Vec<ExportMeasurement>,
-    rectangle_measurements: Vec<ExportRectangleMeasurement>,
-}
+    /// 区間ごとの長さと合計長を返す
+    fn lengths_with_calibration(
+        &self,
+        calibration: Option<&Calibration>,
+    ) -> (Vec<f32>, f32, String) {
+        let segment_lengths: Vec<f32> = self
+            .segments()
+            .iter()
+            .map(|segment| segment.distance_with_calibration(calibration).0)
+            .collect();
+        let total = segment_lengths.iter().sum();
+        let unit = match calibration {
+            Some(cal) => cal.unit_name.clone(),
+            None => "px".to_string(),
+        };
+        (segment_lengths, total, unit)
+    }
 
-#[derive(Serialize)]
-struct ExportMeasurement {
-    id: usize,
-    start_x: f32,
-    start_y: f32,
-    end_x: f32,
-    end_y: f32,
-    distance_px: f32,
-    distance_calibrated: Option<f32>,
-    unit: String,
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![self.point_positions()]
+    }
 }
 
-#[derive(Serialize)]
-struct ExportRectangleMeasurement {
-    id: usize,
-    corner1_x: f32,
-    corner1_y: f32,
-    corner2_x: f32,
-    corner2_y: f32,
-    width_px: f32,
-    height_px: f32,
+/// 多角形測定結果
+#[derive(Clone, Serialize, Deserialize)]
+struct PolygonMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    points: Vec<(f32, f32)>,
     area_px: f32,
-    width_calibrated: Option<f32>,
-    height_calibrated: Option<f32>,
-    area_calibrated: Option<f32>,
-    unit: String,
+    perimeter_px: f32,
+    is_convex: bool,
+    is_self_intersecting: bool,
 }
 
-/// 線分の終点をスナップ角度に合わせて調整する
-/// start: 始点, end: 終点（スナップ前）
-/// 戻り値: スナップ後の終点
-fn snap_to_angle(start: egui::Pos2, end: egui::Pos2) -> egui::Pos2 {
-    let delta = end - start;
-    let distance = delta.length();
-    if distance < 0.001 {
-        return end;
+impl PolygonMeasurement {
+    fn new(points: &[egui::Pos2]) -> Self {
+        let is_self_intersecting = polygon_is_self_intersecting(points);
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            points: points.iter().map(|p| (p.x, p.y)).collect(),
+            area_px: polygon_signed_area(points).abs(),
+            perimeter_px: polygon_perimeter(points),
+            is_convex: !is_self_intersecting && polygon_is_convex(points),
+            is_self_intersecting,
+        }
     }
 
-    // 角度を計算（ラジアン→度）
-    let angle_rad = delta.y.atan2(delta.x);
-    let angle_deg = angle_rad.to_degrees();
+    fn point_positions(&self) -> Vec<egui::Pos2> {
+        self.points.iter().map(|&(x, y)| egui::pos2(x, y)).collect()
+    }
 
-    // 0, 90, 180, -180, -90 にスナップ
-    let snap_angles = [0.0_f32, 90.0, 180.0, -180.0, -90.0];
+    /// 面積・周長を返す
+    fn dimensions_with_calibration(&self, calibration: Option<&Calibration>) -> (f32, f32, String) {
+        match calibration {
+            Some(cal) => (
+                self.area_px / (cal.pixels_per_unit * cal.pixels_per_unit),
+                self.perimeter_px / cal.pixels_per_unit,
+                cal.unit_name.clone(),
+            ),
+            None => (self.area_px, self.perimeter_px, "px".to_string()),
+        }
+    }
 
-    for &snap_angle in &snap_angles {
-        let diff = (angle_deg - snap_angle).abs();
-        if diff <= SNAP_ANGLE_TOLERANCE_DEG {
-            let snapped_rad = snap_angle.to_radians();
-            return egui::pos2(
-                start.x + distance * snapped_rad.cos(),
-                start.y + distance * snapped_rad.sin(),
-            );
+    /// 形状の種類（自己交差の場合、面積は参考値）
+    fn shape_label(&self) -> &'static str {
+        if self.is_self_intersecting {
+            "自己交差"
+        } else if self.is_convex {
+            "凸"
+        } else {
+            "非凸"
         }
     }
 
-    end // スナップしない場合はそのまま
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![closed_outline(self.point_positions())]
+    }
 }
 
-/// 長さを指定した倍数にスナップする
-/// length: 元の長さ, multiple: 倍数（0以下で無効）
-/// 戻り値: スナップ後の長さ
-fn snap_length_to_multiple(length: f32, multiple: f32) -> f32 {
-    if multiple <= 0.0 {
-        return length;
-    }
-    (length / multiple).round() * multiple
+/// 円測定結果
+#[derive(Clone, Serialize, Deserialize)]
+struct CircleMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    center: (f32, f32),
+    radius_px: f32,
 }
 
-/// 線分の終点を長さが倍数になるように調整する
-fn snap_line_length(start: egui::Pos2, end: egui::Pos2, multiple: f32) -> egui::Pos2 {
-    if multiple <= 0.0 {
-        return end;
+impl CircleMeasurement {
+    fn new(center: egui::Pos2, radius_px: f32) -> Self {
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            center: (center.x, center.y),
+            radius_px,
+        }
     }
-    let delta = end - start;
-    let distance = delta.length();
-    if distance < 0.001 {
-        return end;
+
+    /// 円周上の3点を通る円（3点が一直線上にある場合は None）
+    fn from_three_points(a: egui::Pos2, b: egui::Pos2, c: egui::Pos2) -> Option<Self> {
+        circumcircle(a, b, c).map(|(center, radius)| Self::new(center, radius))
     }
-    let snapped_distance = snap_length_to_multiple(distance, multiple);
-    let direction = delta / distance;
-    start + direction * snapped_distance
-}
 
-/// 矩形の対角点を幅・高さが倍数になるように調整する
-fn snap_rect_dimensions(corner1: egui::Pos2, corner2: egui::Pos2, multiple: f32) -> egui::Pos2 {
-    if multiple <= 0.0 {
-        return corner2;
+    fn center_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.center.0, self.center.1)
+    }
+
+    fn diameter_px(&self) -> f32 {
+        self.radius_px * 2.0
+    }
+
+    fn circumference_px(&self) -> f32 {
+        std::f32::consts::TAU * self.radius_px
+    }
+
+    fn area_px(&self) -> f32 {
+        std::f32::consts::PI * self.radius_px * self.radius_px
+    }
+
+    /// 半径・直径・円周・面積を返す
+    fn dimensions_with_calibration(
+        &self,
+        calibration: Option<&Calibration>,
+    ) -> (f32, f32, f32, f32, String) {
+        match calibration {
+            Some(cal) => (
+                self.radius_px / cal.pixels_per_unit,
+                self.diameter_px() / cal.pixels_per_unit,
+                self.circumference_px() / cal.pixels_per_unit,
+                self.area_px() / (cal.pixels_per_unit * cal.pixels_per_unit),
+                cal.unit_name.clone(),
+            ),
+            None => (
+                self.radius_px,
+                self.diameter_px(),
+                self.circumference_px(),
+                self.area_px(),
+                "px".to_string(),
+            ),
+        }
+    }
+
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        let center = self.center_pos();
+        vec![closed_outline((0..64).map(|i| {
+            center + egui::Vec2::angled(std::f32::consts::TAU * i as f32 / 64.0) * self.radius_px
+        }))]
     }
-    let dx = corner2.x - corner1.x;
-    let dy = corner2.y - corner1.y;
-    let snapped_width = snap_length_to_multiple(dx.abs(), multiple) * dx.signum();
-    let snapped_height = snap_length_to_multiple(dy.abs(), multiple) * dy.signum();
-    egui::pos2(corner1.x + snapped_width, corner1.y + snapped_height)
 }
 
-/// アプリケーション状態
-struct SampoApp {
-    image_texture: Option<egui::TextureHandle>,
-    image_dimensions: Option<(u32, u32)>,
-    image_path: Option<String>,
-    measurement_state: MeasurementState,
-    measurement_mode: MeasurementMode,
-    measurements: Vec<Measurement>,
-    rectangle_measurements: Vec<RectangleMeasurement>,
-    calibration: Option<Calibration>,
-    calibration_state: CalibrationState,
-    calibration_input: String,
-    calibration_unit: String,
-    zoom: f32,
-    is_calibrating: bool,
-    text_color: egui::Color32,
-    scroll_offset: egui::Vec2,
-    needs_scroll_reset: bool,
-    show_preview: bool,
-    current_mouse_image_pos: Option<egui::Pos2>,
-    is_ctrl_pressed: bool,
-    length_snap_multiple: f32,
-    history: History,
-    /// 起動時に読み込む画像パス（テスト用）
-    #[cfg(test)]
-    pending_image_path: Option<PathBuf>,
-    /// 起動時に追加する寸法（テスト用）
-    #[cfg(test)]
-    pending_measurements: Vec<(egui::Pos2, egui::Pos2)>,
-    /// Undo回数（テスト用）
-    #[cfg(test)]
-    pending_undo_count: u32,
-    /// Redo回数（テスト用）
-    #[cfg(test)]
-    pending_redo_count: u32,
-    /// デバッグ用マウス位置（テスト用）- 画像座標で指定
-    #[cfg(test)]
-    debug_mouse_position: Option<egui::Pos2>,
-    /// Ctrl押下をシミュレート（テスト用）
-    #[cfg(test)]
-    debug_ctrl_pressed: bool,
-    /// プレビュー表示用に測定の始点を選択状態にする（テスト用）
-    #[cfg(test)]
-    pending_first_point: Option<egui::Pos2>,
+/// 楕円測定結果（回転を含む）
+#[derive(Clone, Serialize, Deserialize)]
+struct EllipseMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    center: (f32, f32),
+    semi_major_px: f32,
+    semi_minor_px: f32,
+    /// 長軸の向き（度, 画像のx軸から時計回り, -90〜90）
+    angle_deg: f32,
 }
 
-impl Default for SampoApp {
-    fn default() -> Self {
+impl EllipseMeasurement {
+    /// 中心・長軸の端点・短軸方向の広がりを示す点から楕円を作る
+    /// 3点目の長軸からの距離が短半径になる（長軸より長い場合は軸を入れ替える）
+    fn from_axes(center: egui::Pos2, major_end: egui::Pos2, minor_point: egui::Pos2) -> Self {
+        let mut semi_major_px = center.distance(major_end);
+        let mut semi_minor_px =
+            minor_point.distance(perpendicular_foot(minor_point, center, major_end));
+        let mut angle_deg = (major_end - center).angle().to_degrees();
+        if semi_minor_px > semi_major_px {
+            std::mem::swap(&mut semi_major_px, &mut semi_minor_px);
+            angle_deg += 90.0;
+        }
         Self {
-            image_texture: None,
-            image_dimensions: None,
-            image_path: None,
-            measurement_state: MeasurementState::default(),
-            measurement_mode: MeasurementMode::default(),
-            measurements: Vec::new(),
-            rectangle_measurements: Vec::new(),
-            calibration: None,
-            calibration_state: CalibrationState::default(),
-            calibration_input: String::new(),
-            calibration_unit: "mm".to_string(),
-            zoom: 1.0,
-            is_calibrating: false,
-            text_color: egui::Color32::BLACK,
-            scroll_offset: egui::Vec2::ZERO,
-            needs_scroll_reset: false,
-            show_preview: true,
-            current_mouse_image_pos: None,
-            is_ctrl_pressed: false,
-            length_snap_multiple: 1.0,
-            history: History::default(),
-            #[cfg(test)]
-            pending_image_path: None,
-            #[cfg(test)]
-            pending_measurements: Vec::new(),
-            #[cfg(test)]
-            pending_undo_count: 0,
-            #[cfg(test)]
-            pending_redo_count: 0,
-            #[cfg(test)]
-            debug_mouse_position: None,
-            #[cfg(test)]
-            debug_ctrl_pressed: false,
-            #[cfg(test)]
-            pending_first_point: None,
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            center: (center.x, center.y),
+            semi_major_px,
+            semi_minor_px,
+            angle_deg: normalize_axis_angle_deg(angle_deg),
         }
     }
-}
-
-impl SampoApp {
-    fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        egui_extras::install_image_loaders(&cc.egui_ctx);
 
-        // NotoSans JP フォントを設定
-        let mut fonts = egui::FontDefinitions::default();
+    fn center_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.center.0, self.center.1)
+    }
 
-        fonts.font_data.insert(
-            "NotoSansJP".to_owned(),
-            std::sync::Arc::new(egui::FontData::from_static(include_bytes!(
-                "../assets/NotoSansJP-Regular.ttf"
-            ))),
-        );
+    fn area_px(&self) -> f32 {
+        std::f32::consts::PI * self.semi_major_px * self.semi_minor_px
+    }
 
-        // 日本語フォントを優先的に使用
-        fonts
-            .families
-            .entry(egui::FontFamily::Proportional)
-            .or_default()
-            .insert(0, "NotoSansJP".to_owned());
+    /// 周長（Ramanujan の近似式）
+    fn perimeter_px(&self) -> f32 {
+        let a = self.semi_major_px;
+        let b = self.semi_minor_px;
+        std::f32::consts::PI * (3.0 * (a + b) - ((3.0 * a + b) * (a + 3.0 * b)).sqrt())
+    }
 
-        fonts
-            .families
-            .entry(egui::FontFamily::Monospace)
-            .or_default()
-            .insert(0, "NotoSansJP".to_owned());
+    /// 楕円周上の点列（画像座標）
+    fn outline_points(&self, segments: usize) -> Vec<egui::Pos2> {
+        let major_dir = egui::Vec2::angled(self.angle_deg.to_radians());
+        let minor_dir = major_dir.rot90();
+        (0..segments)
+            .map(|i| {
+                let t = std::f32::consts::TAU * i as f32 / segments as f32;
+                self.center_pos()
+                    + major_dir * (self.semi_major_px * t.cos())
+                    + minor_dir * (self.semi_minor_px * t.sin())
+            })
+            .collect()
+    }
 
-        cc.egui_ctx.set_fonts(fonts);
+    /// 長半径・短半径・面積・周長を返す
+    fn dimensions_with_calibration(
+        &self,
+        calibration: Option<&Calibration>,
+    ) -> (f32, f32, f32, f32, String) {
+        match calibration {
+            Some(cal) => (
+                self.semi_major_px / cal.pixels_per_unit,
+                self.semi_minor_px / cal.pixels_per_unit,
+                self.area_px() / (cal.pixels_per_unit * cal.pixels_per_unit),
+                self.perimeter_px() / cal.pixels_per_unit,
+                cal.unit_name.clone(),
+            ),
+            None => (
+                self.semi_major_px,
+                self.semi_minor_px,
+                self.area_px(),
+                self.perimeter_px(),
+                "px".to_string(),
+            ),
+        }
+    }
 
-        Self::default()
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![closed_outline(self.outline_points(64))]
     }
+}
 
-    /// テスト用コンストラクタ：初期画像パスと寸法を指定可能
-    #[cfg(test)]
-    fn new_for_test(
-        cc: &eframe::CreationContext<'_>,
-        image_path: Option<PathBuf>,
-        measurements: Vec<(egui::Pos2, egui::Pos2)>,
-    ) -> Self {
-        Self::new_for_test_with_snap(cc, image_path, measurements, 0.0)
+/// 回転矩形測定結果（基準線と、それに垂直な高さで指定）
+#[derive(Clone, Serialize, Deserialize)]
+struct RotatedRectangleMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    base_start: (f32, f32),
+    base_end: (f32, f32),
+    /// 基準線からの垂直方向の符号付き高さ（基準線の向きを rot90 した側が正）
+    height_offset_px: f32,
+    width_px: f32,
+    height_px: f32,
+    /// 基準線の傾き（度, 画像のx軸から時計回り, -90〜90）
+    angle_deg: f32,
+    area_px: f32,
+}
+
+impl RotatedRectangleMeasurement {
+    fn new(base_start: egui::Pos2, base_end: egui::Pos2, height_offset_px: f32) -> Self {
+        let width_px = base_start.distance(base_end);
+        let height_px = height_offset_px.abs();
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            base_start: (base_start.x, base_start.y),
+            base_end: (base_end.x, base_end.y),
+            height_offset_px,
+            width_px,
+            height_px,
+            angle_deg: normalize_axis_angle_deg((base_end - base_start).angle().to_degrees()),
+            area_px: width_px * height_px,
+        }
     }
 
-    /// テスト用コンストラクタ（スナップ倍数を指定可能）
-    #[cfg(test)]
-    fn new_for_test_with_snap(
-        cc: &eframe::CreationContext<'_>,
-        image_path: Option<PathBuf>,
-        measurements: Vec<(egui::Pos2, egui::Pos2)>,
+    /// 基準線と、高さ方向を示す点から回転矩形を作る
+    /// 高さは点から基準線への垂線の長さを倍数にスナップした値
+    fn from_extent_point(
+        base_start: egui::Pos2,
+        base_end: egui::Pos2,
+        extent_point: egui::Pos2,
         length_snap_multiple: f32,
     ) -> Self {
-        egui_extras::install_image_loaders(&cc.egui_ctx);
-
-        // NotoSans JP フォントを設定（通常のnewと同様）
-        let mut fonts = egui::FontDefinitions::default();
-        fonts.font_data.insert(
-            "NotoSansJP".to_owned(),
-            std::sync::Arc::new(egui::FontData::from_static(include_bytes!(
-                "../assets/NotoSansJP-Regular.ttf"
-            ))),
-        );
-        fonts
-            .families
-            .entry(egui::FontFamily::Proportional)
-            .or_default()
-            .insert(0, "NotoSansJP".to_owned());
-        fonts
-            .families
-            .entry(egui::FontFamily::Monospace)
-            .or_default()
-            .insert(0, "NotoSansJP".to_owned());
-        cc.egui_ctx.set_fonts(fonts);
+        let normal = (base_end - base_start).normalized().rot90();
+        let offset = (extent_point - base_start).dot(normal);
+        let height = snap_length_to_multiple(offset.abs(), length_snap_multiple);
+        Self::new(base_start, base_end, height * offset.signum())
+    }
 
-        let mut app = Self::default();
-        app.pending_image_path = image_path;
-        app.pending_measurements = measurements;
-        app.length_snap_multiple = length_snap_multiple;
-        app
+    /// 4つの角（基準線の始点・終点、対辺の終点側・始点側の順）
+    fn corners(&self) -> [egui::Pos2; 4] {
+        let start = egui::pos2(self.base_start.0, self.base_start.1);
+        let end = egui::pos2(self.base_end.0, self.base_end.1);
+        let direction = (end - start).normalized();
+        let offset = direction.rot90() * self.height_offset_px;
+        [start, end, end + offset, start + offset]
     }
 
-    fn open_file_dialog(&mut self, ctx: &egui::Context) {
-        if let Some(path) = rfd::FileDialog::new()
-            .add_filter("Images", &["png", "jpg", "jpeg", "gif", "bmp", "webp"])
-            .pick_file()
-        {
-            self.load_image(ctx, &path);
+    fn dimensions_with_calibration(
+        &self,
+        calibration: Option<&Calibration>,
+    ) -> (f32, f32, f32, String) {
+        match calibration {
+            Some(cal) => (
+                self.width_px / cal.pixels_per_unit,
+                self.height_px / cal.pixels_per_unit,
+                self.area_px / (cal.pixels_per_unit * cal.pixels_per_unit),
+                cal.unit_name.clone(),
+            ),
+            None => (
+                self.width_px,
+                self.height_px,
+                self.area_px,
+                "px".to_string(),
+            ),
         }
     }
 
-    fn load_image(&mut self, ctx: &egui::Context, path: &PathBuf) {
-        match image::open(path) {
-            Ok(img) => {
-                let rgba = img.to_rgba8();
-                let dimensions = rgba.dimensions();
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![closed_outline(self.corners())]
+    }
+}
 
-                let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                    [dimensions.0 as usize, dimensions.1 as usize],
-                    rgba.as_raw(),
-                );
+/// 点と基準線の垂直距離の測定結果
+#[derive(Clone, Serialize, Deserialize)]
+struct PointToLineMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    point: (f32, f32),
+    line_start: (f32, f32),
+    line_end: (f32, f32),
+    distance_px: f32,
+}
 
-                let texture = ctx.load_texture(
-                    path.to_string_lossy(),
-                    color_image,
-                    egui::TextureOptions::LINEAR,
-                );
+impl PointToLineMeasurement {
+    /// 基準線は線分を延長した直線として扱う
+    fn new(point: egui::Pos2, line_start: egui::Pos2, line_end: egui::Pos2) -> Self {
+        let foot = perpendicular_foot(point, line_start, line_end);
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            point: (point.x, point.y),
+            line_start: (line_start.x, line_start.y),
+            line_end: (line_end.x, line_end.y),
+            distance_px: point.distance(foot),
+        }
+    }
 
-                self.image_texture = Some(texture);
-                self.image_dimensions = Some(dimensions);
-                self.image_path = Some(path.to_string_lossy().into_owned());
-                self.measurements.clear();
-                self.rectangle_measurements.clear();
-                self.measurement_state = MeasurementState::Idle;
-                self.calibration = None;
-                self.calibration_state = CalibrationState::Idle;
-                self.is_calibrating = false;
-                self.zoom = 1.0;
-                self.needs_scroll_reset = true;
-                self.history = History::default();
-            }
-            Err(e) => {
-                eprintln!("Failed to load image: {}", e);
-            }
-        }
+    fn point_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.point.0, self.point.1)
     }
 
-    fn load_image_from_rgba(
-        &mut self,
-        ctx: &egui::Context,
-        width: u32,
-        height: u32,
-        rgba_data: Vec<u8>,
-        source_name: &str,
-    ) {
-        let color_image =
-            egui::ColorImage::from_rgba_unmultiplied([width as usize, height as usize], &rgba_data);
-
-        let texture = ctx.load_texture(source_name, color_image, egui::TextureOptions::LINEAR);
+    fn line_start_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.line_start.0, self.line_start.1)
+    }
 
-        self.image_texture = Some(texture);
-        self.image_dimensions = Some((width, height));
-        self.image_path = Some(source_name.to_string());
-        self.measurements.clear();
-        self.rectangle_measurements.clear();
-        self.measurement_state = MeasurementState::Idle;
-        self.calibration = None;
-        self.calibration_state = CalibrationState::Idle;
-        self.is_calibrating = false;
-        self.zoom = 1.0;
-        self.needs_scroll_reset = true;
-        self.history = History::default();
+    fn line_end_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.line_end.0, self.line_end.1)
     }
 
-    fn paste_from_clipboard(&mut self, ctx: &egui::Context) {
-        match Clipboard::new() {
-            Ok(mut clipboard) => match clipboard.get_image() {
-                Ok(img_data) => {
-                    // arboard::ImageData の RGBA データを取得
-                    let width = img_data.width as u32;
-                    let height = img_data.height as u32;
-                    let rgba_data = img_data.bytes.into_owned();
+    /// 垂線の足
+    fn foot_pos(&self) -> egui::Pos2 {
+        perpendicular_foot(self.point_pos(), self.line_start_pos(), self.line_end_pos())
+    }
 
-                    self.load_image_from_rgba(
-                        ctx,
-                        width,
-                        height,
-                        rgba_data,
-                        "[クリップボードから貼り付け]",
-                    );
-                }
-                Err(e) => {
-                    eprintln!("クリップボードに画像がありません: {}", e);
-                }
-            },
-            Err(e) => {
-                eprintln!("クリップボードへのアクセスに失敗: {}", e);
-            }
+    fn distance_with_calibration(&self, calibration: Option<&Calibration>) -> (f32, String) {
+        match calibration {
+            Some(cal) => (
+                self.distance_px / cal.pixels_per_unit,
+                cal.unit_name.clone(),
+            ),
+            None => (self.distance_px, "px".to_string()),
         }
     }
 
-    fn screen_to_image(&self, screen_pos: egui::Pos2, image_rect: egui::Rect) -> egui::Pos2 {
-        if let Some((w, h)) = self.image_dimensions {
-            let normalized = (screen_pos - image_rect.min) / image_rect.size();
-            egui::pos2(normalized.x * w as f32, normalized.y * h as f32)
-        } else {
-            screen_pos
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![
+            vec![self.line_start_pos(), self.line_end_pos()],
+            vec![self.point_pos(), self.foot_pos()],
+        ]
+    }
+}
+
+/// 2本の線分の間隔（平行度）の測定結果
+/// 間隔は各線分の両端点から相手の線分を延長した直線までの垂直距離（4本）で評価する
+#[derive(Clone, Serialize, Deserialize)]
+struct ParallelGapMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    line1_start: (f32, f32),
+    line1_end: (f32, f32),
+    line2_start: (f32, f32),
+    line2_end: (f32, f32),
+    mean_gap_px: f32,
+    min_gap_px: f32,
+    max_gap_px: f32,
+    /// 2本の線分のなす角（度, 0〜90）
+    angle_deviation_deg: f32,
+}
+
+impl ParallelGapMeasurement {
+    fn new(line1: &Measurement, line2: &Measurement) -> Self {
+        let (a1, b1) = (line1.start_pos(), line1.end_pos());
+        let (a2, b2) = (line2.start_pos(), line2.end_pos());
+        let gaps = [
+            a1.distance(perpendicular_foot(a1, a2, b2)),
+            b1.distance(perpendicular_foot(b1, a2, b2)),
+            a2.distance(perpendicular_foot(a2, a1, b1)),
+            b2.distance(perpendicular_foot(b2, a1, b1)),
+        ];
+        let direction_diff = ((b2 - a2).angle() - (b1 - a1).angle()).to_degrees();
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            line1_start: line1.start,
+            line1_end: line1.end,
+            line2_start: line2.start,
+            line2_end: line2.end,
+            mean_gap_px: gaps.iter().sum::<f32>() / gaps.len() as f32,
+            min_gap_px: gaps.iter().copied().fold(f32::INFINITY, f32::min),
+            max_gap_px: gaps.iter().copied().fold(0.0, f32::max),
+            angle_deviation_deg: normalize_axis_angle_deg(direction_diff).abs(),
         }
     }
 
-    fn image_to_screen(&self, image_pos: egui::Pos2, image_rect: egui::Rect) -> egui::Pos2 {
-        if let Some((w, h)) = self.image_dimensions {
-            let normalized = egui::vec2(image_pos.x / w as f32, image_pos.y / h as f32);
-            image_rect.min + normalized * image_rect.size()
-        } else {
-            image_pos
+    fn line1(&self) -> Measurement {
+        Measurement::new(
+            egui::pos2(self.line1_start.0, self.line1_start.1),
+            egui::pos2(self.line1_end.0, self.line1_end.1),
+        )
+    }
+
+    fn line2(&self) -> Measurement {
+        Measurement::new(
+            egui::pos2(self.line2_start.0, self.line2_start.1),
+            egui::pos2(self.line2_end.0, self.line2_end.1),
+        )
+    }
+
+    /// 平均・最小・最大の間隔を返す
+    fn gaps_with_calibration(&self, calibration: Option<&Calibration>) -> (f32, f32, f32, String) {
+        match calibration {
+            Some(cal) => (
+                self.mean_gap_px / cal.pixels_per_unit,
+                self.min_gap_px / cal.pixels_per_unit,
+                self.max_gap_px / cal.pixels_per_unit,
+                cal.unit_name.clone(),
+            ),
+            None => (
+                self.mean_gap_px,
+                self.min_gap_px,
+                self.max_gap_px,
+                "px".to_string(),
+            ),
         }
     }
 
-    fn rebuild_from_history(&mut self) {
-        let (measurements, rectangle_measurements, calibration) = self.history.rebuild_state();
-        self.measurements = measurements;
-        self.rectangle_measurements = rectangle_measurements;
-        self.calibration = calibration;
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        [self.line1(), self.line2()]
+            .iter()
+            .map(|line| vec![line.start_pos(), line.end_pos()])
+            .collect()
     }
+}
 
-    fn handle_canvas_click(&mut self, click_pos: egui::Pos2, image_rect: egui::Rect) {
-        let image_pos = self.screen_to_image(click_pos, image_rect);
+/// 画素領域の測定結果（自動選択で選んだ領域）
+#[derive(Clone, Serialize, Deserialize)]
+struct RegionMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    /// 領域の画素数
+    area_px: f32,
+    /// 境界の長さ（領域の外周に沿った画素の辺の総延長）
+    perimeter_px: f32,
+    /// 外接矩形（画素の角の座標）
+    bbox_min: (f32, f32),
+    bbox_max: (f32, f32),
+    /// 重心（画素中心の平均）
+    centroid: (f32, f32),
+    /// 輪郭（閉じた折れ線, 穴の輪郭も含む）
+    contours: Vec<Vec<(f32, f32)>>,
+}
 
-        if self.is_calibrating {
-            match &self.calibration_state {
-                CalibrationState::Idle => {
-                    self.calibration_state = CalibrationState::FirstPointSelected(image_pos);
-                }
-                CalibrationState::FirstPointSelected(start) => {
-                    let start = *start;
-                    // 角度スナップ（Ctrl）
-                    let angle_snapped = if self.is_ctrl_pressed {
-                        snap_to_angle(start, image_pos)
-                    } else {
-                        image_pos
-                    };
-                    // 倍数スナップ
-                    let end_pos = snap_line_length(start, angle_snapped, self.length_snap_multiple);
-                    let distance_px = start.distance(end_pos);
-                    self.calibration_state = CalibrationState::WaitingForInput {
-                        start,
-                        end: end_pos,
-                        distance_px,
-                    };
+impl RegionMeasurement {
+    /// 画素の集合から領域の測定結果を作る（空の場合は None）
+    fn from_pixels(pixels: &[(u32, u32)]) -> Option<Self> {
+        if pixels.is_empty() {
+            return None;
+        }
+        let pixel_set: std::collections::HashSet<(i64, i64)> =
+            pixels.iter().map(|&(x, y)| (x as i64, y as i64)).collect();
+
+        let mut bbox_min = (f32::INFINITY, f32::INFINITY);
+        let mut bbox_max = (f32::NEG_INFINITY, f32::NEG_INFINITY);
+        let mut centroid_sum = (0.0_f64, 0.0_f64);
+        // 境界の画素辺（領域を右手に見る向き）を頂点ごとにまとめる
+        let mut edges: std::collections::BTreeMap<(i64, i64), Vec<(i64, i64)>> =
+            std::collections::BTreeMap::new();
+        let mut edge_count = 0;
+        for &(x, y) in &pixel_set {
+            bbox_min = (bbox_min.0.min(x as f32), bbox_min.1.min(y as f32));
+            bbox_max = (
+                bbox_max.0.max(x as f32 + 1.0),
+                bbox_max.1.max(y as f32 + 1.0),
+            );
+            centroid_sum.0 += x as f64 + 0.5;
+            centroid_sum.1 += y as f64 + 0.5;
+
+            let sides = [
+                ((x, y - 1), (x, y), (x + 1, y)),
+                ((x + 1, y), (x + 1, y), (x + 1, y + 1)),
+                ((x, y + 1), (x + 1, y + 1), (x, y + 1)),
+                ((x - 1, y), (x, y + 1), (x, y)),
+            ];
+            for (neighbor, from, to) in sides {
+                if !pixel_set.contains(&neighbor) {
+                    edges.entry(from).or_default().push(to);
+                    edge_count += 1;
                 }
-                CalibrationState::WaitingForInput { .. } => {}
             }
-        } else {
-            match &self.measurement_state {
-                MeasurementState::Idle => {
-                    self.measurement_state = MeasurementState::FirstPointSelected(image_pos);
+        }
+
+        // 画素辺をつないで閉じた輪郭にし、一直線上の頂点を省く
+        // たどり終えた頂点は取り除き、残っている先頭の頂点から次の輪郭をたどる
+        let mut contours = Vec::new();
+        while let Some((&start, _)) = edges.first_key_value() {
+            let mut contour = vec![start];
+            let mut current = start;
+            while let Some(ends) = edges.get_mut(&current) {
+                let next = ends.pop();
+                if ends.is_empty() {
+                    edges.remove(&current);
                 }
-                MeasurementState::FirstPointSelected(start) => {
-                    match self.measurement_mode {
-                        MeasurementMode::Line => {
-                            let angle_snapped = if self.is_ctrl_pressed {
-                                snap_to_angle(*start, image_pos)
-                            } else {
-                                image_pos
-                            };
-                            let end_pos =
-                                snap_line_length(*start, angle_snapped, self.length_snap_multiple);
-                            let measurement = Measurement::new(*start, end_pos);
-                            self.history.push_action(Action::AddLine(measurement));
-                            self.rebuild_from_history();
-                        }
-                        MeasurementMode::Rectangle => {
-                            let end_pos =
-                                snap_rect_dimensions(*start, image_pos, self.length_snap_multiple);
-                            let rect_measurement = RectangleMeasurement::new(*start, end_pos);
-                            self.history.push_action(Action::AddRect(rect_measurement));
-                            self.rebuild_from_history();
-                        }
-                    }
-                    self.measurement_state = MeasurementState::Idle;
+                let Some(next) = next else {
+                    break;
+                };
+                if next == start {
+                    break;
                 }
+                contour.push(next);
+                current = next;
             }
+            let n = contour.len();
+            let simplified: Vec<(f32, f32)> = (0..n)
+                .filter(|&i| {
+                    let (prev, p, next) =
+                        (contour[(i + n - 1) % n], contour[i], contour[(i + 1) % n]);
+                    (p.0 - prev.0, p.1 - prev.1) != (next.0 - p.0, next.1 - p.1)
+                })
+                .map(|i| (contour[i].0 as f32, contour[i].1 as f32))
+                .collect();
+            contours.push(simplified);
         }
-    }
 
-    fn draw_measurements(&self, painter: &egui::Painter, image_rect: egui::Rect) {
-        let line_color = egui::Color32::from_rgb(255, 100, 100);
-        let point_color = egui::Color32::from_rgb(100, 255, 100);
-        let stroke = egui::Stroke::new(2.0, line_color);
-        let point_radius = 5.0;
+        let count = pixel_set.len() as f64;
+        Some(Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            area_px: pixel_set.len() as f32,
+            perimeter_px: edge_count as f32,
+            bbox_min,
+            bbox_max,
+            centroid: (
+                (centroid_sum.0 / count) as f32,
+                (centroid_sum.1 / count) as f32,
+            ),
+            contours,
+        })
+    }
 
-        for measurement in &self.measurements {
-            let start_screen = self.image_to_screen(measurement.start_pos(), image_rect);
-            let end_screen = self.image_to_screen(measurement.end_pos(), image_rect);
+    fn centroid_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.centroid.0, self.centroid.1)
+    }
 
-            painter.line_segment([start_screen, end_screen], stroke);
-            painter.circle_filled(start_screen, point_radius, point_color);
-            painter.circle_filled(end_screen, point_radius, point_color);
+    /// 外接矩形の幅・高さ
+    fn bbox_size_px(&self) -> (f32, f32) {
+        (
+            self.bbox_max.0 - self.bbox_min.0,
+            self.bbox_max.1 - self.bbox_min.1,
+        )
+    }
 
-            let midpoint = start_screen + (end_screen - start_screen) * 0.5;
-            let (distance, unit) = measurement.distance_with_calibration(self.calibration.as_ref());
-            painter.text(
-                midpoint + egui::vec2(0.0, -15.0),
-                egui::Align2::CENTER_BOTTOM,
-                format!("{:.1} {}", distance, unit),
-                egui::FontId::default(),
-                self.text_color,
-            );
+    /// 面積・周長・外接矩形の幅・高さを返す
+    fn dimensions_with_calibration(
+        &self,
+        calibration: Option<&Calibration>,
+    ) -> (f32, f32, f32, f32, String) {
+        let (bbox_width, bbox_height) = self.bbox_size_px();
+        match calibration {
+            Some(cal) => (
+                self.area_px / (cal.pixels_per_unit * cal.pixels_per_unit),
+                self.perimeter_px / cal.pixels_per_unit,
+                bbox_width / cal.pixels_per_unit,
+                bbox_height / cal.pixels_per_unit,
+                cal.unit_name.clone(),
+            ),
+            None => (
+                self.area_px,
+                self.perimeter_px,
+                bbox_width,
+                bbox_height,
+                "px".to_string(),
+            ),
         }
+    }
 
-        // 矩形測定を描画
-        let rect_color = egui::Color32::from_rgb(100, 150, 255);
-        let rect_stroke = egui::Stroke::new(2.0, rect_color);
-
-        for rect_m in &self.rectangle_measurements {
-            let min_screen = self.image_to_screen(rect_m.min_corner(), image_rect);
-            let max_screen = self.image_to_screen(rect_m.max_corner(), image_rect);
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        self.contours
+            .iter()
+            .map(|contour| closed_outline(contour.iter().map(|&(x, y)| egui::pos2(x, y))))
+            .collect()
+    }
+}
 
-            // 4辺を描画
-            let top_left = min_screen;
-            let top_right = egui::pos2(max_screen.x, min_screen.y);
-            let bottom_left = egui::pos2(min_screen.x, max_screen.y);
-            let bottom_right = max_screen;
+/// 粒子解析で検出した粒子
+#[derive(Clone, Serialize, Deserialize)]
+struct BlobMeasurement {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    region: RegionMeasurement,
+    /// 外接矩形
+    bounding_rect: RectangleMeasurement,
+    /// 面積が等しい円の直径
+    equivalent_diameter_px: f32,
+}
 
-            painter.line_segment([top_left, top_right], rect_stroke);
-            painter.line_segment([top_right, bottom_right], rect_stroke);
-            painter.line_segment([bottom_right, bottom_left], rect_stroke);
-            painter.line_segment([bottom_left, top_left], rect_stroke);
+impl BlobMeasurement {
+    fn new(region: RegionMeasurement) -> Self {
+        let bounding_rect = RectangleMeasurement::new(
+            egui::pos2(region.bbox_min.0, region.bbox_min.1),
+            egui::pos2(region.bbox_max.0, region.bbox_max.1),
+        );
+        let equivalent_diameter_px = 2.0 * (region.area_px / std::f32::consts::PI).sqrt();
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            region,
+            bounding_rect,
+            equivalent_diameter_px,
+        }
+    }
 
-            // 4つの角に点を描画
-            painter.circle_filled(top_left, point_radius, point_color);
-            painter.circle_filled(top_right, point_radius, point_color);
-            painter.circle_filled(bottom_left, point_radius, point_color);
-            painter.circle_filled(bottom_right, point_radius, point_color);
+    /// 面積・相当径を返す
+    fn dimensions_with_calibration(&self, calibration: Option<&Calibration>) -> (f32, f32, String) {
+        let (area, _, _, _, unit) = self.region.dimensions_with_calibration(calibration);
+        let diameter = match calibration {
+            Some(cal) => self.equivalent_diameter_px / cal.pixels_per_unit,
+            None => self.equivalent_diameter_px,
+        };
+        (area, diameter, unit)
+    }
 
-            let (width, height, area, unit) =
-                rect_m.dimensions_with_calibration(self.calibration.as_ref());
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        self.region.selection_outline()
+    }
+}
 
-            // 幅ラベル（上辺の中央）
-            let width_pos = egui::pos2((top_left.x + top_right.x) / 2.0, top_left.y - 15.0);
-            painter.text(
-                width_pos,
-                egui::Align2::CENTER_BOTTOM,
-                format!("{:.1} {}", width, unit),
-                egui::FontId::default(),
-                self.text_color,
-            );
+/// 角度スナップの刻み
+#[derive(Default, PartialEq, Clone, Copy)]
+enum AngleSnapStep {
+    /// 水平・垂直のみ
+    #[default]
+    Deg90,
+    Deg45,
+    Deg30,
+    Deg15,
+    /// 任意の角度の一覧
+    Custom,
+}
 
-            // 高さラベル（左辺の中央）
-            let height_pos = egui::pos2(top_left.x - 10.0, (top_left.y + bottom_left.y) / 2.0);
-            painter.text(
-                height_pos,
-                egui::Align2::RIGHT_CENTER,
-                format!("{:.1} {}", height, unit),
-                egui::FontId::default(),
-                self.text_color,
-            );
+impl AngleSnapStep {
+    const ALL: [AngleSnapStep; 5] = [
+        AngleSnapStep::Deg90,
+        AngleSnapStep::Deg45,
+        AngleSnapStep::Deg30,
+        AngleSnapStep::Deg15,
+        AngleSnapStep::Custom,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            AngleSnapStep::Deg90 => "90°（水平/垂直）",
+            AngleSnapStep::Deg45 => "45°",
+            AngleSnapStep::Deg30 => "30°",
+            AngleSnapStep::Deg15 => "15°",
+            AngleSnapStep::Custom => "任意の角度",
+        }
+    }
 
-            // 面積ラベル（中央）
-            let area_unit = if unit == "px" {
-                "px²".to_string()
-            } else {
-                format!("{}²", unit)
-            };
-            let center = egui::pos2(
-                (top_left.x + bottom_right.x) / 2.0,
-                (top_left.y + bottom_right.y) / 2.0,
-            );
-            painter.text(
-                center,
-                egui::Align2::CENTER_CENTER,
-                format!("{:.1} {}", area, area_unit),
-                egui::FontId::default(),
-                self.text_color,
-            );
+    fn step_deg(self) -> Option<f32> {
+        match self {
+            AngleSnapStep::Deg90 => Some(90.0),
+            AngleSnapStep::Deg45 => Some(45.0),
+            AngleSnapStep::Deg30 => Some(30.0),
+            AngleSnapStep::Deg15 => Some(15.0),
+            AngleSnapStep::Custom => None,
         }
+    }
+}
 
-        // 測定中の線を描画
-        if let MeasurementState::FirstPointSelected(start) = &self.measurement_state {
-            let start_screen = self.image_to_screen(*start, image_rect);
-            painter.circle_filled(start_screen, point_radius, egui::Color32::YELLOW);
+/// 矩形の縦横比を固定するときの比
+#[derive(Default, PartialEq, Clone, Copy)]
+enum RectAspect {
+    /// 正方形
+    #[default]
+    Square,
+    /// 指定した幅:高さ
+    Ratio,
+}
 
-            // プレビュー描画
-            if self.show_preview {
-                if let Some(mouse_pos) = self.current_mouse_image_pos {
-                    let preview_color = egui::Color32::from_rgba_unmultiplied(255, 255, 0, 150);
-                    let preview_stroke = egui::Stroke::new(1.5, preview_color);
+/// 点のスナップ先の種類（スナップ先に表示する印の形を決める）
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SnapKind {
+    /// 線分の端点
+    Endpoint,
+    /// 線分の中点
+    Midpoint,
+    /// 矩形の角
+    Corner,
+    /// 線分どうしの交点
+    Intersection,
+    /// 画像のエッジ
+    Edge,
+}
 
-                    match self.measurement_mode {
-                        MeasurementMode::Line => {
-                            // 角度スナップ適用（Ctrl）
-                            let angle_snapped = if self.is_ctrl_pressed {
-                                snap_to_angle(*start, mouse_pos)
-                            } else {
-                                mouse_pos
-                            };
-                            // 倍数スナップ適用
-                            let effective_mouse_pos =
-                                snap_line_length(*start, angle_snapped, self.length_snap_multiple);
-                            let effective_mouse_screen =
-                                self.image_to_screen(effective_mouse_pos, image_rect);
-
-                            // 線分のプレビュー
-                            painter.line_segment(
-                                [start_screen, effective_mouse_screen],
-                                preview_stroke,
-                            );
-                            painter.circle_filled(
-                                effective_mouse_screen,
-                                point_radius * 0.7,
-                                preview_color,
-                            );
+/// 粒子解析の二値化のしきい値の決め方
+#[derive(Default, PartialEq, Clone, Copy)]
+enum ThresholdMethod {
+    /// 判別分析法（大津の方法）で自動的に決める
+    #[default]
+    Otsu,
+    Manual,
+}
 
-                            // 距離のプレビュー表示
-                            let distance_px = start.distance(effective_mouse_pos);
-                            let (distance, unit) = match &self.calibration {
-                                Some(cal) => {
-                                    (distance_px / cal.pixels_per_unit, cal.unit_name.clone())
-                                }
-                                None => (distance_px, "px".to_string()),
-                            };
-                            let midpoint =
-                                start_screen + (effective_mouse_screen - start_screen) * 0.5;
-                            painter.text(
-                                midpoint + egui::vec2(0.0, -15.0),
-                                egui::Align2::CENTER_BOTTOM,
-                                format!("{:.1} {}", distance, unit),
-                                egui::FontId::default(),
-                                self.text_color,
-                            );
-                        }
-                        MeasurementMode::Rectangle => {
-                            // 倍数スナップ適用
-                            let effective_mouse_pos =
-                                snap_rect_dimensions(*start, mouse_pos, self.length_snap_multiple);
-                            let effective_mouse_screen =
-                                self.image_to_screen(effective_mouse_pos, image_rect);
+/// マーカー（計数用の点）
+#[derive(Clone, Serialize, Deserialize)]
+struct Marker {
+    /// 測定ID
+    #[serde(default)]
+    id: u64,
+    /// 所属するレイヤーのID（0 は既定のレイヤー）
+    #[serde(default)]
+    layer: u64,
+    /// 表示スタイルの個別設定
+    #[serde(default)]
+    style: MeasurementStyle,
+    position: (f32, f32),
+    /// カテゴリ名
+    category: String,
+}
 
-                            // 矩形のプレビュー
-                            let min_x = start_screen.x.min(effective_mouse_screen.x);
-                            let max_x = start_screen.x.max(effective_mouse_screen.x);
-                            let min_y = start_screen.y.min(effective_mouse_screen.y);
-                            let max_y = start_screen.y.max(effective_mouse_screen.y);
+impl Marker {
+    fn new(position: egui::Pos2, category: &str) -> Self {
+        Self {
+            id: 0,
+            layer: 0,
+            style: MeasurementStyle::default(),
+            position: (position.x, position.y),
+            category: category.to_string(),
+        }
+    }
 
-                            let top_left = egui::pos2(min_x, min_y);
-                            let top_right = egui::pos2(max_x, min_y);
-                            let bottom_left = egui::pos2(min_x, max_y);
-                            let bottom_right = egui::pos2(max_x, max_y);
+    fn position_pos(&self) -> egui::Pos2 {
+        egui::pos2(self.position.0, self.position.1)
+    }
 
-                            painter.line_segment([top_left, top_right], preview_stroke);
-                            painter.line_segment([top_right, bottom_right], preview_stroke);
-                            painter.line_segment([bottom_right, bottom_left], preview_stroke);
-                            painter.line_segment([bottom_left, top_left], preview_stroke);
+    fn selection_outline(&self) -> Vec<Vec<egui::Pos2>> {
+        vec![vec![self.position_pos()]]
+    }
+}
 
-                            painter.circle_filled(top_left, point_radius * 0.7, preview_color);
-                            painter.circle_filled(top_right, point_radius * 0.7, preview_color);
-                            painter.circle_filled(bottom_left, point_radius * 0.7, preview_color);
-                            painter.circle_filled(bottom_right, point_radius * 0.7, preview_color);
+/// マーカーのカテゴリ（名前と表示色）
+struct MarkerCategory {
+    name: String,
+    color: egui::Color32,
+}
 
-                            // 寸法のプレビュー表示
-                            let width_px = (effective_mouse_pos.x - start.x).abs();
-                            let height_px = (effective_mouse_pos.y - start.y).abs();
-                            let area_px = width_px * height_px;
+/// 新しいカテゴリに順に割り当てる色
+const MARKER_CATEGORY_COLORS: [egui::Color32; 6] = [
+    egui::Color32::from_rgb(255, 80, 200),
+    egui::Color32::from_rgb(80, 220, 255),
+    egui::Color32::from_rgb(255, 200, 0),
+    egui::Color32::from_rgb(120, 255, 120),
+    egui::Color32::from_rgb(255, 120, 60),
+    egui::Color32::from_rgb(170, 130, 255),
+];
+
+/// 測定をまとめるレイヤー（表示色と表示・非表示）
+struct Layer {
+    /// 測定から参照するレイヤーのID（既定のレイヤーは 0）
+    id: u64,
+    name: String,
+    /// 線の色（None なら測定の種類ごとの既定の色）
+    line_color: Option<egui::Color32>,
+    /// 文字色（None なら表示設定の寸法文字色）
+    label_color: Option<egui::Color32>,
+    visible: bool,
+}
 
-                            let (width, height, area, unit) = match &self.calibration {
-                                Some(cal) => {
-                                    let w = width_px / cal.pixels_per_unit;
-                                    let h = height_px / cal.pixels_per_unit;
-                                    let a = area_px / (cal.pixels_per_unit * cal.pixels_per_unit);
-                                    (w, h, a, cal.unit_name.clone())
-                                }
-                                None => (width_px, height_px, area_px, "px".to_string()),
-                            };
+/// 線分測定の既定の線の色
+const LINE_COLOR: egui::Color32 = egui::Color32::from_rgb(255, 100, 100);
+/// 矩形測定の既定の線の色
+const RECT_COLOR: egui::Color32 = egui::Color32::from_rgb(100, 150, 255);
+/// 角度測定の既定の線の色（以下、測定の種類ごとの既定の線の色）
+const ANGLE_COLOR: egui::Color32 = egui::Color32::from_rgb(255, 170, 60);
+const POLYLINE_COLOR: egui::Color32 = egui::Color32::from_rgb(220, 100, 255);
+const CIRCLE_COLOR: egui::Color32 = egui::Color32::from_rgb(120, 220, 120);
+const ROTATED_RECT_COLOR: egui::Color32 = egui::Color32::from_rgb(150, 130, 255);
+const POINT_TO_LINE_COLOR: egui::Color32 = egui::Color32::from_rgb(255, 220, 80);
+const PARALLEL_GAP_COLOR: egui::Color32 = egui::Color32::from_rgb(80, 170, 255);
+const REGION_COLOR: egui::Color32 = egui::Color32::from_rgb(255, 140, 0);
+const BLOB_COLOR: egui::Color32 = egui::Color32::from_rgb(0, 230, 180);
+const ELLIPSE_COLOR: egui::Color32 = egui::Color32::from_rgb(240, 120, 170);
+const POLYGON_COLOR: egui::Color32 = egui::Color32::from_rgb(80, 200, 200);
+
+/// 新しいレイヤーに順に割り当てる線の色
+const LAYER_LINE_COLORS: [egui::Color32; 6] = [
+    egui::Color32::from_rgb(255, 100, 100),
+    egui::Color32::from_rgb(100, 150, 255),
+    egui::Color32::from_rgb(255, 170, 60),
+    egui::Color32::from_rgb(120, 220, 120),
+    egui::Color32::from_rgb(220, 100, 255),
+    egui::Color32::from_rgb(0, 200, 200),
+];
 
-                            // 幅ラベル
-                            let width_pos =
-                                egui::pos2((top_left.x + top_right.x) / 2.0, min_y - 15.0);
-                            painter.text(
-                                width_pos,
-                                egui::Align2::CENTER_BOTTOM,
-                                format!("{:.1} {}", width, unit),
-                                egui::FontId::default(),
-                                self.text_color,
-                            );
+/// キャリブレーション設定
+#[derive(Clone, Serialize, Deserialize)]
+struct Calibration {
+    pixels_per_unit: f32,
+    unit_name: String,
+}
 
-                            // 高さラベル
-                            let height_pos = egui::pos2(min_x - 10.0, (min_y + max_y) / 2.0);
-                            painter.text(
-                                height_pos,
-                                egui::Align2::RIGHT_CENTER,
-                                format!("{:.1} {}", height, unit),
-                                egui::FontId::default(),
-                                self.text_color,
-                            );
+/// Undo/Redo 用の操作ログ
+#[derive(Clone)]
+enum Action {
+    AddLine(Measurement),
+    AddRect(RectangleMeasurement),
+    AddAngle(AngleMeasurement),
+    AddPolyline(PolylineMeasurement),
+    AddPolygon(PolygonMeasurement),
+    AddCircle(CircleMeasurement),
+    AddEllipse(EllipseMeasurement),
+    AddRotatedRect(RotatedRectangleMeasurement),
+    AddPointToLine(PointToLineMeasurement),
+    AddParallelGap(ParallelGapMeasurement),
+    AddMarker(Marker),
+    AddRegion(RegionMeasurement),
+    /// 粒子解析の結果（検出した粒子をまとめて1つの操作として追加）
+    AddBlobs(Vec<BlobMeasurement>),
+    /// 測定IDで指定した線分を削除（以下の Remove* も同様に測定IDで指定する）
+    RemoveLine(u64),
+    RemoveRect(u64),
+    RemoveAngle(u64),
+    RemovePolyline(u64),
+    RemovePolygon(u64),
+    RemoveCircle(u64),
+    RemoveEllipse(u64),
+    RemoveRotatedRect(u64),
+    RemovePointToLine(u64),
+    RemoveParallelGap(u64),
+    RemoveMarker(u64),
+    RemoveRegion(u64),
+    RemoveBlob(u64),
+    /// 粒子解析の結果をすべて削除
+    ClearBlobs,
+    /// 同じ測定IDの線分を編集後の線分に置き換える（端点の移動・全体の移動・名前の変更）
+    ReplaceLine(Measurement),
+    /// 同じ測定IDの矩形を編集後の矩形に置き換える（角の移動・全体の移動・名前の変更）
+    ReplaceRect(RectangleMeasurement),
+    /// 同じ測定IDの測定を置き換える（以下の Replace* はスタイル・レイヤーの変更）
+    ReplaceAngle(AngleMeasurement),
+    ReplacePolyline(PolylineMeasurement),
+    ReplacePolygon(PolygonMeasurement),
+    ReplaceCircle(CircleMeasurement),
+    ReplaceEllipse(EllipseMeasurement),
+    ReplaceRotatedRect(RotatedRectangleMeasurement),
+    ReplacePointToLine(PointToLineMeasurement),
+    ReplaceParallelGap(ParallelGapMeasurement),
+    ReplaceMarker(Marker),
+    ReplaceRegion(RegionMeasurement),
+    ReplaceBlob(BlobMeasurement),
+    /// 複数の操作をまとめた1つの操作（複数選択の一括削除・移動）
+    Batch(Vec<Action>),
+    SetCalibration(Option<Calibration>),
+}
 
-                            // 面積ラベル
-                            let area_unit = if unit == "px" {
-                                "px²".to_string()
-                            } else {
-                                format!("{}²", unit)
-                            };
-                            let center = egui::pos2((min_x + max_x) / 2.0, (min_y + max_y) / 2.0);
-                            painter.text(
-                                center,
-                                egui::Align2::CENTER_CENTER,
-                                format!("{:.1} {}", area, area_unit),
-                                egui::FontId::default(),
-                                self.text_color,
-                            );
-                        }
-                    }
+impl Action {
+    /// 追加する測定（まとめた操作の中も含む）のレイヤーを設定する
+    fn set_layer(&mut self, layer: u64) {
+        let target = match self {
+            Action::AddLine(m) => &mut m.layer,
+            Action::AddRect(r) => &mut r.layer,
+            Action::AddAngle(a) => &mut a.layer,
+            Action::AddPolyline(p) => &mut p.layer,
+            Action::AddPolygon(p) => &mut p.layer,
+            Action::AddCircle(c) => &mut c.layer,
+            Action::AddEllipse(e) => &mut e.layer,
+            Action::AddRotatedRect(r) => &mut r.layer,
+            Action::AddPointToLine(p) => &mut p.layer,
+            Action::AddParallelGap(g) => &mut g.layer,
+            Action::AddMarker(m) => &mut m.layer,
+            Action::AddRegion(r) => &mut r.layer,
+            Action::AddBlobs(blobs) => {
+                for blob in blobs {
+                    blob.layer = layer;
+                }
+                return;
+            }
+            Action::Batch(actions) => {
+                for action in actions {
+                    action.set_layer(layer);
                 }
+                return;
             }
+            _ => return,
+        };
+        *target = layer;
+    }
+
+    /// 複数の操作を1つの操作にまとめる（1つだけならそのまま、なければ None）
+    fn compound(mut actions: Vec<Action>) -> Option<Action> {
+        match actions.len() {
+            0 => None,
+            1 => actions.pop(),
+            _ => Some(Action::Batch(actions)),
         }
+    }
 
-        // キャリブレーション中の線を描画
-        match &self.calibration_state {
-            CalibrationState::FirstPointSelected(start) => {
-                let start_screen = self.image_to_screen(*start, image_rect);
-                painter.circle_filled(start_screen, point_radius, egui::Color32::LIGHT_BLUE);
+    /// 操作で追加・置き換える測定（まとめた操作の中も含む）がレイヤーに属するか
+    fn uses_layer(&self, layer: u64) -> bool {
+        match self {
+            Action::AddLine(m) | Action::ReplaceLine(m) => m.layer == layer,
+            Action::AddRect(r) | Action::ReplaceRect(r) => r.layer == layer,
+            Action::AddAngle(a) | Action::ReplaceAngle(a) => a.layer == layer,
+            Action::AddPolyline(p) | Action::ReplacePolyline(p) => p.layer == layer,
+            Action::AddPolygon(p) | Action::ReplacePolygon(p) => p.layer == layer,
+            Action::AddCircle(c) | Action::ReplaceCircle(c) => c.layer == layer,
+            Action::AddEllipse(e) | Action::ReplaceEllipse(e) => e.layer == layer,
+            Action::AddRotatedRect(r) | Action::ReplaceRotatedRect(r) => r.layer == layer,
+            Action::AddPointToLine(p) | Action::ReplacePointToLine(p) => p.layer == layer,
+            Action::AddParallelGap(g) | Action::ReplaceParallelGap(g) => g.layer == layer,
+            Action::AddMarker(m) | Action::ReplaceMarker(m) => m.layer == layer,
+            Action::AddRegion(r) | Action::ReplaceRegion(r) => r.layer == layer,
+            Action::AddBlobs(blobs) => blobs.iter().any(|blob| blob.layer == layer),
+            Action::ReplaceBlob(b) => b.layer == layer,
+            Action::Batch(actions) => actions.iter().any(|action| action.uses_layer(layer)),
+            _ => false,
+        }
+    }
+}
 
-                // キャリブレーションのプレビュー描画
-                if self.show_preview {
-                    if let Some(mouse_pos) = self.current_mouse_image_pos {
-                        let preview_color =
-                            egui::Color32::from_rgba_unmultiplied(100, 200, 255, 150);
-                        let preview_stroke = egui::Stroke::new(1.5, preview_color);
+/// 履歴から再構築した測定状態
+#[derive(Default)]
+struct HistoryState {
+    measurements: Vec<Measurement>,
+    rectangle_measurements: Vec<RectangleMeasurement>,
+    angle_measurements: Vec<AngleMeasurement>,
+    polyline_measurements: Vec<PolylineMeasurement>,
+    polygon_measurements: Vec<PolygonMeasurement>,
+    circle_measurements: Vec<CircleMeasurement>,
+    ellipse_measurements: Vec<EllipseMeasurement>,
+    rotated_rectangle_measurements: Vec<RotatedRectangleMeasurement>,
+    point_to_line_measurements: Vec<PointToLineMeasurement>,
+    parallel_gap_measurements: Vec<ParallelGapMeasurement>,
+    markers: Vec<Marker>,
+    region_measurements: Vec<RegionMeasurement>,
+    blob_measurements: Vec<BlobMeasurement>,
+    calibration: Option<Calibration>,
+}
 
-                        // 角度スナップ（Ctrl）
-                        let angle_snapped = if self.is_ctrl_pressed {
-                            snap_to_angle(*start, mouse_pos)
-                        } else {
-                            mouse_pos
-                        };
-                        // 倍数スナップ
-                        let effective_mouse_pos =
-                            snap_line_length(*start, angle_snapped, self.length_snap_multiple);
-                        let effective_mouse_screen =
-                            self.image_to_screen(effective_mouse_pos, image_rect);
+impl HistoryState {
+    /// すべての種類の測定（`SampoApp::measurement_items` と同じ並び）
+    fn measurement_items(&self) -> impl Iterator<Item = &dyn MeasurementItem> {
+        as_items(&self.measurements)
+            .chain(as_items(&self.rectangle_measurements))
+            .chain(as_items(&self.angle_measurements))
+            .chain(as_items(&self.polyline_measurements))
+            .chain(as_items(&self.circle_measurements))
+            .chain(as_items(&self.rotated_rectangle_measurements))
+            .chain(as_items(&self.point_to_line_measurements))
+            .chain(as_items(&self.parallel_gap_measurements))
+            .chain(as_items(&self.region_measurements))
+            .chain(as_items(&self.blob_measurements))
+            .chain(as_items(&self.markers))
+            .chain(as_items(&self.ellipse_measurements))
+            .chain(as_items(&self.polygon_measurements))
+    }
+}
 
-                        // 線分のプレビュー
-                        painter
-                            .line_segment([start_screen, effective_mouse_screen], preview_stroke);
-                        painter.circle_filled(
-                            effective_mouse_screen,
-                            point_radius * 0.7,
-                            preview_color,
-                        );
+/// ログベースの履歴管理
+#[derive(Default)]
+struct History {
+    actions: Vec<Action>,
+    cursor: usize,
+    /// 最後に割り当てた測定ID（Undo しても戻さず、同じ番号を再利用しない）
+    last_id: u64,
+}
 
-                        // 距離のプレビュー表示
-                        let distance_px = start.distance(effective_mouse_pos);
-                        let midpoint = start_screen + (effective_mouse_screen - start_screen) * 0.5;
-                        painter.text(
-                            midpoint + egui::vec2(0.0, -15.0),
-                            egui::Align2::CENTER_BOTTOM,
-                            format!("{:.1} px", distance_px),
-                            egui::FontId::default(),
-                            self.text_color,
-                        );
-                    }
+impl History {
+    fn push_action(&mut self, mut action: Action) {
+        if self.cursor < self.actions.len() {
+            self.actions.truncate(self.cursor);
+        }
+        self.assign_ids(&mut action);
+        self.actions.push(action);
+        self.cursor = self.actions.len();
+    }
+
+    /// 追加する測定に、まだ割り当てていなければ新しい測定IDを割り当てる
+    fn assign_ids(&mut self, action: &mut Action) {
+        let id = match action {
+            Action::AddLine(m) => &mut m.id,
+            Action::AddRect(r) => &mut r.id,
+            Action::AddAngle(a) => &mut a.id,
+            Action::AddPolyline(p) => &mut p.id,
+            Action::AddPolygon(p) => &mut p.id,
+            Action::AddCircle(c) => &mut c.id,
+            Action::AddEllipse(e) => &mut e.id,
+            Action::AddRotatedRect(r) => &mut r.id,
+            Action::AddPointToLine(p) => &mut p.id,
+            Action::AddParallelGap(g) => &mut g.id,
+            Action::AddMarker(m) => &mut m.id,
+            Action::AddRegion(r) => &mut r.id,
+            Action::AddBlobs(blobs) => {
+                for blob in blobs {
+                    Self::assign_id(&mut self.last_id, &mut blob.id);
                 }
+                return;
             }
-            CalibrationState::WaitingForInput { start, end, .. } => {
-                let start_screen = self.image_to_screen(*start, image_rect);
-                let end_screen = self.image_to_screen(*end, image_rect);
-                let calib_stroke = egui::Stroke::new(2.0, egui::Color32::LIGHT_BLUE);
-                painter.line_segment([start_screen, end_screen], calib_stroke);
-                painter.circle_filled(start_screen, point_radius, egui::Color32::LIGHT_BLUE);
-                painter.circle_filled(end_screen, point_radius, egui::Color32::LIGHT_BLUE);
+            Action::Batch(actions) => {
+                for action in actions {
+                    self.assign_ids(action);
+                }
+                return;
             }
-            _ => {}
-        }
+            _ => return,
+        };
+        Self::assign_id(&mut self.last_id, id);
+    }
 
-        // テスト用：デバッグポインタの描画
-        #[cfg(test)]
-        if let Some(debug_pos) = self.debug_mouse_position {
-            let debug_screen = self.image_to_screen(debug_pos, image_rect);
-            // 真のマウス位置を青い十字で表示
-            let cross_size = 15.0;
-            let cross_color = egui::Color32::from_rgb(0, 100, 255);
-            let cross_stroke = egui::Stroke::new(2.0, cross_color);
-            // 水平線
-            painter.line_segment(
-                [
-                    egui::pos2(debug_screen.x - cross_size, debug_screen.y),
-                    egui::pos2(debug_screen.x + cross_size, debug_screen.y),
-                ],
-                cross_stroke,
-            );
-            // 垂直線
-            painter.line_segment(
-                [
-                    egui::pos2(debug_screen.x, debug_screen.y - cross_size),
-                    egui::pos2(debug_screen.x, debug_screen.y + cross_size),
-                ],
-                cross_stroke,
-            );
-            // 座標ラベル
-            painter.text(
-                debug_screen + egui::vec2(20.0, -10.0),
-                egui::Align2::LEFT_BOTTOM,
-                format!("({:.0}, {:.0})", debug_pos.x, debug_pos.y),
-                egui::FontId::default(),
-                cross_color,
-            );
+    fn assign_id(last_id: &mut u64, id: &mut u64) {
+        if *id == 0 {
+            *last_id += 1;
+            *id = *last_id;
         }
     }
 
-    fn show_image_canvas(&mut self, ui: &mut egui::Ui, viewport_size: egui::Vec2) {
-        let Some(texture) = &self.image_texture else {
-            ui.centered_and_justified(|ui| {
-                ui.label("画像が読み込まれていません。「画像を開く」をクリックしてください。");
-            });
-            return;
-        };
+    fn can_undo(&self) -> bool {
+        self.cursor > 0
+    }
 
-        let texture_size = texture.size_vec2() * self.zoom;
-        let texture_id = texture.id();
+    fn can_redo(&self) -> bool {
+        self.cursor < self.actions.len()
+    }
 
-        // 画像の外側にもスクロールできるようにパディングを追加
-        let padding = viewport_size;
-
-        // 上部パディング
-        ui.allocate_space(egui::vec2(texture_size.x + padding.x * 2.0, padding.y));
+    fn undo(&mut self) -> bool {
+        if self.can_undo() {
+            self.cursor -= 1;
+            true
+        } else {
+            false
+        }
+    }
 
-        // 画像描画用の情報を保持
-        let mut image_rect = None;
-        let mut clicked_pos = None;
-        let mut hover_pos = None;
+    fn redo(&mut self) -> bool {
+        if self.can_redo() {
+            self.cursor += 1;
+            true
+        } else {
+            false
+        }
+    }
 
-        ui.horizontal(|ui| {
-            // 左パディング
-            ui.allocate_space(egui::vec2(padding.x, texture_size.y));
+    fn rebuild_state(&self) -> HistoryState {
+        self.state_after(self.cursor)
+    }
 
-            // 画像を描画
-            let (response, painter) =
-                ui.allocate_painter(texture_size, egui::Sense::click_and_drag());
+    /// 最後の操作を適用する前の状態
+    fn state_before_last_action(&self) -> HistoryState {
+        self.state_after(self.cursor.saturating_sub(1))
+    }
 
-            painter.image(
-                texture_id,
-                response.rect,
-                egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0)),
-                egui::Color32::WHITE,
-            );
+    /// 先頭から `count` 個の操作を適用した状態
+    fn state_after(&self, count: usize) -> HistoryState {
+        let mut state = HistoryState::default();
 
-            image_rect = Some(response.rect);
+        for action in self.actions.iter().take(count) {
+            Self::apply_action(&mut state, action);
+        }
 
-            if response.clicked() {
-                clicked_pos = response.interact_pointer_pos();
-            }
+        state
+    }
 
-            // ホバー位置を取得
-            hover_pos = response.hover_pos();
+    /// 位置 `index` の操作が最後の操作か（やり直せる操作があるときは false）
+    fn is_last_action(&self, index: usize) -> bool {
+        index + 1 == self.actions.len() && self.cursor == self.actions.len()
+    }
 
-            // 右パディング
-            ui.allocate_space(egui::vec2(padding.x, texture_size.y));
-        });
+    /// 最後の操作を置き換える（同じ操作中の変更を1つの操作にまとめる）
+    fn replace_last_action(&mut self, mut action: Action) {
+        self.actions.truncate(self.cursor);
+        self.assign_ids(&mut action);
+        match self.actions.last_mut() {
+            Some(last) => *last = action,
+            None => self.actions.push(action),
+        }
+        self.cursor = self.actions.len();
+    }
 
-        // 下部パディング
-        ui.allocate_space(egui::vec2(texture_size.x + padding.x * 2.0, padding.y));
+    /// 最後の操作を取り除く（同じ操作中の変更が元に戻った場合）
+    fn remove_last_action(&mut self) {
+        self.actions.truncate(self.cursor);
+        self.actions.pop();
+        self.cursor = self.actions.len();
+    }
 
-        // クリック処理と測定線描画
-        if let Some(rect) = image_rect {
-            // マウス位置を画像座標に変換して保存
-            // テスト用：debug_mouse_positionが設定されている場合は上書きしない
-            #[cfg(test)]
-            if self.debug_mouse_position.is_none() {
-                self.current_mouse_image_pos = hover_pos.map(|pos| self.screen_to_image(pos, rect));
+    fn apply_action(state: &mut HistoryState, action: &Action) {
+        match action {
+            Action::AddLine(m) => state.measurements.push(m.clone()),
+            Action::AddRect(r) => state.rectangle_measurements.push(r.clone()),
+            Action::AddAngle(a) => state.angle_measurements.push(a.clone()),
+            Action::AddPolyline(p) => state.polyline_measurements.push(p.clone()),
+            Action::AddPolygon(p) => state.polygon_measurements.push(p.clone()),
+            Action::AddCircle(c) => state.circle_measurements.push(c.clone()),
+            Action::AddEllipse(e) => state.ellipse_measurements.push(e.clone()),
+            Action::AddRotatedRect(r) => state.rotated_rectangle_measurements.push(r.clone()),
+            Action::AddPointToLine(p) => state.point_to_line_measurements.push(p.clone()),
+            Action::AddParallelGap(g) => state.parallel_gap_measurements.push(g.clone()),
+            Action::AddMarker(m) => state.markers.push(m.clone()),
+            Action::AddRegion(r) => state.region_measurements.push(r.clone()),
+            Action::AddBlobs(blobs) => state.blob_measurements.extend(blobs.iter().cloned()),
+            Action::RemoveLine(id) => state.measurements.retain(|m| m.id != *id),
+            Action::RemoveRect(id) => state.rectangle_measurements.retain(|m| m.id != *id),
+            Action::RemoveAngle(id) => state.angle_measurements.retain(|m| m.id != *id),
+            Action::RemovePolyline(id) => state.polyline_measurements.retain(|m| m.id != *id),
+            Action::RemovePolygon(id) => state.polygon_measurements.retain(|m| m.id != *id),
+            Action::RemoveCircle(id) => state.circle_measurements.retain(|m| m.id != *id),
+            Action::RemoveEllipse(id) => state.ellipse_measurements.retain(|m| m.id != *id),
+            Action::RemoveRotatedRect(id) => {
+                state.rotated_rectangle_measurements.retain(|m| m.id != *id)
             }
-            #[cfg(not(test))]
-            {
-                self.current_mouse_image_pos = hover_pos.map(|pos| self.screen_to_image(pos, rect));
+            Action::RemovePointToLine(id) => {
+                state.point_to_line_measurements.retain(|m| m.id != *id)
             }
-
-            if let Some(pointer_pos) = clicked_pos {
-                self.handle_canvas_click(pointer_pos, rect);
+            Action::RemoveParallelGap(id) => {
+                state.parallel_gap_measurements.retain(|m| m.id != *id)
             }
-
-            // 測定線を描画（別のPainterを使用）
-            let painter = ui.painter_at(rect);
-            self.draw_measurements(&painter, rect);
-        } else {
-            // テスト用：debug_mouse_positionが設定されている場合は上書きしない
-            #[cfg(test)]
-            if self.debug_mouse_position.is_none() {
-                self.current_mouse_image_pos = None;
+            Action::RemoveMarker(id) => state.markers.retain(|m| m.id != *id),
+            Action::RemoveRegion(id) => state.region_measurements.retain(|m| m.id != *id),
+            Action::RemoveBlob(id) => state.blob_measurements.retain(|m| m.id != *id),
+            Action::ClearBlobs => state.blob_measurements.clear(),
+            Action::ReplaceLine(m) => Self::replace_item(&mut state.measurements, m),
+            Action::ReplaceRect(r) => Self::replace_item(&mut state.rectangle_measurements, r),
+            Action::ReplaceAngle(a) => Self::replace_item(&mut state.angle_measurements, a),
+            Action::ReplacePolyline(p) => Self::replace_item(&mut state.polyline_measurements, p),
+            Action::ReplacePolygon(p) => Self::replace_item(&mut state.polygon_measurements, p),
+            Action::ReplaceCircle(c) => Self::replace_item(&mut state.circle_measurements, c),
+            Action::ReplaceEllipse(e) => Self::replace_item(&mut state.ellipse_measurements, e),
+            Action::ReplaceRotatedRect(r) => {
+                Self::replace_item(&mut state.rotated_rectangle_measurements, r)
             }
-            #[cfg(not(test))]
-            {
-                self.current_mouse_image_pos = None;
+            Action::ReplacePointToLine(p) => {
+                Self::replace_item(&mut state.point_to_line_measurements, p)
+            }
+            Action::ReplaceParallelGap(g) => {
+                Self::replace_item(&mut state.parallel_gap_measurements, g)
+            }
+            Action::ReplaceMarker(m) => Self::replace_item(&mut state.markers, m),
+            Action::ReplaceRegion(r) => Self::replace_item(&mut state.region_measurements, r),
+            Action::ReplaceBlob(b) => Self::replace_item(&mut state.blob_measurements, b),
+            Action::SetCalibration(cal) => {
+                state.calibration = cal.clone();
+            }
+            Action::Batch(actions) => {
+                for action in actions {
+                    Self::apply_action(state, action);
+                }
             }
         }
     }
 
-    fn export_csv(&self) -> String {
-        let mut csv = String::new();
-
-        // 線分測定
-        if !self.measurements.is_empty() {
-            csv.push_str("# Line Measurements\n");
-            csv.push_str("id,start_x,start_y,end_x,end_y,distance_px,distance_calibrated,unit\n");
-            for (i, m) in self.measurements.iter().enumerate() {
-                let (distance, unit) = m.distance_with_calibration(self.calibration.as_ref());
-                let calibrated = if self.calibration.is_some() {
-                    format!("{:.2}", distance)
-                } else {
-                    String::new()
-                };
-                csv.push_str(&format!(
-                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{},{}\n",
-                    i + 1,
-                    m.start.0,
-                    m.start.1,
-                    m.end.0,
-                    m.end.1,
-                    m.distance_px,
-                    calibrated,
-                    unit
-                ));
-            }
+    /// 同じ測定IDの測定を置き換える
+    fn replace_item<T: MeasurementItem + Clone>(items: &mut [T], item: &T) {
+        if let Some(target) = items.iter_mut().find(|target| target.id() == item.id()) {
+            *target = item.clone();
         }
+    }
 
-        // 矩形測定
-        if !self.rectangle_measurements.is_empty() {
-            if !csv.is_empty() {
-                csv.push('\n');
-            }
-            csv.push_str("# Rectangle Measurements\n");
-            csv.push_str("id,corner1_x,corner1_y,corner2_x,corner2_y,width_px,height_px,area_px,width_calibrated,height_calibrated,area_calibrated,unit\n");
-            for (i, rm) in self.rectangle_measurements.iter().enumerate() {
-                let (width, height, area, unit) =
-                    rm.dimensions_with_calibration(self.calibration.as_ref());
-                let (w_cal, h_cal, a_cal) = if self.calibration.is_some() {
-                    (
-                        format!("{:.2}", width),
-                        format!("{:.2}", height),
-                        format!("{:.2}", area),
-                    )
-                } else {
-                    (String::new(), String::new(), String::new())
-                };
-                csv.push_str(&format!(
-                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{},{},{},{}\n",
-                    i + 1,
-                    rm.corner1.0,
-                    rm.corner1.1,
-                    rm.corner2.0,
-                    rm.corner2.1,
-                    rm.width_px,
-                    rm.height_px,
-                    rm.area_px,
-                    w_cal,
-                    h_cal,
-                    a_cal,
-                    unit
-                ));
-            }
+    fn reset_with_calibration(&mut self, calibration: Option<Calibration>) {
+        self.actions.clear();
+        self.cursor = 0;
+        if let Some(cal) = calibration {
+            self.actions.push(Action::SetCalibration(Some(cal)));
+            self.cursor = self.actions.len();
         }
-
-        csv
     }
+}
 
-    fn export_json(&self) -> String {
-        let measurements: Vec<ExportMeasurement> = self
-            .measurements
-            .iter()
-            .enumerate()
-            .map(|(i, m)| {
-                let (distance, unit) = m.distance_with_calibration(self.calibration.as_ref());
-                ExportMeasurement {
-                    id: i + 1,
-                    start_x: m.start.0,
-                    start_y: m.start.1,
-                    end_x: m.end.0,
-                    end_y: m.end.1,
-                    distance_px: m.distance_px,
-                    distance_calibrated: if self.calibration.is_some() {
-                        Some(distance)
-                    } else {
-                        None
-                    },
-                    unit,
-                }
-            })
-            .collect();
+/// エクスポート用のデータ構造
+#[derive(Serialize)]
+struct ExportData {
+    calibration: Option<Calibration>,
+    measurements: Vec<ExportMeasurement>,
+    rectangle_measurements: Vec<ExportRectangleMeasurement>,
+    angle_measurements: Vec<ExportAngleMeasurement>,
+    polyline_measurements: Vec<ExportPolylineMeasurement>,
+    polygon_measurements: Vec<ExportPolygonMeasurement>,
+    circle_measurements: Vec<ExportCircleMeasurement>,
+    ellipse_measurements: Vec<ExportEllipseMeasurement>,
+    rotated_rectangle_measurements: Vec<ExportRotatedRectangleMeasurement>,
+    point_to_line_measurements: Vec<ExportPointToLineMeasurement>,
+    parallel_gap_measurements: Vec<ExportParallelGapMeasurement>,
+    markers: Vec<ExportMarker>,
+    marker_counts: Vec<ExportMarkerCount>,
+    region_measurements: Vec<ExportRegionMeasurement>,
+    blob_measurements: Vec<ExportBlobMeasurement>,
+}
 
-        let rectangle_measurements: Vec<ExportRectangleMeasurement> = self
-            .rectangle_measurements
-            .iter()
-            .enumerate()
-            .map(|(i, rm)| {
-                let (width, height, area, unit) =
-                    rm.dimensions_with_calibration(self.calibration.as_ref());
-                ExportRectangleMeasurement {
-                    id: i + 1,
-                    corner1_x: rm.corner1.0,
-                    corner1_y: rm.corner1.1,
-                    corner2_x: rm.corner2.0,
-                    corner2_y: rm.corner2.1,
-                    width_px: rm.width_px,
-                    height_px: rm.height_px,
-                    area_px: rm.area_px,
-                    width_calibrated: if self.calibration.is_some() {
-                        Some(width)
-                    } else {
-                        None
-                    },
-                    height_calibrated: if self.calibration.is_some() {
-                        Some(height)
-                    } else {
-                        None
-                    },
-                    area_calibrated: if self.calibration.is_some() {
-                        Some(area)
-                    } else {
-                        None
-                    },
-                    unit,
-                }
-            })
-            .collect();
+#[derive(Serialize)]
+struct ExportMeasurement {
+    id: u64,
+    start_x: f32,
+    start_y: f32,
+    end_x: f32,
+    end_y: f32,
+    distance_px: f32,
+    distance_calibrated: Option<f32>,
+    unit: String,
+    name: String,
+    note: String,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-        let export_data = ExportData {
-            calibration: self.calibration.clone(),
-            measurements,
-            rectangle_measurements,
-        };
+#[derive(Serialize)]
+struct ExportRectangleMeasurement {
+    id: u64,
+    corner1_x: f32,
+    corner1_y: f32,
+    corner2_x: f32,
+    corner2_y: f32,
+    width_px: f32,
+    height_px: f32,
+    area_px: f32,
+    width_calibrated: Option<f32>,
+    height_calibrated: Option<f32>,
+    area_calibrated: Option<f32>,
+    unit: String,
+    name: String,
+    note: String,
+    /// 所属するレイヤーの名前
+    layer: String,
+    style: MeasurementStyle,
+    /// 矩形内の画素統計（画像が読み込まれていない・画素を含まない場合は None）
+    statistics: Option<ExportRegionStatistics>,
+}
 
-        serde_json::to_string_pretty(&export_data).unwrap_or_default()
-    }
+#[derive(Serialize)]
+struct ExportRegionStatistics {
+    pixel_count: usize,
+    channels: Vec<ExportChannelStatistics>,
+}
 
-    fn save_export(&self, format: &str) {
-        let (content, extension, filter_name) = match format {
-            "csv" => (self.export_csv(), "csv", "CSV"),
-            "json" => (self.export_json(), "json", "JSON"),
-            _ => return,
-        };
+#[derive(Serialize)]
+struct ExportChannelStatistics {
+    channel: String,
+    mean: f32,
+    std_dev: f32,
+    min: f32,
+    max: f32,
+    histogram: Vec<u32>,
+}
 
-        if let Some(path) = rfd::FileDialog::new()
-            .add_filter(filter_name, &[extension])
-            .save_file()
-        {
-            if let Err(e) = std::fs::write(&path, content) {
-                eprintln!("Failed to save file: {}", e);
-            }
-        }
-    }
+#[derive(Serialize)]
+struct ExportAngleMeasurement {
+    id: u64,
+    vertex_x: f32,
+    vertex_y: f32,
+    arm1_x: f32,
+    arm1_y: f32,
+    arm2_x: f32,
+    arm2_y: f32,
+    angle_deg: f32,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-    fn show_controls_panel(&mut self, ctx: &egui::Context) {
-        egui::SidePanel::left("controls_panel")
-            .min_width(250.0)
-            .show(ctx, |ui| {
-                ui.heading("Sampo - 画像寸法測定");
-                ui.separator();
+#[derive(Serialize)]
+struct ExportPoint {
+    x: f32,
+    y: f32,
+}
 
-                // Undo / Redo
-                ui.horizontal(|ui| {
-                    let can_undo = self.history.can_undo();
-                    let can_redo = self.history.can_redo();
-                    if ui.add_enabled(can_undo, egui::Button::new("Undo")).clicked() {
-                        if self.history.undo() {
-                            self.rebuild_from_history();
-                        }
-                    }
-                    if ui.add_enabled(can_redo, egui::Button::new("Redo")).clicked() {
-                        if self.history.redo() {
-                            self.rebuild_from_history();
-                        }
-                    }
-                });
+#[derive(Serialize)]
+struct ExportPolylineMeasurement {
+    id: u64,
+    points: Vec<ExportPoint>,
+    segment_lengths_px: Vec<f32>,
+    total_length_px: f32,
+    segment_lengths_calibrated: Option<Vec<f32>>,
+    total_length_calibrated: Option<f32>,
+    unit: String,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                ui.separator();
+#[derive(Serialize)]
+struct ExportPolygonMeasurement {
+    id: u64,
+    points: Vec<ExportPoint>,
+    area_px: f32,
+    perimeter_px: f32,
+    area_calibrated: Option<f32>,
+    perimeter_calibrated: Option<f32>,
+    unit: String,
+    is_convex: bool,
+    is_self_intersecting: bool,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                // ファイル操作
-                ui.horizontal(|ui| {
-                    if ui.button("画像を開く").clicked() {
-                        self.open_file_dialog(ctx);
-                    }
-                    if ui.button("貼り付け").clicked() {
-                        self.paste_from_clipboard(ctx);
-                    }
-                });
-                ui.label("(Ctrl/Cmd+V でも貼り付け可)");
+#[derive(Serialize)]
+struct ExportCircleMeasurement {
+    id: u64,
+    center_x: f32,
+    center_y: f32,
+    radius_px: f32,
+    diameter_px: f32,
+    circumference_px: f32,
+    area_px: f32,
+    radius_calibrated: Option<f32>,
+    diameter_calibrated: Option<f32>,
+    circumference_calibrated: Option<f32>,
+    area_calibrated: Option<f32>,
+    unit: String,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                if let Some(path) = &self.image_path {
-                    let filename = std::path::Path::new(path)
-                        .file_name()
-                        .map(|n| n.to_string_lossy().to_string())
-                        .unwrap_or_else(|| path.clone());
-                    ui.label(format!("ファイル: {}", filename));
-                }
+#[derive(Serialize)]
+struct ExportEllipseMeasurement {
+    id: u64,
+    center_x: f32,
+    center_y: f32,
+    semi_major_px: f32,
+    semi_minor_px: f32,
+    angle_deg: f32,
+    area_px: f32,
+    perimeter_px: f32,
+    semi_major_calibrated: Option<f32>,
+    semi_minor_calibrated: Option<f32>,
+    area_calibrated: Option<f32>,
+    perimeter_calibrated: Option<f32>,
+    unit: String,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                if let Some((w, h)) = self.image_dimensions {
-                    ui.label(format!("サイズ: {}x{} px", w, h));
-                }
+#[derive(Serialize)]
+struct ExportRotatedRectangleMeasurement {
+    id: u64,
+    corners: Vec<ExportPoint>,
+    width_px: f32,
+    height_px: f32,
+    angle_deg: f32,
+    area_px: f32,
+    width_calibrated: Option<f32>,
+    height_calibrated: Option<f32>,
+    area_calibrated: Option<f32>,
+    unit: String,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                ui.separator();
+#[derive(Serialize)]
+struct ExportPointToLineMeasurement {
+    id: u64,
+    point_x: f32,
+    point_y: f32,
+    foot_x: f32,
+    foot_y: f32,
+    line_start_x: f32,
+    line_start_y: f32,
+    line_end_x: f32,
+    line_end_y: f32,
+    distance_px: f32,
+    distance_calibrated: Option<f32>,
+    unit: String,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                // ズーム
-                ui.horizontal(|ui| {
-                    ui.label("ズーム:");
-                    ui.add(
-                        egui::Slider::new(&mut self.zoom, 0.1..=5.0)
-                            .logarithmic(true)
-                            .suffix("x"),
-                    );
-                });
-                ui.label("(画像上でピンチでもズーム可)");
-                if ui.button("リセット").clicked() {
-                    self.zoom = 1.0;
-                }
+#[derive(Serialize)]
+struct ExportParallelGapMeasurement {
+    id: u64,
+    line1_start_x: f32,
+    line1_start_y: f32,
+    line1_end_x: f32,
+    line1_end_y: f32,
+    line2_start_x: f32,
+    line2_start_y: f32,
+    line2_end_x: f32,
+    line2_end_y: f32,
+    mean_gap_px: f32,
+    min_gap_px: f32,
+    max_gap_px: f32,
+    angle_deviation_deg: f32,
+    mean_gap_calibrated: Option<f32>,
+    min_gap_calibrated: Option<f32>,
+    max_gap_calibrated: Option<f32>,
+    unit: String,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                ui.separator();
+#[derive(Serialize)]
+struct ExportMarker {
+    id: u64,
+    category: String,
+    /// カテゴリ内での番号
+    number: usize,
+    x: f32,
+    y: f32,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                // 表示設定
-                ui.heading("表示設定");
-                ui.horizontal(|ui| {
-                    ui.label("寸法文字色:");
-                    ui.color_edit_button_srgba(&mut self.text_color);
-                });
-                ui.checkbox(&mut self.show_preview, "測定プレビューを表示");
+#[derive(Serialize)]
+struct ExportMarkerCount {
+    category: String,
+    count: usize,
+}
 
-                ui.separator();
+#[derive(Serialize)]
+struct ExportRegionMeasurement {
+    id: u64,
+    area_px: f32,
+    perimeter_px: f32,
+    bbox_min_x: f32,
+    bbox_min_y: f32,
+    bbox_max_x: f32,
+    bbox_max_y: f32,
+    centroid_x: f32,
+    centroid_y: f32,
+    area_calibrated: Option<f32>,
+    perimeter_calibrated: Option<f32>,
+    bbox_width_calibrated: Option<f32>,
+    bbox_height_calibrated: Option<f32>,
+    unit: String,
+    contours: Vec<Vec<ExportPoint>>,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                // キャリブレーション
-                ui.heading("キャリブレーション");
+#[derive(Serialize)]
+struct ExportBlobMeasurement {
+    id: u64,
+    area_px: f32,
+    perimeter_px: f32,
+    equivalent_diameter_px: f32,
+    centroid_x: f32,
+    centroid_y: f32,
+    bounding_rect: ExportRectangleMeasurement,
+    area_calibrated: Option<f32>,
+    perimeter_calibrated: Option<f32>,
+    equivalent_diameter_calibrated: Option<f32>,
+    unit: String,
+    layer: String,
+    style: MeasurementStyle,
+}
 
-                if let Some(cal) = &self.calibration {
-                    ui.label(format!(
-                        "設定済み: {:.2} px/{}",
-                        cal.pixels_per_unit, cal.unit_name
-                    ));
-                    if ui.button("キャリブレーションをクリア").clicked() {
-                        self.history.push_action(Action::SetCalibration(None));
-                        self.rebuild_from_history();
-                    }
-                } else {
-                    ui.label("未設定");
-                }
+/// プロジェクトファイルの内容（開き直して測定・編集を続けるための保存形式）
+/// 測定は名前・メモ・レイヤー・スタイルを含めて、測定IDを保ったまま保存する
+#[derive(Serialize, Deserialize)]
+struct ProjectData {
+    /// 測定した画像のパス（開くときに読み込む。貼り付けた画像は保存しない）
+    image_path: Option<String>,
+    calibration: Option<Calibration>,
+    layers: Vec<ProjectLayer>,
+    marker_categories: Vec<ProjectMarkerCategory>,
+    #[serde(default)]
+    measurements: Vec<Measurement>,
+    #[serde(default)]
+    rectangle_measurements: Vec<RectangleMeasurement>,
+    #[serde(default)]
+    angle_measurements: Vec<AngleMeasurement>,
+    #[serde(default)]
+    polyline_measurements: Vec<PolylineMeasurement>,
+    #[serde(default)]
+    polygon_measurements: Vec<PolygonMeasurement>,
+    #[serde(default)]
+    circle_measurements: Vec<CircleMeasurement>,
+    #[serde(default)]
+    ellipse_measurements: Vec<EllipseMeasurement>,
+    #[serde(default)]
+    rotated_rectangle_measurements: Vec<RotatedRectangleMeasurement>,
+    #[serde(default)]
+    point_to_line_measurements: Vec<PointToLineMeasurement>,
+    #[serde(default)]
+    parallel_gap_measurements: Vec<ParallelGapMeasurement>,
+    #[serde(default)]
+    markers: Vec<Marker>,
+    #[serde(default)]
+    region_measurements: Vec<RegionMeasurement>,
+    #[serde(default)]
+    blob_measurements: Vec<BlobMeasurement>,
+}
 
-                let calibrating_text = if self.is_calibrating {
-                    "キャリブレーションをキャンセル"
-                } else {
-                    "キャリブレーションを開始"
-                };
-                if ui.button(calibrating_text).clicked() {
-                    self.is_calibrating = !self.is_calibrating;
-                    self.calibration_state = CalibrationState::Idle;
-                }
+/// プロジェクトファイルのレイヤー（色は RGBA）
+#[derive(Serialize, Deserialize)]
+struct ProjectLayer {
+    id: u64,
+    name: String,
+    line_color: Option<[u8; 4]>,
+    label_color: Option<[u8; 4]>,
+    visible: bool,
+}
 
-                if self.is_calibrating {
-                    match &self.calibration_state {
-                        CalibrationState::Idle => {
-                            ui.label("既知の長さの始点をクリック");
-                        }
-                        CalibrationState::FirstPointSelected(_) => {
-                            ui.label("終点をクリック");
-                        }
-                        CalibrationState::WaitingForInput { distance_px, .. } => {
-                            ui.label(format!("ピクセル距離: {:.1} px", distance_px));
-                            ui.horizontal(|ui| {
-                                ui.label("実寸法:");
-                                ui.add(
-                                    egui::TextEdit::singleline(&mut self.calibration_input)
-                                        .desired_width(60.0),
-                                );
-                                ui.add(
-                                    egui::TextEdit::singleline(&mut self.calibration_unit)
-                                        .desired_width(40.0),
-                                );
-                            });
-                            if ui.button("適用").clicked() {
-                                if let Ok(real_distance) = self.calibration_input.parse::<f32>() {
-                                    if real_distance > 0.0 {
-                                        let calibration = Calibration {
-                                            pixels_per_unit: distance_px / real_distance,
-                                            unit_name: self.calibration_unit.clone(),
-                                        };
-                                        self.history
-                                            .push_action(Action::SetCalibration(Some(calibration)));
-                                        self.rebuild_from_history();
-                                        self.is_calibrating = false;
-                                        self.calibration_state = CalibrationState::Idle;
-                                        self.calibration_input.clear();
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+/// プロジェクトファイルのマーカーのカテゴリ（色は RGBA）
+#[derive(Serialize, Deserialize)]
+struct ProjectMarkerCategory {
+    name: String,
+    color: [u8; 4],
+}
 
-                ui.separator();
+/// 画像座標の位置の画素値を双線形補間で求める
+/// 画素 (i, j) の中心は (i + 0.5, j + 0.5) とし、画像の外側は端の画素で補う
+/// 画素のない画像では 0 を返す
+fn sample_bilinear(image: &image::RgbaImage, pos: egui::Pos2) -> [f32; 4] {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return [0.0; 4];
+    }
+    let max_x = width.saturating_sub(1) as f32;
+    let max_y = height.saturating_sub(1) as f32;
+    let x = (pos.x - 0.5).clamp(0.0, max_x);
+    let y = (pos.y - 0.5).clamp(0.0, max_y);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let p00 = image.get_pixel(x0, y0).0;
+    let p10 = image.get_pixel(x1, y0).0;
+    let p01 = image.get_pixel(x0, y1).0;
+    let p11 = image.get_pixel(x1, y1).0;
+    std::array::from_fn(|c| {
+        let top = p00[c] as f32 * (1.0 - fx) + p10[c] as f32 * fx;
+        let bottom = p01[c] as f32 * (1.0 - fx) + p11[c] as f32 * fx;
+        top * (1.0 - fy) + bottom * fy
+    })
+}
 
-                // 測定操作
-                ui.heading("測定");
+/// 文字列をクリップボードにコピーする（失敗時はログに出力）
+fn copy_to_clipboard(text: String) {
+    match Clipboard::new() {
+        Ok(mut clipboard) => {
+            if let Err(e) = clipboard.set_text(text) {
+                eprintln!("クリップボードへのコピーに失敗: {}", e);
+            }
+        }
+        Err(e) => {
+            eprintln!("クリップボードへのアクセスに失敗: {}", e);
+        }
+    }
+}
 
-                // モード切替
-                ui.horizontal(|ui| {
-                    ui.label("モード:");
-                    ui.selectable_value(&mut self.measurement_mode, MeasurementMode::Line, "線分");
-                    ui.selectable_value(
-                        &mut self.measurement_mode,
-                        MeasurementMode::Rectangle,
-                        "矩形",
-                    );
-                });
+/// 線分に沿って約1px間隔で画素値を読み取る
+/// 戻り値: (始点からの距離[px], 値) の列
+fn intensity_profile(
+    image: &image::RgbaImage,
+    start: egui::Pos2,
+    end: egui::Pos2,
+    channel: ImageChannel,
+) -> Vec<(f32, f32)> {
+    if image.width() == 0 || image.height() == 0 {
+        return Vec::new();
+    }
+    let length = start.distance(end);
+    let sample_count = (length.floor() as usize + 1).max(2);
+    (0..sample_count)
+        .map(|i| {
+            let t = i as f32 / (sample_count - 1) as f32;
+            let pos = start + (end - start) * t;
+            (length * t, channel.value(sample_bilinear(image, pos)))
+        })
+        .collect()
+}
 
-                if self.measurement_mode == MeasurementMode::Line {
-                    ui.label("(Ctrl押下で水平/垂直スナップ)");
-                }
+/// シード画素から色の近い画素を4近傍で塗りつぶし、選ばれた画素を返す
+/// 色の差は R/G/B の差の最大値で比べる
+fn flood_fill_region(
+    image: &image::RgbaImage,
+    seed: (u32, u32),
+    tolerance: f32,
+) -> Vec<(u32, u32)> {
+    let (width, height) = image.dimensions();
+    if seed.0 >= width || seed.1 >= height {
+        return Vec::new();
+    }
+    let seed_color = image.get_pixel(seed.0, seed.1).0;
+    let is_similar = |x: u32, y: u32| {
+        let color = image.get_pixel(x, y).0;
+        (0..3).all(|c| (color[c] as f32 - seed_color[c] as f32).abs() <= tolerance)
+    };
 
-                ui.horizontal(|ui| {
-                    ui.label("長さスナップ:");
-                    ui.add(
-                        egui::DragValue::new(&mut self.length_snap_multiple)
-                            .speed(0.1)
-                            .range(0.0..=100.0)
-                            .suffix(" px"),
-                    );
-                });
-                ui.label("(0で無効)");
+    let mut visited = vec![false; (width * height) as usize];
+    let mut stack = vec![seed];
+    let mut pixels = Vec::new();
+    visited[(seed.1 * width + seed.0) as usize] = true;
+    while let Some((x, y)) = stack.pop() {
+        pixels.push((x, y));
+        let neighbors = [
+            (x.wrapping_sub(1), y),
+            (x + 1, y),
+            (x, y.wrapping_sub(1)),
+            (x, y + 1),
+        ];
+        for (nx, ny) in neighbors {
+            if nx >= width || ny >= height {
+                continue;
+            }
+            let index = (ny * width + nx) as usize;
+            if !visited[index] && is_similar(nx, ny) {
+                visited[index] = true;
+                stack.push((nx, ny));
+            }
+        }
+    }
+    pixels
+}
 
-                match &self.measurement_state {
-                    MeasurementState::Idle => {
-                        if !self.is_calibrating {
-                            let mode_text = match self.measurement_mode {
-                                MeasurementMode::Line => "線分",
-                                MeasurementMode::Rectangle => "矩形",
-                            };
-                            ui.label(format!("画像をクリックして{}測定開始", mode_text));
-                        }
-                    }
-                    MeasurementState::FirstPointSelected(p) => {
-                        ui.label(format!("始点: ({:.0}, {:.0})", p.x, p.y));
-                        let end_text = match self.measurement_mode {
-                            MeasurementMode::Line => "終点をクリック",
-                            MeasurementMode::Rectangle => "対角をクリック",
-                        };
-                        ui.label(end_text);
-                        if ui.button("キャンセル").clicked() {
-                            self.measurement_state = MeasurementState::Idle;
-                        }
-                    }
-                }
+/// CSV の1項目として書き出せるように、カンマ・引用符・改行を含む文字列を引用符で囲む
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
 
-                ui.separator();
+/// 画素統計をエクスポート用の形式に変換する
+fn export_region_statistics(stats: &RegionStatistics) -> ExportRegionStatistics {
+    ExportRegionStatistics {
+        pixel_count: stats.pixel_count,
+        channels: stats
+            .channels
+            .iter()
+            .map(|c| ExportChannelStatistics {
+                channel: c.channel.key().to_string(),
+                mean: c.mean,
+                std_dev: c.std_dev,
+                min: c.min,
+                max: c.max,
+                histogram: c.histogram.clone(),
+            })
+            .collect(),
+    }
+}
 
-                // 測定結果
-                ui.heading("測定結果");
-
-                egui::ScrollArea::vertical()
-                    .max_height(200.0)
-                    .show(ui, |ui| {
-                        // 線分測定結果
-                        let mut line_to_remove = None;
-                        for (i, m) in self.measurements.iter().enumerate() {
-                            let (distance, unit) =
-                                m.distance_with_calibration(self.calibration.as_ref());
-                            ui.horizontal(|ui| {
-                                ui.label(format!("線#{}: {:.1} {}", i + 1, distance, unit));
-                                if ui.small_button("x").clicked() {
-                                    line_to_remove = Some(i);
-                                }
-                            });
-                        }
-                        if let Some(i) = line_to_remove {
-                            self.history.push_action(Action::RemoveLine(i));
-                            self.rebuild_from_history();
-                        }
-
-                        // 矩形測定結果
-                        let mut rect_to_remove = None;
-                        for (i, rm) in self.rectangle_measurements.iter().enumerate() {
-                            let (width, height, area, unit) =
-                                rm.dimensions_with_calibration(self.calibration.as_ref());
-                            let area_unit = if unit == "px" {
-                                "px²".to_string()
-                            } else {
-                                format!("{}²", unit)
-                            };
-                            ui.horizontal(|ui| {
-                                ui.label(format!(
-                                    "矩#{}: {:.1}x{:.1} {}, {:.1} {}",
-                                    i + 1,
-                                    width,
-                                    height,
-                                    unit,
-                                    area,
-                                    area_unit
-                                ));
-                                if ui.small_button("x").clicked() {
-                                    rect_to_remove = Some(i);
-                                }
-                            });
-                        }
-                        if let Some(i) = rect_to_remove {
-                            self.history.push_action(Action::RemoveRect(i));
-                            self.rebuild_from_history();
-                        }
-                    });
-
-                if !self.measurements.is_empty() || !self.rectangle_measurements.is_empty() {
-                    ui.horizontal(|ui| {
-                        if ui.button("すべてクリア").clicked() {
-                            self.measurements.clear();
-                            self.rectangle_measurements.clear();
-                            self.history
-                                .reset_with_calibration(self.calibration.clone());
-                        }
-                    });
-                }
-
-                ui.separator();
+/// 判別分析法（大津の方法）で2クラスの分離が最もよいしきい値を求める
+/// 戻り値 t で「t 以下」と「t より大きい」に分ける
+fn otsu_threshold(histogram: &[u32]) -> u8 {
+    let total: f64 = histogram.iter().map(|&n| n as f64).sum();
+    let total_sum: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(v, &n)| v as f64 * n as f64)
+        .sum();
+    let mut best_threshold = 0;
+    let mut best_variance = -1.0;
+    let mut background_count = 0.0;
+    let mut background_sum = 0.0;
+    for (t, &n) in histogram.iter().enumerate().take(256) {
+        background_count += n as f64;
+        background_sum += t as f64 * n as f64;
+        let foreground_count = total - background_count;
+        if background_count == 0.0 || foreground_count == 0.0 {
+            continue;
+        }
+        let background_mean = background_sum / background_count;
+        let foreground_mean = (total_sum - background_sum) / foreground_count;
+        let variance =
+            background_count * foreground_count * (background_mean - foreground_mean).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best_threshold = t;
+        }
+    }
+    best_threshold as u8
+}
 
-                // エクスポート
-                ui.heading("エクスポート");
+/// 輝度のヒストグラム（0〜255）
+fn luminance_histogram(image: &image::RgbaImage) -> Vec<u32> {
+    let mut histogram = vec![0_u32; 256];
+    for p in image.pixels() {
+        let value = ImageChannel::Luminance.value(p.0.map(f32::from));
+        histogram[(value.round() as usize).min(255)] += 1;
+    }
+    histogram
+}
 
-                ui.horizontal(|ui| {
-                    if ui.button("CSV").clicked() {
-                        self.save_export("csv");
+/// 輝度をしきい値で二値化し、8近傍でつながった粒子ごとの画素を返す
+/// dark_objects が true ならしきい値以下、false ならしきい値より大きい画素を粒子とする
+/// 画素数が min_area 未満の粒子は除く
+fn detect_blobs(
+    image: &image::RgbaImage,
+    threshold: u8,
+    dark_objects: bool,
+    min_area: usize,
+) -> Vec<Vec<(u32, u32)>> {
+    let (width, height) = image.dimensions();
+    let foreground: Vec<bool> = image
+        .pixels()
+        .map(|p| {
+            let value = ImageChannel::Luminance.value(p.0.map(f32::from)).round();
+            (value <= threshold as f32) == dark_objects
+        })
+        .collect();
+
+    let mut visited = vec![false; foreground.len()];
+    let mut blobs = Vec::new();
+    for start in 0..foreground.len() {
+        if !foreground[start] || visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut stack = vec![(start as u32 % width, start as u32 / width)];
+        let mut pixels = Vec::new();
+        while let Some((x, y)) = stack.pop() {
+            pixels.push((x, y));
+            for dy in -1_i64..=1 {
+                for dx in -1_i64..=1 {
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                    if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                        continue;
                     }
-                    if ui.button("JSON").clicked() {
-                        self.save_export("json");
+                    let index = (ny as u32 * width + nx as u32) as usize;
+                    if foreground[index] && !visited[index] {
+                        visited[index] = true;
+                        stack.push((nx as u32, ny as u32));
                     }
-                });
-            });
+                }
+            }
+        }
+        if pixels.len() >= min_area {
+            blobs.push(pixels);
+        }
     }
+    blobs
 }
 
-impl eframe::App for SampoApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        // テスト用：起動時に指定された画像を読み込む
-        #[cfg(test)]
-        if let Some(path) = self.pending_image_path.take() {
-            self.load_image(ctx, &path);
-        }
+/// RGB（0〜255）を HSV（色相[度], 彩度 0〜1, 明度 0〜1）に変換する
+fn rgb_to_hsv(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    let (r, g, b) = (r / 255.0, g / 255.0, b / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let hue = if delta <= f32::EPSILON {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max <= f32::EPSILON {
+        0.0
+    } else {
+        delta / max
+    };
+    (hue, saturation, max)
+}
 
-        // テスト用：起動時に指定された寸法を追加
-        #[cfg(test)]
-        if !self.pending_measurements.is_empty() {
-            let measurements = std::mem::take(&mut self.pending_measurements);
-            for (start, end) in measurements {
-                let measurement = Measurement::new(start, end);
-                self.history.push_action(Action::AddLine(measurement));
+/// 画素 (x, y) を中心とする window×window 画素の平均値（画像の外側は除く）
+fn average_pixel(image: &image::RgbaImage, x: u32, y: u32, window: u32) -> [f32; 4] {
+    let (width, height) = image.dimensions();
+    let half = window / 2;
+    let (x_start, y_start) = (x.saturating_sub(half), y.saturating_sub(half));
+    let (x_end, y_end) = ((x + half + 1).min(width), (y + half + 1).min(height));
+    let mut sum = [0.0_f32; 4];
+    for py in y_start..y_end {
+        for px in x_start..x_end {
+            for (s, v) in sum.iter_mut().zip(image.get_pixel(px, py).0) {
+                *s += v as f32;
             }
-            self.rebuild_from_history();
         }
+    }
+    let count = ((x_end - x_start) * (y_end - y_start)).max(1) as f32;
+    sum.map(|s| s / count)
+}
 
-        // テスト用：Undo操作を実行
-        #[cfg(test)]
-        while self.pending_undo_count > 0 {
-            if self.history.undo() {
-                self.rebuild_from_history();
+/// ピクセルインスペクタの読み取り結果
+#[derive(Clone)]
+struct PixelReading {
+    /// 画像座標
+    pos: egui::Pos2,
+    /// 読み取った画素
+    pixel: (u32, u32),
+    /// RGBA（平均を取った場合は平均値）
+    rgba: [f32; 4],
+}
+
+impl PixelReading {
+    fn hex(&self) -> String {
+        let [r, g, b, _] = self.rgba.map(|v| v.round() as u8);
+        format!("#{:02X}{:02X}{:02X}", r, g, b)
+    }
+
+    /// ステータスバーの表示・クリップボードへのコピーに使う文字列
+    fn summary(&self) -> String {
+        let [r, g, b, a] = self.rgba;
+        let (h, s, v) = rgb_to_hsv(r, g, b);
+        format!(
+            "({:.1}, {:.1}) 画素[{}, {}]  RGBA({:.0}, {:.0}, {:.0}, {:.0})  {}  HSV({:.0}°, {:.0}%, {:.0}%)",
+            self.pos.x,
+            self.pos.y,
+            self.pixel.0,
+            self.pixel.1,
+            r,
+            g,
+            b,
+            a,
+            self.hex(),
+            h,
+            s * 100.0,
+            v * 100.0
+        )
+    }
+}
+
+/// 領域内の1チャンネル分の画素統計
+#[derive(Clone)]
+struct ChannelStatistics {
+    channel: ImageChannel,
+    mean: f32,
+    std_dev: f32,
+    min: f32,
+    max: f32,
+    /// 0〜255 の値ごとの画素数（輝度は四捨五入した値で数える）
+    histogram: Vec<u32>,
+}
+
+/// 矩形領域内の画素統計
+#[derive(Clone)]
+struct RegionStatistics {
+    pixel_count: usize,
+    channels: Vec<ChannelStatistics>,
+}
+
+/// 統計を求めるチャンネル
+const STATISTICS_CHANNELS: [ImageChannel; 4] = [
+    ImageChannel::Red,
+    ImageChannel::Green,
+    ImageChannel::Blue,
+    ImageChannel::Luminance,
+];
+
+/// 矩形領域内の画素統計を求める
+/// 画素の中心 (i + 0.5, j + 0.5) が矩形内にある画素を対象とし、1画素も含まない場合は None
+fn region_statistics(
+    image: &image::RgbaImage,
+    min: egui::Pos2,
+    max: egui::Pos2,
+) -> Option<RegionStatistics> {
+    let (width, height) = image.dimensions();
+    let x_start = (min.x - 0.5).ceil().max(0.0) as u32;
+    let y_start = (min.y - 0.5).ceil().max(0.0) as u32;
+    let x_end = ((max.x - 0.5).floor() + 1.0).clamp(0.0, width as f32) as u32;
+    let y_end = ((max.y - 0.5).floor() + 1.0).clamp(0.0, height as f32) as u32;
+    if x_start >= x_end || y_start >= y_end {
+        return None;
+    }
+
+    let pixel_count = ((x_end - x_start) * (y_end - y_start)) as usize;
+    let channels = STATISTICS_CHANNELS
+        .iter()
+        .map(|&channel| {
+            let mut sum = 0.0_f64;
+            let mut sum_sq = 0.0_f64;
+            let mut min_value = f32::INFINITY;
+            let mut max_value = f32::NEG_INFINITY;
+            let mut histogram = vec![0_u32; 256];
+            for y in y_start..y_end {
+                for x in x_start..x_end {
+                    let p = image.get_pixel(x, y).0;
+                    let value = channel.value(p.map(f32::from));
+                    sum += value as f64;
+                    sum_sq += (value as f64) * (value as f64);
+                    min_value = min_value.min(value);
+                    max_value = max_value.max(value);
+                    histogram[(value.round() as usize).min(255)] += 1;
+                }
             }
-            self.pending_undo_count -= 1;
+            let mean = sum / pixel_count as f64;
+            let variance = (sum_sq / pixel_count as f64 - mean * mean).max(0.0);
+            ChannelStatistics {
+                channel,
+                mean: mean as f32,
+                std_dev: variance.sqrt() as f32,
+                min: min_value,
+                max: max_value,
+                histogram,
+            }
+        })
+        .collect();
+
+    Some(RegionStatistics {
+        pixel_count,
+        channels,
+    })
+}
+
+/// スナップ先の印を描く（端点: 四角, 中点: 三角, 角: ひし形, 交点: ×, エッジ: 円と十字）
+fn draw_snap_glyph(painter: &egui::Painter, center: egui::Pos2, kind: SnapKind) {
+    let stroke = egui::Stroke::new(1.5, egui::Color32::from_rgb(0, 220, 255));
+    let size = 6.0;
+    match kind {
+        SnapKind::Endpoint => {
+            painter.rect_stroke(
+                egui::Rect::from_center_size(center, egui::vec2(size * 2.0, size * 2.0)),
+                0.0,
+                stroke,
+                egui::StrokeKind::Middle,
+            );
+        }
+        SnapKind::Midpoint => {
+            let points = vec![
+                center + egui::vec2(0.0, -size),
+                center + egui::vec2(size, size * 0.8),
+                center + egui::vec2(-size, size * 0.8),
+            ];
+            painter.add(egui::Shape::closed_line(points, stroke));
+        }
+        SnapKind::Corner => {
+            let points = vec![
+                center + egui::vec2(0.0, -size),
+                center + egui::vec2(size, 0.0),
+                center + egui::vec2(0.0, size),
+                center + egui::vec2(-size, 0.0),
+            ];
+            painter.add(egui::Shape::closed_line(points, stroke));
+        }
+        SnapKind::Intersection => {
+            painter.line_segment(
+                [
+                    center + egui::vec2(-size, -size),
+                    center + egui::vec2(size, size),
+                ],
+                stroke,
+            );
+            painter.line_segment(
+                [
+                    center + egui::vec2(-size, size),
+                    center + egui::vec2(size, -size),
+                ],
+                stroke,
+            );
+        }
+        SnapKind::Edge => {
+            painter.circle_stroke(center, size, stroke);
+            painter.line_segment(
+                [center - egui::vec2(3.0, 0.0), center + egui::vec2(3.0, 0.0)],
+                stroke,
+            );
+            painter.line_segment(
+                [center - egui::vec2(0.0, 3.0), center + egui::vec2(0.0, 3.0)],
+                stroke,
+            );
         }
+    }
+}
 
-        // テスト用：Redo操作を実行
-        #[cfg(test)]
-        while self.pending_redo_count > 0 {
-            if self.history.redo() {
-                self.rebuild_from_history();
+/// 2本の線分の交点（交わらない・平行なら None）
+fn segment_intersection(
+    a_start: egui::Pos2,
+    a_end: egui::Pos2,
+    b_start: egui::Pos2,
+    b_end: egui::Pos2,
+) -> Option<egui::Pos2> {
+    let r = a_end - a_start;
+    let s = b_end - b_start;
+    let denom = r.x * s.y - r.y * s.x;
+    if denom.abs() < 1e-6 {
+        return None;
+    }
+    let q = b_start - a_start;
+    let t = (q.x * s.y - q.y * s.x) / denom;
+    let u = (q.x * r.y - q.y * r.x) / denom;
+    if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
+        Some(a_start + r * t)
+    } else {
+        None
+    }
+}
+
+/// 画素 (x, y) での輝度の勾配を Sobel フィルタで求める（画像の外は端の画素で延長）
+fn luminance_gradient(image: &image::RgbaImage, x: i64, y: i64) -> egui::Vec2 {
+    let (width, height) = image.dimensions();
+    let lum = |dx: i64, dy: i64| {
+        let px = (x + dx).clamp(0, width as i64 - 1) as u32;
+        let py = (y + dy).clamp(0, height as i64 - 1) as u32;
+        ImageChannel::Luminance.value(image.get_pixel(px, py).0.map(f32::from))
+    };
+    let gx =
+        (lum(1, -1) + 2.0 * lum(1, 0) + lum(1, 1)) - (lum(-1, -1) + 2.0 * lum(-1, 0) + lum(-1, 1));
+    let gy =
+        (lum(-1, 1) + 2.0 * lum(0, 1) + lum(1, 1)) - (lum(-1, -1) + 2.0 * lum(0, -1) + lum(1, -1));
+    egui::vec2(gx, gy)
+}
+
+/// 勾配の大きさを画素中心の間で双線形補間する
+fn gradient_magnitude_at(image: &image::RgbaImage, pos: egui::Pos2) -> f32 {
+    let fx = pos.x - 0.5;
+    let fy = pos.y - 0.5;
+    let (x0, y0) = (fx.floor(), fy.floor());
+    let (tx, ty) = (fx - x0, fy - y0);
+    let m = |dx: i64, dy: i64| luminance_gradient(image, x0 as i64 + dx, y0 as i64 + dy).length();
+    let top = m(0, 0) * (1.0 - tx) + m(1, 0) * tx;
+    let bottom = m(0, 1) * (1.0 - tx) + m(1, 1) * tx;
+    top * (1.0 - ty) + bottom * ty
+}
+
+/// pos から radius 以内で勾配が最も強いエッジに点をスナップする
+/// エッジの位置は勾配方向の放物線近似で小数画素まで求め、点はエッジに沿った位置を保ったまま移動する
+/// 範囲内に十分強いエッジがなければ None
+fn snap_to_edge(image: &image::RgbaImage, pos: egui::Pos2, radius: f32) -> Option<egui::Pos2> {
+    let (width, height) = image.dimensions();
+    let reach = radius.ceil() as i64;
+    let (cx, cy) = (pos.x.floor() as i64, pos.y.floor() as i64);
+    let mut best: Option<(egui::Pos2, egui::Vec2, f32)> = None;
+    for y in (cy - reach).max(0)..=(cy + reach).min(height as i64 - 1) {
+        for x in (cx - reach).max(0)..=(cx + reach).min(width as i64 - 1) {
+            let center = egui::pos2(x as f32 + 0.5, y as f32 + 0.5);
+            if center.distance(pos) > radius {
+                continue;
+            }
+            let gradient = luminance_gradient(image, x, y);
+            let magnitude = gradient.length();
+            // 同じ強さなら近い方を優先
+            let better = best.is_none_or(|(best_center, _, best_magnitude)| {
+                magnitude > best_magnitude
+                    || (magnitude == best_magnitude
+                        && center.distance(pos) < best_center.distance(pos))
+            });
+            if better {
+                best = Some((center, gradient, magnitude));
             }
-            self.pending_redo_count -= 1;
         }
+    }
+    let (center, gradient, magnitude) = best?;
+    if magnitude < EDGE_SNAP_MIN_GRADIENT {
+        return None;
+    }
 
-        // テスト用：測定の始点選択状態を設定（プレビューテスト用）
-        #[cfg(test)]
-        if let Some(start) = self.pending_first_point.take() {
-            self.measurement_state = MeasurementState::FirstPointSelected(start);
-        }
+    // 勾配方向の前後の値から放物線の頂点を求める
+    let direction = gradient.normalized();
+    let before = gradient_magnitude_at(image, center - direction);
+    let after = gradient_magnitude_at(image, center + direction);
+    let curvature = before - 2.0 * magnitude + after;
+    let offset = if curvature < 0.0 {
+        (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    let edge_point = center + direction * offset;
 
-        // テスト用：デバッグマウス位置をcurrent_mouse_image_posに設定
-        #[cfg(test)]
-        if let Some(debug_pos) = self.debug_mouse_position {
-            self.current_mouse_image_pos = Some(debug_pos);
-        }
+    // エッジに垂直な方向だけ動かす
+    Some(pos + direction * (edge_point - pos).dot(direction))
+}
 
-        // テスト用：Ctrl押下状態をシミュレート
-        #[cfg(test)]
-        if self.debug_ctrl_pressed {
-            self.is_ctrl_pressed = true;
-        }
+/// 線分の終点をスナップ角度に合わせて調整する
+/// start: 始点, end: 終点（スナップ前）
+/// snap_angles: スナップする線の向き（度, 画像座標で時計回りが正）。180°違う向きも同じ向きとみなす
+/// tolerance_deg: スナップする角度の差の上限
+/// 戻り値: スナップ後の終点
+fn snap_to_angle(
+    start: egui::Pos2,
+    end: egui::Pos2,
+    snap_angles: &[f32],
+    tolerance_deg: f32,
+) -> egui::Pos2 {
+    let delta = end - start;
+    let distance = delta.length();
+    if distance < 0.001 {
+        return end;
+    }
 
-        // Ctrlキーの状態を取得（テストでdebug_ctrl_pressedがfalseの場合も含む）
-        #[cfg(not(test))]
-        {
-            self.is_ctrl_pressed = ctx.input(|i| i.modifiers.ctrl);
-        }
-        #[cfg(test)]
-        if !self.debug_ctrl_pressed {
-            self.is_ctrl_pressed = ctx.input(|i| i.modifiers.ctrl);
+    // 角度を計算（ラジアン→度）
+    let angle_rad = delta.y.atan2(delta.x);
+    let angle_deg = angle_rad.to_degrees();
+
+    // 最も近い向きにスナップ
+    let nearest_diff = snap_angles
+        .iter()
+        .map(|&snap_angle| normalize_axis_angle_deg(angle_deg - snap_angle))
+        .min_by(|a, b| a.abs().total_cmp(&b.abs()));
+    match nearest_diff {
+        Some(diff) if diff.abs() <= tolerance_deg => {
+            let snapped_rad = (angle_deg - diff).to_radians();
+            egui::pos2(
+                start.x + distance * snapped_rad.cos(),
+                start.y + distance * snapped_rad.sin(),
+            )
         }
+        _ => end, // スナップしない場合はそのまま
+    }
+}
 
-        // キーボードショートカット: Ctrl+V / Cmd+V でクリップボードから貼り付け
-        let paste_shortcut = ctx.input(|i| i.key_pressed(egui::Key::V) && i.modifiers.command);
-        if paste_shortcut {
-            self.paste_from_clipboard(ctx);
+/// カンマまたは空白区切りの角度の一覧を読み取る（読めない項目は無視）
+fn parse_angle_list(text: &str) -> Vec<f32> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|item| item.trim().trim_end_matches('°').parse::<f32>().ok())
+        .filter(|angle| angle.is_finite())
+        .collect()
+}
+
+/// 頂点から2点へ向かう辺のなす角（0〜180度）を求める
+fn included_angle_deg(vertex: egui::Pos2, arm1: egui::Pos2, arm2: egui::Pos2) -> f32 {
+    let u = arm1 - vertex;
+    let v = arm2 - vertex;
+    if u.length() < 0.001 || v.length() < 0.001 {
+        return 0.0;
+    }
+    let cross = u.x * v.y - u.y * v.x;
+    cross.abs().atan2(u.dot(v)).to_degrees()
+}
+
+/// 頂点を中心に、2本の辺の間（小さい方の角）を結ぶ円弧の点列を求める
+fn angle_arc_points(
+    vertex: egui::Pos2,
+    arm1: egui::Pos2,
+    arm2: egui::Pos2,
+    radius: f32,
+) -> Vec<egui::Pos2> {
+    let start_angle = (arm1 - vertex).angle();
+    let mut sweep = (arm2 - vertex).angle() - start_angle;
+    // -π〜πに正規化して小さい方の角を描く
+    if sweep > std::f32::consts::PI {
+        sweep -= std::f32::consts::TAU;
+    } else if sweep < -std::f32::consts::PI {
+        sweep += std::f32::consts::TAU;
+    }
+    let segments = 32;
+    (0..=segments)
+        .map(|i| {
+            let angle = start_angle + sweep * i as f32 / segments as f32;
+            vertex + egui::Vec2::angled(angle) * radius
+        })
+        .collect()
+}
+
+/// 2次元ベクトルの外積（z成分）
+fn cross_2d(a: egui::Vec2, b: egui::Vec2) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+/// 多角形の符号付き面積（靴紐公式）
+fn polygon_signed_area(points: &[egui::Pos2]) -> f32 {
+    let n = points.len();
+    let twice_area: f32 = (0..n)
+        .map(|i| cross_2d(points[i].to_vec2(), points[(i + 1) % n].to_vec2()))
+        .sum();
+    twice_area * 0.5
+}
+
+/// 多角形の周長（終点から始点へ戻る辺を含む）
+fn polygon_perimeter(points: &[egui::Pos2]) -> f32 {
+    let n = points.len();
+    (0..n)
+        .map(|i| points[i].distance(points[(i + 1) % n]))
+        .sum()
+}
+
+/// 2つの線分が互いの内部で交差するか判定する（端点での接触・重なりは除く）
+fn segments_intersect(p1: egui::Pos2, p2: egui::Pos2, q1: egui::Pos2, q2: egui::Pos2) -> bool {
+    let d1 = cross_2d(q2 - q1, p1 - q1);
+    let d2 = cross_2d(q2 - q1, p2 - q1);
+    let d3 = cross_2d(p2 - p1, q1 - p1);
+    let d4 = cross_2d(p2 - p1, q2 - p1);
+    d1 * d2 < 0.0 && d3 * d4 < 0.0
+}
+
+/// 隣接しない辺同士が交差する（自己交差する）多角形か判定する
+fn polygon_is_self_intersecting(points: &[egui::Pos2]) -> bool {
+    let n = points.len();
+    if n < 4 {
+        return false;
+    }
+    for i in 0..n {
+        for j in (i + 2)..n {
+            // 始点を共有する最初と最後の辺は隣接している
+            if i == 0 && j == n - 1 {
+                continue;
+            }
+            if segments_intersect(points[i], points[i + 1], points[j], points[(j + 1) % n]) {
+                return true;
+            }
         }
+    }
+    false
+}
 
-        // Undo/Redo ショートカット: Ctrl/Cmd+Z, Shift+Ctrl/Cmd+Z
-        let undo_shortcut = ctx
-            .input(|i| i.key_pressed(egui::Key::Z) && i.modifiers.command && !i.modifiers.shift);
-        let redo_shortcut = ctx
-            .input(|i| i.key_pressed(egui::Key::Z) && i.modifiers.command && i.modifiers.shift);
-        if undo_shortcut && self.history.undo() {
-            self.rebuild_from_history();
+/// すべての頂点で曲がる向きが同じ（凸）か判定する（自己交差の判定は含まない）
+fn polygon_is_convex(points: &[egui::Pos2]) -> bool {
+    let n = points.len();
+    if n < 3 {
+        return false;
+    }
+    let mut turn_sign = 0.0;
+    for i in 0..n {
+        let a = points[i];
+        let b = points[(i + 1) % n];
+        let c = points[(i + 2) % n];
+        let turn = cross_2d(b - a, c - b);
+        // 一直線上の頂点は無視する
+        if turn.abs() < 1e-6 {
+            continue;
         }
-        if redo_shortcut && self.history.redo() {
-            self.rebuild_from_history();
+        if turn_sign == 0.0 {
+            turn_sign = turn.signum();
+        } else if turn.signum() != turn_sign {
+            return false;
         }
+    }
+    true
+}
+
+/// 点から直線（a, b を通る）へ下ろした垂線の足を求める
+fn perpendicular_foot(p: egui::Pos2, a: egui::Pos2, b: egui::Pos2) -> egui::Pos2 {
+    let ab = b - a;
+    let len_sq = ab.length_sq();
+    if len_sq < 1e-6 {
+        return a;
+    }
+    a + ab * ((p - a).dot(ab) / len_sq)
+}
+
+/// 測定の一覧を種類によらない共通部分の一覧として扱う
+fn as_items<T: MeasurementItem>(items: &[T]) -> impl Iterator<Item = &dyn MeasurementItem> {
+    items.iter().map(|item| item as &dyn MeasurementItem)
+}
+
+fn as_items_mut<T: MeasurementItem>(
+    items: &mut [T],
+) -> impl Iterator<Item = &mut dyn MeasurementItem> {
+    items
+        .iter_mut()
+        .map(|item| item as &mut dyn MeasurementItem)
+}
+
+/// 点から線分 ab までの距離
+fn distance_to_segment(p: egui::Pos2, a: egui::Pos2, b: egui::Pos2) -> f32 {
+    let t = ((p - a).dot(b - a) / (b - a).length_sq().max(1e-6)).clamp(0.0, 1.0);
+    p.distance(a + (b - a) * t)
+}
+
+/// 軸の向きの角度（度）を -90〜90 の範囲に正規化する
+fn normalize_axis_angle_deg(angle_deg: f32) -> f32 {
+    let normalized = angle_deg.rem_euclid(180.0);
+    if normalized > 90.0 {
+        normalized - 180.0
+    } else {
+        normalized
+    }
+}
+
+/// 3点を通る円の中心と半径を求める（3点が一直線上にある場合は None）
+fn circumcircle(a: egui::Pos2, b: egui::Pos2, c: egui::Pos2) -> Option<(egui::Pos2, f32)> {
+    let ab = b - a;
+    let ac = c - a;
+    let d = 2.0 * cross_2d(ab, ac);
+    if d.abs() < 1e-6 {
+        return None;
+    }
+    let ab_sq = ab.length_sq();
+    let ac_sq = ac.length_sq();
+    let offset = egui::vec2(
+        (ac.y * ab_sq - ab.y * ac_sq) / d,
+        (ab.x * ac_sq - ac.x * ab_sq) / d,
+    );
+    Some((a + offset, offset.length()))
+}
+
+/// 長さを指定した倍数にスナップする
+/// length: 元の長さ, multiple: 倍数（0以下で無効）
+/// 戻り値: スナップ後の長さ
+fn snap_length_to_multiple(length: f32, multiple: f32) -> f32 {
+    if multiple <= 0.0 {
+        return length;
+    }
+    (length / multiple).round() * multiple
+}
+
+/// 線分の終点を長さが倍数になるように調整する
+fn snap_line_length(start: egui::Pos2, end: egui::Pos2, multiple: f32) -> egui::Pos2 {
+    if multiple <= 0.0 {
+        return end;
+    }
+    let delta = end - start;
+    let distance = delta.length();
+    if distance < 0.001 {
+        return end;
+    }
+    let snapped_distance = snap_length_to_multiple(distance, multiple);
+    let direction = delta / distance;
+    start + direction * snapped_distance
+}
+
+/// 矩形の対角点を幅・高さが倍数になるように調整する
+/// aspect_ratio（幅/高さ）を指定すると縦横比を固定し、カーソルを覆う方の辺を倍数にそろえてもう一方を比から決める
+fn snap_rect_dimensions(
+    corner1: egui::Pos2,
+    corner2: egui::Pos2,
+    multiple: f32,
+    aspect_ratio: Option<f32>,
+) -> egui::Pos2 {
+    let dx = corner2.x - corner1.x;
+    let dy = corner2.y - corner1.y;
+    let (width, height) = match aspect_ratio {
+        Some(ratio) if ratio > 0.0 => {
+            if dx.abs() >= dy.abs() * ratio {
+                let width = snap_length_to_multiple(dx.abs(), multiple);
+                (width, width / ratio)
+            } else {
+                let height = snap_length_to_multiple(dy.abs(), multiple);
+                (height * ratio, height)
+            }
+        }
+        _ => (
+            snap_length_to_multiple(dx.abs(), multiple),
+            snap_length_to_multiple(dy.abs(), multiple),
+        ),
+    };
+    egui::pos2(
+        corner1.x + width * dx.signum(),
+        corner1.y + height * dy.signum(),
+    )
+}
+
+/// アプリケーション状態
+struct SampoApp {
+    image_texture: Option<egui::TextureHandle>,
+    image_dimensions: Option<(u32, u32)>,
+    /// 解析用に保持するデコード済みの画像
+    image_rgba: Option<image::RgbaImage>,
+    /// ピクセルインスペクタで読み取る位置（カーソルが画像の外に出ると None）
+    inspected_pos: Option<egui::Pos2>,
+    /// 画像上の右クリックメニューでコピーする画素値（メニューを開いた位置の値）
+    context_menu_reading: Option<PixelReading>,
+    /// ピクセルインスペクタで平均を取る範囲（N×N画素）
+    inspector_window_size: u32,
+    region_measurements: Vec<RegionMeasurement>,
+    /// 自動選択で同じ領域とみなす色の差（0〜255）
+    magic_wand_tolerance: f32,
+    /// 測定結果一覧でメモを編集中の測定
+    note_editing: Option<Selection>,
+    /// 入力中の名前・メモの変更を記録した測定・項目と履歴の位置（同じ入力欄での続く入力はこの記録にまとめる）
+    text_edit_action: Option<(Selection, TextField, usize)>,
+    /// 選択モードで選択中の測定
+    selection: Vec<Selection>,
+    /// ドラッグ中の編集
+    drag_edit: Option<DragEdit>,
+    /// 範囲選択の始点と現在位置（画像座標）
+    rubber_band: Option<(egui::Pos2, egui::Pos2)>,
+    blob_measurements: Vec<BlobMeasurement>,
+    blob_threshold_method: ThresholdMethod,
+    /// 粒子解析の手動しきい値（輝度 0〜255）
+    blob_threshold: u8,
+    /// 背景より暗い物体を粒子とする
+    blob_dark_objects: bool,
+    /// 粒子とみなす最小の画素数
+    blob_min_area: usize,
+    /// 画像の輝度から大津の方法で求めたしきい値（画像を読み込んだときに求める）
+    blob_otsu_threshold: Option<u8>,
+    /// 矩形の測定IDごとの、画素統計を求めた範囲と結果（範囲が変わったものだけ求め直す）
+    rectangle_statistics: HashMap<u64, (egui::Rect, Option<RegionStatistics>)>,
+    image_path: Option<String>,
+    measurement_state: MeasurementState,
+    measurement_mode: MeasurementMode,
+    measurements: Vec<Measurement>,
+    rectangle_measurements: Vec<RectangleMeasurement>,
+    angle_measurements: Vec<AngleMeasurement>,
+    polyline_measurements: Vec<PolylineMeasurement>,
+    polygon_measurements: Vec<PolygonMeasurement>,
+    circle_measurements: Vec<CircleMeasurement>,
+    circle_method: CircleMethod,
+    ellipse_measurements: Vec<EllipseMeasurement>,
+    rotated_rectangle_measurements: Vec<RotatedRectangleMeasurement>,
+    point_to_line_measurements: Vec<PointToLineMeasurement>,
+    /// 点と線の距離の基準線（線分の測定ID, None なら新たに描く）
+    point_line_reference: Option<u64>,
+    parallel_gap_measurements: Vec<ParallelGapMeasurement>,
+    /// 間隔測定に使う2本の線分（線分の測定ID, None なら新たに描く）
+    parallel_gap_references: [Option<u64>; 2],
+    /// 間隔測定のために描いた線分（2本そろうまで保持）
+    parallel_gap_drawn_lines: Vec<Measurement>,
+    markers: Vec<Marker>,
+    marker_categories: Vec<MarkerCategory>,
+    /// 配置に使うカテゴリ（`marker_categories` のインデックス）
+    active_marker_category: usize,
+    new_marker_category_name: String,
+    /// 測定のレイヤー（先頭は既定のレイヤーで削除できない）
+    layers: Vec<Layer>,
+    /// 新しい測定を追加するレイヤー（レイヤーのID）
+    active_layer: u64,
+    /// 最後に割り当てたレイヤーのID
+    last_layer_id: u64,
+    new_layer_name: String,
+    /// 操作中（ドラッグ・入力中）に記録したスタイル変更の履歴の位置（続く変更はこの記録にまとめる）
+    style_edit_action: Option<usize>,
+    /// 輝度プロファイルを表示する線分（線分の測定ID）
+    profile_line: Option<u64>,
+    profile_channel: ImageChannel,
+    /// プロファイル上でホバーしている位置（始点からの距離[px]）
+    profile_hover_distance: Option<f32>,
+    calibration: Option<Calibration>,
+    calibration_state: CalibrationState,
+    calibration_input: String,
+    calibration_unit: String,
+    zoom: f32,
+    is_calibrating: bool,
+    text_color: egui::Color32,
+    scroll_offset: egui::Vec2,
+    needs_scroll_reset: bool,
+    show_preview: bool,
+    current_mouse_image_pos: Option<egui::Pos2>,
+    is_ctrl_pressed: bool,
+    is_shift_pressed: bool,
+    length_snap_multiple: f32,
+    /// 長さスナップの倍数をキャリブレーションの単位で指定する（未キャリブレーション時はピクセル）
+    length_snap_calibrated: bool,
+    /// 縦横比を固定したときの比（Shift押下中または常に固定で有効）
+    rect_aspect: RectAspect,
+    /// 縦横比の幅:高さ
+    rect_aspect_ratio: (f32, f32),
+    /// Shift を押さなくても縦横比を固定する
+    rect_aspect_always: bool,
+    /// 角度スナップの刻み
+    angle_snap_step: AngleSnapStep,
+    /// 任意の角度の一覧（カンマ区切り, 度）
+    angle_snap_custom_angles: String,
+    /// 角度スナップが効く角度の差（度）
+    angle_snap_tolerance_deg: f32,
+    /// 角度スナップの基準線（線分の測定ID, None なら画像の水平方向）
+    angle_snap_reference: Option<u64>,
+    /// 点を画像のエッジにスナップする
+    edge_snap_enabled: bool,
+    /// エッジを探す範囲（画像ピクセル）
+    edge_snap_radius: f32,
+    /// 線分の端点にスナップする
+    snap_to_endpoints: bool,
+    /// 線分の中点にスナップする
+    snap_to_midpoints: bool,
+    /// 矩形の角にスナップする
+    snap_to_corners: bool,
+    /// 線分どうしの交点にスナップする
+    snap_to_intersections: bool,
+    history: History,
+    /// 起動時に読み込む画像パス（テスト用）
+    #[cfg(test)]
+    pending_image_path: Option<PathBuf>,
+    /// 起動時に追加する寸法（テスト用）
+    #[cfg(test)]
+    pending_measurements: Vec<(egui::Pos2, egui::Pos2)>,
+    /// Undo回数（テスト用）
+    #[cfg(test)]
+    pending_undo_count: u32,
+    /// Redo回数（テスト用）
+    #[cfg(test)]
+    pending_redo_count: u32,
+    /// デバッグ用マウス位置（テスト用）- 画像座標で指定
+    #[cfg(test)]
+    debug_mouse_position: Option<egui::Pos2>,
+    /// Ctrl押下をシミュレート（テスト用）
+    #[cfg(test)]
+    debug_ctrl_pressed: bool,
+    /// プレビュー表示用に測定の始点を選択状態にする（テスト用）
+    #[cfg(test)]
+    pending_first_point: Option<egui::Pos2>,
+}
+
+impl Default for SampoApp {
+    fn default() -> Self {
+        Self {
+            image_texture: None,
+            image_dimensions: None,
+            image_rgba: None,
+            inspected_pos: None,
+            context_menu_reading: None,
+            inspector_window_size: 1,
+            region_measurements: Vec::new(),
+            magic_wand_tolerance: 32.0,
+            note_editing: None,
+            text_edit_action: None,
+            selection: Vec::new(),
+            drag_edit: None,
+            rubber_band: None,
+            blob_measurements: Vec::new(),
+            blob_threshold_method: ThresholdMethod::default(),
+            blob_threshold: 128,
+            blob_dark_objects: true,
+            blob_min_area: 10,
+            blob_otsu_threshold: None,
+            rectangle_statistics: HashMap::new(),
+            image_path: None,
+            measurement_state: MeasurementState::default(),
+            measurement_mode: MeasurementMode::default(),
+            measurements: Vec::new(),
+            rectangle_measurements: Vec::new(),
+            angle_measurements: Vec::new(),
+            polyline_measurements: Vec::new(),
+            polygon_measurements: Vec::new(),
+            circle_measurements: Vec::new(),
+            circle_method: CircleMethod::default(),
+            ellipse_measurements: Vec::new(),
+            rotated_rectangle_measurements: Vec::new(),
+            point_to_line_measurements: Vec::new(),
+            point_line_reference: None,
+            parallel_gap_measurements: Vec::new(),
+            parallel_gap_references: [None, None],
+            parallel_gap_drawn_lines: Vec::new(),
+            markers: Vec::new(),
+            marker_categories: vec![MarkerCategory {
+                name: "マーカー".to_string(),
+                color: MARKER_CATEGORY_COLORS[0],
+            }],
+            active_marker_category: 0,
+            new_marker_category_name: String::new(),
+            layers: vec![Layer {
+                id: 0,
+                name: "既定".to_string(),
+                line_color: None,
+                label_color: None,
+                visible: true,
+            }],
+            active_layer: 0,
+            last_layer_id: 0,
+            new_layer_name: String::new(),
+            style_edit_action: None,
+            profile_line: None,
+            profile_channel: ImageChannel::default(),
+            profile_hover_distance: None,
+            calibration: None,
+            calibration_state: CalibrationState::default(),
+            calibration_input: String::new(),
+            calibration_unit: "mm".to_string(),
+            zoom: 1.0,
+            is_calibrating: false,
+            text_color: egui::Color32::BLACK,
+            scroll_offset: egui::Vec2::ZERO,
+            needs_scroll_reset: false,
+            show_preview: true,
+            current_mouse_image_pos: None,
+            is_ctrl_pressed: false,
+            is_shift_pressed: false,
+            length_snap_multiple: 1.0,
+            length_snap_calibrated: false,
+            rect_aspect: RectAspect::default(),
+            rect_aspect_ratio: (4.0, 3.0),
+            rect_aspect_always: false,
+            angle_snap_step: AngleSnapStep::default(),
+            angle_snap_custom_angles: "0, 90".to_string(),
+            angle_snap_tolerance_deg: 5.0,
+            angle_snap_reference: None,
+            edge_snap_enabled: false,
+            edge_snap_radius: 5.0,
+            snap_to_endpoints: true,
+            snap_to_midpoints: true,
+            snap_to_corners: true,
+            snap_to_intersections: true,
+            history: History::default(),
+            #[cfg(test)]
+            pending_image_path: None,
+            #[cfg(test)]
+            pending_measurements: Vec::new(),
+            #[cfg(test)]
+            pending_undo_count: 0,
+            #[cfg(test)]
+            pending_redo_count: 0,
+            #[cfg(test)]
+            debug_mouse_position: None,
+            #[cfg(test)]
+            debug_ctrl_pressed: false,
+            #[cfg(test)]
+            pending_first_point: None,
+        }
+    }
+}
+
+impl SampoApp {
+    fn new(cc: &eframe::CreationContext<'_>) -> Self {
+        egui_extras::install_image_loaders(&cc.egui_ctx);
+
+        // NotoSans JP フォントを設定
+        let mut fonts = egui::FontDefinitions::default();
+
+        fonts.font_data.insert(
+            "NotoSansJP".to_owned(),
+            std::sync::Arc::new(egui::FontData::from_static(include_bytes!(
+                "../assets/NotoSansJP-Regular.ttf"
+            ))),
+        );
+
+        // 日本語フォントを優先的に使用
+        fonts
+            .families
+            .entry(egui::FontFamily::Proportional)
+            .or_default()
+            .insert(0, "NotoSansJP".to_owned());
+
+        fonts
+            .families
+            .entry(egui::FontFamily::Monospace)
+            .or_default()
+            .insert(0, "NotoSansJP".to_owned());
+
+        cc.egui_ctx.set_fonts(fonts);
+
+        Self::default()
+    }
+
+    /// テスト用コンストラクタ：初期画像パスと寸法を指定可能
+    #[cfg(test)]
+    fn new_for_test(
+        cc: &eframe::CreationContext<'_>,
+        image_path: Option<PathBuf>,
+        measurements: Vec<(egui::Pos2, egui::Pos2)>,
+    ) -> Self {
+        Self::new_for_test_with_snap(cc, image_path, measurements, 0.0)
+    }
+
+    /// テスト用コンストラクタ（スナップ倍数を指定可能）
+    #[cfg(test)]
+    fn new_for_test_with_snap(
+        cc: &eframe::CreationContext<'_>,
+        image_path: Option<PathBuf>,
+        measurements: Vec<(egui::Pos2, egui::Pos2)>,
+        length_snap_multiple: f32,
+    ) -> Self {
+        egui_extras::install_image_loaders(&cc.egui_ctx);
+
+        // NotoSans JP フォントを設定（通常のnewと同様）
+        let mut fonts = egui::FontDefinitions::default();
+        fonts.font_data.insert(
+            "NotoSansJP".to_owned(),
+            std::sync::Arc::new(egui::FontData::from_static(include_bytes!(
+                "../assets/NotoSansJP-Regular.ttf"
+            ))),
+        );
+        fonts
+            .families
+            .entry(egui::FontFamily::Proportional)
+            .or_default()
+            .insert(0, "NotoSansJP".to_owned());
+        fonts
+            .families
+            .entry(egui::FontFamily::Monospace)
+            .or_default()
+            .insert(0, "NotoSansJP".to_owned());
+        cc.egui_ctx.set_fonts(fonts);
+
+        let mut app = Self::default();
+        app.pending_image_path = image_path;
+        app.pending_measurements = measurements;
+        app.length_snap_multiple = length_snap_multiple;
+        app
+    }
+
+    fn open_file_dialog(&mut self, ctx: &egui::Context) {
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("Images", &["png", "jpg", "jpeg", "gif", "bmp", "webp"])
+            .pick_file()
+        {
+            self.load_image(ctx, &path);
+        }
+    }
+
+    fn load_image(&mut self, ctx: &egui::Context, path: &PathBuf) {
+        match image::open(path) {
+            Ok(img) => {
+                let rgba = img.to_rgba8();
+                let dimensions = rgba.dimensions();
+
+                let color_image = egui::ColorImage::from_rgba_unmultiplied(
+                    [dimensions.0 as usize, dimensions.1 as usize],
+                    rgba.as_raw(),
+                );
+
+                let texture = ctx.load_texture(
+                    path.to_string_lossy(),
+                    color_image,
+                    egui::TextureOptions::LINEAR,
+                );
+
+                self.image_texture = Some(texture);
+                self.image_dimensions = Some(dimensions);
+                self.set_image_rgba(Some(rgba));
+                self.image_path = Some(path.to_string_lossy().into_owned());
+                self.clear_measurements();
+                self.measurement_state = MeasurementState::Idle;
+                self.calibration = None;
+                self.calibration_state = CalibrationState::Idle;
+                self.is_calibrating = false;
+                self.zoom = 1.0;
+                self.needs_scroll_reset = true;
+                self.history = History::default();
+            }
+            Err(e) => {
+                eprintln!("Failed to load image: {}", e);
+            }
+        }
+    }
+
+    fn load_image_from_rgba(
+        &mut self,
+        ctx: &egui::Context,
+        width: u32,
+        height: u32,
+        rgba_data: Vec<u8>,
+        source_name: &str,
+    ) {
+        let color_image =
+            egui::ColorImage::from_rgba_unmultiplied([width as usize, height as usize], &rgba_data);
+
+        let texture = ctx.load_texture(source_name, color_image, egui::TextureOptions::LINEAR);
+
+        self.image_texture = Some(texture);
+        self.image_dimensions = Some((width, height));
+        self.set_image_rgba(image::RgbaImage::from_raw(width, height, rgba_data));
+        self.image_path = Some(source_name.to_string());
+        self.clear_measurements();
+        self.measurement_state = MeasurementState::Idle;
+        self.calibration = None;
+        self.calibration_state = CalibrationState::Idle;
+        self.is_calibrating = false;
+        self.zoom = 1.0;
+        self.needs_scroll_reset = true;
+        self.history = History::default();
+    }
+
+    fn paste_from_clipboard(&mut self, ctx: &egui::Context) {
+        match Clipboard::new() {
+            Ok(mut clipboard) => match clipboard.get_image() {
+                Ok(img_data) => {
+                    // arboard::ImageData の RGBA データを取得
+                    let width = img_data.width as u32;
+                    let height = img_data.height as u32;
+                    let rgba_data = img_data.bytes.into_owned();
+
+                    self.load_image_from_rgba(
+                        ctx,
+                        width,
+                        height,
+                        rgba_data,
+                        "[クリップボードから貼り付け]",
+                    );
+                }
+                Err(e) => {
+                    eprintln!("クリップボードに画像がありません: {}", e);
+                }
+            },
+            Err(e) => {
+                eprintln!("クリップボードへのアクセスに失敗: {}", e);
+            }
+        }
+    }
+
+    fn screen_to_image(&self, screen_pos: egui::Pos2, image_rect: egui::Rect) -> egui::Pos2 {
+        if let Some((w, h)) = self.image_dimensions {
+            let normalized = (screen_pos - image_rect.min) / image_rect.size();
+            egui::pos2(normalized.x * w as f32, normalized.y * h as f32)
+        } else {
+            screen_pos
+        }
+    }
+
+    fn image_to_screen(&self, image_pos: egui::Pos2, image_rect: egui::Rect) -> egui::Pos2 {
+        if let Some((w, h)) = self.image_dimensions {
+            let normalized = egui::vec2(image_pos.x / w as f32, image_pos.y / h as f32);
+            image_rect.min + normalized * image_rect.size()
+        } else {
+            image_pos
+        }
+    }
+
+    fn rebuild_from_history(&mut self) {
+        let state = self.history.rebuild_state();
+        self.measurements = state.measurements;
+        self.rectangle_measurements = state.rectangle_measurements;
+        self.angle_measurements = state.angle_measurements;
+        self.polyline_measurements = state.polyline_measurements;
+        self.polygon_measurements = state.polygon_measurements;
+        self.circle_measurements = state.circle_measurements;
+        self.ellipse_measurements = state.ellipse_measurements;
+        self.rotated_rectangle_measurements = state.rotated_rectangle_measurements;
+        self.point_to_line_measurements = state.point_to_line_measurements;
+        self.parallel_gap_measurements = state.parallel_gap_measurements;
+        self.markers = state.markers;
+        self.region_measurements = state.region_measurements;
+        self.blob_measurements = state.blob_measurements;
+        self.calibration = state.calibration;
+        self.refresh_rectangle_statistics();
+        // Undo などでなくなった測定は選択から外す
+        let selection = std::mem::take(&mut self.selection);
+        self.selection = selection
+            .into_iter()
+            .filter(|&selection| self.selection_exists(selection))
+            .collect();
+    }
+
+    /// 矩形測定の画素統計を求め直す（範囲の変わっていない矩形は前回の結果を使う）
+    fn refresh_rectangle_statistics(&mut self) {
+        let Some(image) = &self.image_rgba else {
+            self.rectangle_statistics.clear();
+            return;
+        };
+        // 削除した矩形の結果は持ち続けない
+        self.rectangle_statistics
+            .retain(|id, _| self.rectangle_measurements.iter().any(|rm| rm.id == *id));
+        for rm in &self.rectangle_measurements {
+            let bounds = egui::Rect::from_min_max(rm.min_corner(), rm.max_corner());
+            let is_current = self
+                .rectangle_statistics
+                .get(&rm.id)
+                .is_some_and(|(cached_bounds, _)| *cached_bounds == bounds);
+            if !is_current {
+                let stats = region_statistics(image, bounds.min, bounds.max);
+                self.rectangle_statistics.insert(rm.id, (bounds, stats));
+            }
+        }
+    }
+
+    /// 矩形の現在の範囲の画素統計（画像がない・画素を含まない場合は None）
+    fn rect_statistics(&self, rect: &RectangleMeasurement) -> Option<&RegionStatistics> {
+        let bounds = egui::Rect::from_min_max(rect.min_corner(), rect.max_corner());
+        match self.rectangle_statistics.get(&rect.id) {
+            Some((cached_bounds, stats)) if *cached_bounds == bounds => stats.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// 解析用の画像を置き換え、画像から求める値を求め直す
+    fn set_image_rgba(&mut self, image: Option<image::RgbaImage>) {
+        self.blob_otsu_threshold = image
+            .as_ref()
+            .map(|image| otsu_threshold(&luminance_histogram(image)));
+        self.image_rgba = image;
+        // 別の画像で求めた画素統計は使わない
+        self.rectangle_statistics.clear();
+        self.refresh_rectangle_statistics();
+    }
+
+    /// すべての種類の測定結果を破棄する（履歴は別途リセットする）
+    fn clear_measurements(&mut self) {
+        self.measurements.clear();
+        self.rectangle_measurements.clear();
+        self.angle_measurements.clear();
+        self.polyline_measurements.clear();
+        self.polygon_measurements.clear();
+        self.circle_measurements.clear();
+        self.ellipse_measurements.clear();
+        self.rotated_rectangle_measurements.clear();
+        self.point_to_line_measurements.clear();
+        self.parallel_gap_measurements.clear();
+        self.parallel_gap_drawn_lines.clear();
+        self.markers.clear();
+        self.region_measurements.clear();
+        self.blob_measurements.clear();
+        self.rectangle_statistics.clear();
+        self.selection.clear();
+        self.drag_edit = None;
+        self.rubber_band = None;
+    }
+
+    fn has_measurements(&self) -> bool {
+        !self.measurements.is_empty()
+            || !self.rectangle_measurements.is_empty()
+            || !self.angle_measurements.is_empty()
+            || !self.polyline_measurements.is_empty()
+            || !self.polygon_measurements.is_empty()
+            || !self.circle_measurements.is_empty()
+            || !self.ellipse_measurements.is_empty()
+            || !self.rotated_rectangle_measurements.is_empty()
+            || !self.point_to_line_measurements.is_empty()
+            || !self.parallel_gap_measurements.is_empty()
+            || !self.markers.is_empty()
+            || !self.region_measurements.is_empty()
+            || !self.blob_measurements.is_empty()
+    }
+
+    /// Ctrl押下中は終点に角度スナップを適用する
+    fn apply_angle_snap(&self, start: egui::Pos2, end: egui::Pos2) -> egui::Pos2 {
+        if self.is_ctrl_pressed {
+            snap_to_angle(
+                start,
+                end,
+                &self.angle_snap_angles(),
+                self.angle_snap_tolerance_deg,
+            )
+        } else {
+            end
+        }
+    }
+
+    /// pos の近くにある既存の測定の端点・中点・角・交点のうち、最も近いもの
+    fn object_snap_target(&self, pos: egui::Pos2) -> Option<(egui::Pos2, SnapKind)> {
+        // 非表示のレイヤーの測定と、ドラッグで動かしている測定にはスナップしない
+        let dragging = |id: u64| {
+            self.drag_edit.as_ref().is_some_and(|drag| {
+                drag.original_lines.iter().any(|line| line.id == id)
+                    || drag.original_rects.iter().any(|rect| rect.id == id)
+            })
+        };
+        let lines: Vec<&Measurement> = self
+            .measurements
+            .iter()
+            .filter(|line| self.layer(line.layer).visible && !dragging(line.id))
+            .collect();
+        let mut candidates = Vec::new();
+        for line in &lines {
+            if self.snap_to_endpoints {
+                candidates.push((line.start_pos(), SnapKind::Endpoint));
+                candidates.push((line.end_pos(), SnapKind::Endpoint));
+            }
+            if self.snap_to_midpoints {
+                candidates.push((
+                    line.start_pos().lerp(line.end_pos(), 0.5),
+                    SnapKind::Midpoint,
+                ));
+            }
+        }
+        if self.snap_to_corners {
+            let rects = self
+                .rectangle_measurements
+                .iter()
+                .filter(|rect| self.layer(rect.layer).visible && !dragging(rect.id));
+            for rect in rects {
+                for corner in rect.corners() {
+                    candidates.push((corner, SnapKind::Corner));
+                }
+            }
+        }
+        if self.snap_to_intersections {
+            for (i, a) in lines.iter().enumerate() {
+                for b in &lines[i + 1..] {
+                    if let Some(p) =
+                        segment_intersection(a.start_pos(), a.end_pos(), b.start_pos(), b.end_pos())
+                    {
+                        candidates.push((p, SnapKind::Intersection));
+                    }
+                }
+            }
+        }
+
+        // 画面上の距離で判定する
+        let max_distance = OBJECT_SNAP_DISTANCE / self.zoom;
+        candidates
+            .into_iter()
+            .map(|(p, kind)| (p, kind, p.distance(pos)))
+            .filter(|&(_, _, d)| d <= max_distance)
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+            .map(|(p, kind, _)| (p, kind))
+    }
+
+    /// 配置する点にスナップ（既存の測定の特徴点を優先し、次にエッジ）を適用する
+    /// 自動選択は点を配置しないので対象外
+    fn snap_point_with_kind(&self, pos: egui::Pos2) -> (egui::Pos2, Option<SnapKind>) {
+        if !self.is_calibrating && self.measurement_mode == MeasurementMode::MagicWand {
+            return (pos, None);
+        }
+        if let Some((target, kind)) = self.object_snap_target(pos) {
+            return (target, Some(kind));
+        }
+        if self.edge_snap_enabled
+            && let Some(snapped) = self
+                .image_rgba
+                .as_ref()
+                .and_then(|image| snap_to_edge(image, pos, self.edge_snap_radius))
+        {
+            return (snapped, Some(SnapKind::Edge));
+        }
+        (pos, None)
+    }
+
+    /// 矩形を描くときに固定する縦横比（幅/高さ）。固定しない場合は None
+    fn rect_aspect_ratio(&self) -> Option<f32> {
+        if !self.is_shift_pressed && !self.rect_aspect_always {
+            return None;
+        }
+        match self.rect_aspect {
+            RectAspect::Square => Some(1.0),
+            RectAspect::Ratio => {
+                let (width, height) = self.rect_aspect_ratio;
+                (width > 0.0 && height > 0.0).then(|| width / height)
+            }
+        }
+    }
+
+    /// 長さスナップの倍数の単位（キャリブレーション単位で指定していればその単位名）
+    fn length_snap_unit(&self) -> String {
+        match &self.calibration {
+            Some(cal) if self.length_snap_calibrated => cal.unit_name.clone(),
+            _ => "px".to_string(),
+        }
+    }
+
+    /// 長さスナップの倍数をピクセルに換算した値
+    fn length_snap_multiple_px(&self) -> f32 {
+        match &self.calibration {
+            Some(cal) if self.length_snap_calibrated => {
+                self.length_snap_multiple * cal.pixels_per_unit
+            }
+            _ => self.length_snap_multiple,
+        }
+    }
+
+    /// 角度スナップする線の向きの一覧（基準線があればその向きからの角度）
+    fn angle_snap_angles(&self) -> Vec<f32> {
+        let angles = match self.angle_snap_step.step_deg() {
+            Some(step) => (0..(180.0 / step) as usize)
+                .map(|k| k as f32 * step)
+                .collect(),
+            None => parse_angle_list(&self.angle_snap_custom_angles),
+        };
+        let reference_deg = self
+            .angle_snap_reference
+            .and_then(|id| self.line_by_id(id))
+            .map(|line| (line.end_pos() - line.start_pos()).angle().to_degrees())
+            .unwrap_or(0.0);
+        angles
+            .into_iter()
+            .map(|angle| angle + reference_deg)
+            .collect()
+    }
+
+    /// 点と線の距離の基準線として選ばれている線分（削除済みなら None）
+    fn point_line_reference_line(&self) -> Option<&Measurement> {
+        self.point_line_reference.and_then(|id| self.line_by_id(id))
+    }
+
+    /// 粒子解析に使うしきい値（判別分析法の場合は画像を読み込んだときに求めた値）
+    fn effective_blob_threshold(&self) -> Option<u8> {
+        self.image_rgba.as_ref()?;
+        match self.blob_threshold_method {
+            ThresholdMethod::Otsu => self.blob_otsu_threshold,
+            ThresholdMethod::Manual => Some(self.blob_threshold),
+        }
+    }
+
+    /// 粒子解析を実行し、検出した粒子をまとめて1つの履歴として追加する
+    /// 戻り値: 検出した粒子の数
+    fn run_blob_detection(&mut self) -> usize {
+        let (Some(image), Some(threshold)) = (&self.image_rgba, self.effective_blob_threshold())
+        else {
+            return 0;
+        };
+        let blobs: Vec<BlobMeasurement> =
+            detect_blobs(image, threshold, self.blob_dark_objects, self.blob_min_area)
+                .iter()
+                .filter_map(|pixels| RegionMeasurement::from_pixels(pixels))
+                .map(BlobMeasurement::new)
+                .collect();
+        let count = blobs.len();
+        if count > 0 {
+            self.push_new_measurement(Action::AddBlobs(blobs));
+            self.rebuild_from_history();
+        }
+        count
+    }
+
+    /// ピクセルインスペクタの位置の画素値（画像の外なら None）
+    fn pixel_reading(&self) -> Option<PixelReading> {
+        let pos = self.inspected_pos?;
+        let image = self.image_rgba.as_ref()?;
+        let (width, height) = image.dimensions();
+        if pos.x < 0.0 || pos.y < 0.0 || pos.x >= width as f32 || pos.y >= height as f32 {
+            return None;
+        }
+        let pixel = (pos.x as u32, pos.y as u32);
+        Some(PixelReading {
+            pos,
+            pixel,
+            rgba: average_pixel(image, pixel.0, pixel.1, self.inspector_window_size),
+        })
+    }
+
+    /// 輝度プロファイルを表示する線分と、その上の画素値（線分が削除済みなら None）
+    fn current_profile(&self) -> Option<(&Measurement, Vec<(f32, f32)>)> {
+        let line = self.line_by_id(self.profile_line?)?;
+        let image = self.image_rgba.as_ref()?;
+        let profile = intensity_profile(
+            image,
+            line.start_pos(),
+            line.end_pos(),
+            self.profile_channel,
+        );
+        Some((line, profile))
+    }
+
+    /// 測定の所属するレイヤー（削除済みのレイヤーなら既定のレイヤー）
+    fn layer(&self, id: u64) -> &Layer {
+        self.layers
+            .iter()
+            .find(|layer| layer.id == id)
+            .unwrap_or(&self.layers[0])
+    }
+
+    /// 表示中のレイヤーにある測定
+    fn visible_in_layers<'a, T>(
+        &'a self,
+        measurements: &'a [T],
+        layer: impl Fn(&T) -> u64 + 'a,
+    ) -> impl Iterator<Item = &'a T> + 'a {
+        measurements
+            .iter()
+            .filter(move |m| self.layer(layer(m)).visible)
+    }
+
+    /// レイヤーの線の色（レイヤーで指定がなければ測定の種類ごとの色）
+    fn layer_line_color(&self, layer: u64, default: egui::Color32) -> egui::Color32 {
+        self.layer(layer).line_color.unwrap_or(default)
+    }
+
+    /// レイヤーの文字色（レイヤーで指定がなければ寸法文字色）
+    fn layer_label_color(&self, layer: u64) -> egui::Color32 {
+        self.layer(layer).label_color.unwrap_or(self.text_color)
+    }
+
+    /// 新しい測定を選択中のレイヤーに入れて履歴に追加する
+    fn push_new_measurement(&mut self, mut action: Action) {
+        action.set_layer(self.active_layer);
+        self.history.push_action(action);
+    }
+
+    /// レイヤーごとの測定の数（`layers` と同じ並び）
+    fn layer_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.layers.len()];
+        for item in self.measurement_items() {
+            let index = self
+                .layers
+                .iter()
+                .position(|layer| layer.id == item.layer_id())
+                .unwrap_or(0);
+            counts[index] += 1;
+        }
+        counts
+    }
+
+    /// すべての種類の測定（描画と同じ種類の順）
+    fn measurement_items(&self) -> impl Iterator<Item = &dyn MeasurementItem> {
+        as_items(&self.measurements)
+            .chain(as_items(&self.rectangle_measurements))
+            .chain(as_items(&self.angle_measurements))
+            .chain(as_items(&self.polyline_measurements))
+            .chain(as_items(&self.circle_measurements))
+            .chain(as_items(&self.rotated_rectangle_measurements))
+            .chain(as_items(&self.point_to_line_measurements))
+            .chain(as_items(&self.parallel_gap_measurements))
+            .chain(as_items(&self.region_measurements))
+            .chain(as_items(&self.blob_measurements))
+            .chain(as_items(&self.markers))
+            .chain(as_items(&self.ellipse_measurements))
+            .chain(as_items(&self.polygon_measurements))
+    }
+
+    fn measurement_items_mut(&mut self) -> impl Iterator<Item = &mut dyn MeasurementItem> {
+        as_items_mut(&mut self.measurements)
+            .chain(as_items_mut(&mut self.rectangle_measurements))
+            .chain(as_items_mut(&mut self.angle_measurements))
+            .chain(as_items_mut(&mut self.polyline_measurements))
+            .chain(as_items_mut(&mut self.circle_measurements))
+            .chain(as_items_mut(&mut self.rotated_rectangle_measurements))
+            .chain(as_items_mut(&mut self.point_to_line_measurements))
+            .chain(as_items_mut(&mut self.parallel_gap_measurements))
+            .chain(as_items_mut(&mut self.region_measurements))
+            .chain(as_items_mut(&mut self.blob_measurements))
+            .chain(as_items_mut(&mut self.markers))
+            .chain(as_items_mut(&mut self.ellipse_measurements))
+            .chain(as_items_mut(&mut self.polygon_measurements))
+    }
+
+    /// 選択した測定
+    fn measurement_item(&self, selection: Selection) -> Option<&dyn MeasurementItem> {
+        self.measurement_items()
+            .find(|item| item.selection() == selection)
+    }
+
+    fn measurement_item_mut(&mut self, selection: Selection) -> Option<&mut dyn MeasurementItem> {
+        self.measurement_items_mut()
+            .find(|item| item.selection() == selection)
+    }
+
+    /// マーカーのカテゴリの表示色（カテゴリが削除済みなら灰色）
+    fn marker_category_color(&self, category: &str) -> egui::Color32 {
+        self.marker_categories
+            .iter()
+            .find(|c| c.name == category)
+            .map(|c| c.color)
+            .unwrap_or(egui::Color32::GRAY)
+    }
+
+    /// 各マーカーのカテゴリ内での番号（1始まり）
+    fn marker_numbers(&self) -> Vec<usize> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        self.markers
+            .iter()
+            .map(|marker| {
+                let count = counts.entry(marker.category.as_str()).or_insert(0);
+                *count += 1;
+                *count
+            })
+            .collect()
+    }
+
+    /// カテゴリごとのマーカー数（カテゴリの並び順、削除済みのカテゴリは末尾）
+    fn marker_counts(&self) -> Vec<(String, usize)> {
+        let mut counts: Vec<(String, usize)> = self
+            .marker_categories
+            .iter()
+            .map(|c| (c.name.clone(), 0))
+            .collect();
+        for marker in &self.markers {
+            match counts.iter_mut().find(|(name, _)| *name == marker.category) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((marker.category.clone(), 1)),
+            }
+        }
+        counts
+    }
+
+    /// 間隔測定に使う2本の線分（既存の線分を選んだものと描いたものを順に割り当てる）
+    /// 足りない場合は None
+    fn parallel_gap_lines(&self) -> Option<(Measurement, Measurement)> {
+        if let [Some(first), Some(second)] = self.parallel_gap_references
+            && first == second
+        {
+            return None;
+        }
+        let mut drawn = self.parallel_gap_drawn_lines.iter();
+        let mut lines = Vec::with_capacity(2);
+        for reference in self.parallel_gap_references {
+            let line = match reference {
+                Some(id) => self.line_by_id(id)?,
+                None => drawn.next()?,
+            };
+            lines.push(line.clone());
+        }
+        let line2 = lines.pop()?;
+        let line1 = lines.pop()?;
+        Some((line1, line2))
+    }
+
+    /// 2本の線分がそろっていれば間隔測定を確定する
+    fn try_finish_parallel_gap(&mut self) {
+        if let Some((line1, line2)) = self.parallel_gap_lines() {
+            let gap = ParallelGapMeasurement::new(&line1, &line2);
+            self.push_new_measurement(Action::AddParallelGap(gap));
+            self.rebuild_from_history();
+            self.parallel_gap_drawn_lines.clear();
+        }
+    }
+
+    /// 測定IDが id の線分
+    fn line_by_id(&self, id: u64) -> Option<&Measurement> {
+        self.measurements.iter().find(|line| line.id == id)
+    }
+
+    fn line_by_id_mut(&mut self, id: u64) -> Option<&mut Measurement> {
+        self.measurements.iter_mut().find(|line| line.id == id)
+    }
+
+    /// 測定IDが id の矩形
+    fn rect_by_id(&self, id: u64) -> Option<&RectangleMeasurement> {
+        self.rectangle_measurements
+            .iter()
+            .find(|rect| rect.id == id)
+    }
+
+    fn rect_by_id_mut(&mut self, id: u64) -> Option<&mut RectangleMeasurement> {
+        self.rectangle_measurements
+            .iter_mut()
+            .find(|rect| rect.id == id)
+    }
+
+    /// 選択できる測定か（存在し、表示中のレイヤーにある）
+    fn selection_exists(&self, selection: Selection) -> bool {
+        self.measurement_item(selection)
+            .is_some_and(|item| self.layer(item.layer_id()).visible)
+    }
+
+    /// クリック位置にある測定（線分、矩形、ほかの測定の順に優先し、同じ種類では後に追加したものを優先。非表示のレイヤーは除く）
+    /// 矩形は内側でも選択でき、ほかの測定は輪郭の近くで選択できる
+    fn hit_test_measurement(
+        &self,
+        screen_pos: egui::Pos2,
+        image_rect: egui::Rect,
+    ) -> Option<Selection> {
+        let line_hit = self
+            .measurements
+            .iter()
+            .rev()
+            .filter(|line| self.layer(line.layer).visible)
+            .find(|line| {
+                let a = self.image_to_screen(line.start_pos(), image_rect);
+                let b = self.image_to_screen(line.end_pos(), image_rect);
+                distance_to_segment(screen_pos, a, b) <= SELECT_HIT_DISTANCE
+            });
+        if let Some(line) = line_hit {
+            return Some(Selection::Line(line.id));
+        }
+        let rect_hit = self
+            .rectangle_measurements
+            .iter()
+            .rev()
+            .filter(|rect| self.layer(rect.layer).visible)
+            .find(|rect| {
+                let screen_rect = egui::Rect::from_min_max(
+                    self.image_to_screen(rect.min_corner(), image_rect),
+                    self.image_to_screen(rect.max_corner(), image_rect),
+                );
+                screen_rect.expand(SELECT_HIT_DISTANCE).contains(screen_pos)
+            });
+        if let Some(rect) = rect_hit {
+            return Some(Selection::Rect(rect.id));
+        }
+        let items: Vec<&dyn MeasurementItem> = self
+            .measurement_items()
+            .filter(|item| self.layer(item.layer_id()).visible)
+            .collect();
+        items
+            .into_iter()
+            .rev()
+            .find(|item| {
+                item.outline().iter().any(|path| {
+                    let screen: Vec<egui::Pos2> = path
+                        .iter()
+                        .map(|&p| self.image_to_screen(p, image_rect))
+                        .collect();
+                    match screen[..] {
+                        [point] => point.distance(screen_pos) <= SELECT_HIT_DISTANCE,
+                        _ => screen.windows(2).any(|w| {
+                            distance_to_segment(screen_pos, w[0], w[1]) <= SELECT_HIT_DISTANCE
+                        }),
+                    }
+                })
+            })
+            .map(|item| item.selection())
+    }
+
+    /// 選択中の測定（1つだけ選択しているとき）の端点・角のうち、つかめる位置にあるもの
+    fn hit_test_handle(
+        &self,
+        screen_pos: egui::Pos2,
+        image_rect: egui::Rect,
+    ) -> Option<DragHandle> {
+        let near = |p: egui::Pos2| {
+            self.image_to_screen(p, image_rect).distance(screen_pos) <= HANDLE_HIT_DISTANCE
+        };
+        let [selection] = self.selection[..] else {
+            return None;
+        };
+        match selection {
+            Selection::Line(id) => {
+                let line = self.line_by_id(id)?;
+                if near(line.end_pos()) {
+                    Some(DragHandle::LineEnd)
+                } else if near(line.start_pos()) {
+                    Some(DragHandle::LineStart)
+                } else {
+                    None
+                }
+            }
+            Selection::Rect(id) => {
+                let rect = self.rect_by_id(id)?;
+                rect.corners()
+                    .iter()
+                    .position(|&corner| near(corner))
+                    .map(DragHandle::RectCorner)
+            }
+            _ => None,
+        }
+    }
+
+    /// ドラッグ開始: 選択中の端点・角か、押した位置の測定（選択中ならすべて）をつかむ
+    /// 何もない所から始めたら範囲選択
+    fn begin_drag_edit(&mut self, press_pos: egui::Pos2, image_rect: egui::Rect) {
+        let grab_pos = self.screen_to_image(press_pos, image_rect);
+        let handle = match self.hit_test_handle(press_pos, image_rect) {
+            Some(handle) => handle,
+            None => match self.hit_test_measurement(press_pos, image_rect) {
+                Some(hit) => {
+                    if !self.selection.contains(&hit) {
+                        if !self.is_shift_pressed {
+                            self.selection.clear();
+                        }
+                        self.selection.push(hit);
+                    }
+                    DragHandle::Body
+                }
+                None => {
+                    self.rubber_band = Some((grab_pos, grab_pos));
+                    return;
+                }
+            },
+        };
+        // ドラッグで動かせるのは線分・矩形のみ
+        let mut original_lines = Vec::new();
+        let mut original_rects = Vec::new();
+        for &selection in &self.selection {
+            match selection {
+                Selection::Line(id) => {
+                    if let Some(line) = self.line_by_id(id) {
+                        original_lines.push(line.clone());
+                    }
+                }
+                Selection::Rect(id) => {
+                    if let Some(rect) = self.rect_by_id(id) {
+                        original_rects.push(rect.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.drag_edit = Some(DragEdit {
+            handle,
+            grab_pos,
+            original_lines,
+            original_rects,
+        });
+    }
+
+    /// ドラッグ中: つかんだ部分をポインタに合わせて動かす（確定はドラッグ終了時）
+    fn update_drag_edit(&mut self, pointer_pos: egui::Pos2, image_rect: egui::Rect) {
+        let pos = self.screen_to_image(pointer_pos, image_rect);
+        if let Some((_, current)) = &mut self.rubber_band {
+            *current = pos;
+            return;
+        }
+        let Some(drag) = &self.drag_edit else {
+            return;
+        };
+        let offset = pos - drag.grab_pos;
+        // 端点・角の移動には測定を描くときと同じスナップを適用
+        let (snapped, snap_kind) = match drag.handle {
+            DragHandle::Body => (pos, None),
+            _ => self.snap_point_with_kind(pos),
+        };
+        let mut edited_lines = Vec::new();
+        for original in &drag.original_lines {
+            let (start, end) = (original.start_pos(), original.end_pos());
+            let edited = match drag.handle {
+                DragHandle::LineStart => {
+                    original.with_points(self.snap_segment_end(end, snapped, snap_kind), end)
+                }
+                DragHandle::LineEnd => {
+                    original.with_points(start, self.snap_segment_end(start, snapped, snap_kind))
+                }
+                _ => original.with_points(start + offset, end + offset),
+            };
+            edited_lines.push(edited);
+        }
+        let mut edited_rects = Vec::new();
+        for original in &drag.original_rects {
+            let edited = match drag.handle {
+                DragHandle::RectCorner(corner) => {
+                    let opposite = original.corners()[(corner + 2) % 4];
+                    let moved = self.snap_rect_corner(opposite, snapped, snap_kind);
+                    original.with_corners(opposite, moved)
+                }
+                _ => original.with_corners(
+                    original.min_corner() + offset,
+                    original.max_corner() + offset,
+                ),
+            };
+            edited_rects.push(edited);
+        }
+        for edited in edited_lines {
+            if let Some(line) = self.line_by_id_mut(edited.id) {
+                *line = edited;
+            }
+        }
+        for edited in edited_rects {
+            if let Some(rect) = self.rect_by_id_mut(edited.id) {
+                *rect = edited;
+            }
+        }
+        // ドラッグ中も矩形の画素統計を今の範囲に合わせる
+        self.refresh_rectangle_statistics();
+    }
+
+    /// ドラッグ終了: 編集結果を1つの操作として履歴に記録する（範囲選択なら選択を確定）
+    fn finish_drag_edit(&mut self) {
+        if let Some((start, end)) = self.rubber_band.take() {
+            let band = egui::Rect::from_two_pos(start, end);
+            if !self.is_shift_pressed {
+                self.selection.clear();
+            }
+            // 輪郭がすべて枠に入っている測定を選択する
+            let hits: Vec<Selection> = self
+                .measurement_items()
+                .filter(|item| {
+                    self.layer(item.layer_id()).visible
+                        && item.outline().iter().flatten().all(|&p| band.contains(p))
+                })
+                .map(|item| item.selection())
+                .collect();
+            for hit in hits {
+                if !self.selection.contains(&hit) {
+                    self.selection.push(hit);
+                }
+            }
+            return;
+        }
+        let Some(drag) = self.drag_edit.take() else {
+            return;
+        };
+        // 動かなかった測定は記録しない
+        let line_actions = drag.original_lines.iter().filter_map(|original| {
+            self.line_by_id(original.id)
+                .filter(|line| line.start != original.start || line.end != original.end)
+                .map(|line| Action::ReplaceLine(line.clone()))
+        });
+        let rect_actions = drag.original_rects.iter().filter_map(|original| {
+            self.rect_by_id(original.id)
+                .filter(|rect| rect.corners() != original.corners())
+                .map(|rect| Action::ReplaceRect(rect.clone()))
+        });
+        let actions = line_actions.chain(rect_actions).collect();
+        self.push_compound_action(actions);
+        self.rebuild_from_history();
+    }
+
+    /// 名前・メモ（線分・矩形のみ）の入力を置き換えの操作として履歴に記録する
+    /// 入力欄の内容は測定に直接書き込まず、入力のたびに記録するので、Undo などで履歴から作り直しても失われない
+    fn record_text_edit(&mut self, selection: Selection, field: TextField, text: String) {
+        let previous = self
+            .text_edit_action
+            .filter(|&(target, target_field, _)| target == selection && target_field == field)
+            .map(|(_, _, index)| index);
+        let index = self.record_merged_edit(previous, |_, saved| {
+            let action = match selection {
+                Selection::Line(id) => saved
+                    .measurements
+                    .iter()
+                    .find(|line| line.id == id)
+                    .filter(|line| field.of(&line.name, &line.note) != text)
+                    .map(|line| {
+                        let mut line = line.clone();
+                        *field.of_mut(&mut line.name, &mut line.note) = text;
+                        Action::ReplaceLine(line)
+                    }),
+                Selection::Rect(id) => saved
+                    .rectangle_measurements
+                    .iter()
+                    .find(|rect| rect.id == id)
+                    .filter(|rect| field.of(&rect.name, &rect.note) != text)
+                    .map(|rect| {
+                        let mut rect = rect.clone();
+                        *field.of_mut(&mut rect.name, &mut rect.note) = text;
+                        Action::ReplaceRect(rect)
+                    }),
+                _ => None,
+            };
+            action.into_iter().collect()
+        });
+        self.text_edit_action = index.map(|index| (selection, field, index));
+    }
+
+    /// 測定の名前・メモの現在の値
+    fn measurement_text(&self, selection: Selection, field: TextField) -> Option<String> {
+        match selection {
+            Selection::Line(id) => self
+                .line_by_id(id)
+                .map(|line| field.of(&line.name, &line.note).to_string()),
+            Selection::Rect(id) => self
+                .rect_by_id(id)
+                .map(|rect| field.of(&rect.name, &rect.note).to_string()),
+            _ => None,
+        }
+    }
+
+    /// 複数の操作を1つの操作として履歴に追加する（1つだけならそのまま）
+    fn push_compound_action(&mut self, actions: Vec<Action>) {
+        if let Some(action) = Action::compound(actions) {
+            self.history.push_action(action);
+        }
+    }
+
+    /// 同じ操作中（ドラッグ・入力中）の変更を1つの操作として履歴に記録する
+    /// `previous` がこの操作中に記録した最後の操作の位置なら、その操作を記録し直す
+    /// `changes` は記録前の状態から変わった測定を置き換える操作を返す
+    /// 戻り値: 記録した操作の位置（変更がなければ None）
+    fn record_merged_edit(
+        &mut self,
+        previous: Option<usize>,
+        changes: impl FnOnce(&Self, &HistoryState) -> Vec<Action>,
+    ) -> Option<usize> {
+        let merge = previous.is_some_and(|index| self.history.is_last_action(index));
+        let saved = if merge {
+            self.history.state_before_last_action()
+        } else {
+            self.history.rebuild_state()
+        };
+        let action = Action::compound(changes(self, &saved));
+        let index = match (merge, action) {
+            (true, Some(action)) => {
+                self.history.replace_last_action(action);
+                previous
+            }
+            (true, None) => {
+                self.history.remove_last_action();
+                None
+            }
+            (false, Some(action)) => {
+                self.history.push_action(action);
+                Some(self.history.cursor - 1)
+            }
+            (false, None) => None,
+        };
+        self.rebuild_from_history();
+        index
+    }
+
+    /// 選択中の測定をまとめて削除する（1回のUndoで元に戻る）
+    fn delete_selection(&mut self) {
+        let actions = self
+            .selection
+            .iter()
+            .map(|&selection| selection.remove_action())
+            .collect();
+        self.push_compound_action(actions);
+        self.selection.clear();
+        self.rebuild_from_history();
+    }
+
+    fn handle_canvas_click(&mut self, click_pos: egui::Pos2, image_rect: egui::Rect) {
+        // 選択モードでは測定を選択するだけ（何もない所なら選択解除、Shiftで追加・解除）
+        if !self.is_calibrating && self.measurement_mode == MeasurementMode::Select {
+            let hit = self.hit_test_measurement(click_pos, image_rect);
+            if self.is_shift_pressed {
+                if let Some(hit) = hit {
+                    match self.selection.iter().position(|&s| s == hit) {
+                        Some(index) => {
+                            self.selection.remove(index);
+                        }
+                        None => self.selection.push(hit),
+                    }
+                }
+            } else {
+                self.selection = hit.into_iter().collect();
+            }
+            return;
+        }
+
+        let (image_pos, snap_kind) =
+            self.snap_point_with_kind(self.screen_to_image(click_pos, image_rect));
+
+        if self.is_calibrating {
+            match &self.calibration_state {
+                CalibrationState::Idle => {
+                    self.calibration_state = CalibrationState::FirstPointSelected(image_pos);
+                }
+                CalibrationState::FirstPointSelected(start) => {
+                    let start = *start;
+                    // 角度スナップ（Ctrl）と倍数スナップ
+                    let end_pos = self.snap_segment_end(start, image_pos, snap_kind);
+                    let distance_px = start.distance(end_pos);
+                    self.calibration_state = CalibrationState::WaitingForInput {
+                        start,
+                        end: end_pos,
+                        distance_px,
+                    };
+                }
+                CalibrationState::WaitingForInput { .. } => {}
+            }
+        } else {
+            match &self.measurement_state {
+                MeasurementState::Idle => {
+                    // 既存の線分を基準線に選んでいれば、1クリックで距離を確定
+                    if self.measurement_mode == MeasurementMode::PointToLine
+                        && let Some(reference) = self.point_line_reference_line()
+                    {
+                        let point_to_line = PointToLineMeasurement::new(
+                            image_pos,
+                            reference.start_pos(),
+                            reference.end_pos(),
+                        );
+                        self.push_new_measurement(Action::AddPointToLine(point_to_line));
+                        self.rebuild_from_history();
+                        return;
+                    }
+                    // 自動選択はクリックした画素から塗りつぶした領域を測定
+                    if self.measurement_mode == MeasurementMode::MagicWand {
+                        if let Some(image) = &self.image_rgba
+                            && image_pos.x >= 0.0
+                            && image_pos.y >= 0.0
+                        {
+                            let seed = (image_pos.x as u32, image_pos.y as u32);
+                            let pixels = flood_fill_region(image, seed, self.magic_wand_tolerance);
+                            if let Some(region) = RegionMeasurement::from_pixels(&pixels) {
+                                self.push_new_measurement(Action::AddRegion(region));
+                                self.rebuild_from_history();
+                            }
+                        }
+                        return;
+                    }
+                    // マーカーは1クリックで配置
+                    if self.measurement_mode == MeasurementMode::Marker {
+                        if let Some(category) =
+                            self.marker_categories.get(self.active_marker_category)
+                        {
+                            let marker = Marker::new(image_pos, &category.name);
+                            self.push_new_measurement(Action::AddMarker(marker));
+                            self.rebuild_from_history();
+                        }
+                        return;
+                    }
+                    // 間隔測定で2本とも既存の線分を選んでいれば描く線分はない
+                    if self.measurement_mode == MeasurementMode::ParallelGap
+                        && self.parallel_gap_references.iter().all(Option::is_some)
+                    {
+                        return;
+                    }
+                    self.measurement_state = match self.measurement_mode {
+                        MeasurementMode::Polyline | MeasurementMode::Polygon => {
+                            MeasurementState::Vertices(vec![image_pos])
+                        }
+                        _ => MeasurementState::FirstPointSelected(image_pos),
+                    };
+                }
+                MeasurementState::FirstPointSelected(start) => {
+                    let start = *start;
+                    self.measurement_state = match self.measurement_mode {
+                        MeasurementMode::Line => {
+                            let end_pos = self.snap_segment_end(start, image_pos, snap_kind);
+                            let measurement = Measurement::new(start, end_pos);
+                            self.push_new_measurement(Action::AddLine(measurement));
+                            self.rebuild_from_history();
+                            MeasurementState::Idle
+                        }
+                        MeasurementMode::Rectangle => {
+                            let end_pos = self.snap_rect_corner(start, image_pos, snap_kind);
+                            let rect_measurement = RectangleMeasurement::new(start, end_pos);
+                            self.push_new_measurement(Action::AddRect(rect_measurement));
+                            self.rebuild_from_history();
+                            MeasurementState::Idle
+                        }
+                        MeasurementMode::Angle => {
+                            // 始点を頂点として1本目の辺を確定
+                            let arm1 = self.snap_arm_end(start, image_pos, snap_kind);
+                            MeasurementState::SecondPointSelected(start, arm1)
+                        }
+                        MeasurementMode::Circle => match self.circle_method {
+                            CircleMethod::CenterRadius => {
+                                // 始点を中心として半径を確定
+                                let radius_end = snap_line_length(
+                                    start,
+                                    image_pos,
+                                    self.length_snap_multiple_for(snap_kind),
+                                );
+                                let circle =
+                                    CircleMeasurement::new(start, start.distance(radius_end));
+                                self.push_new_measurement(Action::AddCircle(circle));
+                                self.rebuild_from_history();
+                                MeasurementState::Idle
+                            }
+                            CircleMethod::ThreePoint => {
+                                MeasurementState::SecondPointSelected(start, image_pos)
+                            }
+                        },
+                        MeasurementMode::Ellipse => {
+                            // 始点を中心として長軸の端点を確定
+                            let major_end = self.snap_segment_end(start, image_pos, snap_kind);
+                            MeasurementState::SecondPointSelected(start, major_end)
+                        }
+                        MeasurementMode::RotatedRectangle | MeasurementMode::PointToLine => {
+                            // 基準線を確定
+                            let base_end = self.snap_segment_end(start, image_pos, snap_kind);
+                            MeasurementState::SecondPointSelected(start, base_end)
+                        }
+                        MeasurementMode::ParallelGap => {
+                            let end_pos = self.snap_segment_end(start, image_pos, snap_kind);
+                            self.parallel_gap_drawn_lines
+                                .push(Measurement::new(start, end_pos));
+                            self.try_finish_parallel_gap();
+                            MeasurementState::Idle
+                        }
+                        MeasurementMode::Polyline
+                        | MeasurementMode::Polygon
+                        | MeasurementMode::Marker
+                        | MeasurementMode::MagicWand
+                        | MeasurementMode::Select => MeasurementState::Idle,
+                    };
+                }
+                MeasurementState::SecondPointSelected(first, second) => {
+                    let (first, second) = (*first, *second);
+                    self.measurement_state = match self.measurement_mode {
+                        MeasurementMode::Angle => {
+                            let arm2 = self.snap_arm_end(first, image_pos, snap_kind);
+                            let angle_measurement = AngleMeasurement::new(first, second, arm2);
+                            self.push_new_measurement(Action::AddAngle(angle_measurement));
+                            self.rebuild_from_history();
+                            MeasurementState::Idle
+                        }
+                        MeasurementMode::Circle => {
+                            match CircleMeasurement::from_three_points(first, second, image_pos) {
+                                Some(circle) => {
+                                    self.push_new_measurement(Action::AddCircle(circle));
+                                    self.rebuild_from_history();
+                                    MeasurementState::Idle
+                                }
+                                // 一直線上の3点では円が決まらないので、3点目を選び直してもらう
+                                None => MeasurementState::SecondPointSelected(first, second),
+                            }
+                        }
+                        MeasurementMode::Ellipse => {
+                            let ellipse = EllipseMeasurement::from_axes(first, second, image_pos);
+                            self.push_new_measurement(Action::AddEllipse(ellipse));
+                            self.rebuild_from_history();
+                            MeasurementState::Idle
+                        }
+                        MeasurementMode::RotatedRectangle => {
+                            let rotated_rect = RotatedRectangleMeasurement::from_extent_point(
+                                first,
+                                second,
+                                image_pos,
+                                self.length_snap_multiple_for(snap_kind),
+                            );
+                            self.push_new_measurement(Action::AddRotatedRect(rotated_rect));
+                            self.rebuild_from_history();
+                            MeasurementState::Idle
+                        }
+                        MeasurementMode::PointToLine => {
+                            let point_to_line =
+                                PointToLineMeasurement::new(image_pos, first, second);
+                            self.push_new_measurement(Action::AddPointToLine(point_to_line));
+                            self.rebuild_from_history();
+                            MeasurementState::Idle
+                        }
+                        _ => MeasurementState::Idle,
+                    };
+                }
+                MeasurementState::Vertices(points) => {
+                    // 多角形は始点付近のクリックで閉じて確定する
+                    let closes_polygon = self.measurement_mode == MeasurementMode::Polygon
+                        && points.len() >= 3
+                        && self
+                            .image_to_screen(points[0], image_rect)
+                            .distance(click_pos)
+                            <= POLYGON_CLOSE_DISTANCE;
+                    if closes_polygon {
+                        self.finish_vertex_measurement();
+                    } else if let Some(&last) = points.last() {
+                        let vertex = self.snap_segment_end(last, image_pos, snap_kind);
+                        if let MeasurementState::Vertices(points) = &mut self.measurement_state {
+                            points.push(vertex);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// 線分・区間の終点に角度スナップ（Ctrl）と長さスナップを適用する
+    /// 既存の測定・エッジにスナップした点（kind が Some）は、その位置を共有できるようにそのまま使う
+    fn snap_segment_end(
+        &self,
+        start: egui::Pos2,
+        end: egui::Pos2,
+        kind: Option<SnapKind>,
+    ) -> egui::Pos2 {
+        let angle_snapped = self.snap_arm_end(start, end, kind);
+        snap_line_length(start, angle_snapped, self.length_snap_multiple_for(kind))
+    }
+
+    /// 角度スナップ（Ctrl）だけを適用する（スナップした点はそのまま）
+    fn snap_arm_end(
+        &self,
+        start: egui::Pos2,
+        end: egui::Pos2,
+        kind: Option<SnapKind>,
+    ) -> egui::Pos2 {
+        if kind.is_some() {
+            end
+        } else {
+            self.apply_angle_snap(start, end)
+        }
+    }
+
+    /// 矩形の対角点に長さスナップと縦横比の固定を適用する（スナップした点はそのまま）
+    fn snap_rect_corner(
+        &self,
+        start: egui::Pos2,
+        end: egui::Pos2,
+        kind: Option<SnapKind>,
+    ) -> egui::Pos2 {
+        if kind.is_some() {
+            end
+        } else {
+            snap_rect_dimensions(
+                start,
+                end,
+                self.length_snap_multiple_px(),
+                self.rect_aspect_ratio(),
+            )
+        }
+    }
+
+    /// 置いた点に使う長さスナップの倍数（スナップした点には長さスナップを適用しない）
+    fn length_snap_multiple_for(&self, kind: Option<SnapKind>) -> f32 {
+        if kind.is_some() {
+            0.0
+        } else {
+            self.length_snap_multiple_px()
+        }
+    }
+
+    /// 頂点を順に追加する測定（折れ線・多角形）を確定する
+    /// 頂点が足りない場合は何もしない
+    fn finish_vertex_measurement(&mut self) {
+        let MeasurementState::Vertices(points) = &self.measurement_state else {
+            return;
+        };
+        let action = match self.measurement_mode {
+            MeasurementMode::Polyline if points.len() >= 2 => {
+                Action::AddPolyline(PolylineMeasurement::new(points))
+            }
+            MeasurementMode::Polygon if points.len() >= 3 => {
+                Action::AddPolygon(PolygonMeasurement::new(points))
+            }
+            _ => return,
+        };
+        self.push_new_measurement(action);
+        self.rebuild_from_history();
+        self.measurement_state = MeasurementState::Idle;
+    }
+
+    fn draw_measurements(&self, painter: &egui::Painter, image_rect: egui::Rect) {
+        let point_color = egui::Color32::from_rgb(100, 255, 100);
+        let point_radius = 5.0;
+        let mouse_snap = self
+            .current_mouse_image_pos
+            .map(|pos| self.snap_point_with_kind(pos));
+        let snapped_mouse_pos = mouse_snap.map(|(pos, _)| pos);
+        let mouse_snap_kind = mouse_snap.and_then(|(_, kind)| kind);
+
+        // 測定はレイヤーの色（指定がなければ種類ごとの色）で描画し、非表示のレイヤーは描かない
+        // 測定ごとのスタイルがあれば、線の色・太さ・点の大きさ・ラベルはそちらを使う
+        for measurement in &self.measurements {
+            let layer = self.layer(measurement.layer);
+            if !layer.visible {
+                continue;
+            }
+            let style = &measurement.style;
+            let label = style.label_style(self.layer_label_color(measurement.layer));
+            let radius = style.point_radius.unwrap_or(point_radius);
+            let start_screen = self.image_to_screen(measurement.start_pos(), image_rect);
+            let end_screen = self.image_to_screen(measurement.end_pos(), image_rect);
+
+            painter.line_segment(
+                [start_screen, end_screen],
+                style.stroke(layer.line_color.unwrap_or(LINE_COLOR)),
+            );
+            painter.circle_filled(start_screen, radius, point_color);
+            painter.circle_filled(end_screen, radius, point_color);
+
+            let midpoint = start_screen + (end_screen - start_screen) * 0.5;
+            let (distance, unit) = measurement.distance_with_calibration(self.calibration.as_ref());
+            let text = if measurement.name.is_empty() {
+                format!("{:.1} {}", distance, unit)
+            } else {
+                format!("{}: {:.1} {}", measurement.name, distance, unit)
+            };
+            label.text(
+                painter,
+                midpoint + egui::vec2(0.0, -15.0),
+                egui::Align2::CENTER_BOTTOM,
+                text,
+            );
+        }
+
+        // 矩形測定を描画
+        for rect_m in &self.rectangle_measurements {
+            let layer = self.layer(rect_m.layer);
+            if !layer.visible {
+                continue;
+            }
+            let style = &rect_m.style;
+            let label = style.label_style(self.layer_label_color(rect_m.layer));
+            let rect_stroke = style.stroke(layer.line_color.unwrap_or(RECT_COLOR));
+            let radius = style.point_radius.unwrap_or(point_radius);
+            let min_screen = self.image_to_screen(rect_m.min_corner(), image_rect);
+            let max_screen = self.image_to_screen(rect_m.max_corner(), image_rect);
+
+            // 4辺を描画
+            let top_left = min_screen;
+            let top_right = egui::pos2(max_screen.x, min_screen.y);
+            let bottom_left = egui::pos2(min_screen.x, max_screen.y);
+            let bottom_right = max_screen;
+
+            painter.line_segment([top_left, top_right], rect_stroke);
+            painter.line_segment([top_right, bottom_right], rect_stroke);
+            painter.line_segment([bottom_right, bottom_left], rect_stroke);
+            painter.line_segment([bottom_left, top_left], rect_stroke);
+
+            // 4つの角に点を描画
+            painter.circle_filled(top_left, radius, point_color);
+            painter.circle_filled(top_right, radius, point_color);
+            painter.circle_filled(bottom_left, radius, point_color);
+            painter.circle_filled(bottom_right, radius, point_color);
+
+            let (width, height, area, unit) =
+                rect_m.dimensions_with_calibration(self.calibration.as_ref());
+
+            // 幅ラベル（上辺の中央）
+            let width_pos = egui::pos2((top_left.x + top_right.x) / 2.0, top_left.y - 15.0);
+            label.text(
+                painter,
+                width_pos,
+                egui::Align2::CENTER_BOTTOM,
+                format!("{:.1} {}", width, unit),
+            );
+
+            // 高さラベル（左辺の中央）
+            let height_pos = egui::pos2(top_left.x - 10.0, (top_left.y + bottom_left.y) / 2.0);
+            label.text(
+                painter,
+                height_pos,
+                egui::Align2::RIGHT_CENTER,
+                format!("{:.1} {}", height, unit),
+            );
+
+            // 面積ラベル（中央）
+            let area_unit = if unit == "px" {
+                "px²".to_string()
+            } else {
+                format!("{}²", unit)
+            };
+            let center = egui::pos2(
+                (top_left.x + bottom_right.x) / 2.0,
+                (top_left.y + bottom_right.y) / 2.0,
+            );
+            let text = if rect_m.name.is_empty() {
+                format!("{:.1} {}", area, area_unit)
+            } else {
+                format!("{}\n{:.1} {}", rect_m.name, area, area_unit)
+            };
+            label.text(painter, center, egui::Align2::CENTER_CENTER, text);
+        }
+
+        // 角度測定を描画
+        for angle_m in self.visible_in_layers(&self.angle_measurements, |a| a.layer) {
+            let style = &angle_m.style;
+            let angle_stroke = style.stroke(self.layer_line_color(angle_m.layer, ANGLE_COLOR));
+            let radius = style.point_radius.unwrap_or(point_radius);
+            let vertex_screen = self.image_to_screen(angle_m.vertex_pos(), image_rect);
+            let arm1_screen = self.image_to_screen(angle_m.arm1_pos(), image_rect);
+            let arm2_screen = self.image_to_screen(angle_m.arm2_pos(), image_rect);
+
+            painter.line_segment([vertex_screen, arm1_screen], angle_stroke);
+            painter.line_segment([vertex_screen, arm2_screen], angle_stroke);
+            painter.circle_filled(vertex_screen, radius, point_color);
+            painter.circle_filled(arm1_screen, radius, point_color);
+            painter.circle_filled(arm2_screen, radius, point_color);
+
+            self.draw_angle_arc(
+                painter,
+                vertex_screen,
+                arm1_screen,
+                arm2_screen,
+                angle_stroke,
+                &style.label_style(self.layer_label_color(angle_m.layer)),
+            );
+        }
+
+        // 折れ線測定を描画
+        for polyline in self.visible_in_layers(&self.polyline_measurements, |p| p.layer) {
+            let style = &polyline.style;
+            let polyline_stroke =
+                style.stroke(self.layer_line_color(polyline.layer, POLYLINE_COLOR));
+            let screen_points: Vec<egui::Pos2> = polyline
+                .point_positions()
+                .into_iter()
+                .map(|p| self.image_to_screen(p, image_rect))
+                .collect();
+            self.draw_polyline(
+                painter,
+                &screen_points,
+                polyline,
+                polyline_stroke,
+                &style.label_style(self.layer_label_color(polyline.layer)),
+            );
+            let radius = style.point_radius.unwrap_or(point_radius);
+            for &p in &screen_points {
+                painter.circle_filled(p, radius, point_color);
+            }
+        }
+
+        // 円測定を描画
+        for circle in self.visible_in_layers(&self.circle_measurements, |c| c.layer) {
+            let style = &circle.style;
+            let circle_stroke = style.stroke(self.layer_line_color(circle.layer, CIRCLE_COLOR));
+            let label = style.label_style(self.layer_label_color(circle.layer));
+            self.draw_circle(painter, image_rect, circle, circle_stroke, &label);
+            let center_screen = self.image_to_screen(circle.center_pos(), image_rect);
+            let radius = style.point_radius.unwrap_or(point_radius);
+            painter.circle_filled(center_screen, radius, point_color);
+        }
+
+        // 回転矩形測定を描画
+        for rotated_rect in
+            self.visible_in_layers(&self.rotated_rectangle_measurements, |r| r.layer)
+        {
+            let style = &rotated_rect.style;
+            let rotated_rect_stroke =
+                style.stroke(self.layer_line_color(rotated_rect.layer, ROTATED_RECT_COLOR));
+            let label = style.label_style(self.layer_label_color(rotated_rect.layer));
+            self.draw_rotated_rectangle(
+                painter,
+                image_rect,
+                rotated_rect,
+                rotated_rect_stroke,
+                &label,
+            );
+            let radius = style.point_radius.unwrap_or(point_radius);
+            for corner in rotated_rect.corners() {
+                let corner_screen = self.image_to_screen(corner, image_rect);
+                painter.circle_filled(corner_screen, radius, point_color);
+            }
+        }
+
+        // 点と線の距離を描画
+        for point_to_line in self.visible_in_layers(&self.point_to_line_measurements, |p| p.layer) {
+            let style = &point_to_line.style;
+            let point_to_line_stroke =
+                style.stroke(self.layer_line_color(point_to_line.layer, POINT_TO_LINE_COLOR));
+            let label = style.label_style(self.layer_label_color(point_to_line.layer));
+            self.draw_point_to_line(
+                painter,
+                image_rect,
+                point_to_line,
+                point_to_line_stroke,
+                &label,
+            );
+            let point_screen = self.image_to_screen(point_to_line.point_pos(), image_rect);
+            let radius = style.point_radius.unwrap_or(point_radius);
+            painter.circle_filled(point_screen, radius, point_color);
+        }
+
+        // 平行線の間隔を描画
+        for gap in self.visible_in_layers(&self.parallel_gap_measurements, |g| g.layer) {
+            let style = &gap.style;
+            let parallel_gap_stroke =
+                style.stroke(self.layer_line_color(gap.layer, PARALLEL_GAP_COLOR));
+            let label = style.label_style(self.layer_label_color(gap.layer));
+            self.draw_parallel_gap(painter, image_rect, gap, parallel_gap_stroke, &label);
+        }
+
+        // 輝度プロファイルを表示中の線分と、プロファイル上のホバー位置
+        if let Some(line) = self.profile_line.and_then(|id| self.line_by_id(id)) {
+            let start_screen = self.image_to_screen(line.start_pos(), image_rect);
+            let end_screen = self.image_to_screen(line.end_pos(), image_rect);
+            painter.line_segment(
+                [start_screen, end_screen],
+                egui::Stroke::new(5.0, egui::Color32::from_rgba_unmultiplied(0, 200, 255, 90)),
+            );
+            if let Some(distance) = self.profile_hover_distance
+                && line.distance_px > 0.0
+            {
+                let t = (distance / line.distance_px).clamp(0.0, 1.0);
+                let hover_screen = start_screen + (end_screen - start_screen) * t;
+                painter.circle_stroke(
+                    hover_screen,
+                    point_radius + 2.0,
+                    egui::Stroke::new(2.0, egui::Color32::from_rgb(0, 200, 255)),
+                );
+            }
+        }
+
+        // 自動選択の領域を描画（輪郭と重心）
+        for region in self.visible_in_layers(&self.region_measurements, |r| r.layer) {
+            let style = &region.style;
+            let region_stroke = style.stroke(self.layer_line_color(region.layer, REGION_COLOR));
+            let label = style.label_style(self.layer_label_color(region.layer));
+            self.draw_region(painter, image_rect, region, region_stroke, &label);
+        }
+
+        // 粒子解析の結果を描画（輪郭・外接矩形・番号）
+        for blob in self.visible_in_layers(&self.blob_measurements, |b| b.layer) {
+            let style = &blob.style;
+            let blob_stroke = style.stroke_or(self.layer_line_color(blob.layer, BLOB_COLOR), 1.5);
+            for contour in &blob.region.contours {
+                let points: Vec<egui::Pos2> = contour
+                    .iter()
+                    .map(|&(x, y)| self.image_to_screen(egui::pos2(x, y), image_rect))
+                    .collect();
+                painter.add(egui::Shape::closed_line(points, blob_stroke));
+            }
+            let bbox = egui::Rect::from_min_max(
+                self.image_to_screen(blob.bounding_rect.min_corner(), image_rect),
+                self.image_to_screen(blob.bounding_rect.max_corner(), image_rect),
+            );
+            painter.rect_stroke(
+                bbox,
+                0.0,
+                egui::Stroke::new(blob_stroke.width / 3.0, blob_stroke.color),
+                egui::StrokeKind::Middle,
+            );
+            let mut label = style.label_style(self.layer_label_color(blob.layer));
+            if style.label_font_size.is_none() {
+                label.font_id = egui::FontId::proportional(11.0);
+            }
+            label.text(
+                painter,
+                bbox.left_top() + egui::vec2(0.0, -2.0),
+                egui::Align2::LEFT_BOTTOM,
+                blob.id.to_string(),
+            );
+        }
+
+        // マーカーを描画（カテゴリ内の番号付き, 色はカテゴリの色）
+        for (marker, number) in self.markers.iter().zip(self.marker_numbers()) {
+            if !self.layer(marker.layer).visible {
+                continue;
+            }
+            let style = &marker.style;
+            let color = style.color_or(self.marker_category_color(&marker.category));
+            let marker_screen = self.image_to_screen(marker.position_pos(), image_rect);
+            painter.circle(
+                marker_screen,
+                style.point_radius.unwrap_or(point_radius + 1.0),
+                color,
+                egui::Stroke::new(style.stroke_width.unwrap_or(1.5), egui::Color32::BLACK),
+            );
+            style
+                .label_style(self.layer_label_color(marker.layer))
+                .text(
+                    painter,
+                    marker_screen + egui::vec2(8.0, -8.0),
+                    egui::Align2::LEFT_BOTTOM,
+                    number.to_string(),
+                );
+        }
+
+        // 楕円測定を描画
+        for ellipse in self.visible_in_layers(&self.ellipse_measurements, |e| e.layer) {
+            let style = &ellipse.style;
+            let ellipse_stroke = style.stroke(self.layer_line_color(ellipse.layer, ELLIPSE_COLOR));
+            let label = style.label_style(self.layer_label_color(ellipse.layer));
+            self.draw_ellipse(painter, image_rect, ellipse, ellipse_stroke, &label);
+            let center_screen = self.image_to_screen(ellipse.center_pos(), image_rect);
+            let radius = style.point_radius.unwrap_or(point_radius);
+            painter.circle_filled(center_screen, radius, point_color);
+        }
+
+        // 多角形測定を描画
+        for polygon in self.visible_in_layers(&self.polygon_measurements, |p| p.layer) {
+            let style = &polygon.style;
+            let polygon_stroke = style.stroke(self.layer_line_color(polygon.layer, POLYGON_COLOR));
+            let screen_points: Vec<egui::Pos2> = polygon
+                .point_positions()
+                .into_iter()
+                .map(|p| self.image_to_screen(p, image_rect))
+                .collect();
+            let label = style.label_style(self.layer_label_color(polygon.layer));
+            self.draw_polygon(painter, &screen_points, polygon, polygon_stroke, &label);
+            let radius = style.point_radius.unwrap_or(point_radius);
+            for &p in &screen_points {
+                painter.circle_filled(p, radius, point_color);
+            }
+        }
+
+        // 既存の線分を基準線にした点と線の距離のプレビュー
+        if self.measurement_mode == MeasurementMode::PointToLine
+            && matches!(self.measurement_state, MeasurementState::Idle)
+            && !self.is_calibrating
+            && let Some(reference) = self.point_line_reference_line()
+        {
+            let preview_color = egui::Color32::from_rgba_unmultiplied(255, 255, 0, 150);
+            let preview_stroke = egui::Stroke::new(1.5, preview_color);
+            let start_screen = self.image_to_screen(reference.start_pos(), image_rect);
+            let end_screen = self.image_to_screen(reference.end_pos(), image_rect);
+            painter.line_segment(
+                [start_screen, end_screen],
+                egui::Stroke::new(4.0, preview_color),
+            );
+            if self.show_preview
+                && let Some(mouse_pos) = snapped_mouse_pos
+            {
+                let point_to_line = PointToLineMeasurement::new(
+                    mouse_pos,
+                    reference.start_pos(),
+                    reference.end_pos(),
+                );
+                self.draw_point_to_line(
+                    painter,
+                    image_rect,
+                    &point_to_line,
+                    preview_stroke,
+                    &LabelStyle::plain(self.text_color),
+                );
+            }
+        }
+
+        // 間隔測定で選んだ線分・描いた線分を強調表示
+        if self.measurement_mode == MeasurementMode::ParallelGap && !self.is_calibrating {
+            let highlight_stroke =
+                egui::Stroke::new(4.0, egui::Color32::from_rgba_unmultiplied(255, 255, 0, 150));
+            let selected_lines = self
+                .parallel_gap_references
+                .iter()
+                .filter_map(|reference| reference.and_then(|id| self.line_by_id(id)))
+                .chain(&self.parallel_gap_drawn_lines);
+            for line in selected_lines {
+                painter.line_segment(
+                    [
+                        self.image_to_screen(line.start_pos(), image_rect),
+                        self.image_to_screen(line.end_pos(), image_rect),
+                    ],
+                    highlight_stroke,
+                );
+            }
+        }
+
+        // 選択中の測定を強調し、1つだけ選択していれば端点・角にハンドルを表示
+        let highlight_stroke =
+            egui::Stroke::new(4.0, egui::Color32::from_rgba_unmultiplied(255, 255, 0, 120));
+        for &selection in &self.selection {
+            let Some(item) = self.measurement_item(selection) else {
+                continue;
+            };
+            for path in item.outline() {
+                let screen_path: Vec<egui::Pos2> = path
+                    .iter()
+                    .map(|&p| self.image_to_screen(p, image_rect))
+                    .collect();
+                match screen_path[..] {
+                    [point] => {
+                        painter.circle_stroke(point, SELECT_HIT_DISTANCE, highlight_stroke);
+                    }
+                    _ => {
+                        painter.add(egui::Shape::line(screen_path, highlight_stroke));
+                    }
+                }
+            }
+            if self.selection.len() > 1 {
+                continue;
+            }
+            // 端点・角をドラッグで動かせるのは線分・矩形のみ
+            let handles = match selection {
+                Selection::Line(id) => self
+                    .line_by_id(id)
+                    .map(|line| vec![line.start_pos(), line.end_pos()]),
+                Selection::Rect(id) => self.rect_by_id(id).map(|rect| rect.corners().to_vec()),
+                _ => None,
+            };
+            for handle in handles.into_iter().flatten() {
+                let handle = self.image_to_screen(handle, image_rect);
+                let handle_rect = egui::Rect::from_center_size(handle, egui::vec2(8.0, 8.0));
+                painter.rect_filled(handle_rect, 0.0, egui::Color32::WHITE);
+                painter.rect_stroke(
+                    handle_rect,
+                    0.0,
+                    egui::Stroke::new(1.0, egui::Color32::BLACK),
+                    egui::StrokeKind::Middle,
+                );
+            }
+        }
+
+        // 範囲選択の枠
+        if let Some((start, end)) = self.rubber_band {
+            let band = egui::Rect::from_two_pos(
+                self.image_to_screen(start, image_rect),
+                self.image_to_screen(end, image_rect),
+            );
+            painter.rect_filled(
+                band,
+                0.0,
+                egui::Color32::from_rgba_unmultiplied(100, 150, 255, 40),
+            );
+            painter.rect_stroke(
+                band,
+                0.0,
+                egui::Stroke::new(1.0, egui::Color32::from_rgb(100, 150, 255)),
+                egui::StrokeKind::Middle,
+            );
+        }
+
+        // スナップ先に種類ごとの印を表示
+        if let Some((snapped, Some(kind))) = mouse_snap {
+            draw_snap_glyph(painter, self.image_to_screen(snapped, image_rect), kind);
+        }
+
+        // 測定中の線を描画
+        if let MeasurementState::FirstPointSelected(start) = &self.measurement_state {
+            let start_screen = self.image_to_screen(*start, image_rect);
+            painter.circle_filled(start_screen, point_radius, egui::Color32::YELLOW);
+
+            // プレビュー描画
+            if self.show_preview {
+                if let Some(mouse_pos) = snapped_mouse_pos {
+                    let preview_color = egui::Color32::from_rgba_unmultiplied(255, 255, 0, 150);
+                    let preview_stroke = egui::Stroke::new(1.5, preview_color);
+
+                    match self.measurement_mode {
+                        MeasurementMode::Line => {
+                            // 角度スナップ（Ctrl）・倍数スナップ適用
+                            let effective_mouse_pos =
+                                self.snap_segment_end(*start, mouse_pos, mouse_snap_kind);
+                            let effective_mouse_screen =
+                                self.image_to_screen(effective_mouse_pos, image_rect);
+
+                            // 線分のプレビュー
+                            painter.line_segment(
+                                [start_screen, effective_mouse_screen],
+                                preview_stroke,
+                            );
+                            painter.circle_filled(
+                                effective_mouse_screen,
+                                point_radius * 0.7,
+                                preview_color,
+                            );
+
+                            // 距離のプレビュー表示
+                            let distance_px = start.distance(effective_mouse_pos);
+                            let (distance, unit) = match &self.calibration {
+                                Some(cal) => {
+                                    (distance_px / cal.pixels_per_unit, cal.unit_name.clone())
+                                }
+                                None => (distance_px, "px".to_string()),
+                            };
+                            let midpoint =
+                                start_screen + (effective_mouse_screen - start_screen) * 0.5;
+                            painter.text(
+                                midpoint + egui::vec2(0.0, -15.0),
+                                egui::Align2::CENTER_BOTTOM,
+                                format!("{:.1} {}", distance, unit),
+                                egui::FontId::default(),
+                                self.text_color,
+                            );
+                        }
+                        MeasurementMode::Rectangle => {
+                            // 倍数スナップ・縦横比の固定を適用
+                            let effective_mouse_pos =
+                                self.snap_rect_corner(*start, mouse_pos, mouse_snap_kind);
+                            let effective_mouse_screen =
+                                self.image_to_screen(effective_mouse_pos, image_rect);
+
+                            // 矩形のプレビュー
+                            let min_x = start_screen.x.min(effective_mouse_screen.x);
+                            let max_x = start_screen.x.max(effective_mouse_screen.x);
+                            let min_y = start_screen.y.min(effective_mouse_screen.y);
+                            let max_y = start_screen.y.max(effective_mouse_screen.y);
+
+                            let top_left = egui::pos2(min_x, min_y);
+                            let top_right = egui::pos2(max_x, min_y);
+                            let bottom_left = egui::pos2(min_x, max_y);
+                            let bottom_right = egui::pos2(max_x, max_y);
+
+                            painter.line_segment([top_left, top_right], preview_stroke);
+                            painter.line_segment([top_right, bottom_right], preview_stroke);
+                            painter.line_segment([bottom_right, bottom_left], preview_stroke);
+                            painter.line_segment([bottom_left, top_left], preview_stroke);
+
+                            painter.circle_filled(top_left, point_radius * 0.7, preview_color);
+                            painter.circle_filled(top_right, point_radius * 0.7, preview_color);
+                            painter.circle_filled(bottom_left, point_radius * 0.7, preview_color);
+                            painter.circle_filled(bottom_right, point_radius * 0.7, preview_color);
+
+                            // 寸法のプレビュー表示
+                            let width_px = (effective_mouse_pos.x - start.x).abs();
+                            let height_px = (effective_mouse_pos.y - start.y).abs();
+                            let area_px = width_px * height_px;
+
+                            let (width, height, area, unit) = match &self.calibration {
+                                Some(cal) => {
+                                    let w = width_px / cal.pixels_per_unit;
+                                    let h = height_px / cal.pixels_per_unit;
+                                    let a = area_px / (cal.pixels_per_unit * cal.pixels_per_unit);
+                                    (w, h, a, cal.unit_name.clone())
+                                }
+                                None => (width_px, height_px, area_px, "px".to_string()),
+                            };
+
+                            // 幅ラベル
+                            let width_pos =
+                                egui::pos2((top_left.x + top_right.x) / 2.0, min_y - 15.0);
+                            painter.text(
+                                width_pos,
+                                egui::Align2::CENTER_BOTTOM,
+                                format!("{:.1} {}", width, unit),
+                                egui::FontId::default(),
+                                self.text_color,
+                            );
+
+                            // 高さラベル
+                            let height_pos = egui::pos2(min_x - 10.0, (min_y + max_y) / 2.0);
+                            painter.text(
+                                height_pos,
+                                egui::Align2::RIGHT_CENTER,
+                                format!("{:.1} {}", height, unit),
+                                egui::FontId::default(),
+                                self.text_color,
+                            );
+
+                            // 面積ラベル
+                            let area_unit = if unit == "px" {
+                                "px²".to_string()
+                            } else {
+                                format!("{}²", unit)
+                            };
+                            let center = egui::pos2((min_x + max_x) / 2.0, (min_y + max_y) / 2.0);
+                            painter.text(
+                                center,
+                                egui::Align2::CENTER_CENTER,
+                                format!("{:.1} {}", area, area_unit),
+                                egui::FontId::default(),
+                                self.text_color,
+                            );
+                        }
+                        MeasurementMode::Angle => {
+                            // 1本目の辺のプレビュー
+                            let arm_pos = self.snap_arm_end(*start, mouse_pos, mouse_snap_kind);
+                            let arm_screen = self.image_to_screen(arm_pos, image_rect);
+                            painter.line_segment([start_screen, arm_screen], preview_stroke);
+                            painter.circle_filled(arm_screen, point_radius * 0.7, preview_color);
+                        }
+                        MeasurementMode::Circle => match self.circle_method {
+                            CircleMethod::CenterRadius => {
+                                let radius_end = snap_line_length(
+                                    *start,
+                                    mouse_pos,
+                                    self.length_snap_multiple_for(mouse_snap_kind),
+                                );
+                                let circle =
+                                    CircleMeasurement::new(*start, start.distance(radius_end));
+                                self.draw_circle(
+                                    painter,
+                                    image_rect,
+                                    &circle,
+                                    preview_stroke,
+                                    &LabelStyle::plain(self.text_color),
+                                );
+                            }
+                            CircleMethod::ThreePoint => {
+                                let mouse_screen = self.image_to_screen(mouse_pos, image_rect);
+                                painter.line_segment([start_screen, mouse_screen], preview_stroke);
+                                painter.circle_filled(
+                                    mouse_screen,
+                                    point_radius * 0.7,
+                                    preview_color,
+                                );
+                            }
+                        },
+                        MeasurementMode::RotatedRectangle
+                        | MeasurementMode::PointToLine
+                        | MeasurementMode::ParallelGap => {
+                            // 基準線のプレビュー
+                            let base_end =
+                                self.snap_segment_end(*start, mouse_pos, mouse_snap_kind);
+                            let base_end_screen = self.image_to_screen(base_end, image_rect);
+                            painter.line_segment([start_screen, base_end_screen], preview_stroke);
+                            painter.circle_filled(
+                                base_end_screen,
+                                point_radius * 0.7,
+                                preview_color,
+                            );
+                        }
+                        MeasurementMode::Ellipse => {
+                            // 長軸（中心から端点まで）のプレビュー
+                            let major_end =
+                                self.snap_segment_end(*start, mouse_pos, mouse_snap_kind);
+                            let major_end_screen = self.image_to_screen(major_end, image_rect);
+                            painter.line_segment([start_screen, major_end_screen], preview_stroke);
+                            painter.circle_filled(
+                                major_end_screen,
+                                point_radius * 0.7,
+                                preview_color,
+                            );
+                        }
+                        // 折れ線・多角形は頂点追加中のプレビューで描画する
+                        // マーカー・自動選択は1クリックで確定するため始点選択の状態にならない
+                        MeasurementMode::Polyline
+                        | MeasurementMode::Polygon
+                        | MeasurementMode::Marker
+                        | MeasurementMode::MagicWand
+                        | MeasurementMode::Select => {}
+                    }
+                }
+            }
+        }
+
+        // 折れ線・多角形の頂点追加中のプレビュー
+        if let MeasurementState::Vertices(points) = &self.measurement_state {
+            let preview_color = egui::Color32::from_rgba_unmultiplied(255, 255, 0, 150);
+            let preview_stroke = egui::Stroke::new(1.5, preview_color);
+
+            let mut preview_points = points.clone();
+            if self.show_preview
+                && let (Some(mouse_pos), Some(&last)) = (snapped_mouse_pos, points.last())
+            {
+                preview_points.push(self.snap_segment_end(last, mouse_pos, mouse_snap_kind));
+            }
+
+            let screen_points: Vec<egui::Pos2> = preview_points
+                .iter()
+                .map(|&p| self.image_to_screen(p, image_rect))
+                .collect();
+            if self.measurement_mode == MeasurementMode::Polygon {
+                if preview_points.len() >= 3 {
+                    let polygon = PolygonMeasurement::new(&preview_points);
+                    self.draw_polygon(
+                        painter,
+                        &screen_points,
+                        &polygon,
+                        preview_stroke,
+                        &LabelStyle::plain(self.text_color),
+                    );
+                } else if screen_points.len() == 2 {
+                    painter.line_segment([screen_points[0], screen_points[1]], preview_stroke);
+                }
+            } else {
+                let polyline = PolylineMeasurement::new(&preview_points);
+                self.draw_polyline(
+                    painter,
+                    &screen_points,
+                    &polyline,
+                    preview_stroke,
+                    &LabelStyle::plain(self.text_color),
+                );
+            }
+            for p in points {
+                let p_screen = self.image_to_screen(*p, image_rect);
+                painter.circle_filled(p_screen, point_radius, egui::Color32::YELLOW);
+            }
+        }
+
+        // 3点で指定する測定で2点目まで選択済みの場合のプレビュー
+        if let MeasurementState::SecondPointSelected(first, second) = &self.measurement_state {
+            let first_screen = self.image_to_screen(*first, image_rect);
+            let second_screen = self.image_to_screen(*second, image_rect);
+            let preview_color = egui::Color32::from_rgba_unmultiplied(255, 255, 0, 150);
+            let preview_stroke = egui::Stroke::new(1.5, preview_color);
+
+            painter.line_segment([first_screen, second_screen], preview_stroke);
+            painter.circle_filled(first_screen, point_radius, egui::Color32::YELLOW);
+            painter.circle_filled(second_screen, point_radius, egui::Color32::YELLOW);
+
+            if self.show_preview
+                && let Some(mouse_pos) = snapped_mouse_pos
+            {
+                match self.measurement_mode {
+                    MeasurementMode::Angle => {
+                        let arm2 = self.snap_arm_end(*first, mouse_pos, mouse_snap_kind);
+                        let arm2_screen = self.image_to_screen(arm2, image_rect);
+                        painter.line_segment([first_screen, arm2_screen], preview_stroke);
+                        painter.circle_filled(arm2_screen, point_radius * 0.7, preview_color);
+
+                        self.draw_angle_arc(
+                            painter,
+                            first_screen,
+                            second_screen,
+                            arm2_screen,
+                            preview_stroke,
+                            &LabelStyle::plain(self.text_color),
+                        );
+                    }
+                    MeasurementMode::Circle => {
+                        let mouse_screen = self.image_to_screen(mouse_pos, image_rect);
+                        painter.circle_filled(mouse_screen, point_radius * 0.7, preview_color);
+                        if let Some(circle) =
+                            CircleMeasurement::from_three_points(*first, *second, mouse_pos)
+                        {
+                            self.draw_circle(
+                                painter,
+                                image_rect,
+                                &circle,
+                                preview_stroke,
+                                &LabelStyle::plain(self.text_color),
+                            );
+                        }
+                    }
+                    MeasurementMode::Ellipse => {
+                        let ellipse = EllipseMeasurement::from_axes(*first, *second, mouse_pos);
+                        self.draw_ellipse(
+                            painter,
+                            image_rect,
+                            &ellipse,
+                            preview_stroke,
+                            &LabelStyle::plain(self.text_color),
+                        );
+                    }
+                    MeasurementMode::RotatedRectangle => {
+                        let rotated_rect = RotatedRectangleMeasurement::from_extent_point(
+                            *first,
+                            *second,
+                            mouse_pos,
+                            self.length_snap_multiple_for(mouse_snap_kind),
+                        );
+                        self.draw_rotated_rectangle(
+                            painter,
+                            image_rect,
+                            &rotated_rect,
+                            preview_stroke,
+                            &LabelStyle::plain(self.text_color),
+                        );
+                    }
+                    MeasurementMode::PointToLine => {
+                        let point_to_line = PointToLineMeasurement::new(mouse_pos, *first, *second);
+                        self.draw_point_to_line(
+                            painter,
+                            image_rect,
+                            &point_to_line,
+                            preview_stroke,
+                            &LabelStyle::plain(self.text_color),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // キャリブレーション中の線を描画
+        match &self.calibration_state {
+            CalibrationState::FirstPointSelected(start) => {
+                let start_screen = self.image_to_screen(*start, image_rect);
+                painter.circle_filled(start_screen, point_radius, egui::Color32::LIGHT_BLUE);
+
+                // キャリブレーションのプレビュー描画
+                if self.show_preview {
+                    if let Some(mouse_pos) = snapped_mouse_pos {
+                        let preview_color =
+                            egui::Color32::from_rgba_unmultiplied(100, 200, 255, 150);
+                        let preview_stroke = egui::Stroke::new(1.5, preview_color);
+
+                        // 角度スナップ（Ctrl）・倍数スナップ
+                        let effective_mouse_pos =
+                            self.snap_segment_end(*start, mouse_pos, mouse_snap_kind);
+                        let effective_mouse_screen =
+                            self.image_to_screen(effective_mouse_pos, image_rect);
+
+                        // 線分のプレビュー
+                        painter
+                            .line_segment([start_screen, effective_mouse_screen], preview_stroke);
+                        painter.circle_filled(
+                            effective_mouse_screen,
+                            point_radius * 0.7,
+                            preview_color,
+                        );
+
+                        // 距離のプレビュー表示
+                        let distance_px = start.distance(effective_mouse_pos);
+                        let midpoint = start_screen + (effective_mouse_screen - start_screen) * 0.5;
+                        painter.text(
+                            midpoint + egui::vec2(0.0, -15.0),
+                            egui::Align2::CENTER_BOTTOM,
+                            format!("{:.1} px", distance_px),
+                            egui::FontId::default(),
+                            self.text_color,
+                        );
+                    }
+                }
+            }
+            CalibrationState::WaitingForInput { start, end, .. } => {
+                let start_screen = self.image_to_screen(*start, image_rect);
+                let end_screen = self.image_to_screen(*end, image_rect);
+                let calib_stroke = egui::Stroke::new(2.0, egui::Color32::LIGHT_BLUE);
+                painter.line_segment([start_screen, end_screen], calib_stroke);
+                painter.circle_filled(start_screen, point_radius, egui::Color32::LIGHT_BLUE);
+                painter.circle_filled(end_screen, point_radius, egui::Color32::LIGHT_BLUE);
+            }
+            _ => {}
+        }
+
+        // テスト用：デバッグポインタの描画
+        #[cfg(test)]
+        if let Some(debug_pos) = self.debug_mouse_position {
+            let debug_screen = self.image_to_screen(debug_pos, image_rect);
+            // 真のマウス位置を青い十字で表示
+            let cross_size = 15.0;
+            let cross_color = egui::Color32::from_rgb(0, 100, 255);
+            let cross_stroke = egui::Stroke::new(2.0, cross_color);
+            // 水平線
+            painter.line_segment(
+                [
+                    egui::pos2(debug_screen.x - cross_size, debug_screen.y),
+                    egui::pos2(debug_screen.x + cross_size, debug_screen.y),
+                ],
+                cross_stroke,
+            );
+            // 垂直線
+            painter.line_segment(
+                [
+                    egui::pos2(debug_screen.x, debug_screen.y - cross_size),
+                    egui::pos2(debug_screen.x, debug_screen.y + cross_size),
+                ],
+                cross_stroke,
+            );
+            // 座標ラベル
+            painter.text(
+                debug_screen + egui::vec2(20.0, -10.0),
+                egui::Align2::LEFT_BOTTOM,
+                format!("({:.0}, {:.0})", debug_pos.x, debug_pos.y),
+                egui::FontId::default(),
+                cross_color,
+            );
+        }
+    }
+
+    /// 角度測定の円弧とラベルを描画する（座標はスクリーン座標）
+    fn draw_angle_arc(
+        &self,
+        painter: &egui::Painter,
+        vertex: egui::Pos2,
+        arm1: egui::Pos2,
+        arm2: egui::Pos2,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        // 表示は一様な拡大と平行移動なので、スクリーン座標でも角度は変わらない
+        let angle_deg = included_angle_deg(vertex, arm1, arm2);
+        let shorter_arm = vertex.distance(arm1).min(vertex.distance(arm2));
+        let radius = (shorter_arm * 0.5).min(30.0);
+        if radius < 1.0 {
+            return;
+        }
+
+        let arc = angle_arc_points(vertex, arm1, arm2, radius);
+        let label_dir = arc[arc.len() / 2] - vertex;
+        painter.add(egui::Shape::line(arc, stroke));
+
+        // 角の二等分線上にラベルを配置
+        label.text(
+            painter,
+            vertex + label_dir.normalized() * (radius + 15.0),
+            egui::Align2::CENTER_CENTER,
+            format!("{:.1}°", angle_deg),
+        );
+    }
+
+    /// 折れ線の区間と長さラベルを描画する（座標はスクリーン座標）
+    fn draw_polyline(
+        &self,
+        painter: &egui::Painter,
+        screen_points: &[egui::Pos2],
+        polyline: &PolylineMeasurement,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        let (segment_lengths, total, unit) =
+            polyline.lengths_with_calibration(self.calibration.as_ref());
+        for (segment, length) in screen_points.windows(2).zip(&segment_lengths) {
+            painter.line_segment([segment[0], segment[1]], stroke);
+            let midpoint = segment[0] + (segment[1] - segment[0]) * 0.5;
+            label.text(
+                painter,
+                midpoint + egui::vec2(0.0, -15.0),
+                egui::Align2::CENTER_BOTTOM,
+                format!("{:.1} {}", length, unit),
+            );
+        }
+
+        // 合計長は終点の横に表示
+        if segment_lengths.len() >= 2
+            && let Some(&last) = screen_points.last()
+        {
+            label.text(
+                painter,
+                last + egui::vec2(10.0, 10.0),
+                egui::Align2::LEFT_TOP,
+                format!("計 {:.1} {}", total, unit),
+            );
+        }
+    }
+
+    /// 多角形の輪郭と面積・周長ラベルを描画する（座標はスクリーン座標）
+    fn draw_polygon(
+        &self,
+        painter: &egui::Painter,
+        screen_points: &[egui::Pos2],
+        polygon: &PolygonMeasurement,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        painter.add(egui::Shape::closed_line(screen_points.to_vec(), stroke));
+
+        let (area, perimeter, unit) =
+            polygon.dimensions_with_calibration(self.calibration.as_ref());
+        let mut text = format!("{:.1} {}²\n周長 {:.1} {}", area, unit, perimeter, unit);
+        if !polygon.is_convex {
+            text.push_str(&format!("\n({})", polygon.shape_label()));
+        }
+
+        // ラベルは頂点の重心に配置
+        let center = screen_points
+            .iter()
+            .fold(egui::Vec2::ZERO, |acc, p| acc + p.to_vec2())
+            / screen_points.len() as f32;
+        label.text(painter, center.to_pos2(), egui::Align2::CENTER_CENTER, text);
+    }
+
+    /// 円と半径線、直径ラベルを描画する
+    fn draw_circle(
+        &self,
+        painter: &egui::Painter,
+        image_rect: egui::Rect,
+        circle: &CircleMeasurement,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        let center = circle.center_pos();
+        let center_screen = self.image_to_screen(center, image_rect);
+        let radius_screen = self
+            .image_to_screen(center + egui::vec2(circle.radius_px, 0.0), image_rect)
+            .x
+            - center_screen.x;
+        painter.circle_stroke(center_screen, radius_screen, stroke);
+
+        // 半径線（中心から右方向）
+        let radius_end = center_screen + egui::vec2(radius_screen, 0.0);
+        painter.line_segment([center_screen, radius_end], stroke);
+
+        let (radius, diameter, _, area, unit) =
+            circle.dimensions_with_calibration(self.calibration.as_ref());
+        label.text(
+            painter,
+            center_screen + egui::vec2(radius_screen * 0.5, -10.0),
+            egui::Align2::CENTER_BOTTOM,
+            format!("r {:.1} {}", radius, unit),
+        );
+        label.text(
+            painter,
+            center_screen + egui::vec2(0.0, 10.0),
+            egui::Align2::CENTER_TOP,
+            format!("φ{:.1} {}\n{:.1} {}²", diameter, unit, area, unit),
+        );
+    }
+
+    /// 画素領域の輪郭・重心と、面積のラベルを描画する
+    fn draw_region(
+        &self,
+        painter: &egui::Painter,
+        image_rect: egui::Rect,
+        region: &RegionMeasurement,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        for contour in &region.contours {
+            let points: Vec<egui::Pos2> = contour
+                .iter()
+                .map(|&(x, y)| self.image_to_screen(egui::pos2(x, y), image_rect))
+                .collect();
+            painter.add(egui::Shape::closed_line(points, stroke));
+        }
+
+        let centroid_screen = self.image_to_screen(region.centroid_pos(), image_rect);
+        let cross = 5.0;
+        painter.line_segment(
+            [
+                centroid_screen - egui::vec2(cross, 0.0),
+                centroid_screen + egui::vec2(cross, 0.0),
+            ],
+            stroke,
+        );
+        painter.line_segment(
+            [
+                centroid_screen - egui::vec2(0.0, cross),
+                centroid_screen + egui::vec2(0.0, cross),
+            ],
+            stroke,
+        );
+
+        let (area, _, _, _, unit) = region.dimensions_with_calibration(self.calibration.as_ref());
+        label.text(
+            painter,
+            centroid_screen + egui::vec2(8.0, -8.0),
+            egui::Align2::LEFT_BOTTOM,
+            format!("{:.1} {}²", area, unit),
+        );
+    }
+
+    /// 回転矩形の4辺と、幅・高さ・傾きのラベルを描画する
+    fn draw_rotated_rectangle(
+        &self,
+        painter: &egui::Painter,
+        image_rect: egui::Rect,
+        rotated_rect: &RotatedRectangleMeasurement,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        let corners_screen: Vec<egui::Pos2> = rotated_rect
+            .corners()
+            .into_iter()
+            .map(|p| self.image_to_screen(p, image_rect))
+            .collect();
+        painter.add(egui::Shape::closed_line(corners_screen.clone(), stroke));
+
+        let (width, height, area, unit) =
+            rotated_rect.dimensions_with_calibration(self.calibration.as_ref());
+
+        // 幅ラベル（基準線の中央）
+        let base_mid = corners_screen[0] + (corners_screen[1] - corners_screen[0]) / 2.0;
+        label.text(
+            painter,
+            base_mid + egui::vec2(0.0, -15.0),
+            egui::Align2::CENTER_BOTTOM,
+            format!("{:.1} {} ({:.1}°)", width, unit, rotated_rect.angle_deg),
+        );
+
+        // 高さ・面積ラベル（終点側の辺の中央）
+        let side_mid = corners_screen[1] + (corners_screen[2] - corners_screen[1]) / 2.0;
+        label.text(
+            painter,
+            side_mid + egui::vec2(10.0, 0.0),
+            egui::Align2::LEFT_CENTER,
+            format!("{:.1} {}, {:.1} {}²", height, unit, area, unit),
+        );
+    }
+
+    /// 基準線・垂線・垂線の足と距離ラベルを描画する
+    /// 垂線の足が基準線の外側にある場合は、基準線の延長を破線で描く
+    fn draw_point_to_line(
+        &self,
+        painter: &egui::Painter,
+        image_rect: egui::Rect,
+        point_to_line: &PointToLineMeasurement,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        let point_screen = self.image_to_screen(point_to_line.point_pos(), image_rect);
+        let foot_screen = self.image_to_screen(point_to_line.foot_pos(), image_rect);
+        let line_start_screen = self.image_to_screen(point_to_line.line_start_pos(), image_rect);
+        let line_end_screen = self.image_to_screen(point_to_line.line_end_pos(), image_rect);
+
+        let thin_stroke = egui::Stroke::new(stroke.width * 0.5, stroke.color);
+        painter.line_segment([line_start_screen, line_end_screen], thin_stroke);
+
+        // 延長線（垂線の足に近い方の端点から）
+        let line_vec = line_end_screen - line_start_screen;
+        let t = if line_vec.length_sq() > 1e-6 {
+            (foot_screen - line_start_screen).dot(line_vec) / line_vec.length_sq()
+        } else {
+            0.0
+        };
+        if !(0.0..=1.0).contains(&t) {
+            let nearest_end = if t < 0.0 {
+                line_start_screen
+            } else {
+                line_end_screen
+            };
+            painter.add(egui::Shape::dashed_line(
+                &[nearest_end, foot_screen],
+                thin_stroke,
+                6.0,
+                4.0,
+            ));
+        }
+
+        painter.line_segment([point_screen, foot_screen], stroke);
+        painter.circle_filled(foot_screen, 3.0, stroke.color);
+
+        // 直角マーク
+        let perpendicular = point_screen - foot_screen;
+        if perpendicular.length() > 12.0 && line_vec.length() > 1e-3 {
+            let along = line_vec.normalized() * 8.0;
+            let across = perpendicular.normalized() * 8.0;
+            painter.add(egui::Shape::line(
+                vec![
+                    foot_screen + along,
+                    foot_screen + along + across,
+                    foot_screen + across,
+                ],
+                thin_stroke,
+            ));
+        }
+
+        let (distance, unit) = point_to_line.distance_with_calibration(self.calibration.as_ref());
+        let midpoint = foot_screen + perpendicular * 0.5;
+        label.text(
+            painter,
+            midpoint + egui::vec2(10.0, 0.0),
+            egui::Align2::LEFT_CENTER,
+            format!("⊥ {:.1} {}", distance, unit),
+        );
+    }
+
+    /// 2本の線分と、各端点から相手の線への垂線（破線）、間隔ラベルを描画する
+    fn draw_parallel_gap(
+        &self,
+        painter: &egui::Painter,
+        image_rect: egui::Rect,
+        gap: &ParallelGapMeasurement,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        let (line1, line2) = (gap.line1(), gap.line2());
+        let thin_stroke = egui::Stroke::new(stroke.width * 0.5, stroke.color);
+        for (line, other) in [(&line1, &line2), (&line2, &line1)] {
+            let start_screen = self.image_to_screen(line.start_pos(), image_rect);
+            let end_screen = self.image_to_screen(line.end_pos(), image_rect);
+            painter.line_segment([start_screen, end_screen], stroke);
+            for endpoint in [line.start_pos(), line.end_pos()] {
+                let foot = perpendicular_foot(endpoint, other.start_pos(), other.end_pos());
+                painter.add(egui::Shape::dashed_line(
+                    &[
+                        self.image_to_screen(endpoint, image_rect),
+                        self.image_to_screen(foot, image_rect),
+                    ],
+                    thin_stroke,
+                    4.0,
+                    3.0,
+                ));
+            }
+        }
+
+        let (mean, min, max, unit) = gap.gaps_with_calibration(self.calibration.as_ref());
+        let line1_mid = line1.start_pos() + (line1.end_pos() - line1.start_pos()) * 0.5;
+        let line2_mid = line2.start_pos() + (line2.end_pos() - line2.start_pos()) * 0.5;
+        let label_pos = self.image_to_screen(line1_mid + (line2_mid - line1_mid) * 0.5, image_rect);
+        label.text(
+            painter,
+            label_pos + egui::vec2(10.0, 0.0),
+            egui::Align2::LEFT_CENTER,
+            format!(
+                "平均 {:.1} {}\n({:.1}〜{:.1}), {:.2}°",
+                mean, unit, min, max, gap.angle_deviation_deg
+            ),
+        );
+    }
+
+    /// 楕円と長軸・短軸、寸法ラベルを描画する
+    fn draw_ellipse(
+        &self,
+        painter: &egui::Painter,
+        image_rect: egui::Rect,
+        ellipse: &EllipseMeasurement,
+        stroke: egui::Stroke,
+        label: &LabelStyle,
+    ) {
+        let outline: Vec<egui::Pos2> = ellipse
+            .outline_points(64)
+            .into_iter()
+            .map(|p| self.image_to_screen(p, image_rect))
+            .collect();
+        painter.add(egui::Shape::closed_line(outline, stroke));
+
+        // 長軸・短軸（細線）
+        let center = ellipse.center_pos();
+        let major_dir = egui::Vec2::angled(ellipse.angle_deg.to_radians());
+        let minor_dir = major_dir.rot90();
+        let axis_stroke = egui::Stroke::new(stroke.width * 0.5, stroke.color);
+        for (dir, length) in [
+            (major_dir, ellipse.semi_major_px),
+            (minor_dir, ellipse.semi_minor_px),
+        ] {
+            painter.line_segment(
+                [
+                    self.image_to_screen(center - dir * length, image_rect),
+                    self.image_to_screen(center + dir * length, image_rect),
+                ],
+                axis_stroke,
+            );
+        }
+
+        let (semi_major, semi_minor, area, _, unit) =
+            ellipse.dimensions_with_calibration(self.calibration.as_ref());
+        label.text(
+            painter,
+            self.image_to_screen(center, image_rect) + egui::vec2(0.0, 10.0),
+            egui::Align2::CENTER_TOP,
+            format!(
+                "a {:.1} / b {:.1} {}\n{:.1}°, {:.1} {}²",
+                semi_major, semi_minor, unit, ellipse.angle_deg, area, unit
+            ),
+        );
+    }
+
+    fn show_image_canvas(&mut self, ui: &mut egui::Ui, viewport_size: egui::Vec2) {
+        let Some(texture) = &self.image_texture else {
+            ui.centered_and_justified(|ui| {
+                ui.label("画像が読み込まれていません。「画像を開く」をクリックしてください。");
+            });
+            return;
+        };
+
+        let texture_size = texture.size_vec2() * self.zoom;
+        let texture_id = texture.id();
+
+        // 画像の外側にもスクロールできるようにパディングを追加
+        let padding = viewport_size;
+
+        // 上部パディング
+        ui.allocate_space(egui::vec2(texture_size.x + padding.x * 2.0, padding.y));
+
+        // 画像描画用の情報を保持
+        let mut image_rect = None;
+        let mut clicked_pos = None;
+        let mut double_clicked = false;
+        let mut hover_pos = None;
+        let mut drag_started_pos = None;
+        let mut drag_pos = None;
+        let mut drag_stopped = false;
+
+        ui.horizontal(|ui| {
+            // 左パディング
+            ui.allocate_space(egui::vec2(padding.x, texture_size.y));
+
+            // 画像を描画
+            let (response, painter) =
+                ui.allocate_painter(texture_size, egui::Sense::click_and_drag());
+
+            painter.image(
+                texture_id,
+                response.rect,
+                egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0)),
+                egui::Color32::WHITE,
+            );
+
+            image_rect = Some(response.rect);
+
+            // 右クリックした位置の画素値をメニューからコピーできるようにする
+            if response.secondary_clicked() {
+                self.context_menu_reading = self.pixel_reading();
+            }
+            response.context_menu(|ui| {
+                let Some(reading) = &self.context_menu_reading else {
+                    ui.label("画像上で右クリックすると画素値をコピーできます");
+                    return;
+                };
+                if ui.button("画素値をコピー").clicked() {
+                    copy_to_clipboard(reading.summary());
+                    ui.close();
+                }
+            });
+
+            if response.clicked() {
+                clicked_pos = response.interact_pointer_pos();
+            }
+            double_clicked = response.double_clicked();
+            if response.drag_started() {
+                drag_started_pos = ui.input(|i| i.pointer.press_origin());
+            }
+            if response.dragged() {
+                drag_pos = response.interact_pointer_pos();
+            }
+            drag_stopped = response.drag_stopped();
+
+            // ホバー位置を取得
+            hover_pos = response.hover_pos();
+
+            // 右パディング
+            ui.allocate_space(egui::vec2(padding.x, texture_size.y));
+        });
+
+        // 下部パディング
+        ui.allocate_space(egui::vec2(texture_size.x + padding.x * 2.0, padding.y));
+
+        // クリック処理と測定線描画
+        if let Some(rect) = image_rect {
+            // マウス位置を画像座標に変換して保存
+            // テスト用：debug_mouse_positionが設定されている場合は上書きしない
+            #[cfg(test)]
+            if self.debug_mouse_position.is_none() {
+                self.current_mouse_image_pos = hover_pos.map(|pos| self.screen_to_image(pos, rect));
+            }
+            #[cfg(not(test))]
+            {
+                self.current_mouse_image_pos = hover_pos.map(|pos| self.screen_to_image(pos, rect));
+            }
+
+            self.inspected_pos = self.current_mouse_image_pos;
+
+            // 頂点追加中のダブルクリックは測定の確定として扱う
+            // （ダブルクリックの1回目のクリックで頂点は追加済み）
+            let is_adding_vertices =
+                matches!(self.measurement_state, MeasurementState::Vertices(_));
+            if double_clicked && is_adding_vertices {
+                self.finish_vertex_measurement();
+            } else if let Some(pointer_pos) = clicked_pos {
+                self.handle_canvas_click(pointer_pos, rect);
+            }
+
+            // 選択モードではドラッグで測定を編集
+            if !self.is_calibrating && self.measurement_mode == MeasurementMode::Select {
+                if let Some(press_pos) = drag_started_pos {
+                    self.begin_drag_edit(press_pos, rect);
+                }
+                if let Some(pointer_pos) = drag_pos {
+                    self.update_drag_edit(pointer_pos, rect);
+                }
+                if drag_stopped {
+                    self.finish_drag_edit();
+                }
+            }
+
+            // 測定線を描画（別のPainterを使用）
+            let painter = ui.painter_at(rect);
+            self.draw_measurements(&painter, rect);
+        } else {
+            // テスト用：debug_mouse_positionが設定されている場合は上書きしない
+            #[cfg(test)]
+            if self.debug_mouse_position.is_none() {
+                self.current_mouse_image_pos = None;
+            }
+            #[cfg(not(test))]
+            {
+                self.current_mouse_image_pos = None;
+            }
+        }
+    }
+
+    fn export_csv(&self) -> String {
+        let mut csv = String::new();
+
+        // 線分測定
+        if !self.measurements.is_empty() {
+            csv.push_str("# Line Measurements\n");
+            csv.push_str(
+                "id,start_x,start_y,end_x,end_y,distance_px,distance_calibrated,unit,name,note,layer\n",
+            );
+            for m in &self.measurements {
+                let (distance, unit) = m.distance_with_calibration(self.calibration.as_ref());
+                let calibrated = if self.calibration.is_some() {
+                    format!("{:.2}", distance)
+                } else {
+                    String::new()
+                };
+                csv.push_str(&format!(
+                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{},{},{},{},{}\n",
+                    m.id,
+                    m.start.0,
+                    m.start.1,
+                    m.end.0,
+                    m.end.1,
+                    m.distance_px,
+                    calibrated,
+                    unit,
+                    csv_field(&m.name),
+                    csv_field(&m.note),
+                    csv_field(&self.layer(m.layer).name)
+                ));
+            }
+        }
+
+        // 矩形測定
+        if !self.rectangle_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Rectangle Measurements\n");
+            csv.push_str("id,corner1_x,corner1_y,corner2_x,corner2_y,width_px,height_px,area_px,width_calibrated,height_calibrated,area_calibrated,unit,name,note,layer\n");
+            for rm in &self.rectangle_measurements {
+                let (width, height, area, unit) =
+                    rm.dimensions_with_calibration(self.calibration.as_ref());
+                let (w_cal, h_cal, a_cal) = if self.calibration.is_some() {
+                    (
+                        format!("{:.2}", width),
+                        format!("{:.2}", height),
+                        format!("{:.2}", area),
+                    )
+                } else {
+                    (String::new(), String::new(), String::new())
+                };
+                csv.push_str(&format!(
+                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{},{},{},{},{},{},{}\n",
+                    rm.id,
+                    rm.corner1.0,
+                    rm.corner1.1,
+                    rm.corner2.0,
+                    rm.corner2.1,
+                    rm.width_px,
+                    rm.height_px,
+                    rm.area_px,
+                    w_cal,
+                    h_cal,
+                    a_cal,
+                    unit,
+                    csv_field(&rm.name),
+                    csv_field(&rm.note),
+                    csv_field(&self.layer(rm.layer).name)
+                ));
+            }
+
+            // 矩形内の画素統計とヒストグラム
+            let statistics: Vec<(u64, &RegionStatistics)> = self
+                .rectangle_measurements
+                .iter()
+                .filter_map(|rm| Some((rm.id, self.rect_statistics(rm)?)))
+                .collect();
+            if !statistics.is_empty() {
+                csv.push_str("\n# Rectangle Region Statistics\n");
+                csv.push_str("id,channel,pixel_count,mean,std_dev,min,max\n");
+                for (id, stats) in &statistics {
+                    for c in &stats.channels {
+                        csv.push_str(&format!(
+                            "{},{},{},{:.2},{:.2},{:.2},{:.2}\n",
+                            id,
+                            c.channel.key(),
+                            stats.pixel_count,
+                            c.mean,
+                            c.std_dev,
+                            c.min,
+                            c.max
+                        ));
+                    }
+                }
+
+                csv.push_str("\n# Rectangle Region Histograms\n");
+                let bins: Vec<String> = (0..256).map(|v| format!("bin_{}", v)).collect();
+                csv.push_str(&format!("id,channel,{}\n", bins.join(",")));
+                for (id, stats) in &statistics {
+                    for c in &stats.channels {
+                        let counts: Vec<String> =
+                            c.histogram.iter().map(|n| n.to_string()).collect();
+                        csv.push_str(&format!(
+                            "{},{},{}\n",
+                            id,
+                            c.channel.key(),
+                            counts.join(",")
+                        ));
+                    }
+                }
+            }
+        }
+
+        // 角度測定
+        if !self.angle_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Angle Measurements\n");
+            csv.push_str("id,vertex_x,vertex_y,arm1_x,arm1_y,arm2_x,arm2_y,angle_deg,layer\n");
+            for am in &self.angle_measurements {
+                csv.push_str(&format!(
+                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{}\n",
+                    am.id,
+                    am.vertex.0,
+                    am.vertex.1,
+                    am.arm1.0,
+                    am.arm1.1,
+                    am.arm2.0,
+                    am.arm2.1,
+                    am.angle_deg,
+                    csv_field(&self.layer(am.layer).name)
+                ));
+            }
+        }
+
+        // 折れ線測定（区間ごとの行と合計行）
+        if !self.polyline_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Polyline Measurements\n");
+            csv.push_str(
+                "id,segment,start_x,start_y,end_x,end_y,length_px,length_calibrated,unit,layer\n",
+            );
+            for pm in &self.polyline_measurements {
+                let (segment_lengths, total, unit) =
+                    pm.lengths_with_calibration(self.calibration.as_ref());
+                let layer = csv_field(&self.layer(pm.layer).name);
+                for (j, (segment, length)) in pm.segments().iter().zip(&segment_lengths).enumerate()
+                {
+                    let calibrated = if self.calibration.is_some() {
+                        format!("{:.2}", length)
+                    } else {
+                        String::new()
+                    };
+                    csv.push_str(&format!(
+                        "{},{},{:.2},{:.2},{:.2},{:.2},{:.2},{},{},{}\n",
+                        pm.id,
+                        j + 1,
+                        segment.start.0,
+                        segment.start.1,
+                        segment.end.0,
+                        segment.end.1,
+                        segment.distance_px,
+                        calibrated,
+                        unit,
+                        layer
+                    ));
+                }
+                let total_calibrated = if self.calibration.is_some() {
+                    format!("{:.2}", total)
+                } else {
+                    String::new()
+                };
+                csv.push_str(&format!(
+                    "{},total,,,,,{:.2},{},{},{}\n",
+                    pm.id, pm.total_length_px, total_calibrated, unit, layer
+                ));
+            }
+        }
+
+        // 多角形測定（集計と頂点座標）
+        if !self.polygon_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Polygon Measurements\n");
+            csv.push_str("id,vertex_count,area_px,perimeter_px,area_calibrated,perimeter_calibrated,unit,convex,self_intersecting,layer\n");
+            for pg in &self.polygon_measurements {
+                let (area, perimeter, unit) =
+                    pg.dimensions_with_calibration(self.calibration.as_ref());
+                let (a_cal, p_cal) = if self.calibration.is_some() {
+                    (format!("{:.2}", area), format!("{:.2}", perimeter))
+                } else {
+                    (String::new(), String::new())
+                };
+                csv.push_str(&format!(
+                    "{},{},{:.2},{:.2},{},{},{},{},{},{}\n",
+                    pg.id,
+                    pg.points.len(),
+                    pg.area_px,
+                    pg.perimeter_px,
+                    a_cal,
+                    p_cal,
+                    unit,
+                    pg.is_convex,
+                    pg.is_self_intersecting,
+                    csv_field(&self.layer(pg.layer).name)
+                ));
+            }
+
+            csv.push_str("\n# Polygon Vertices\n");
+            csv.push_str("polygon_id,vertex,x,y\n");
+            for pg in &self.polygon_measurements {
+                for (j, (x, y)) in pg.points.iter().enumerate() {
+                    csv.push_str(&format!("{},{},{:.2},{:.2}\n", pg.id, j + 1, x, y));
+                }
+            }
+        }
+
+        // 円測定
+        if !self.circle_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Circle Measurements\n");
+            csv.push_str("id,center_x,center_y,radius_px,diameter_px,circumference_px,area_px,radius_calibrated,diameter_calibrated,circumference_calibrated,area_calibrated,unit,layer\n");
+            for cm in &self.circle_measurements {
+                let (radius, diameter, circumference, area, unit) =
+                    cm.dimensions_with_calibration(self.calibration.as_ref());
+                let calibrated = if self.calibration.is_some() {
+                    format!(
+                        "{:.2},{:.2},{:.2},{:.2}",
+                        radius, diameter, circumference, area
+                    )
+                } else {
+                    ",,,".to_string()
+                };
+                csv.push_str(&format!(
+                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{},{},{}\n",
+                    cm.id,
+                    cm.center.0,
+                    cm.center.1,
+                    cm.radius_px,
+                    cm.diameter_px(),
+                    cm.circumference_px(),
+                    cm.area_px(),
+                    calibrated,
+                    unit,
+                    csv_field(&self.layer(cm.layer).name)
+                ));
+            }
+        }
+
+        // 楕円測定
+        if !self.ellipse_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Ellipse Measurements\n");
+            csv.push_str("id,center_x,center_y,semi_major_px,semi_minor_px,angle_deg,area_px,perimeter_px,semi_major_calibrated,semi_minor_calibrated,area_calibrated,perimeter_calibrated,unit,layer\n");
+            for em in &self.ellipse_measurements {
+                let (semi_major, semi_minor, area, perimeter, unit) =
+                    em.dimensions_with_calibration(self.calibration.as_ref());
+                let calibrated = if self.calibration.is_some() {
+                    format!(
+                        "{:.2},{:.2},{:.2},{:.2}",
+                        semi_major, semi_minor, area, perimeter
+                    )
+                } else {
+                    ",,,".to_string()
+                };
+                csv.push_str(&format!(
+                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{},{},{}\n",
+                    em.id,
+                    em.center.0,
+                    em.center.1,
+                    em.semi_major_px,
+                    em.semi_minor_px,
+                    em.angle_deg,
+                    em.area_px(),
+                    em.perimeter_px(),
+                    calibrated,
+                    unit,
+                    csv_field(&self.layer(em.layer).name)
+                ));
+            }
+        }
+
+        // 回転矩形測定
+        if !self.rotated_rectangle_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Rotated Rectangle Measurements\n");
+            csv.push_str("id,corner1_x,corner1_y,corner2_x,corner2_y,corner3_x,corner3_y,corner4_x,corner4_y,width_px,height_px,angle_deg,area_px,width_calibrated,height_calibrated,area_calibrated,unit,layer\n");
+            for rr in &self.rotated_rectangle_measurements {
+                let (width, height, area, unit) =
+                    rr.dimensions_with_calibration(self.calibration.as_ref());
+                let corners = rr
+                    .corners()
+                    .iter()
+                    .map(|p| format!("{:.2},{:.2}", p.x, p.y))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let calibrated = if self.calibration.is_some() {
+                    format!("{:.2},{:.2},{:.2}", width, height, area)
+                } else {
+                    ",,".to_string()
+                };
+                csv.push_str(&format!(
+                    "{},{},{:.2},{:.2},{:.2},{:.2},{},{},{}\n",
+                    rr.id,
+                    corners,
+                    rr.width_px,
+                    rr.height_px,
+                    rr.angle_deg,
+                    rr.area_px,
+                    calibrated,
+                    unit,
+                    csv_field(&self.layer(rr.layer).name)
+                ));
+            }
+        }
+
+        // 点と線の距離
+        if !self.point_to_line_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Point-to-Line Measurements\n");
+            csv.push_str("id,point_x,point_y,foot_x,foot_y,line_start_x,line_start_y,line_end_x,line_end_y,distance_px,distance_calibrated,unit,layer\n");
+            for pl in &self.point_to_line_measurements {
+                let (distance, unit) = pl.distance_with_calibration(self.calibration.as_ref());
+                let foot = pl.foot_pos();
+                let calibrated = if self.calibration.is_some() {
+                    format!("{:.2}", distance)
+                } else {
+                    String::new()
+                };
+                csv.push_str(&format!(
+                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{},{},{}\n",
+                    pl.id,
+                    pl.point.0,
+                    pl.point.1,
+                    foot.x,
+                    foot.y,
+                    pl.line_start.0,
+                    pl.line_start.1,
+                    pl.line_end.0,
+                    pl.line_end.1,
+                    pl.distance_px,
+                    calibrated,
+                    unit,
+                    csv_field(&self.layer(pl.layer).name)
+                ));
+            }
+        }
+
+        // 平行線の間隔
+        if !self.parallel_gap_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Parallel Gap Measurements\n");
+            csv.push_str("id,line1_start_x,line1_start_y,line1_end_x,line1_end_y,line2_start_x,line2_start_y,line2_end_x,line2_end_y,mean_gap_px,min_gap_px,max_gap_px,angle_deviation_deg,mean_gap_calibrated,min_gap_calibrated,max_gap_calibrated,unit,layer\n");
+            for gap in &self.parallel_gap_measurements {
+                let (mean, min, max, unit) = gap.gaps_with_calibration(self.calibration.as_ref());
+                let calibrated = if self.calibration.is_some() {
+                    format!("{:.2},{:.2},{:.2}", mean, min, max)
+                } else {
+                    ",,".to_string()
+                };
+                csv.push_str(&format!(
+                    "{},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{},{},{}\n",
+                    gap.id,
+                    gap.line1_start.0,
+                    gap.line1_start.1,
+                    gap.line1_end.0,
+                    gap.line1_end.1,
+                    gap.line2_start.0,
+                    gap.line2_start.1,
+                    gap.line2_end.0,
+                    gap.line2_end.1,
+                    gap.mean_gap_px,
+                    gap.min_gap_px,
+                    gap.max_gap_px,
+                    gap.angle_deviation_deg,
+                    calibrated,
+                    unit,
+                    csv_field(&self.layer(gap.layer).name)
+                ));
+            }
+        }
+
+        // 自動選択の領域
+        if !self.region_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Region Measurements\n");
+            csv.push_str("id,area_px,perimeter_px,bbox_min_x,bbox_min_y,bbox_max_x,bbox_max_y,centroid_x,centroid_y,area_calibrated,perimeter_calibrated,bbox_width_calibrated,bbox_height_calibrated,unit,layer\n");
+            for region in &self.region_measurements {
+                let (area, perimeter, bbox_width, bbox_height, unit) =
+                    region.dimensions_with_calibration(self.calibration.as_ref());
+                let calibrated = if self.calibration.is_some() {
+                    format!(
+                        "{:.2},{:.2},{:.2},{:.2}",
+                        area, perimeter, bbox_width, bbox_height
+                    )
+                } else {
+                    ",,,".to_string()
+                };
+                csv.push_str(&format!(
+                    "{},{:.0},{:.0},{:.0},{:.0},{:.0},{:.0},{:.2},{:.2},{},{},{}\n",
+                    region.id,
+                    region.area_px,
+                    region.perimeter_px,
+                    region.bbox_min.0,
+                    region.bbox_min.1,
+                    region.bbox_max.0,
+                    region.bbox_max.1,
+                    region.centroid.0,
+                    region.centroid.1,
+                    calibrated,
+                    unit,
+                    csv_field(&self.layer(region.layer).name)
+                ));
+            }
+        }
+
+        // 粒子解析
+        if !self.blob_measurements.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Blob Measurements\n");
+            csv.push_str("id,area_px,perimeter_px,equivalent_diameter_px,centroid_x,centroid_y,bbox_min_x,bbox_min_y,bbox_max_x,bbox_max_y,bbox_width_px,bbox_height_px,area_calibrated,perimeter_calibrated,equivalent_diameter_calibrated,unit,layer\n");
+            for blob in &self.blob_measurements {
+                let (area, perimeter, _, _, unit) = blob
+                    .region
+                    .dimensions_with_calibration(self.calibration.as_ref());
+                let (_, diameter, _) = blob.dimensions_with_calibration(self.calibration.as_ref());
+                let calibrated = if self.calibration.is_some() {
+                    format!("{:.2},{:.2},{:.2}", area, perimeter, diameter)
+                } else {
+                    ",,".to_string()
+                };
+                let min = blob.bounding_rect.min_corner();
+                let max = blob.bounding_rect.max_corner();
+                csv.push_str(&format!(
+                    "{},{:.0},{:.0},{:.2},{:.2},{:.2},{:.0},{:.0},{:.0},{:.0},{:.0},{:.0},{},{},{}\n",
+                    blob.id,
+                    blob.region.area_px,
+                    blob.region.perimeter_px,
+                    blob.equivalent_diameter_px,
+                    blob.region.centroid.0,
+                    blob.region.centroid.1,
+                    min.x,
+                    min.y,
+                    max.x,
+                    max.y,
+                    blob.bounding_rect.width_px,
+                    blob.bounding_rect.height_px,
+                    calibrated,
+                    unit,
+                    csv_field(&self.layer(blob.layer).name)
+                ));
+            }
+        }
+
+        // マーカー
+        if !self.markers.is_empty() {
+            if !csv.is_empty() {
+                csv.push('\n');
+            }
+            csv.push_str("# Markers\n");
+            csv.push_str("id,category,number,x,y,layer\n");
+            for (marker, number) in self.markers.iter().zip(self.marker_numbers()) {
+                csv.push_str(&format!(
+                    "{},{},{},{:.2},{:.2},{}\n",
+                    marker.id,
+                    csv_field(&marker.category),
+                    number,
+                    marker.position.0,
+                    marker.position.1,
+                    csv_field(&self.layer(marker.layer).name)
+                ));
+            }
+
+            csv.push_str("\n# Marker Counts\n");
+            csv.push_str("category,count\n");
+            for (category, count) in self.marker_counts() {
+                csv.push_str(&format!("{},{}\n", csv_field(&category), count));
+            }
+        }
+
+        csv
+    }
+
+    fn export_json(&self) -> String {
+        let measurements: Vec<ExportMeasurement> = self
+            .measurements
+            .iter()
+            .map(|m| {
+                let (distance, unit) = m.distance_with_calibration(self.calibration.as_ref());
+                ExportMeasurement {
+                    id: m.id,
+                    start_x: m.start.0,
+                    start_y: m.start.1,
+                    end_x: m.end.0,
+                    end_y: m.end.1,
+                    distance_px: m.distance_px,
+                    distance_calibrated: if self.calibration.is_some() {
+                        Some(distance)
+                    } else {
+                        None
+                    },
+                    unit,
+                    name: m.name.clone(),
+                    note: m.note.clone(),
+                    layer: self.layer(m.layer).name.clone(),
+                    style: m.style.clone(),
+                }
+            })
+            .collect();
+
+        let rectangle_measurements: Vec<ExportRectangleMeasurement> = self
+            .rectangle_measurements
+            .iter()
+            .map(|rm| {
+                let (width, height, area, unit) =
+                    rm.dimensions_with_calibration(self.calibration.as_ref());
+                ExportRectangleMeasurement {
+                    id: rm.id,
+                    corner1_x: rm.corner1.0,
+                    corner1_y: rm.corner1.1,
+                    corner2_x: rm.corner2.0,
+                    corner2_y: rm.corner2.1,
+                    width_px: rm.width_px,
+                    height_px: rm.height_px,
+                    area_px: rm.area_px,
+                    width_calibrated: if self.calibration.is_some() {
+                        Some(width)
+                    } else {
+                        None
+                    },
+                    height_calibrated: if self.calibration.is_some() {
+                        Some(height)
+                    } else {
+                        None
+                    },
+                    area_calibrated: if self.calibration.is_some() {
+                        Some(area)
+                    } else {
+                        None
+                    },
+                    unit,
+                    name: rm.name.clone(),
+                    note: rm.note.clone(),
+                    layer: self.layer(rm.layer).name.clone(),
+                    style: rm.style.clone(),
+                    statistics: self.rect_statistics(rm).map(export_region_statistics),
+                }
+            })
+            .collect();
+
+        let angle_measurements: Vec<ExportAngleMeasurement> = self
+            .angle_measurements
+            .iter()
+            .map(|am| ExportAngleMeasurement {
+                id: am.id,
+                layer: self.layer(am.layer).name.clone(),
+                style: am.style.clone(),
+                vertex_x: am.vertex.0,
+                vertex_y: am.vertex.1,
+                arm1_x: am.arm1.0,
+                arm1_y: am.arm1.1,
+                arm2_x: am.arm2.0,
+                arm2_y: am.arm2.1,
+                angle_deg: am.angle_deg,
+            })
+            .collect();
+
+        let polyline_measurements: Vec<ExportPolylineMeasurement> = self
+            .polyline_measurements
+            .iter()
+            .map(|pm| {
+                let (segment_lengths, total, unit) =
+                    pm.lengths_with_calibration(self.calibration.as_ref());
+                ExportPolylineMeasurement {
+                    id: pm.id,
+                    layer: self.layer(pm.layer).name.clone(),
+                    style: pm.style.clone(),
+                    points: pm
+                        .points
+                        .iter()
+                        .map(|&(x, y)| ExportPoint { x, y })
+                        .collect(),
+                    segment_lengths_px: pm.segments().iter().map(|s| s.distance_px).collect(),
+                    total_length_px: pm.total_length_px,
+                    segment_lengths_calibrated: if self.calibration.is_some() {
+                        Some(segment_lengths)
+                    } else {
+                        None
+                    },
+                    total_length_calibrated: if self.calibration.is_some() {
+                        Some(total)
+                    } else {
+                        None
+                    },
+                    unit,
+                }
+            })
+            .collect();
+
+        let polygon_measurements: Vec<ExportPolygonMeasurement> = self
+            .polygon_measurements
+            .iter()
+            .map(|pg| {
+                let (area, perimeter, unit) =
+                    pg.dimensions_with_calibration(self.calibration.as_ref());
+                ExportPolygonMeasurement {
+                    id: pg.id,
+                    layer: self.layer(pg.layer).name.clone(),
+                    style: pg.style.clone(),
+                    points: pg
+                        .points
+                        .iter()
+                        .map(|&(x, y)| ExportPoint { x, y })
+                        .collect(),
+                    area_px: pg.area_px,
+                    perimeter_px: pg.perimeter_px,
+                    area_calibrated: if self.calibration.is_some() {
+                        Some(area)
+                    } else {
+                        None
+                    },
+                    perimeter_calibrated: if self.calibration.is_some() {
+                        Some(perimeter)
+                    } else {
+                        None
+                    },
+                    unit,
+                    is_convex: pg.is_convex,
+                    is_self_intersecting: pg.is_self_intersecting,
+                }
+            })
+            .collect();
+
+        let circle_measurements: Vec<ExportCircleMeasurement> = self
+            .circle_measurements
+            .iter()
+            .map(|cm| {
+                let (radius, diameter, circumference, area, unit) =
+                    cm.dimensions_with_calibration(self.calibration.as_ref());
+                ExportCircleMeasurement {
+                    id: cm.id,
+                    layer: self.layer(cm.layer).name.clone(),
+                    style: cm.style.clone(),
+                    center_x: cm.center.0,
+                    center_y: cm.center.1,
+                    radius_px: cm.radius_px,
+                    diameter_px: cm.diameter_px(),
+                    circumference_px: cm.circumference_px(),
+                    area_px: cm.area_px(),
+                    radius_calibrated: if self.calibration.is_some() {
+                        Some(radius)
+                    } else {
+                        None
+                    },
+                    diameter_calibrated: if self.calibration.is_some() {
+                        Some(diameter)
+                    } else {
+                        None
+                    },
+                    circumference_calibrated: if self.calibration.is_some() {
+                        Some(circumference)
+                    } else {
+                        None
+                    },
+                    area_calibrated: if self.calibration.is_some() {
+                        Some(area)
+                    } else {
+                        None
+                    },
+                    unit,
+                }
+            })
+            .collect();
+
+        let ellipse_measurements: Vec<ExportEllipseMeasurement> = self
+            .ellipse_measurements
+            .iter()
+            .map(|em| {
+                let (semi_major, semi_minor, area, perimeter, unit) =
+                    em.dimensions_with_calibration(self.calibration.as_ref());
+                ExportEllipseMeasurement {
+                    id: em.id,
+                    layer: self.layer(em.layer).name.clone(),
+                    style: em.style.clone(),
+                    center_x: em.center.0,
+                    center_y: em.center.1,
+                    semi_major_px: em.semi_major_px,
+                    semi_minor_px: em.semi_minor_px,
+                    angle_deg: em.angle_deg,
+                    area_px: em.area_px(),
+                    perimeter_px: em.perimeter_px(),
+                    semi_major_calibrated: if self.calibration.is_some() {
+                        Some(semi_major)
+                    } else {
+                        None
+                    },
+                    semi_minor_calibrated: if self.calibration.is_some() {
+                        Some(semi_minor)
+                    } else {
+                        None
+                    },
+                    area_calibrated: if self.calibration.is_some() {
+                        Some(area)
+                    } else {
+                        None
+                    },
+                    perimeter_calibrated: if self.calibration.is_some() {
+                        Some(perimeter)
+                    } else {
+                        None
+                    },
+                    unit,
+                }
+            })
+            .collect();
+
+        let rotated_rectangle_measurements: Vec<ExportRotatedRectangleMeasurement> = self
+            .rotated_rectangle_measurements
+            .iter()
+            .map(|rr| {
+                let (width, height, area, unit) =
+                    rr.dimensions_with_calibration(self.calibration.as_ref());
+                ExportRotatedRectangleMeasurement {
+                    id: rr.id,
+                    layer: self.layer(rr.layer).name.clone(),
+                    style: rr.style.clone(),
+                    corners: rr
+                        .corners()
+                        .iter()
+                        .map(|p| ExportPoint { x: p.x, y: p.y })
+                        .collect(),
+                    width_px: rr.width_px,
+                    height_px: rr.height_px,
+                    angle_deg: rr.angle_deg,
+                    area_px: rr.area_px,
+                    width_calibrated: if self.calibration.is_some() {
+                        Some(width)
+                    } else {
+                        None
+                    },
+                    height_calibrated: if self.calibration.is_some() {
+                        Some(height)
+                    } else {
+                        None
+                    },
+                    area_calibrated: if self.calibration.is_some() {
+                        Some(area)
+                    } else {
+                        None
+                    },
+                    unit,
+                }
+            })
+            .collect();
+
+        let point_to_line_measurements: Vec<ExportPointToLineMeasurement> = self
+            .point_to_line_measurements
+            .iter()
+            .map(|pl| {
+                let (distance, unit) = pl.distance_with_calibration(self.calibration.as_ref());
+                let foot = pl.foot_pos();
+                ExportPointToLineMeasurement {
+                    id: pl.id,
+                    layer: self.layer(pl.layer).name.clone(),
+                    style: pl.style.clone(),
+                    point_x: pl.point.0,
+                    point_y: pl.point.1,
+                    foot_x: foot.x,
+                    foot_y: foot.y,
+                    line_start_x: pl.line_start.0,
+                    line_start_y: pl.line_start.1,
+                    line_end_x: pl.line_end.0,
+                    line_end_y: pl.line_end.1,
+                    distance_px: pl.distance_px,
+                    distance_calibrated: if self.calibration.is_some() {
+                        Some(distance)
+                    } else {
+                        None
+                    },
+                    unit,
+                }
+            })
+            .collect();
+
+        let parallel_gap_measurements: Vec<ExportParallelGapMeasurement> = self
+            .parallel_gap_measurements
+            .iter()
+            .map(|gap| {
+                let (mean, min, max, unit) = gap.gaps_with_calibration(self.calibration.as_ref());
+                ExportParallelGapMeasurement {
+                    id: gap.id,
+                    layer: self.layer(gap.layer).name.clone(),
+                    style: gap.style.clone(),
+                    line1_start_x: gap.line1_start.0,
+                    line1_start_y: gap.line1_start.1,
+                    line1_end_x: gap.line1_end.0,
+                    line1_end_y: gap.line1_end.1,
+                    line2_start_x: gap.line2_start.0,
+                    line2_start_y: gap.line2_start.1,
+                    line2_end_x: gap.line2_end.0,
+                    line2_end_y: gap.line2_end.1,
+                    mean_gap_px: gap.mean_gap_px,
+                    min_gap_px: gap.min_gap_px,
+                    max_gap_px: gap.max_gap_px,
+                    angle_deviation_deg: gap.angle_deviation_deg,
+                    mean_gap_calibrated: if self.calibration.is_some() {
+                        Some(mean)
+                    } else {
+                        None
+                    },
+                    min_gap_calibrated: if self.calibration.is_some() {
+                        Some(min)
+                    } else {
+                        None
+                    },
+                    max_gap_calibrated: if self.calibration.is_some() {
+                        Some(max)
+                    } else {
+                        None
+                    },
+                    unit,
+                }
+            })
+            .collect();
+
+        let markers: Vec<ExportMarker> = self
+            .markers
+            .iter()
+            .zip(self.marker_numbers())
+            .map(|(marker, number)| ExportMarker {
+                id: marker.id,
+                layer: self.layer(marker.layer).name.clone(),
+                style: marker.style.clone(),
+                category: marker.category.clone(),
+                number,
+                x: marker.position.0,
+                y: marker.position.1,
+            })
+            .collect();
+
+        let marker_counts: Vec<ExportMarkerCount> = if self.markers.is_empty() {
+            Vec::new()
+        } else {
+            self.marker_counts()
+                .into_iter()
+                .map(|(category, count)| ExportMarkerCount { category, count })
+                .collect()
+        };
+
+        let region_measurements: Vec<ExportRegionMeasurement> = self
+            .region_measurements
+            .iter()
+            .map(|region| {
+                let (area, perimeter, bbox_width, bbox_height, unit) =
+                    region.dimensions_with_calibration(self.calibration.as_ref());
+                let calibrated = self.calibration.is_some();
+                ExportRegionMeasurement {
+                    id: region.id,
+                    layer: self.layer(region.layer).name.clone(),
+                    style: region.style.clone(),
+                    area_px: region.area_px,
+                    perimeter_px: region.perimeter_px,
+                    bbox_min_x: region.bbox_min.0,
+                    bbox_min_y: region.bbox_min.1,
+                    bbox_max_x: region.bbox_max.0,
+                    bbox_max_y: region.bbox_max.1,
+                    centroid_x: region.centroid.0,
+                    centroid_y: region.centroid.1,
+                    area_calibrated: if calibrated { Some(area) } else { None },
+                    perimeter_calibrated: if calibrated { Some(perimeter) } else { None },
+                    bbox_width_calibrated: if calibrated { Some(bbox_width) } else { None },
+                    bbox_height_calibrated: if calibrated { Some(bbox_height) } else { None },
+                    unit,
+                    contours: region
+                        .contours
+                        .iter()
+                        .map(|contour| contour.iter().map(|&(x, y)| ExportPoint { x, y }).collect())
+                        .collect(),
+                }
+            })
+            .collect();
+
+        let blob_measurements: Vec<ExportBlobMeasurement> = self
+            .blob_measurements
+            .iter()
+            .map(|blob| {
+                let (area, perimeter, bbox_width, bbox_height, unit) = blob
+                    .region
+                    .dimensions_with_calibration(self.calibration.as_ref());
+                let (_, diameter, _) = blob.dimensions_with_calibration(self.calibration.as_ref());
+                let calibrated = self.calibration.is_some();
+                let rect = &blob.bounding_rect;
+                ExportBlobMeasurement {
+                    id: blob.id,
+                    layer: self.layer(blob.layer).name.clone(),
+                    style: blob.style.clone(),
+                    area_px: blob.region.area_px,
+                    perimeter_px: blob.region.perimeter_px,
+                    equivalent_diameter_px: blob.equivalent_diameter_px,
+                    centroid_x: blob.region.centroid.0,
+                    centroid_y: blob.region.centroid.1,
+                    bounding_rect: ExportRectangleMeasurement {
+                        id: blob.id,
+                        corner1_x: rect.corner1.0,
+                        corner1_y: rect.corner1.1,
+                        corner2_x: rect.corner2.0,
+                        corner2_y: rect.corner2.1,
+                        width_px: rect.width_px,
+                        height_px: rect.height_px,
+                        area_px: rect.area_px,
+                        width_calibrated: if calibrated { Some(bbox_width) } else { None },
+                        height_calibrated: if calibrated { Some(bbox_height) } else { None },
+                        area_calibrated: if calibrated {
+                            Some(bbox_width * bbox_height)
+                        } else {
+                            None
+                        },
+                        unit: unit.clone(),
+                        name: rect.name.clone(),
+                        note: rect.note.clone(),
+                        layer: self.layer(blob.layer).name.clone(),
+                        style: blob.style.clone(),
+                        statistics: None,
+                    },
+                    area_calibrated: if calibrated { Some(area) } else { None },
+                    perimeter_calibrated: if calibrated { Some(perimeter) } else { None },
+                    equivalent_diameter_calibrated: if calibrated { Some(diameter) } else { None },
+                    unit,
+                }
+            })
+            .collect();
+
+        let export_data = ExportData {
+            calibration: self.calibration.clone(),
+            measurements,
+            rectangle_measurements,
+            angle_measurements,
+            polyline_measurements,
+            polygon_measurements,
+            circle_measurements,
+            ellipse_measurements,
+            rotated_rectangle_measurements,
+            point_to_line_measurements,
+            parallel_gap_measurements,
+            markers,
+            marker_counts,
+            region_measurements,
+            blob_measurements,
+        };
+
+        serde_json::to_string_pretty(&export_data).unwrap_or_default()
+    }
+
+    fn save_export(&self, format: &str) {
+        let (content, extension, filter_name) = match format {
+            "csv" => (self.export_csv(), "csv", "CSV"),
+            "json" => (self.export_json(), "json", "JSON"),
+            _ => return,
+        };
+
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter(filter_name, &[extension])
+            .save_file()
+        {
+            if let Err(e) = std::fs::write(&path, content) {
+                eprintln!("Failed to save file: {}", e);
+            }
+        }
+    }
+
+    /// 測定・レイヤー・キャリブレーション・マーカーのカテゴリをプロジェクトファイルの内容にする
+    fn project_json(&self) -> String {
+        let color = |c: egui::Color32| c.to_srgba_unmultiplied();
+        let project = ProjectData {
+            image_path: self
+                .image_path
+                .clone()
+                .filter(|path| std::path::Path::new(path).is_file()),
+            calibration: self.calibration.clone(),
+            layers: self
+                .layers
+                .iter()
+                .map(|layer| ProjectLayer {
+                    id: layer.id,
+                    name: layer.name.clone(),
+                    line_color: layer.line_color.map(color),
+                    label_color: layer.label_color.map(color),
+                    visible: layer.visible,
+                })
+                .collect(),
+            marker_categories: self
+                .marker_categories
+                .iter()
+                .map(|category| ProjectMarkerCategory {
+                    name: category.name.clone(),
+                    color: color(category.color),
+                })
+                .collect(),
+            measurements: self.measurements.clone(),
+            rectangle_measurements: self.rectangle_measurements.clone(),
+            angle_measurements: self.angle_measurements.clone(),
+            polyline_measurements: self.polyline_measurements.clone(),
+            polygon_measurements: self.polygon_measurements.clone(),
+            circle_measurements: self.circle_measurements.clone(),
+            ellipse_measurements: self.ellipse_measurements.clone(),
+            rotated_rectangle_measurements: self.rotated_rectangle_measurements.clone(),
+            point_to_line_measurements: self.point_to_line_measurements.clone(),
+            parallel_gap_measurements: self.parallel_gap_measurements.clone(),
+            markers: self.markers.clone(),
+            region_measurements: self.region_measurements.clone(),
+            blob_measurements: self.blob_measurements.clone(),
+        };
+
+        serde_json::to_string_pretty(&project).unwrap_or_default()
+    }
+
+    /// プロジェクトファイルの測定・レイヤーなどで置き換える（画像はそのまま）
+    /// 読み込んだ測定は1つの操作として履歴に入る
+    fn apply_project(&mut self, project: ProjectData) {
+        let color = |[r, g, b, a]: [u8; 4]| egui::Color32::from_rgba_unmultiplied(r, g, b, a);
+        let layers: Vec<Layer> = project
+            .layers
+            .into_iter()
+            .map(|layer| Layer {
+                id: layer.id,
+                name: layer.name,
+                line_color: layer.line_color.map(color),
+                label_color: layer.label_color.map(color),
+                visible: layer.visible,
+            })
+            .collect();
+        // 既定のレイヤー（ID 0）は先頭に置く
+        if layers.first().is_some_and(|layer| layer.id == 0) {
+            self.layers = layers;
+        }
+        self.last_layer_id = self.layers.iter().map(|layer| layer.id).max().unwrap_or(0);
+        self.active_layer = 0;
+        if !project.marker_categories.is_empty() {
+            self.marker_categories = project
+                .marker_categories
+                .into_iter()
+                .map(|category| MarkerCategory {
+                    name: category.name,
+                    color: color(category.color),
+                })
+                .collect();
+            self.active_marker_category = 0;
+        }
+
+        let mut actions = vec![Action::SetCalibration(project.calibration)];
+        actions.extend(project.measurements.into_iter().map(Action::AddLine));
+        actions.extend(
+            project
+                .rectangle_measurements
+                .into_iter()
+                .map(Action::AddRect),
+        );
+        actions.extend(project.angle_measurements.into_iter().map(Action::AddAngle));
+        actions.extend(
+            project
+                .polyline_measurements
+                .into_iter()
+                .map(Action::AddPolyline),
+        );
+        actions.extend(
+            project
+                .polygon_measurements
+                .into_iter()
+                .map(Action::AddPolygon),
+        );
+        actions.extend(
+            project
+                .circle_measurements
+                .into_iter()
+                .map(Action::AddCircle),
+        );
+        actions.extend(
+            project
+                .ellipse_measurements
+                .into_iter()
+                .map(Action::AddEllipse),
+        );
+        actions.extend(
+            project
+                .rotated_rectangle_measurements
+                .into_iter()
+                .map(Action::AddRotatedRect),
+        );
+        actions.extend(
+            project
+                .point_to_line_measurements
+                .into_iter()
+                .map(Action::AddPointToLine),
+        );
+        actions.extend(
+            project
+                .parallel_gap_measurements
+                .into_iter()
+                .map(Action::AddParallelGap),
+        );
+        actions.extend(project.markers.into_iter().map(Action::AddMarker));
+        actions.extend(
+            project
+                .region_measurements
+                .into_iter()
+                .map(Action::AddRegion),
+        );
+        if !project.blob_measurements.is_empty() {
+            actions.push(Action::AddBlobs(project.blob_measurements));
+        }
+
+        // 保存した測定IDはそのまま使い、新しい測定にはそれより大きいIDを割り当てる
+        let mut loaded = HistoryState::default();
+        for action in &actions {
+            History::apply_action(&mut loaded, action);
+        }
+        self.clear_measurements();
+        self.history = History {
+            last_id: loaded
+                .measurement_items()
+                .map(|item| item.id())
+                .max()
+                .unwrap_or(0),
+            ..History::default()
+        };
+        self.push_compound_action(actions);
+        self.rebuild_from_history();
+    }
+
+    fn save_project(&self) {
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("Sampo プロジェクト", &["sampo"])
+            .save_file()
+            && let Err(e) = std::fs::write(&path, self.project_json())
+        {
+            eprintln!("Failed to save project: {}", e);
+        }
+    }
+
+    /// プロジェクトファイルを開き、保存した画像があれば読み込んでから測定を復元する
+    fn open_project_dialog(&mut self, ctx: &egui::Context) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Sampo プロジェクト", &["sampo"])
+            .pick_file()
+        else {
+            return;
+        };
+        let project = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str::<ProjectData>(&json).map_err(|e| e.to_string()));
+        match project {
+            Ok(project) => {
+                if let Some(image_path) = &project.image_path {
+                    self.load_image(ctx, &PathBuf::from(image_path));
+                }
+                self.apply_project(project);
+            }
+            Err(e) => {
+                eprintln!("Failed to open project: {}", e);
+            }
+        }
+    }
+
+    /// カーソル位置の画素値を表示するステータスバー（クリックでクリップボードにコピー）
+    fn show_status_bar(&mut self, ctx: &egui::Context) {
+        egui::TopBottomPanel::bottom("status_bar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                match self.pixel_reading() {
+                    Some(reading) => {
+                        let [r, g, b, a] = reading.rgba.map(|v| v.round() as u8);
+                        let (swatch, _) =
+                            ui.allocate_exact_size(egui::vec2(14.0, 14.0), egui::Sense::hover());
+                        ui.painter().rect_filled(
+                            swatch,
+                            2.0,
+                            egui::Color32::from_rgba_unmultiplied(r, g, b, a),
+                        );
+                        let summary = reading.summary();
+                        let response = ui
+                            .add(egui::Label::new(&summary).sense(egui::Sense::click()))
+                            .on_hover_text("クリックでコピー（画像上の右クリックでもコピー可）");
+                        if response.clicked() {
+                            copy_to_clipboard(summary);
+                        }
+                    }
+                    None => {
+                        ui.label("画像上にカーソルを置くと画素値を表示");
+                    }
+                }
+
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    egui::ComboBox::from_id_salt("inspector_window_size")
+                        .selected_text(format!("{0}×{0}", self.inspector_window_size))
+                        .show_ui(ui, |ui| {
+                            for size in [1, 3, 5, 7, 9] {
+                                ui.selectable_value(
+                                    &mut self.inspector_window_size,
+                                    size,
+                                    format!("{0}×{0}", size),
+                                );
+                            }
+                        });
+                    ui.label("平均範囲:");
+                });
+            });
+        });
+    }
+
+    /// 輝度プロファイルのCSV（始点からの距離と値）
+    fn export_profile_csv(&self) -> Option<String> {
+        let (_, profile) = self.current_profile()?;
+        let mut csv = String::new();
+        csv.push_str(&format!(
+            "distance_px,distance_calibrated,{},unit\n",
+            self.profile_channel.key()
+        ));
+        for (distance_px, value) in profile {
+            let (calibrated, unit) = match &self.calibration {
+                Some(cal) => (
+                    format!("{:.2}", distance_px / cal.pixels_per_unit),
+                    cal.unit_name.clone(),
+                ),
+                None => (String::new(), "px".to_string()),
+            };
+            csv.push_str(&format!(
+                "{:.2},{},{:.2},{}\n",
+                distance_px, calibrated, value, unit
+            ));
+        }
+        Some(csv)
+    }
+
+    fn save_profile_csv(&self) {
+        let Some(content) = self.export_profile_csv() else {
+            return;
+        };
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("CSV", &["csv"])
+            .save_file()
+            && let Err(e) = std::fs::write(&path, content)
+        {
+            eprintln!("Failed to save file: {}", e);
+        }
+    }
+
+    /// 選択した線分の輝度プロファイルのグラフ（画面下部のパネル）
+    fn show_profile_panel(&mut self, ctx: &egui::Context) {
+        self.profile_hover_distance = None;
+        if self.profile_line.is_some() && self.current_profile().is_none() {
+            // 線分が削除された・画像が変わった場合は閉じる
+            self.profile_line = None;
+        }
+        let Some(line_name) = self
+            .profile_line
+            .and_then(|id| self.line_by_id(id))
+            .map(Measurement::display_name)
+        else {
+            return;
+        };
+
+        egui::TopBottomPanel::bottom("profile_panel")
+            .resizable(true)
+            .default_height(220.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.heading(format!("輝度プロファイル: {}", line_name));
+                    egui::ComboBox::from_id_salt("profile_channel")
+                        .selected_text(self.profile_channel.label())
+                        .show_ui(ui, |ui| {
+                            for channel in ImageChannel::ALL {
+                                ui.selectable_value(
+                                    &mut self.profile_channel,
+                                    channel,
+                                    channel.label(),
+                                );
+                            }
+                        });
+                    if ui.button("CSVを保存").clicked() {
+                        self.save_profile_csv();
+                    }
+                    if ui.button("閉じる").clicked() {
+                        self.profile_line = None;
+                    }
+                });
+
+                let Some((_, profile)) = self.current_profile() else {
+                    return;
+                };
+                let (plot_response, painter) =
+                    ui.allocate_painter(ui.available_size(), egui::Sense::hover());
+                let plot_rect = plot_response.rect.shrink2(egui::vec2(40.0, 16.0));
+                painter.rect_stroke(
+                    plot_rect,
+                    0.0,
+                    egui::Stroke::new(1.0, egui::Color32::GRAY),
+                    egui::StrokeKind::Inside,
+                );
+
+                // x: 始点からの距離, y: 0〜255
+                let total_length = profile.last().map_or(0.0, |(d, _)| *d).max(1e-3);
+                let to_screen = |distance: f32, value: f32| {
+                    egui::pos2(
+                        plot_rect.left() + plot_rect.width() * distance / total_length,
+                        plot_rect.bottom() - plot_rect.height() * value / 255.0,
+                    )
+                };
+                let points: Vec<egui::Pos2> =
+                    profile.iter().map(|&(d, v)| to_screen(d, v)).collect();
+                painter.add(egui::Shape::line(
+                    points,
+                    egui::Stroke::new(1.5, egui::Color32::from_rgb(0, 200, 255)),
+                ));
+
+                let (length, unit) = match &self.calibration {
+                    Some(cal) => (total_length / cal.pixels_per_unit, cal.unit_name.clone()),
+                    None => (total_length, "px".to_string()),
+                };
+                let axis_font = egui::FontId::proportional(11.0);
+                let axis_color = ui.visuals().text_color();
+                for (value, align) in [
+                    (0.0, egui::Align2::RIGHT_BOTTOM),
+                    (255.0, egui::Align2::RIGHT_TOP),
+                ] {
+                    painter.text(
+                        egui::pos2(plot_rect.left() - 4.0, to_screen(0.0, value).y),
+                        align,
+                        format!("{:.0}", value),
+                        axis_font.clone(),
+                        axis_color,
+                    );
+                }
+                painter.text(
+                    plot_rect.left_bottom() + egui::vec2(0.0, 2.0),
+                    egui::Align2::LEFT_TOP,
+                    "0",
+                    axis_font.clone(),
+                    axis_color,
+                );
+                painter.text(
+                    plot_rect.right_bottom() + egui::vec2(0.0, 2.0),
+                    egui::Align2::RIGHT_TOP,
+                    format!("{:.1} {}", length, unit),
+                    axis_font.clone(),
+                    axis_color,
+                );
+
+                // ホバー位置の値を表示し、画像上にも位置を示す
+                if let Some(hover) = plot_response.hover_pos()
+                    && plot_rect.x_range().contains(hover.x)
+                {
+                    let distance = (hover.x - plot_rect.left()) / plot_rect.width() * total_length;
+                    let index = profile
+                        .iter()
+                        .position(|(d, _)| *d >= distance)
+                        .unwrap_or(profile.len() - 1);
+                    let (sample_distance, value) = profile[index];
+                    let sample_screen = to_screen(sample_distance, value);
+                    painter.vline(
+                        sample_screen.x,
+                        plot_rect.y_range(),
+                        egui::Stroke::new(1.0, egui::Color32::GRAY),
+                    );
+                    painter.circle_filled(sample_screen, 3.0, egui::Color32::from_rgb(0, 200, 255));
+                    let display_distance = match &self.calibration {
+                        Some(cal) => sample_distance / cal.pixels_per_unit,
+                        None => sample_distance,
+                    };
+                    painter.text(
+                        plot_rect.left_top() + egui::vec2(6.0, 4.0),
+                        egui::Align2::LEFT_TOP,
+                        format!("{:.1} {}: {:.1}", display_distance, unit, value),
+                        axis_font,
+                        axis_color,
+                    );
+                    self.profile_hover_distance = Some(sample_distance);
+                }
+            });
+    }
+
+    /// 測定結果一覧の名前の入力欄とメモの切り替えボタン
+    fn name_editor(&mut self, ui: &mut egui::Ui, selection: Selection, placeholder: &str) {
+        let Some(mut name) = self.measurement_text(selection, TextField::Name) else {
+            return;
+        };
+        let response = ui.add(
+            egui::TextEdit::singleline(&mut name)
+                .hint_text(placeholder)
+                .desired_width(70.0),
+        );
+        self.handle_text_edit(&response, selection, TextField::Name, name);
+        let editing_note = self.note_editing == Some(selection);
+        if ui.selectable_label(editing_note, "メモ").clicked() {
+            self.note_editing = if editing_note { None } else { Some(selection) };
+        }
+    }
+
+    /// メモを編集中ならメモの入力欄を表示する
+    fn note_editor(&mut self, ui: &mut egui::Ui, selection: Selection) {
+        if self.note_editing != Some(selection) {
+            return;
+        }
+        let Some(mut note) = self.measurement_text(selection, TextField::Note) else {
+            return;
+        };
+        let response = ui.add(
+            egui::TextEdit::multiline(&mut note)
+                .hint_text("メモ")
+                .desired_rows(2),
+        );
+        self.handle_text_edit(&response, selection, TextField::Note, note);
+    }
+
+    /// 名前・メモの入力欄の変更を記録し、入力欄から離れたら次の入力を別の操作にする
+    fn handle_text_edit(
+        &mut self,
+        response: &egui::Response,
+        selection: Selection,
+        field: TextField,
+        text: String,
+    ) {
+        if response.changed() {
+            self.record_text_edit(selection, field, text);
+        }
+        if response.lost_focus() {
+            self.text_edit_action = None;
+        }
+    }
+
+    /// 粒子解析の設定と実行ボタン
+    fn show_blob_detection_controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("しきい値:");
+            ui.radio_value(
+                &mut self.blob_threshold_method,
+                ThresholdMethod::Otsu,
+                "自動(大津)",
+            );
+            ui.radio_value(
+                &mut self.blob_threshold_method,
+                ThresholdMethod::Manual,
+                "手動",
+            );
+        });
+        match self.blob_threshold_method {
+            ThresholdMethod::Otsu => {
+                if let Some(threshold) = self.effective_blob_threshold() {
+                    ui.label(format!("自動しきい値: {}", threshold));
+                }
+            }
+            ThresholdMethod::Manual => {
+                ui.add(egui::Slider::new(&mut self.blob_threshold, 0..=255).text("輝度"));
+            }
+        }
+        ui.checkbox(&mut self.blob_dark_objects, "背景より暗い物体を検出");
+        ui.horizontal(|ui| {
+            ui.label("最小面積:");
+            ui.add(
+                egui::DragValue::new(&mut self.blob_min_area)
+                    .range(1..=1_000_000)
+                    .suffix(" px²"),
+            );
+        });
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(self.image_rgba.is_some(), egui::Button::new("粒子を検出"))
+                .clicked()
+            {
+                self.run_blob_detection();
+            }
+            if ui
+                .add_enabled(
+                    !self.blob_measurements.is_empty(),
+                    egui::Button::new("検出結果を削除"),
+                )
+                .clicked()
+            {
+                self.history.push_action(Action::ClearBlobs);
+                self.rebuild_from_history();
+            }
+        });
+    }
+
+    /// 画素統計の表とヒストグラム
+    fn show_region_statistics(ui: &mut egui::Ui, stats: &RegionStatistics) {
+        egui::Grid::new(ui.next_auto_id())
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("");
+                ui.label("平均");
+                ui.label("標準偏差");
+                ui.label("最小");
+                ui.label("最大");
+                ui.end_row();
+                for c in &stats.channels {
+                    ui.label(c.channel.label());
+                    ui.label(format!("{:.1}", c.mean));
+                    ui.label(format!("{:.1}", c.std_dev));
+                    ui.label(format!("{:.0}", c.min));
+                    ui.label(format!("{:.0}", c.max));
+                    ui.end_row();
+                }
+            });
+
+        // チャンネルごとのヒストグラムを重ねて描画（縦軸は最大頻度で正規化）
+        let (response, painter) =
+            ui.allocate_painter(egui::vec2(ui.available_width(), 60.0), egui::Sense::hover());
+        let rect = response.rect;
+        painter.rect_stroke(
+            rect,
+            0.0,
+            egui::Stroke::new(1.0, egui::Color32::GRAY),
+            egui::StrokeKind::Inside,
+        );
+        let max_count = stats
+            .channels
+            .iter()
+            .flat_map(|c| c.histogram.iter().copied())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        for c in &stats.channels {
+            let color = match c.channel {
+                ImageChannel::Red => egui::Color32::from_rgb(230, 70, 70),
+                ImageChannel::Green => egui::Color32::from_rgb(70, 200, 70),
+                ImageChannel::Blue => egui::Color32::from_rgb(80, 120, 255),
+                _ => egui::Color32::GRAY,
+            };
+            let points: Vec<egui::Pos2> = c
+                .histogram
+                .iter()
+                .enumerate()
+                .map(|(value, &count)| {
+                    egui::pos2(
+                        rect.left() + rect.width() * value as f32 / 255.0,
+                        rect.bottom() - rect.height() * count as f32 / max_count as f32,
+                    )
+                })
+                .collect();
+            painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, color)));
+        }
+    }
+
+    /// マーカーのカテゴリ一覧（選択・色・個数）と、カテゴリの追加欄
+    fn show_marker_categories(&mut self, ui: &mut egui::Ui) {
+        let counts = self.marker_counts();
+        let mut category_to_remove = None;
+        for (i, category) in self.marker_categories.iter_mut().enumerate() {
+            let count = counts
+                .iter()
+                .find(|(name, _)| *name == category.name)
+                .map_or(0, |(_, count)| *count);
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.active_marker_category, i, &category.name);
+                ui.color_edit_button_srgba(&mut category.color);
+                ui.label(format!("{}個", count));
+                // 使用中のカテゴリと最後の1つは削除できない
+                if count == 0 && ui.small_button("x").clicked() {
+                    category_to_remove = Some(i);
+                }
+            });
+        }
+        if let Some(i) = category_to_remove
+            && self.marker_categories.len() > 1
+        {
+            self.marker_categories.remove(i);
+            if self.active_marker_category >= self.marker_categories.len() {
+                self.active_marker_category = self.marker_categories.len() - 1;
+            }
+        }
+
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.new_marker_category_name)
+                    .hint_text("カテゴリ名")
+                    .desired_width(100.0),
+            );
+            let name = self.new_marker_category_name.trim().to_string();
+            let is_new = !name.is_empty() && !self.marker_categories.iter().any(|c| c.name == name);
+            if ui.add_enabled(is_new, egui::Button::new("追加")).clicked() {
+                let color = MARKER_CATEGORY_COLORS
+                    [self.marker_categories.len() % MARKER_CATEGORY_COLORS.len()];
+                self.marker_categories.push(MarkerCategory { name, color });
+                self.active_marker_category = self.marker_categories.len() - 1;
+                self.new_marker_category_name.clear();
+            }
+        });
+    }
+
+    /// レイヤー一覧（追加先の選択・表示・色・測定数）と、レイヤーの追加欄
+    fn show_layers(&mut self, ui: &mut egui::Ui) {
+        let counts = self.layer_counts();
+        let text_color = self.text_color;
+        let mut layer_to_remove = None;
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.active_layer, layer.id, &layer.name);
+                ui.checkbox(&mut layer.visible, "")
+                    .on_hover_text("表示・非表示");
+                Self::layer_color_edit(
+                    ui,
+                    "線",
+                    &mut layer.line_color,
+                    LAYER_LINE_COLORS[i % LAYER_LINE_COLORS.len()],
+                    "線の色（チェックを外すと測定の種類ごとの色）",
+                );
+                Self::layer_color_edit(
+                    ui,
+                    "文字",
+                    &mut layer.label_color,
+                    text_color,
+                    "文字色（チェックを外すと表示設定の寸法文字色）",
+                );
+                ui.label(format!("{}件", counts[i]));
+                // 使用中（やり直せる操作を含む）のレイヤーと既定のレイヤーは削除できない
+                let in_use = counts[i] > 0
+                    || self
+                        .history
+                        .actions
+                        .iter()
+                        .any(|action| action.uses_layer(layer.id));
+                if i > 0 && !in_use && ui.small_button("x").clicked() {
+                    layer_to_remove = Some(i);
+                }
+            });
+        }
+        if let Some(i) = layer_to_remove {
+            let removed = self.layers.remove(i);
+            if self.active_layer == removed.id {
+                self.active_layer = 0;
+            }
+        }
+        // 非表示にしたレイヤーの測定は選択から外す
+        let selection = std::mem::take(&mut self.selection);
+        self.selection = selection
+            .into_iter()
+            .filter(|&selection| self.selection_exists(selection))
+            .collect();
+
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.new_layer_name)
+                    .hint_text("レイヤー名")
+                    .desired_width(100.0),
+            );
+            let name = self.new_layer_name.trim().to_string();
+            // 同じ名前のレイヤーは作らない（エクスポートのレイヤー名で区別できなくなる）
+            let is_duplicate = self.layers.iter().any(|l| l.name == name);
+            if ui
+                .add_enabled(!name.is_empty() && !is_duplicate, egui::Button::new("追加"))
+                .clicked()
+            {
+                let line_color = LAYER_LINE_COLORS[self.layers.len() % LAYER_LINE_COLORS.len()];
+                self.last_layer_id += 1;
+                self.layers.push(Layer {
+                    id: self.last_layer_id,
+                    name,
+                    line_color: Some(line_color),
+                    label_color: None,
+                    visible: true,
+                });
+                self.active_layer = self.last_layer_id;
+                self.new_layer_name.clear();
+            } else if is_duplicate {
+                ui.colored_label(ui.visuals().warn_fg_color, "同じ名前のレイヤーがあります");
+            }
+        });
+
+        let active = self.layer(self.active_layer);
+        let (active_id, active_name) = (active.id, active.name.clone());
+        if ui
+            .add_enabled(
+                !self.selection.is_empty(),
+                egui::Button::new(format!("選択中の測定を「{}」に移す", active_name)),
+            )
+            .clicked()
+        {
+            self.move_selection_to_layer(active_id);
+        }
+    }
+
+    /// レイヤーの色を指定するかのチェックボックスと、指定する色の選択
+    fn layer_color_edit(
+        ui: &mut egui::Ui,
+        label: &str,
+        color: &mut Option<egui::Color32>,
+        initial: egui::Color32,
+        hover_text: &str,
+    ) {
+        let mut enabled = color.is_some();
+        if ui
+            .checkbox(&mut enabled, label)
+            .on_hover_text(hover_text)
+            .changed()
+        {
+            *color = enabled.then_some(initial);
+        }
+        if let Some(color) = color {
+            ui.color_edit_button_srgba(color).on_hover_text(hover_text);
+        }
+    }
+
+    /// 選択中の測定を指定したレイヤーに移す（1回のUndoで元に戻る）
+    fn move_selection_to_layer(&mut self, layer: u64) {
+        let mut actions = Vec::new();
+        for selection in self.selection.clone() {
+            if let Some(item) = self.measurement_item_mut(selection)
+                && item.layer_id() != layer
+            {
+                item.set_layer_id(layer);
+                actions.push(item.replace_action());
+            }
+        }
+        self.push_compound_action(actions);
+        self.rebuild_from_history();
+    }
+
+    /// スタイルで線の色を指定していないときの測定の線の色（マーカーはカテゴリの色）
+    fn default_line_color(&self, item: &dyn MeasurementItem) -> egui::Color32 {
+        let type_color = match item.selection() {
+            Selection::Line(_) => LINE_COLOR,
+            Selection::Rect(_) => RECT_COLOR,
+            Selection::Angle(_) => ANGLE_COLOR,
+            Selection::Polyline(_) => POLYLINE_COLOR,
+            Selection::Polygon(_) => POLYGON_COLOR,
+            Selection::Circle(_) => CIRCLE_COLOR,
+            Selection::Ellipse(_) => ELLIPSE_COLOR,
+            Selection::RotatedRect(_) => ROTATED_RECT_COLOR,
+            Selection::PointToLine(_) => POINT_TO_LINE_COLOR,
+            Selection::ParallelGap(_) => PARALLEL_GAP_COLOR,
+            Selection::Region(_) => REGION_COLOR,
+            Selection::Blob(_) => BLOB_COLOR,
+            Selection::Marker(id) => {
+                return self
+                    .markers
+                    .iter()
+                    .find(|marker| marker.id == id)
+                    .map_or(egui::Color32::GRAY, |marker| {
+                        self.marker_category_color(&marker.category)
+                    });
+            }
+        };
+        self.layer_line_color(item.layer_id(), type_color)
+    }
+
+    /// 選択中の測定の表示スタイル（最初に選んだ測定の値を表示し、変更した項目を選択中のすべてに適用）
+    fn show_style_properties(&mut self, ui: &mut egui::Ui) {
+        let Some(item) = self
+            .selection
+            .first()
+            .and_then(|&selection| self.measurement_item(selection))
+        else {
+            return;
+        };
+        let mut style = item.style().clone();
+        let layer_color = self.default_line_color(item);
+        // チェックを入れたときに始める値（チェックを外したときの表示と同じ）
+        let (default_width, default_radius, default_font_size) = match item.selection() {
+            Selection::Blob(_) => (1.5, 5.0, 11.0),
+            Selection::Marker(_) => (1.5, 6.0, 14.0),
+            _ => (2.0, 5.0, 14.0),
+        };
+        let before = style.clone();
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            let mut enabled = style.stroke_color.is_some();
+            if ui.checkbox(&mut enabled, "線の色").changed() {
+                // レイヤーの色から変更を始める
+                style.stroke_color = enabled.then(|| layer_color.to_srgba_unmultiplied());
+                changed = true;
+            }
+            if let Some([r, g, b, a]) = style.stroke_color {
+                let mut color = egui::Color32::from_rgba_unmultiplied(r, g, b, a);
+                if ui.color_edit_button_srgba(&mut color).changed() {
+                    style.stroke_color = Some(color.to_srgba_unmultiplied());
+                    changed = true;
+                }
+            }
+        });
+        changed |= Self::style_value_row(
+            ui,
+            "線の太さ",
+            &mut style.stroke_width,
+            default_width,
+            0.5..=20.0,
+        );
+        changed |= Self::style_value_row(
+            ui,
+            "点の大きさ",
+            &mut style.point_radius,
+            default_radius,
+            0.0..=20.0,
+        );
+        changed |= Self::style_value_row(
+            ui,
+            "文字サイズ",
+            &mut style.label_font_size,
+            default_font_size,
+            6.0..=72.0,
+        );
+        ui.horizontal(|ui| {
+            ui.label("ラベルの位置:");
+            let (x, y) = &mut style.label_offset;
+            changed |= ui.add(egui::DragValue::new(x).prefix("x ")).changed();
+            changed |= ui.add(egui::DragValue::new(y).prefix("y ")).changed();
+        });
+
+        if ui.button("既定に戻す").clicked() {
+            self.apply_style_to_selection(|s| *s = MeasurementStyle::default());
+            self.record_style_edit();
+        } else if changed {
+            self.apply_style_to_selection(|s| s.apply_changes(&before, &style));
+            self.record_style_edit();
+        }
+    }
+
+    /// 既定値を上書きするかのチェックボックスと、上書きする値の入力欄
+    /// 戻り値: 変更した
+    fn style_value_row(
+        ui: &mut egui::Ui,
+        label: &str,
+        value: &mut Option<f32>,
+        default: f32,
+        range: std::ops::RangeInclusive<f32>,
+    ) -> bool {
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            let mut enabled = value.is_some();
+            if ui.checkbox(&mut enabled, label).changed() {
+                *value = enabled.then_some(default);
+                changed = true;
+            }
+            if let Some(value) = value {
+                changed |= ui
+                    .add(
+                        egui::DragValue::new(value)
+                            .speed(0.1)
+                            .range(range)
+                            .suffix(" px"),
+                    )
+                    .changed();
+            }
+        });
+        changed
+    }
+
+    /// 選択中の測定すべてのスタイルを変更する（履歴への記録は `record_style_edit`）
+    fn apply_style_to_selection(&mut self, edit: impl Fn(&mut MeasurementStyle)) {
+        for selection in self.selection.clone() {
+            if let Some(item) = self.measurement_item_mut(selection) {
+                edit(item.style_mut());
+            }
+        }
+    }
+
+    /// スタイルを変更した測定を置き換える操作を1つの操作として履歴に記録する
+    /// 同じ操作中（ドラッグ・入力中）の変更は直前の記録にまとめる
+    fn record_style_edit(&mut self) {
+        // 置き換えの操作では測定の並びが変わらないので、記録前の状態と順に比べる
+        self.style_edit_action = self.record_merged_edit(self.style_edit_action, |app, saved| {
+            app.measurement_items()
+                .zip(saved.measurement_items())
+                .filter(|(item, saved)| {
+                    item.selection() == saved.selection() && item.style() != saved.style()
+                })
+                .map(|(item, _)| item.replace_action())
+                .collect()
+        });
+    }
+
+    /// 線分測定の測定IDと表示名の一覧（基準線の選択肢）
+    fn line_choices(&self) -> Vec<(u64, String)> {
+        self.measurements
+            .iter()
+            .map(|line| (line.id, line.display_name()))
+            .collect()
+    }
+
+    /// 既存の線分（`measurements`）を選ぶか、新たに描くかを選ぶコンボボックス
+    fn reference_line_combo(
+        ui: &mut egui::Ui,
+        label: &str,
+        id_salt: &str,
+        reference: &mut Option<u64>,
+        line_choices: &[(u64, String)],
+        none_label: &str,
+    ) {
+        let selected_text = match reference {
+            Some(id) => line_choices
+                .iter()
+                .find(|(choice, _)| choice == id)
+                .map(|(_, name)| name.clone())
+                .unwrap_or_default(),
+            None => none_label.to_string(),
+        };
+        ui.horizontal(|ui| {
+            ui.label(label);
+            egui::ComboBox::from_id_salt(id_salt)
+                .selected_text(selected_text)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(reference, None, none_label);
+                    for (id, name) in line_choices {
+                        ui.selectable_value(reference, Some(*id), name);
+                    }
+                });
+        });
+    }
+
+    fn show_controls_panel(&mut self, ctx: &egui::Context) {
+        egui::SidePanel::left("controls_panel")
+            .min_width(250.0)
+            .show(ctx, |ui| {
+                ui.heading("Sampo - 画像寸法測定");
+                ui.separator();
+
+                // Undo / Redo
+                ui.horizontal(|ui| {
+                    let can_undo = self.history.can_undo();
+                    let can_redo = self.history.can_redo();
+                    if ui.add_enabled(can_undo, egui::Button::new("Undo")).clicked() {
+                        if self.history.undo() {
+                            self.rebuild_from_history();
+                        }
+                    }
+                    if ui.add_enabled(can_redo, egui::Button::new("Redo")).clicked() {
+                        if self.history.redo() {
+                            self.rebuild_from_history();
+                        }
+                    }
+                });
+
+                ui.separator();
+
+                // ファイル操作
+                ui.horizontal(|ui| {
+                    if ui.button("画像を開く").clicked() {
+                        self.open_file_dialog(ctx);
+                    }
+                    if ui.button("貼り付け").clicked() {
+                        self.paste_from_clipboard(ctx);
+                    }
+                });
+                ui.label("(Ctrl/Cmd+V でも貼り付け可)");
+                ui.horizontal(|ui| {
+                    if ui.button("プロジェクトを開く").clicked() {
+                        self.open_project_dialog(ctx);
+                    }
+                    if ui.button("プロジェクトを保存").clicked() {
+                        self.save_project();
+                    }
+                });
+
+                if let Some(path) = &self.image_path {
+                    let filename = std::path::Path::new(path)
+                        .file_name()
+                        .map(|n| n.to_string_lossy().to_string())
+                        .unwrap_or_else(|| path.clone());
+                    ui.label(format!("ファイル: {}", filename));
+                }
+
+                if let Some((w, h)) = self.image_dimensions {
+                    ui.label(format!("サイズ: {}x{} px", w, h));
+                }
+
+                ui.separator();
+
+                // ズーム
+                ui.horizontal(|ui| {
+                    ui.label("ズーム:");
+                    ui.add(
+                        egui::Slider::new(&mut self.zoom, 0.1..=5.0)
+                            .logarithmic(true)
+                            .suffix("x"),
+                    );
+                });
+                ui.label("(画像上でピンチでもズーム可)");
+                if ui.button("リセット").clicked() {
+                    self.zoom = 1.0;
+                }
+
+                ui.separator();
+
+                // 表示設定
+                ui.heading("表示設定");
+                ui.horizontal(|ui| {
+                    ui.label("寸法文字色:");
+                    ui.color_edit_button_srgba(&mut self.text_color);
+                });
+                ui.checkbox(&mut self.show_preview, "測定プレビューを表示");
+
+                ui.separator();
+
+                // レイヤー（測定の色と表示）
+                ui.heading("レイヤー");
+                self.show_layers(ui);
+
+                // 選択中の測定の表示スタイル
+                if !self.selection.is_empty() {
+                    ui.separator();
+                    ui.heading("プロパティ");
+                    self.show_style_properties(ui);
+                }
+                // ドラッグ・入力が終わったら、次のスタイルの変更は別の操作として記録する
+                if self.style_edit_action.is_some()
+                    && !ui.input(|i| i.pointer.any_down())
+                    && ui.memory(|m| m.focused().is_none())
+                {
+                    self.style_edit_action = None;
+                }
+
+                ui.separator();
+
+                // キャリブレーション
+                ui.heading("キャリブレーション");
+
+                if let Some(cal) = &self.calibration {
+                    ui.label(format!(
+                        "設定済み: {:.2} px/{}",
+                        cal.pixels_per_unit, cal.unit_name
+                    ));
+                    if ui.button("キャリブレーションをクリア").clicked() {
+                        self.history.push_action(Action::SetCalibration(None));
+                        self.rebuild_from_history();
+                    }
+                } else {
+                    ui.label("未設定");
+                }
+
+                let calibrating_text = if self.is_calibrating {
+                    "キャリブレーションをキャンセル"
+                } else {
+                    "キャリブレーションを開始"
+                };
+                if ui.button(calibrating_text).clicked() {
+                    self.is_calibrating = !self.is_calibrating;
+                    self.calibration_state = CalibrationState::Idle;
+                }
+
+                if self.is_calibrating {
+                    match &self.calibration_state {
+                        CalibrationState::Idle => {
+                            ui.label("既知の長さの始点をクリック");
+                        }
+                        CalibrationState::FirstPointSelected(_) => {
+                            ui.label("終点をクリック");
+                        }
+                        CalibrationState::WaitingForInput { distance_px, .. } => {
+                            ui.label(format!("ピクセル距離: {:.1} px", distance_px));
+                            ui.horizontal(|ui| {
+                                ui.label("実寸法:");
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut self.calibration_input)
+                                        .desired_width(60.0),
+                                );
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut self.calibration_unit)
+                                        .desired_width(40.0),
+                                );
+                            });
+                            if ui.button("適用").clicked() {
+                                if let Ok(real_distance) = self.calibration_input.parse::<f32>() {
+                                    if real_distance > 0.0 {
+                                        let calibration = Calibration {
+                                            pixels_per_unit: distance_px / real_distance,
+                                            unit_name: self.calibration_unit.clone(),
+                                        };
+                                        self.history
+                                            .push_action(Action::SetCalibration(Some(calibration)));
+                                        self.rebuild_from_history();
+                                        self.is_calibrating = false;
+                                        self.calibration_state = CalibrationState::Idle;
+                                        self.calibration_input.clear();
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                ui.separator();
+
+                // 測定操作
+                ui.heading("測定");
+
+                // モード切替
+                let previous_mode = self.measurement_mode;
+                ui.horizontal_wrapped(|ui| {
+                    ui.label("モード:");
+                    ui.selectable_value(&mut self.measurement_mode, MeasurementMode::Line, "線分");
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::Rectangle,
+                        "矩形",
+                    );
+                    ui.selectable_value(&mut self.measurement_mode, MeasurementMode::Angle, "角度");
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::Polyline,
+                        "折れ線",
+                    );
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::Polygon,
+                        "多角形",
+                    );
+                    ui.selectable_value(&mut self.measurement_mode, MeasurementMode::Circle, "円");
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::Ellipse,
+                        "楕円",
+                    );
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::RotatedRectangle,
+                        "回転矩形",
+                    );
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::PointToLine,
+                        "点と線の距離",
+                    );
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::ParallelGap,
+                        "平行線の間隔",
+                    );
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::Marker,
+                        "マーカー",
+                    );
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::MagicWand,
+                        "自動選択",
+                    );
+                    ui.selectable_value(
+                        &mut self.measurement_mode,
+                        MeasurementMode::Select,
+                        "選択",
+                    );
+                });
+
+                if self.measurement_mode == MeasurementMode::Circle {
+                    let previous_method = self.circle_method;
+                    ui.horizontal(|ui| {
+                        ui.label("指定方法:");
+                        ui.radio_value(
+                            &mut self.circle_method,
+                            CircleMethod::CenterRadius,
+                            "中心+半径",
+                        );
+                        ui.radio_value(
+                            &mut self.circle_method,
+                            CircleMethod::ThreePoint,
+                            "円周上の3点",
+                        );
+                    });
+                    if self.circle_method != previous_method {
+                        self.measurement_state = MeasurementState::Idle;
+                    }
+                }
+                if self.measurement_mode == MeasurementMode::PointToLine {
+                    // 削除などで存在しなくなった線分は選択を解除
+                    if self.point_line_reference_line().is_none() {
+                        self.point_line_reference = None;
+                    }
+                    let previous_reference = self.point_line_reference;
+                    let line_choices = self.line_choices();
+                    Self::reference_line_combo(
+                        ui,
+                        "基準線:",
+                        "point_line_reference",
+                        &mut self.point_line_reference,
+                        &line_choices,
+                        "新しく描く",
+                    );
+                    if self.point_line_reference != previous_reference {
+                        self.measurement_state = MeasurementState::Idle;
+                    }
+                }
+                if self.measurement_mode == MeasurementMode::ParallelGap {
+                    let line_choices = self.line_choices();
+                    let previous_references = self.parallel_gap_references;
+                    for i in 0..2 {
+                        // 線2には線1で選んだ線分を選べない（同じ線分どうしの間隔は0になる）
+                        let first = (i == 1)
+                            .then_some(self.parallel_gap_references[0])
+                            .flatten();
+                        let choices: Vec<(u64, String)> = line_choices
+                            .iter()
+                            .filter(|(id, _)| Some(*id) != first)
+                            .cloned()
+                            .collect();
+                        let reference = &mut self.parallel_gap_references[i];
+                        // 削除などで存在しなくなった線分は選択を解除
+                        if reference.is_some_and(|id| choices.iter().all(|(c, _)| *c != id)) {
+                            *reference = None;
+                        }
+                        Self::reference_line_combo(
+                            ui,
+                            &format!("線{}:", i + 1),
+                            &format!("parallel_gap_reference_{}", i),
+                            reference,
+                            &choices,
+                            "新しく描く",
+                        );
+                    }
+                    if self.parallel_gap_references != previous_references {
+                        self.measurement_state = MeasurementState::Idle;
+                        self.parallel_gap_drawn_lines.clear();
+                    }
+                    if self.parallel_gap_references.iter().all(Option::is_some)
+                        && ui.button("選択した線分で測定").clicked()
+                    {
+                        self.try_finish_parallel_gap();
+                    }
+                }
+                if self.measurement_mode == MeasurementMode::Marker {
+                    self.show_marker_categories(ui);
+                }
+                if self.measurement_mode == MeasurementMode::Rectangle {
+                    ui.horizontal(|ui| {
+                        ui.label("縦横比:");
+                        ui.radio_value(&mut self.rect_aspect, RectAspect::Square, "正方形");
+                        ui.radio_value(&mut self.rect_aspect, RectAspect::Ratio, "比率");
+                        if self.rect_aspect == RectAspect::Ratio {
+                            let (width, height) = &mut self.rect_aspect_ratio;
+                            ui.add(egui::DragValue::new(width).speed(0.1).range(0.1..=100.0));
+                            ui.label(":");
+                            ui.add(egui::DragValue::new(height).speed(0.1).range(0.1..=100.0));
+                        }
+                    });
+                    ui.checkbox(&mut self.rect_aspect_always, "常に縦横比を固定");
+                    ui.label("(Shift押下で縦横比を固定)");
+                }
+                if self.measurement_mode == MeasurementMode::MagicWand {
+                    ui.horizontal(|ui| {
+                        ui.label("許容差:");
+                        ui.add(egui::Slider::new(
+                            &mut self.magic_wand_tolerance,
+                            0.0..=255.0,
+                        ));
+                    });
+                }
+                // モードが変わったら測定途中の点と選択を破棄
+                if self.measurement_mode != previous_mode {
+                    self.measurement_state = MeasurementState::Idle;
+                    self.parallel_gap_drawn_lines.clear();
+                    self.selection.clear();
+                }
+
+                if matches!(
+                    self.measurement_mode,
+                    MeasurementMode::Line
+                        | MeasurementMode::Angle
+                        | MeasurementMode::Polyline
+                        | MeasurementMode::Polygon
+                        | MeasurementMode::Ellipse
+                        | MeasurementMode::RotatedRectangle
+                        | MeasurementMode::PointToLine
+                        | MeasurementMode::ParallelGap
+                ) {
+                    ui.label("(Ctrl押下で角度スナップ)");
+                }
+
+                egui::CollapsingHeader::new("角度スナップ設定")
+                    .id_salt("angle_snap_settings")
+                    .show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("刻み:");
+                            egui::ComboBox::from_id_salt("angle_snap_step")
+                                .selected_text(self.angle_snap_step.label())
+                                .show_ui(ui, |ui| {
+                                    for step in AngleSnapStep::ALL {
+                                        ui.selectable_value(
+                                            &mut self.angle_snap_step,
+                                            step,
+                                            step.label(),
+                                        );
+                                    }
+                                });
+                        });
+                        if self.angle_snap_step == AngleSnapStep::Custom {
+                            ui.horizontal(|ui| {
+                                ui.label("角度:");
+                                ui.text_edit_singleline(&mut self.angle_snap_custom_angles)
+                                    .on_hover_text("カンマ区切りで度単位（例: 0, 60, 120）");
+                            });
+                        }
+                        ui.horizontal(|ui| {
+                            ui.label("許容差:");
+                            ui.add(
+                                egui::DragValue::new(&mut self.angle_snap_tolerance_deg)
+                                    .speed(0.1)
+                                    .range(0.1..=45.0)
+                                    .suffix("°"),
+                            );
+                        });
+                        if self
+                            .angle_snap_reference
+                            .is_some_and(|id| self.line_by_id(id).is_none())
+                        {
+                            self.angle_snap_reference = None;
+                        }
+                        let line_choices = self.line_choices();
+                        Self::reference_line_combo(
+                            ui,
+                            "基準線:",
+                            "angle_snap_reference",
+                            &mut self.angle_snap_reference,
+                            &line_choices,
+                            "水平",
+                        );
+                    });
+
+                ui.horizontal(|ui| {
+                    ui.label("長さスナップ:");
+                    let unit = self.length_snap_unit();
+                    ui.add(
+                        egui::DragValue::new(&mut self.length_snap_multiple)
+                            .speed(if unit == "px" { 0.1 } else { 0.01 })
+                            .range(0.0..=100.0)
+                            .suffix(format!(" {}", unit)),
+                    );
+                    // キャリブレーション済みなら実寸の単位でも指定できる
+                    if let Some(cal) = &self.calibration {
+                        egui::ComboBox::from_id_salt("length_snap_unit")
+                            .selected_text(unit.as_str())
+                            .width(60.0)
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.length_snap_calibrated, false, "px");
+                                ui.selectable_value(
+                                    &mut self.length_snap_calibrated,
+                                    true,
+                                    cal.unit_name.as_str(),
+                                );
+                            });
+                    }
+                });
+                ui.label("(0で無効)");
+
+                ui.horizontal_wrapped(|ui| {
+                    ui.label("オブジェクトスナップ:");
+                    ui.checkbox(&mut self.snap_to_endpoints, "端点");
+                    ui.checkbox(&mut self.snap_to_midpoints, "中点");
+                    ui.checkbox(&mut self.snap_to_corners, "角");
+                    ui.checkbox(&mut self.snap_to_intersections, "交点");
+                });
+
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.edge_snap_enabled, "エッジスナップ");
+                    ui.add_enabled(
+                        self.edge_snap_enabled,
+                        egui::DragValue::new(&mut self.edge_snap_radius)
+                            .speed(0.1)
+                            .range(1.0..=30.0)
+                            .prefix("範囲 ")
+                            .suffix(" px"),
+                    );
+                });
+
+                match &self.measurement_state {
+                    MeasurementState::Idle => {
+                        if !self.is_calibrating {
+                            let mode_text = match self.measurement_mode {
+                                MeasurementMode::Line => "線分",
+                                MeasurementMode::Rectangle => "矩形",
+                                MeasurementMode::Angle => "角度",
+                                MeasurementMode::Polyline => "折れ線",
+                                MeasurementMode::Polygon => "多角形",
+                                MeasurementMode::Circle => "円",
+                                MeasurementMode::Ellipse => "楕円",
+                                MeasurementMode::RotatedRectangle => "回転矩形",
+                                MeasurementMode::PointToLine => "点と線の距離",
+                                MeasurementMode::ParallelGap => "平行線の間隔",
+                                MeasurementMode::Marker => "マーカー",
+                                MeasurementMode::MagicWand => "自動選択",
+                                MeasurementMode::Select => "選択",
+                            };
+                            if self.measurement_mode == MeasurementMode::PointToLine
+                                && self.point_line_reference_line().is_some()
+                            {
+                                ui.label("測定する点をクリック");
+                            } else if self.measurement_mode == MeasurementMode::Marker {
+                                ui.label("画像をクリックしてマーカーを配置");
+                            } else if self.measurement_mode == MeasurementMode::MagicWand {
+                                ui.label("画像をクリックして色の近い領域を選択");
+                            } else if self.measurement_mode == MeasurementMode::Select {
+                                ui.label("測定をクリックして選択");
+                                ui.label("Shift+クリックで追加、空き領域のドラッグで範囲選択");
+                                ui.label("線分・矩形はドラッグで移動、端点・角のドラッグで変形");
+                                if !self.selection.is_empty() {
+                                    ui.horizontal(|ui| {
+                                        ui.label(format!("選択中: {}件", self.selection.len()));
+                                        if ui.button("削除").clicked() {
+                                            self.delete_selection();
+                                        }
+                                        if ui.button("選択解除").clicked() {
+                                            self.selection.clear();
+                                        }
+                                    });
+                                }
+                            } else if self.measurement_mode == MeasurementMode::ParallelGap
+                                && !self.parallel_gap_drawn_lines.is_empty()
+                            {
+                                ui.label("2本目の線分の始点をクリック");
+                                if ui.button("キャンセル").clicked() {
+                                    self.parallel_gap_drawn_lines.clear();
+                                }
+                            } else {
+                                ui.label(format!("画像をクリックして{}測定開始", mode_text));
+                            }
+                        }
+                    }
+                    MeasurementState::FirstPointSelected(p) => {
+                        let start_text = match (self.measurement_mode, self.circle_method) {
+                            (MeasurementMode::Angle, _) => "頂点",
+                            (MeasurementMode::Circle, CircleMethod::CenterRadius) => "中心",
+                            (MeasurementMode::Circle, CircleMethod::ThreePoint) => "1点目",
+                            (MeasurementMode::Ellipse, _) => "中心",
+                            (MeasurementMode::RotatedRectangle, _) => "基準線の始点",
+                            (MeasurementMode::PointToLine, _) => "基準線の始点",
+                            _ => "始点",
+                        };
+                        ui.label(format!("{}: ({:.0}, {:.0})", start_text, p.x, p.y));
+                        let end_text = match self.measurement_mode {
+                            MeasurementMode::Line => "終点をクリック",
+                            MeasurementMode::Rectangle => "対角をクリック",
+                            MeasurementMode::Angle => "1本目の辺上の点をクリック",
+                            MeasurementMode::Polyline | MeasurementMode::Polygon => {
+                                "次の頂点をクリック"
+                            }
+                            MeasurementMode::Circle => match self.circle_method {
+                                CircleMethod::CenterRadius => "円周上の点をクリック",
+                                CircleMethod::ThreePoint => "円周上の2点目をクリック",
+                            },
+                            MeasurementMode::Ellipse => "長軸の端点をクリック",
+                            MeasurementMode::RotatedRectangle | MeasurementMode::PointToLine => {
+                                "基準線の終点をクリック"
+                            }
+                            MeasurementMode::ParallelGap => "線分の終点をクリック",
+                            MeasurementMode::Marker
+                            | MeasurementMode::MagicWand
+                            | MeasurementMode::Select => "",
+                        };
+                        ui.label(end_text);
+                        if ui.button("キャンセル").clicked() {
+                            self.measurement_state = MeasurementState::Idle;
+                        }
+                    }
+                    MeasurementState::SecondPointSelected(first, _) => {
+                        if self.measurement_mode == MeasurementMode::Circle {
+                            ui.label("円周上の3点目をクリック");
+                            ui.label("(一直線上の3点では円が決まりません)");
+                        } else if self.measurement_mode == MeasurementMode::Ellipse {
+                            ui.label(format!("中心: ({:.0}, {:.0})", first.x, first.y));
+                            ui.label("短軸方向の広がりをクリック");
+                        } else if self.measurement_mode == MeasurementMode::RotatedRectangle {
+                            ui.label(format!("始点: ({:.0}, {:.0})", first.x, first.y));
+                            ui.label("高さ方向の広がりをクリック");
+                        } else if self.measurement_mode == MeasurementMode::PointToLine {
+                            ui.label(format!("始点: ({:.0}, {:.0})", first.x, first.y));
+                            ui.label("測定する点をクリック");
+                        } else {
+                            ui.label(format!("頂点: ({:.0}, {:.0})", first.x, first.y));
+                            ui.label("2本目の辺上の点をクリック");
+                        }
+                        if ui.button("キャンセル").clicked() {
+                            self.measurement_state = MeasurementState::Idle;
+                        }
+                    }
+                    MeasurementState::Vertices(points) => {
+                        ui.label(format!("頂点数: {}", points.len()));
+                        ui.label("クリックで頂点を追加、ダブルクリック/Enterで確定");
+                        if self.measurement_mode == MeasurementMode::Polygon {
+                            ui.label("(始点をクリックしても閉じて確定)");
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.button("確定").clicked() {
+                                self.finish_vertex_measurement();
+                            }
+                            if ui.button("キャンセル").clicked() {
+                                self.measurement_state = MeasurementState::Idle;
+                            }
+                        });
+                    }
+                }
+
+                ui.separator();
+
+                // 粒子解析
+                ui.heading("粒子解析");
+                self.show_blob_detection_controls(ui);
+
+                ui.separator();
+
+                // 測定結果
+                ui.heading("測定結果");
+
+                egui::ScrollArea::vertical()
+                    .max_height(200.0)
+                    .show(ui, |ui| {
+                        // 線分測定結果
+                        let mut line_to_remove = None;
+                        for i in 0..self.measurements.len() {
+                            let id = self.measurements[i].id;
+                            let (distance, unit) = self.measurements[i]
+                                .distance_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                self.name_editor(ui, Selection::Line(id), &format!("線#{}", id));
+                                let value = ui.label(format!("{:.1} {}", distance, unit));
+                                if !self.measurements[i].note.is_empty() {
+                                    value.on_hover_text(&self.measurements[i].note);
+                                }
+                                let showing_profile = self.profile_line == Some(id);
+                                if ui
+                                    .selectable_label(showing_profile, "プロファイル")
+                                    .clicked()
+                                {
+                                    self.profile_line =
+                                        if showing_profile { None } else { Some(id) };
+                                }
+                                if ui.small_button("x").clicked() {
+                                    line_to_remove = Some(id);
+                                }
+                            });
+                            self.note_editor(ui, Selection::Line(id));
+                        }
+                        if let Some(id) = line_to_remove {
+                            self.history.push_action(Action::RemoveLine(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 矩形測定結果
+                        let mut rect_to_remove = None;
+                        for i in 0..self.rectangle_measurements.len() {
+                            let id = self.rectangle_measurements[i].id;
+                            let (width, height, area, unit) = self.rectangle_measurements[i]
+                                .dimensions_with_calibration(self.calibration.as_ref());
+                            let area_unit = if unit == "px" {
+                                "px²".to_string()
+                            } else {
+                                format!("{}²", unit)
+                            };
+                            ui.horizontal(|ui| {
+                                self.name_editor(ui, Selection::Rect(id), &format!("矩#{}", id));
+                                let value = ui.label(format!(
+                                    "{:.1}x{:.1} {}, {:.1} {}",
+                                    width, height, unit, area, area_unit
+                                ));
+                                if !self.rectangle_measurements[i].note.is_empty() {
+                                    value.on_hover_text(&self.rectangle_measurements[i].note);
+                                }
+                                if ui.small_button("x").clicked() {
+                                    rect_to_remove = Some(id);
+                                }
+                            });
+                            self.note_editor(ui, Selection::Rect(id));
+                            if let Some(stats) =
+                                self.rect_statistics(&self.rectangle_measurements[i])
+                            {
+                                egui::CollapsingHeader::new(format!(
+                                    "画素統計 ({}画素)",
+                                    stats.pixel_count
+                                ))
+                                .id_salt(("rect_statistics", id))
+                                .show(ui, |ui| {
+                                    Self::show_region_statistics(ui, stats);
+                                });
+                            }
+                        }
+                        if let Some(id) = rect_to_remove {
+                            self.history.push_action(Action::RemoveRect(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 回転矩形測定結果
+                        let mut rotated_rect_to_remove = None;
+                        for rr in &self.rotated_rectangle_measurements {
+                            let (width, height, area, unit) =
+                                rr.dimensions_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "回矩#{}: {:.1}x{:.1} {}, {:.1}°, {:.1} {}²",
+                                    rr.id, width, height, unit, rr.angle_deg, area, unit
+                                ));
+                                if ui.small_button("x").clicked() {
+                                    rotated_rect_to_remove = Some(rr.id);
+                                }
+                            });
+                        }
+                        if let Some(id) = rotated_rect_to_remove {
+                            self.history.push_action(Action::RemoveRotatedRect(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 点と線の距離の測定結果
+                        let mut point_to_line_to_remove = None;
+                        for pl in &self.point_to_line_measurements {
+                            let (distance, unit) =
+                                pl.distance_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                ui.label(format!("垂#{}: {:.1} {}", pl.id, distance, unit));
+                                if ui.small_button("x").clicked() {
+                                    point_to_line_to_remove = Some(pl.id);
+                                }
+                            });
+                        }
+                        if let Some(id) = point_to_line_to_remove {
+                            self.history.push_action(Action::RemovePointToLine(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 平行線の間隔の測定結果
+                        let mut parallel_gap_to_remove = None;
+                        for gap in &self.parallel_gap_measurements {
+                            let (mean, min, max, unit) =
+                                gap.gaps_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "間#{}: 平均 {:.1} {} ({:.1}〜{:.1}), {:.2}°",
+                                    gap.id, mean, unit, min, max, gap.angle_deviation_deg
+                                ));
+                                if ui.small_button("x").clicked() {
+                                    parallel_gap_to_remove = Some(gap.id);
+                                }
+                            });
+                        }
+                        if let Some(id) = parallel_gap_to_remove {
+                            self.history.push_action(Action::RemoveParallelGap(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 自動選択の領域
+                        let mut region_to_remove = None;
+                        for region in &self.region_measurements {
+                            let (area, perimeter, bbox_width, bbox_height, unit) =
+                                region.dimensions_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "領#{}: {:.1} {}², 周 {:.1} {}",
+                                    region.id, area, unit, perimeter, unit
+                                ));
+                                if ui.small_button("x").clicked() {
+                                    region_to_remove = Some(region.id);
+                                }
+                            });
+                            ui.label(format!(
+                                "  外接 {:.1}x{:.1} {}, 重心 ({:.1}, {:.1})",
+                                bbox_width, bbox_height, unit, region.centroid.0, region.centroid.1
+                            ));
+                        }
+                        if let Some(id) = region_to_remove {
+                            self.history.push_action(Action::RemoveRegion(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 粒子解析の結果（まとめて折りたたみ表示）
+                        let mut blob_to_remove = None;
+                        if !self.blob_measurements.is_empty() {
+                            let total_area: f32 = self
+                                .blob_measurements
+                                .iter()
+                                .map(|b| b.dimensions_with_calibration(self.calibration.as_ref()).0)
+                                .sum();
+                            let (_, _, unit) = self.blob_measurements[0]
+                                .dimensions_with_calibration(self.calibration.as_ref());
+                            egui::CollapsingHeader::new(format!(
+                                "粒子: {}個, 平均面積 {:.1} {}²",
+                                self.blob_measurements.len(),
+                                total_area / self.blob_measurements.len() as f32,
+                                unit
+                            ))
+                            .id_salt("blob_results")
+                            .show(ui, |ui| {
+                                for blob in &self.blob_measurements {
+                                    let (area, diameter, unit) =
+                                        blob.dimensions_with_calibration(self.calibration.as_ref());
+                                    ui.horizontal(|ui| {
+                                        ui.label(format!(
+                                            "#{}: {:.1} {}², 相当径 {:.1} {}",
+                                            blob.id, area, unit, diameter, unit
+                                        ));
+                                        if ui.small_button("x").clicked() {
+                                            blob_to_remove = Some(blob.id);
+                                        }
+                                    });
+                                }
+                            });
+                        }
+                        if let Some(id) = blob_to_remove {
+                            self.history.push_action(Action::RemoveBlob(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // マーカー（カテゴリごとに折りたたみ表示）
+                        let mut marker_to_remove = None;
+                        let marker_numbers = self.marker_numbers();
+                        for (category, count) in self.marker_counts() {
+                            if count == 0 {
+                                continue;
+                            }
+                            egui::CollapsingHeader::new(format!("{}: {}個", category, count))
+                                .id_salt(("marker_result", &category))
+                                .show(ui, |ui| {
+                                    for (i, marker) in self.markers.iter().enumerate() {
+                                        if marker.category != category {
+                                            continue;
+                                        }
+                                        ui.horizontal(|ui| {
+                                            ui.label(format!(
+                                                "#{}: ({:.0}, {:.0})",
+                                                marker_numbers[i],
+                                                marker.position.0,
+                                                marker.position.1
+                                            ));
+                                            if ui.small_button("x").clicked() {
+                                                marker_to_remove = Some(i);
+                                            }
+                                        });
+                                    }
+                                });
+                        }
+                        if let Some(i) = marker_to_remove {
+                            self.history
+                                .push_action(Action::RemoveMarker(self.markers[i].id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 角度測定結果
+                        let mut angle_to_remove = None;
+                        for am in &self.angle_measurements {
+                            ui.horizontal(|ui| {
+                                ui.label(format!("角#{}: {:.1}°", am.id, am.angle_deg));
+                                if ui.small_button("x").clicked() {
+                                    angle_to_remove = Some(am.id);
+                                }
+                            });
+                        }
+                        if let Some(id) = angle_to_remove {
+                            self.history.push_action(Action::RemoveAngle(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 折れ線測定結果（区間ごとの長さは折りたたみ表示）
+                        let mut polyline_to_remove = None;
+                        for pm in &self.polyline_measurements {
+                            let (segment_lengths, total, unit) =
+                                pm.lengths_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                egui::CollapsingHeader::new(format!(
+                                    "折#{}: 計 {:.1} {}",
+                                    pm.id, total, unit
+                                ))
+                                .id_salt(("polyline_result", pm.id))
+                                .show(ui, |ui| {
+                                    for (j, length) in segment_lengths.iter().enumerate() {
+                                        ui.label(format!("区間{}: {:.1} {}", j + 1, length, unit));
+                                    }
+                                });
+                                if ui.small_button("x").clicked() {
+                                    polyline_to_remove = Some(pm.id);
+                                }
+                            });
+                        }
+                        if let Some(id) = polyline_to_remove {
+                            self.history.push_action(Action::RemovePolyline(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 多角形測定結果
+                        let mut polygon_to_remove = None;
+                        for pg in &self.polygon_measurements {
+                            let (area, perimeter, unit) =
+                                pg.dimensions_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "多#{}: {:.1} {}², 周長 {:.1} {} ({})",
+                                    pg.id,
+                                    area,
+                                    unit,
+                                    perimeter,
+                                    unit,
+                                    pg.shape_label()
+                                ));
+                                if ui.small_button("x").clicked() {
+                                    polygon_to_remove = Some(pg.id);
+                                }
+                            });
+                        }
+                        if let Some(id) = polygon_to_remove {
+                            self.history.push_action(Action::RemovePolygon(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 円測定結果
+                        let mut circle_to_remove = None;
+                        for cm in &self.circle_measurements {
+                            let (radius, diameter, circumference, area, unit) =
+                                cm.dimensions_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "円#{}: φ{:.1} (r {:.1}) {}, 周 {:.1} {}, {:.1} {}²",
+                                    cm.id, diameter, radius, unit, circumference, unit, area, unit
+                                ));
+                                if ui.small_button("x").clicked() {
+                                    circle_to_remove = Some(cm.id);
+                                }
+                            });
+                        }
+                        if let Some(id) = circle_to_remove {
+                            self.history.push_action(Action::RemoveCircle(id));
+                            self.rebuild_from_history();
+                        }
+
+                        // 楕円測定結果
+                        let mut ellipse_to_remove = None;
+                        for em in &self.ellipse_measurements {
+                            let (semi_major, semi_minor, area, perimeter, unit) =
+                                em.dimensions_with_calibration(self.calibration.as_ref());
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "楕#{}: a {:.1} / b {:.1} {}, {:.1}°, {:.1} {}², 周 {:.1} {}",
+                                    em.id,
+                                    semi_major,
+                                    semi_minor,
+                                    unit,
+                                    em.angle_deg,
+                                    area,
+                                    unit,
+                                    perimeter,
+                                    unit
+                                ));
+                                if ui.small_button("x").clicked() {
+                                    ellipse_to_remove = Some(em.id);
+                                }
+                            });
+                        }
+                        if let Some(id) = ellipse_to_remove {
+                            self.history.push_action(Action::RemoveEllipse(id));
+                            self.rebuild_from_history();
+                        }
+                    });
+
+                if self.has_measurements() {
+                    ui.horizontal(|ui| {
+                        if ui.button("すべてクリア").clicked() {
+                            self.clear_measurements();
+                            self.history
+                                .reset_with_calibration(self.calibration.clone());
+                        }
+                    });
+                }
+
+                ui.separator();
+
+                // エクスポート
+                ui.heading("エクスポート");
+
+                ui.horizontal(|ui| {
+                    if ui.button("CSV").clicked() {
+                        self.save_export("csv");
+                    }
+                    if ui.button("JSON").clicked() {
+                        self.save_export("json");
+                    }
+                });
+            });
+    }
+}
+
+impl eframe::App for SampoApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // テスト用：起動時に指定された画像を読み込む
+        #[cfg(test)]
+        if let Some(path) = self.pending_image_path.take() {
+            self.load_image(ctx, &path);
+        }
+
+        // テスト用：起動時に指定された寸法を追加
+        #[cfg(test)]
+        if !self.pending_measurements.is_empty() {
+            let measurements = std::mem::take(&mut self.pending_measurements);
+            for (start, end) in measurements {
+                let measurement = Measurement::new(start, end);
+                self.history.push_action(Action::AddLine(measurement));
+            }
+            self.rebuild_from_history();
+        }
+
+        // テスト用：Undo操作を実行
+        #[cfg(test)]
+        while self.pending_undo_count > 0 {
+            if self.history.undo() {
+                self.rebuild_from_history();
+            }
+            self.pending_undo_count -= 1;
+        }
+
+        // テスト用：Redo操作を実行
+        #[cfg(test)]
+        while self.pending_redo_count > 0 {
+            if self.history.redo() {
+                self.rebuild_from_history();
+            }
+            self.pending_redo_count -= 1;
+        }
+
+        // テスト用：測定の始点選択状態を設定（プレビューテスト用）
+        #[cfg(test)]
+        if let Some(start) = self.pending_first_point.take() {
+            self.measurement_state = MeasurementState::FirstPointSelected(start);
+        }
+
+        // テスト用：デバッグマウス位置をcurrent_mouse_image_posに設定
+        #[cfg(test)]
+        if let Some(debug_pos) = self.debug_mouse_position {
+            self.current_mouse_image_pos = Some(debug_pos);
+        }
+
+        // テスト用：Ctrl押下状態をシミュレート
+        #[cfg(test)]
+        if self.debug_ctrl_pressed {
+            self.is_ctrl_pressed = true;
+        }
+
+        // Ctrlキーの状態を取得（テストでdebug_ctrl_pressedがfalseの場合も含む）
+        #[cfg(not(test))]
+        {
+            self.is_ctrl_pressed = ctx.input(|i| i.modifiers.ctrl);
+        }
+        #[cfg(test)]
+        if !self.debug_ctrl_pressed {
+            self.is_ctrl_pressed = ctx.input(|i| i.modifiers.ctrl);
+        }
+
+        // Shiftキーの状態を取得（矩形の縦横比の固定・選択の追加）
+        self.is_shift_pressed = ctx.input(|i| i.modifiers.shift);
+
+        // Delete / Backspace で選択中の測定を削除（テキスト入力中は除く）
+        if self.measurement_mode == MeasurementMode::Select
+            && !self.selection.is_empty()
+            && !ctx.wants_keyboard_input()
+            && ctx
+                .input(|i| i.key_pressed(egui::Key::Delete) || i.key_pressed(egui::Key::Backspace))
+        {
+            self.delete_selection();
+        }
+
+        // キーボードショートカット: Ctrl+V / Cmd+V でクリップボードから貼り付け
+        let paste_shortcut = ctx.input(|i| i.key_pressed(egui::Key::V) && i.modifiers.command);
+        if paste_shortcut {
+            self.paste_from_clipboard(ctx);
+        }
+
+        // Undo/Redo ショートカット: Ctrl/Cmd+Z, Shift+Ctrl/Cmd+Z
+        let undo_shortcut = ctx
+            .input(|i| i.key_pressed(egui::Key::Z) && i.modifiers.command && !i.modifiers.shift);
+        let redo_shortcut = ctx
+            .input(|i| i.key_pressed(egui::Key::Z) && i.modifiers.command && i.modifiers.shift);
+        if undo_shortcut && self.history.undo() {
+            self.rebuild_from_history();
+        }
+        if redo_shortcut && self.history.redo() {
+            self.rebuild_from_history();
+        }
+
+        // Enter で頂点追加中の測定（折れ線）を確定（テキスト入力中は除く）
+        let finish_shortcut =
+            !ctx.wants_keyboard_input() && ctx.input(|i| i.key_pressed(egui::Key::Enter));
+        if finish_shortcut {
+            self.finish_vertex_measurement();
+        }
+
+        self.show_controls_panel(ctx);
+        self.show_status_bar(ctx);
+        self.show_profile_panel(ctx);
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            // スクロールエリアの位置を取得
+            let scroll_area_rect = ui.available_rect_before_wrap();
+
+            // ピンチズームの処理（マウス位置を中心に）
+            let zoom_delta = ui.input(|i| i.zoom_delta());
+            let pointer_pos = ui.input(|i| i.pointer.hover_pos());
+
+            let viewport_size = scroll_area_rect.size();
+
+            // パディングはviewport_sizeと同じ
+            let padding = viewport_size;
+
+            // 画像読み込み時にスクロール位置をリセット（画像左上が画面左上に来るように）
+            if self.needs_scroll_reset {
+                self.scroll_offset = padding;
+                self.needs_scroll_reset = false;
+            }
+
+            if zoom_delta != 1.0 && self.image_texture.is_some() {
+                if let Some(pointer) = pointer_pos {
+                    // ポインタがスクロールエリア内にあるか確認
+                    if scroll_area_rect.contains(pointer) {
+                        // ポインタの位置（スクロールエリア左上からの相対位置）
+                        let pointer_rel = pointer - scroll_area_rect.min;
+
+                        // ポインタが指しているコンテンツ上の位置
+                        let content_pos = self.scroll_offset + pointer_rel;
+
+                        // コンテンツ座標から画像座標を計算（パディングを引く）
+                        let image_pos = content_pos - padding;
+
+                        // 新しいズームを計算
+                        let old_zoom = self.zoom;
+                        let new_zoom = (old_zoom * zoom_delta).clamp(0.1, 5.0);
+                        let zoom_ratio = new_zoom / old_zoom;
+
+                        // 画像座標をズーム比率で拡大
+                        let new_image_pos = image_pos * zoom_ratio;
+
+                        // 新しいコンテンツ座標 = パディング + 新しい画像座標
+                        let new_content_pos = padding + new_image_pos;
+
+                        // 新しいスクロールオフセット = 新しいコンテンツ座標 - ポインタ相対位置
+                        self.scroll_offset = new_content_pos - pointer_rel;
+
+                        self.zoom = new_zoom;
+                    }
+                }
+            }
+
+            let scroll_output = egui::ScrollArea::both()
+                .auto_shrink([false, false])
+                .scroll_offset(self.scroll_offset)
+                .show(ui, |ui| {
+                    self.show_image_canvas(ui, viewport_size);
+                });
+
+            // ScrollAreaの実際のスクロール位置を同期
+            self.scroll_offset = scroll_output.state.offset;
+        });
+    }
+}
+
+fn main() -> eframe::Result<()> {
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([1024.0, 768.0]),
+        ..Default::default()
+    };
+
+    eframe::run_native(
+        "Sampo - 画像寸法測定ツール",
+        options,
+        Box::new(|cc| Ok(Box::new(SampoApp::new(cc)))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui_kittest::Harness;
+    use std::path::PathBuf;
+
+    /// テスト用ハーネスを作成するヘルパー関数
+    fn create_test_harness(
+        measurements: Vec<(egui::Pos2, egui::Pos2)>,
+    ) -> Harness<'static, SampoApp> {
+        let image_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets/lenna.png");
+        Harness::builder()
+            .with_size(egui::vec2(1024.0, 768.0))
+            .build_eframe(|cc| SampoApp::new_for_test(cc, Some(image_path), measurements))
+    }
+
+    #[test]
+    fn test_load_image_and_add_dimension() {
+        // 追加する寸法を準備（画像座標）
+        let start = egui::pos2(100.0, 100.0);
+        let end = egui::pos2(200.0, 150.0);
+        let measurements = vec![(start, end)];
+
+        let mut harness = create_test_harness(measurements);
+
+        // 初回のupdateで画像と寸法が読み込まれる
+        harness.run();
+
+        // 画像が読み込まれたことを確認
+        assert!(
+            harness.state().image_texture.is_some(),
+            "画像が読み込まれていません"
+        );
+        assert!(
+            harness.state().image_dimensions.is_some(),
+            "画像サイズが設定されていません"
+        );
+
+        // 検証：寸法が1つ追加されていることを確認
+        assert_eq!(
+            harness.state().measurements.len(),
+            1,
+            "寸法が1つ追加されているべき"
+        );
+
+        // 追加された寸法の検証
+        let measurement = &harness.state().measurements[0];
+        assert!(
+            (measurement.start.0 - 100.0).abs() < 0.1,
+            "始点Xが約100であるべき（実際: {:.1}）",
+            measurement.start.0
+        );
+        assert!(
+            (measurement.start.1 - 100.0).abs() < 0.1,
+            "始点Yが約100であるべき（実際: {:.1}）",
+            measurement.start.1
+        );
+        assert!(
+            (measurement.end.0 - 200.0).abs() < 0.1,
+            "終点Xが約200であるべき（実際: {:.1}）",
+            measurement.end.0
+        );
+        assert!(
+            (measurement.end.1 - 150.0).abs() < 0.1,
+            "終点Yが約150であるべき（実際: {:.1}）",
+            measurement.end.1
+        );
+
+        // 距離の検証（100^2 + 50^2 = 10000 + 2500 = 12500, sqrt(12500) ≈ 111.8）
+        let expected_distance = ((100.0_f32).powi(2) + (50.0_f32).powi(2)).sqrt();
+        assert!(
+            (measurement.distance_px - expected_distance).abs() < 0.1,
+            "距離が約{:.1}pxであるべき（実際: {:.1}）",
+            expected_distance,
+            measurement.distance_px
+        );
+
+        // スナップショットテスト
+        harness.snapshot("load_image_and_add_dimension");
+    }
+
+    /// シナリオ: 寸法を1つ追加 → Undo → 寸法が消える
+    #[test]
+    fn test_undo_single_measurement() {
+        // 1つの寸法を追加
+        let measurements = vec![(egui::pos2(100.0, 100.0), egui::pos2(200.0, 150.0))];
+        let mut harness = create_test_harness(measurements);
+
+        // 初回のupdateで寸法が追加される
+        harness.run();
+        assert_eq!(
+            harness.state().measurements.len(),
+            1,
+            "初期状態: 寸法が1つあるべき"
+        );
+
+        // Undoを実行するためにpending_undo_countを設定
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+
+        // 検証: 寸法が消えている
+        assert_eq!(
+            harness.state().measurements.len(),
+            0,
+            "Undo後: 寸法が0になるべき"
+        );
+
+        // スナップショット: Undo後の状態
+        harness.snapshot("undo_single_measurement");
+    }
+
+    /// シナリオ: 寸法を追加 → Undo → Redo → 寸法が復活
+    #[test]
+    fn test_redo_after_undo() {
+        // 1つの寸法を追加
+        let measurements = vec![(egui::pos2(100.0, 100.0), egui::pos2(200.0, 150.0))];
+        let mut harness = create_test_harness(measurements);
+
+        // 初回のupdate
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 1, "初期状態: 1つ");
+
+        // Undo
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 0, "Undo後: 0");
+
+        // Redo
+        harness.state_mut().pending_redo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 1, "Redo後: 1つ復活");
+
+        // 復活した寸法の検証
+        let measurement = &harness.state().measurements[0];
+        assert!(
+            (measurement.start.0 - 100.0).abs() < 0.1,
+            "Redo後の始点Xが100であるべき"
+        );
+        assert!(
+            (measurement.end.0 - 200.0).abs() < 0.1,
+            "Redo後の終点Xが200であるべき"
+        );
+
+        // スナップショット: Redo後の状態
+        harness.snapshot("redo_after_undo");
+    }
+
+    /// シナリオ: 複数の寸法を追加 → 複数回Undo → 複数回Redo
+    #[test]
+    fn test_multiple_undo_redo() {
+        // 3つの寸法を追加
+        let measurements = vec![
+            (egui::pos2(100.0, 100.0), egui::pos2(150.0, 100.0)), // 水平線 50px
+            (egui::pos2(200.0, 100.0), egui::pos2(200.0, 180.0)), // 垂直線 80px
+            (egui::pos2(300.0, 100.0), egui::pos2(400.0, 200.0)), // 斜め線
+        ];
+        let mut harness = create_test_harness(measurements);
+
+        // 初回のupdate
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 3, "初期状態: 3つ");
+
+        // 1回Undo → 2つになる
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 2, "Undo 1回後: 2つ");
+
+        // スナップショット: 2つの寸法
+        harness.snapshot("multiple_undo_redo_step1_after_undo");
+
+        // もう1回Undo → 1つになる
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 1, "Undo 2回後: 1つ");
+
+        // 残った寸法は最初のもの（水平線 50px）
+        let remaining = &harness.state().measurements[0];
+        let expected_dist = 50.0;
+        assert!(
+            (remaining.distance_px - expected_dist).abs() < 0.1,
+            "残った寸法は50pxであるべき（実際: {:.1}）",
+            remaining.distance_px
+        );
+
+        // スナップショット: 1つの寸法
+        harness.snapshot("multiple_undo_redo_step2_after_undo");
+
+        // Redo 1回 → 2つに戻る
+        harness.state_mut().pending_redo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 2, "Redo 1回後: 2つ");
+
+        // 2番目の寸法が復活（垂直線 80px）
+        let second = &harness.state().measurements[1];
+        let expected_dist = 80.0;
+        assert!(
+            (second.distance_px - expected_dist).abs() < 0.1,
+            "復活した寸法は80pxであるべき（実際: {:.1}）",
+            second.distance_px
+        );
+
+        // Redo もう1回 → 3つに戻る
+        harness.state_mut().pending_redo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 3, "Redo 2回後: 3つ");
+
+        // スナップショット: 全て復活
+        harness.snapshot("multiple_undo_redo_step3_all_restored");
+    }
+
+    /// シナリオ: Undo後に新しい操作 → Redoできなくなる
+    #[test]
+    fn test_undo_then_new_action_clears_redo() {
+        // 2つの寸法を追加
+        let measurements = vec![
+            (egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0)), // 100px
+            (egui::pos2(100.0, 200.0), egui::pos2(200.0, 200.0)), // 100px
+        ];
+        let mut harness = create_test_harness(measurements);
+
+        // 初回のupdate
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 2, "初期状態: 2つ");
+
+        // Undo → 1つになる
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 1, "Undo後: 1つ");
+
+        // Redoできるか確認
+        assert!(harness.state().history.can_redo(), "Redo可能であるべき");
+
+        // 新しい寸法を追加（これによりRedo履歴がクリアされる）
+        harness.state_mut().pending_measurements =
+            vec![(egui::pos2(300.0, 300.0), egui::pos2(400.0, 300.0))];
+        harness.run();
+
+        // 2つになる（Undo前の1つ + 新しい1つ）
+        assert_eq!(
+            harness.state().measurements.len(),
+            2,
+            "新しい操作後: 2つ"
+        );
+
+        // Redoできなくなっている
+        assert!(
+            !harness.state().history.can_redo(),
+            "新しい操作後はRedoできないべき"
+        );
+
+        // スナップショット
+        harness.snapshot("undo_then_new_action_clears_redo");
+    }
+
+    // ========================================
+    // スナップモードのシナリオテスト
+    // ========================================
+
+    /// スナップ対応テスト用ハーネスを作成するヘルパー関数
+    fn create_test_harness_with_snap(
+        measurements: Vec<(egui::Pos2, egui::Pos2)>,
+        length_snap_multiple: f32,
+    ) -> Harness<'static, SampoApp> {
+        let image_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets/lenna.png");
+        Harness::builder()
+            .with_size(egui::vec2(1024.0, 768.0))
+            .build_eframe(|cc| {
+                SampoApp::new_for_test_with_snap(cc, Some(image_path), measurements, length_snap_multiple)
+            })
+    }
+
+    /// シナリオ: 角度スナップのプレビュー表示
+    /// - 始点(100, 100)を選択
+    /// - マウスを(200, 103)に移動（ほぼ水平）
+    /// - Ctrlを押した状態でプレビュー表示
+    /// - 青い十字で真のマウス位置、黄色いプレビュー線で水平にスナップされた線を表示
+    #[test]
+    fn test_angle_snap_preview() {
+        let mut harness = create_test_harness_with_snap(vec![], 0.0);
+
+        // 初期化
+        harness.run();
+
+        // 始点を選択状態にする
+        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
+        // マウス位置を設定（ほぼ水平だが、少しずれている）
+        harness.state_mut().debug_mouse_position = Some(egui::pos2(200.0, 103.0));
+        // Ctrlを押した状態
+        harness.state_mut().debug_ctrl_pressed = true;
+
+        harness.run();
+
+        // 始点が選択されていることを確認
+        assert!(
+            matches!(
+                harness.state().measurement_state,
+                MeasurementState::FirstPointSelected(_)
+            ),
+            "始点選択状態であるべき"
+        );
+
+        // Ctrlが押されていることを確認
+        assert!(
+            harness.state().is_ctrl_pressed,
+            "Ctrl押下状態であるべき"
+        );
+
+        // スナップショット: 角度スナップのプレビュー
+        // - 青い十字: 真のマウス位置(200, 103)
+        // - 黄色いプレビュー線: (100, 100)から水平にスナップされた線
+        harness.snapshot("angle_snap_preview");
+    }
+
+    /// シナリオ: 角度スナップなしのプレビュー表示（比較用）
+    /// - Ctrl押下なしで斜めの線のプレビュー
+    #[test]
+    fn test_no_angle_snap_preview() {
+        let mut harness = create_test_harness_with_snap(vec![], 0.0);
+
+        // 初期化
+        harness.run();
+
+        // 始点を選択状態にする
+        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
+        // マウス位置を設定（斜め）
+        harness.state_mut().debug_mouse_position = Some(egui::pos2(200.0, 130.0));
+        // Ctrlは押していない
+        harness.state_mut().debug_ctrl_pressed = false;
+
+        harness.run();
+
+        // スナップショット: 角度スナップなしのプレビュー
+        // - 青い十字: 真のマウス位置(200, 130)
+        // - 黄色いプレビュー線: 斜めのまま
+        harness.snapshot("no_angle_snap_preview");
+    }
+
+    /// シナリオ: 長さスナップが有効な状態で寸法を追加
+    /// - length_snap_multiple = 10.0 に設定
+    /// - (100, 100)から(143, 100)の線を追加（長さ43px）
+    /// - 長さは40pxにスナップされる
+    #[test]
+    fn test_length_snap() {
+        // 長さスナップを10pxに設定
+        let mut harness = create_test_harness_with_snap(vec![], 10.0);
+
+        // 初期化
+        harness.run();
+
+        // 始点を選択状態にする
+        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
+        // マウス位置（43px離れている）
+        harness.state_mut().debug_mouse_position = Some(egui::pos2(143.0, 100.0));
+
+        harness.run();
+
+        // スナップショット: 長さスナップのプレビュー
+        // プレビュー線は40pxにスナップされているはず
+        harness.snapshot("length_snap_preview");
+
+        // 実際に寸法を追加（pending_measurementsを使うとスナップが適用されないので、
+        // handle_canvas_clickをシミュレートするために直接追加）
+        // まずは状態をリセット
+        let start = egui::pos2(100.0, 100.0);
+        let end = egui::pos2(143.0, 100.0);
+        // 長さスナップを適用
+        let snapped_end = snap_line_length(start, end, 10.0);
+        let measurement = Measurement::new(start, snapped_end);
 
-        self.show_controls_panel(ctx);
+        // 寸法を追加
+        harness.state_mut().history.push_action(Action::AddLine(measurement.clone()));
+        harness.state_mut().rebuild_from_history();
+        harness.state_mut().measurement_state = MeasurementState::Idle;
+        harness.state_mut().debug_mouse_position = None;
 
-        egui::CentralPanel::default().show(ctx, |ui| {
-            // スクロールエリアの位置を取得
-            let scroll_area_rect = ui.available_rect_before_wrap();
+        harness.run();
 
-            // ピンチズームの処理（マウス位置を中心に）
-            let zoom_delta = ui.input(|i| i.zoom_delta());
-            let pointer_pos = ui.input(|i| i.pointer.hover_pos());
+        // 追加された寸法の検証
+        assert_eq!(
+            harness.state().measurements.len(),
+            1,
+            "寸法が1つ追加されているべき"
+        );
 
-            let viewport_size = scroll_area_rect.size();
+        // スナップされた距離を検証（40pxになるはず）
+        let added_measurement = &harness.state().measurements[0];
+        assert!(
+            (added_measurement.distance_px - 40.0).abs() < 0.1,
+            "長さは40pxにスナップされるべき（実際: {:.1}）",
+            added_measurement.distance_px
+        );
 
-            // パディングはviewport_sizeと同じ
-            let padding = viewport_size;
+        // 終点X座標を検証（100 + 40 = 140）
+        assert!(
+            (added_measurement.end.0 - 140.0).abs() < 0.1,
+            "終点Xは140であるべき（実際: {:.1}）",
+            added_measurement.end.0
+        );
 
-            // 画像読み込み時にスクロール位置をリセット（画像左上が画面左上に来るように）
-            if self.needs_scroll_reset {
-                self.scroll_offset = padding;
-                self.needs_scroll_reset = false;
-            }
+        harness.snapshot("length_snap_result");
+    }
 
-            if zoom_delta != 1.0 && self.image_texture.is_some() {
-                if let Some(pointer) = pointer_pos {
-                    // ポインタがスクロールエリア内にあるか確認
-                    if scroll_area_rect.contains(pointer) {
-                        // ポインタの位置（スクロールエリア左上からの相対位置）
-                        let pointer_rel = pointer - scroll_area_rect.min;
+    /// シナリオ: 角度スナップ + 長さスナップの複合
+    /// - Ctrl押下で水平にスナップ
+    /// - 長さも10pxの倍数にスナップ
+    #[test]
+    fn test_angle_and_length_combined_snap() {
+        // 長さスナップを10pxに設定
+        let mut harness = create_test_harness_with_snap(vec![], 10.0);
 
-                        // ポインタが指しているコンテンツ上の位置
-                        let content_pos = self.scroll_offset + pointer_rel;
+        // 初期化
+        harness.run();
 
-                        // コンテンツ座標から画像座標を計算（パディングを引く）
-                        let image_pos = content_pos - padding;
+        // 始点を選択状態にする
+        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
+        // マウス位置（斜め、長さも半端）
+        harness.state_mut().debug_mouse_position = Some(egui::pos2(153.0, 104.0));
+        // Ctrl押下で角度スナップ
+        harness.state_mut().debug_ctrl_pressed = true;
 
-                        // 新しいズームを計算
-                        let old_zoom = self.zoom;
-                        let new_zoom = (old_zoom * zoom_delta).clamp(0.1, 5.0);
-                        let zoom_ratio = new_zoom / old_zoom;
+        harness.run();
 
-                        // 画像座標をズーム比率で拡大
-                        let new_image_pos = image_pos * zoom_ratio;
+        // スナップショット: 角度+長さスナップのプレビュー
+        // - 青い十字: 真のマウス位置(153, 104)
+        // - 黄色いプレビュー線: 水平にスナップ、長さも50pxにスナップ
+        harness.snapshot("angle_and_length_snap_preview");
 
-                        // 新しいコンテンツ座標 = パディング + 新しい画像座標
-                        let new_content_pos = padding + new_image_pos;
+        // 実際に角度+長さスナップを適用した寸法を追加
+        let start = egui::pos2(100.0, 100.0);
+        let raw_end = egui::pos2(153.0, 104.0);
+        // 角度スナップを適用
+        let angle_snapped = snap_to_angle(start, raw_end, &[0.0, 90.0], 5.0);
+        // 長さスナップを適用
+        let snapped_end = snap_line_length(start, angle_snapped, 10.0);
+        let measurement = Measurement::new(start, snapped_end);
 
-                        // 新しいスクロールオフセット = 新しいコンテンツ座標 - ポインタ相対位置
-                        self.scroll_offset = new_content_pos - pointer_rel;
+        harness.state_mut().history.push_action(Action::AddLine(measurement.clone()));
+        harness.state_mut().rebuild_from_history();
+        harness.state_mut().measurement_state = MeasurementState::Idle;
+        harness.state_mut().debug_mouse_position = None;
+        harness.state_mut().debug_ctrl_pressed = false;
 
-                        self.zoom = new_zoom;
-                    }
-                }
-            }
+        harness.run();
 
-            let scroll_output = egui::ScrollArea::both()
-                .auto_shrink([false, false])
-                .scroll_offset(self.scroll_offset)
-                .show(ui, |ui| {
-                    self.show_image_canvas(ui, viewport_size);
-                });
+        // 検証
+        assert_eq!(harness.state().measurements.len(), 1, "寸法が1つ追加されているべき");
 
-            // ScrollAreaの実際のスクロール位置を同期
-            self.scroll_offset = scroll_output.state.offset;
-        });
+        let added = &harness.state().measurements[0];
+        // 角度スナップで水平になっているはず（Y座標が同じ）
+        assert!(
+            (added.end.1 - added.start.1).abs() < 0.1,
+            "角度スナップで水平になるべき（Y差: {:.1}）",
+            (added.end.1 - added.start.1).abs()
+        );
+        // 長さスナップで10の倍数になっているはず
+        let distance_mod_10 = added.distance_px % 10.0;
+        assert!(
+            distance_mod_10 < 0.1 || (10.0 - distance_mod_10) < 0.1,
+            "長さは10の倍数であるべき（実際: {:.1}）",
+            added.distance_px
+        );
+
+        harness.snapshot("angle_and_length_snap_result");
     }
-}
 
-fn main() -> eframe::Result<()> {
-    let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([1024.0, 768.0]),
-        ..Default::default()
-    };
+    /// シナリオ: 垂直方向への角度スナップ
+    /// - 始点(100, 100)を選択
+    /// - マウスを(103, 200)に移動（ほぼ垂直）
+    /// - Ctrlを押した状態でプレビュー表示
+    #[test]
+    fn test_angle_snap_vertical() {
+        let mut harness = create_test_harness_with_snap(vec![], 0.0);
 
-    eframe::run_native(
-        "Sampo - 画像寸法測定ツール",
-        options,
-        Box::new(|cc| Ok(Box::new(SampoApp::new(cc)))),
-    )
-}
+        // 初期化
+        harness.run();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use egui_kittest::Harness;
-    use std::path::PathBuf;
+        // 始点を選択状態にする
+        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
+        // マウス位置を設定（ほぼ垂直）
+        harness.state_mut().debug_mouse_position = Some(egui::pos2(103.0, 200.0));
+        // Ctrlを押した状態
+        harness.state_mut().debug_ctrl_pressed = true;
 
-    /// テスト用ハーネスを作成するヘルパー関数
-    fn create_test_harness(
-        measurements: Vec<(egui::Pos2, egui::Pos2)>,
-    ) -> Harness<'static, SampoApp> {
-        let image_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets/lenna.png");
-        Harness::builder()
-            .with_size(egui::vec2(1024.0, 768.0))
-            .build_eframe(|cc| SampoApp::new_for_test(cc, Some(image_path), measurements))
+        harness.run();
+
+        // スナップショット: 垂直方向への角度スナップ
+        harness.snapshot("angle_snap_vertical");
     }
 
-    #[test]
-    fn test_load_image_and_add_dimension() {
-        // 追加する寸法を準備（画像座標）
-        let start = egui::pos2(100.0, 100.0);
-        let end = egui::pos2(200.0, 150.0);
-        let measurements = vec![(start, end)];
+    // ========================================
+    // 測定モードのシナリオテスト
+    // ========================================
 
-        let mut harness = create_test_harness(measurements);
+    /// 画像座標でキャンバスをクリックしたことにする（ズーム1倍・原点一致の画像矩形を使用）
+    fn click_image_pos(app: &mut SampoApp, image_pos: egui::Pos2) {
+        let (w, h) = app.image_dimensions.expect("画像が読み込まれているべき");
+        let image_rect =
+            egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(w as f32, h as f32));
+        app.handle_canvas_click(image_pos, image_rect);
+    }
 
-        // 初回のupdateで画像と寸法が読み込まれる
+    /// シナリオ: 角度モードで頂点・2本の辺上の点をクリック → 90°の角度測定が追加される → Undoで消える
+    #[test]
+    fn test_angle_measurement() {
+        let mut harness = create_test_harness(vec![]);
         harness.run();
 
-        // 画像が読み込まれたことを確認
+        harness.state_mut().measurement_mode = MeasurementMode::Angle;
+        click_image_pos(harness.state_mut(), egui::pos2(100.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(200.0, 100.0));
         assert!(
-            harness.state().image_texture.is_some(),
-            "画像が読み込まれていません"
-        );
-        assert!(
-            harness.state().image_dimensions.is_some(),
-            "画像サイズが設定されていません"
+            matches!(
+                harness.state().measurement_state,
+                MeasurementState::SecondPointSelected(_, _)
+            ),
+            "2点目まで選択済みであるべき"
         );
+        click_image_pos(harness.state_mut(), egui::pos2(100.0, 250.0));
+        harness.run();
 
-        // 検証：寸法が1つ追加されていることを確認
         assert_eq!(
-            harness.state().measurements.len(),
+            harness.state().angle_measurements.len(),
             1,
-            "寸法が1つ追加されているべき"
+            "角度測定が1つ追加されているべき"
         );
-
-        // 追加された寸法の検証
-        let measurement = &harness.state().measurements[0];
+        let angle = harness.state().angle_measurements[0].angle_deg;
         assert!(
-            (measurement.start.0 - 100.0).abs() < 0.1,
-            "始点Xが約100であるべき（実際: {:.1}）",
-            measurement.start.0
+            (angle - 90.0).abs() < 0.01,
+            "角度は90°であるべき（実際: {:.2}）",
+            angle
         );
         assert!(
-            (measurement.start.1 - 100.0).abs() < 0.1,
-            "始点Yが約100であるべき（実際: {:.1}）",
-            measurement.start.1
+            harness
+                .state()
+                .export_csv()
+                .contains("# Angle Measurements"),
+            "CSVに角度測定のセクションが含まれるべき"
         );
+
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
         assert!(
-            (measurement.end.0 - 200.0).abs() < 0.1,
-            "終点Xが約200であるべき（実際: {:.1}）",
-            measurement.end.0
+            harness.state().angle_measurements.is_empty(),
+            "Undo後: 角度測定が0になるべき"
         );
+    }
+
+    /// シナリオ: 折れ線モードで3頂点をクリック → 確定 → 区間ごとの長さと合計長が得られる
+    /// - 途中の頂点は1回の操作としてまとめてUndoされる
+    #[test]
+    fn test_polyline_measurement() {
+        let mut harness = create_test_harness(vec![]);
+        harness.run();
+
+        harness.state_mut().measurement_mode = MeasurementMode::Polyline;
+        click_image_pos(harness.state_mut(), egui::pos2(100.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(200.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(200.0, 150.0));
         assert!(
-            (measurement.end.1 - 150.0).abs() < 0.1,
-            "終点Yが約150であるべき（実際: {:.1}）",
-            measurement.end.1
+            harness.state().polyline_measurements.is_empty(),
+            "確定前は折れ線測定が追加されないべき"
         );
+        harness.state_mut().finish_vertex_measurement();
+        harness.run();
 
-        // 距離の検証（100^2 + 50^2 = 10000 + 2500 = 12500, sqrt(12500) ≈ 111.8）
-        let expected_distance = ((100.0_f32).powi(2) + (50.0_f32).powi(2)).sqrt();
+        assert_eq!(
+            harness.state().polyline_measurements.len(),
+            1,
+            "折れ線測定が1つ追加されているべき"
+        );
+        let (segment_lengths, total, unit) =
+            harness.state().polyline_measurements[0].lengths_with_calibration(None);
+        assert_eq!(segment_lengths.len(), 2, "区間は2つであるべき");
         assert!(
-            (measurement.distance_px - expected_distance).abs() < 0.1,
-            "距離が約{:.1}pxであるべき（実際: {:.1}）",
-            expected_distance,
-            measurement.distance_px
+            (segment_lengths[0] - 100.0).abs() < 0.1 && (segment_lengths[1] - 50.0).abs() < 0.1,
+            "区間の長さは100pxと50pxであるべき（実際: {:?}）",
+            segment_lengths
+        );
+        assert!(
+            (total - 150.0).abs() < 0.1,
+            "合計長は150pxであるべき（実際: {:.1}）",
+            total
         );
+        assert_eq!(unit, "px");
 
-        // スナップショットテスト
-        harness.snapshot("load_image_and_add_dimension");
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert!(
+            harness.state().polyline_measurements.is_empty(),
+            "Undo後: 折れ線測定が0になるべき"
+        );
     }
 
-    /// シナリオ: 寸法を1つ追加 → Undo → 寸法が消える
+    /// シナリオ: 多角形モードで正方形の4頂点をクリック → 始点クリックで閉じる → 面積・周長が得られる
+    /// - 非凸・自己交差の多角形も判定される
     #[test]
-    fn test_undo_single_measurement() {
-        // 1つの寸法を追加
-        let measurements = vec![(egui::pos2(100.0, 100.0), egui::pos2(200.0, 150.0))];
-        let mut harness = create_test_harness(measurements);
+    fn test_polygon_measurement() {
+        let mut harness = create_test_harness(vec![]);
+        harness.run();
+
+        harness.state_mut().measurement_mode = MeasurementMode::Polygon;
+        for p in [
+            egui::pos2(100.0, 100.0),
+            egui::pos2(200.0, 100.0),
+            egui::pos2(200.0, 200.0),
+            egui::pos2(100.0, 200.0),
+            // 始点をクリックして閉じる
+            egui::pos2(101.0, 101.0),
+        ] {
+            click_image_pos(harness.state_mut(), p);
+        }
+        harness.run();
+
+        assert_eq!(
+            harness.state().polygon_measurements.len(),
+            1,
+            "多角形測定が1つ追加されているべき"
+        );
+        let square = &harness.state().polygon_measurements[0];
+        assert_eq!(square.points.len(), 4, "頂点は4つであるべき");
+        assert!(
+            (square.area_px - 10000.0).abs() < 0.1,
+            "面積は10000px²であるべき（実際: {:.1}）",
+            square.area_px
+        );
+        assert!(
+            (square.perimeter_px - 400.0).abs() < 0.1,
+            "周長は400pxであるべき（実際: {:.1}）",
+            square.perimeter_px
+        );
+        assert!(square.is_convex && !square.is_self_intersecting);
+
+        // L字型（非凸）
+        let l_shape = PolygonMeasurement::new(&[
+            egui::pos2(0.0, 0.0),
+            egui::pos2(20.0, 0.0),
+            egui::pos2(20.0, 10.0),
+            egui::pos2(10.0, 10.0),
+            egui::pos2(10.0, 20.0),
+            egui::pos2(0.0, 20.0),
+        ]);
+        assert!(!l_shape.is_convex && !l_shape.is_self_intersecting);
+        assert!((l_shape.area_px - 300.0).abs() < 0.1);
+
+        // 蝶ネクタイ型（自己交差）
+        let bowtie = PolygonMeasurement::new(&[
+            egui::pos2(0.0, 0.0),
+            egui::pos2(10.0, 10.0),
+            egui::pos2(10.0, 0.0),
+            egui::pos2(0.0, 10.0),
+        ]);
+        assert!(!bowtie.is_convex && bowtie.is_self_intersecting);
+    }
 
-        // 初回のupdateで寸法が追加される
+    /// シナリオ: 円モード（中心+半径、円周上の3点）で円を測定する
+    /// - 一直線上の3点では円は追加されず、3点目の選び直しを待つ
+    #[test]
+    fn test_circle_measurement() {
+        let mut harness = create_test_harness(vec![]);
+        harness.run();
+
+        // 中心(150, 150)・半径50
+        harness.state_mut().measurement_mode = MeasurementMode::Circle;
+        click_image_pos(harness.state_mut(), egui::pos2(150.0, 150.0));
+        click_image_pos(harness.state_mut(), egui::pos2(150.0, 200.0));
         harness.run();
+        assert_eq!(harness.state().circle_measurements.len(), 1);
+        let circle = &harness.state().circle_measurements[0];
+        assert!(
+            (circle.radius_px - 50.0).abs() < 0.01,
+            "半径は50pxであるべき（実際: {:.2}）",
+            circle.radius_px
+        );
+        assert!((circle.diameter_px() - 100.0).abs() < 0.01);
+
+        // 円周上の3点: 中心(300, 300)・半径40の円
+        harness.state_mut().circle_method = CircleMethod::ThreePoint;
+        click_image_pos(harness.state_mut(), egui::pos2(340.0, 300.0));
+        click_image_pos(harness.state_mut(), egui::pos2(300.0, 260.0));
+        // 一直線上の点は無視される
+        click_image_pos(harness.state_mut(), egui::pos2(260.0, 220.0));
         assert_eq!(
-            harness.state().measurements.len(),
+            harness.state().circle_measurements.len(),
             1,
-            "初期状態: 寸法が1つあるべき"
+            "一直線上の3点では円が追加されないべき"
         );
+        click_image_pos(harness.state_mut(), egui::pos2(260.0, 300.0));
+        harness.run();
 
-        // Undoを実行するためにpending_undo_countを設定
-        harness.state_mut().pending_undo_count = 1;
+        assert_eq!(harness.state().circle_measurements.len(), 2);
+        let circle = &harness.state().circle_measurements[1];
+        assert!(
+            circle.center_pos().distance(egui::pos2(300.0, 300.0)) < 0.01,
+            "中心は(300, 300)であるべき（実際: {:?}）",
+            circle.center
+        );
+        assert!(
+            (circle.radius_px - 40.0).abs() < 0.01,
+            "半径は40pxであるべき（実際: {:.2}）",
+            circle.radius_px
+        );
+    }
+
+    /// シナリオ: 楕円モードで中心・長軸の端点・短軸方向の点をクリック → 半径・向き・面積が得られる
+    #[test]
+    fn test_ellipse_measurement() {
+        let mut harness = create_test_harness(vec![]);
         harness.run();
 
-        // 検証: 寸法が消えている
-        assert_eq!(
-            harness.state().measurements.len(),
-            0,
-            "Undo後: 寸法が0になるべき"
+        harness.state_mut().measurement_mode = MeasurementMode::Ellipse;
+        let center = egui::pos2(200.0, 200.0);
+        click_image_pos(harness.state_mut(), center);
+        // 長軸: 45°方向に長さ60
+        click_image_pos(
+            harness.state_mut(),
+            center + egui::Vec2::angled(45_f32.to_radians()) * 60.0,
+        );
+        // 短軸方向: 長軸から30離れた点
+        click_image_pos(
+            harness.state_mut(),
+            center + egui::Vec2::angled(135_f32.to_radians()) * 30.0,
         );
+        harness.run();
 
-        // スナップショット: Undo後の状態
-        harness.snapshot("undo_single_measurement");
+        assert_eq!(harness.state().ellipse_measurements.len(), 1);
+        let ellipse = &harness.state().ellipse_measurements[0];
+        assert!((ellipse.semi_major_px - 60.0).abs() < 0.01);
+        assert!((ellipse.semi_minor_px - 30.0).abs() < 0.01);
+        assert!(
+            (ellipse.angle_deg - 45.0).abs() < 0.01,
+            "長軸の向きは45°であるべき（実際: {:.2}）",
+            ellipse.angle_deg
+        );
+        let expected_area = std::f32::consts::PI * 60.0 * 30.0;
+        assert!((ellipse.area_px() - expected_area).abs() < 0.1);
+
+        // 短軸方向の点が長軸より遠い場合は軸が入れ替わる
+        let swapped = EllipseMeasurement::from_axes(
+            egui::pos2(0.0, 0.0),
+            egui::pos2(20.0, 0.0),
+            egui::pos2(5.0, 50.0),
+        );
+        assert!((swapped.semi_major_px - 50.0).abs() < 0.01);
+        assert!((swapped.semi_minor_px - 20.0).abs() < 0.01);
+        assert!((swapped.angle_deg - 90.0).abs() < 0.01);
     }
 
-    /// シナリオ: 寸法を追加 → Undo → Redo → 寸法が復活
+    /// シナリオ: 回転矩形モードで傾いた基準線と高さをクリック → 幅・高さ・傾き・面積が得られる
     #[test]
-    fn test_redo_after_undo() {
-        // 1つの寸法を追加
-        let measurements = vec![(egui::pos2(100.0, 100.0), egui::pos2(200.0, 150.0))];
-        let mut harness = create_test_harness(measurements);
+    fn test_rotated_rectangle_measurement() {
+        let mut harness = create_test_harness(vec![]);
+        harness.run();
 
-        // 初回のupdate
+        harness.state_mut().measurement_mode = MeasurementMode::RotatedRectangle;
+        let start = egui::pos2(100.0, 100.0);
+        let direction = egui::Vec2::angled(30_f32.to_radians());
+        click_image_pos(harness.state_mut(), start);
+        // 基準線: 30°方向に長さ80
+        click_image_pos(harness.state_mut(), start + direction * 80.0);
+        // 基準線の中ほどから垂直方向に40離れた点
+        click_image_pos(
+            harness.state_mut(),
+            start + direction * 50.0 + direction.rot90() * 40.0,
+        );
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 1, "初期状態: 1つ");
 
-        // Undo
-        harness.state_mut().pending_undo_count = 1;
+        assert_eq!(harness.state().rotated_rectangle_measurements.len(), 1);
+        let rotated_rect = &harness.state().rotated_rectangle_measurements[0];
+        assert!((rotated_rect.width_px - 80.0).abs() < 0.01);
+        assert!((rotated_rect.height_px - 40.0).abs() < 0.01);
+        assert!(
+            (rotated_rect.angle_deg - 30.0).abs() < 0.01,
+            "傾きは30°であるべき（実際: {:.2}）",
+            rotated_rect.angle_deg
+        );
+        assert!((rotated_rect.area_px - 3200.0).abs() < 0.5);
+
+        // 対辺の角は基準線からクリックした側に40離れる
+        let corners = rotated_rect.corners();
+        let expected = start + direction * 80.0 + direction.rot90() * 40.0;
+        assert!(corners[2].distance(expected) < 0.01);
+    }
+
+    /// シナリオ: 点と線の距離モードで基準線を描いて点をクリック → 垂直距離が得られる
+    /// 既存の線分を基準線に選ぶと1クリックで測定できる → Undoで消える
+    #[test]
+    fn test_point_to_line_measurement() {
+        let reference = (egui::pos2(0.0, 100.0), egui::pos2(200.0, 100.0));
+        let mut harness = create_test_harness(vec![reference]);
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 0, "Undo後: 0");
 
-        // Redo
-        harness.state_mut().pending_redo_count = 1;
+        // 基準線を新たに描く（垂線の足は線分の延長上）
+        harness.state_mut().measurement_mode = MeasurementMode::PointToLine;
+        click_image_pos(harness.state_mut(), egui::pos2(300.0, 0.0));
+        click_image_pos(harness.state_mut(), egui::pos2(300.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(250.0, 180.0));
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 1, "Redo後: 1つ復活");
 
-        // 復活した寸法の検証
-        let measurement = &harness.state().measurements[0];
+        assert_eq!(harness.state().point_to_line_measurements.len(), 1);
+        let drawn = &harness.state().point_to_line_measurements[0];
         assert!(
-            (measurement.start.0 - 100.0).abs() < 0.1,
-            "Redo後の始点Xが100であるべき"
+            (drawn.distance_px - 50.0).abs() < 0.01,
+            "距離は50pxであるべき（実際: {:.2}）",
+            drawn.distance_px
         );
+        assert!(drawn.foot_pos().distance(egui::pos2(300.0, 180.0)) < 0.01);
+
+        // 既存の線分を基準線に選ぶ
+        harness.state_mut().point_line_reference = Some(1);
+        click_image_pos(harness.state_mut(), egui::pos2(50.0, 40.0));
+        harness.run();
+
+        assert_eq!(harness.state().point_to_line_measurements.len(), 2);
+        let picked = &harness.state().point_to_line_measurements[1];
+        assert!((picked.distance_px - 60.0).abs() < 0.01);
         assert!(
-            (measurement.end.0 - 200.0).abs() < 0.1,
-            "Redo後の終点Xが200であるべき"
+            harness
+                .state()
+                .export_csv()
+                .contains("# Point-to-Line Measurements"),
+            "CSVに点と線の距離のセクションが含まれるべき"
         );
 
-        // スナップショット: Redo後の状態
-        harness.snapshot("redo_after_undo");
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().point_to_line_measurements.len(), 1);
     }
 
-    /// シナリオ: 複数の寸法を追加 → 複数回Undo → 複数回Redo
+    /// シナリオ: 平行線の間隔モードで既存の線分2本を選んで測定 → 平均・最小・最大の間隔と角度のずれが得られる
+    /// 2本とも描いて測定することもできる
     #[test]
-    fn test_multiple_undo_redo() {
-        // 3つの寸法を追加
-        let measurements = vec![
-            (egui::pos2(100.0, 100.0), egui::pos2(150.0, 100.0)), // 水平線 50px
-            (egui::pos2(200.0, 100.0), egui::pos2(200.0, 180.0)), // 垂直線 80px
-            (egui::pos2(300.0, 100.0), egui::pos2(400.0, 200.0)), // 斜め線
+    fn test_parallel_gap_measurement() {
+        let lines = vec![
+            (egui::pos2(0.0, 100.0), egui::pos2(200.0, 100.0)),
+            (egui::pos2(0.0, 150.0), egui::pos2(200.0, 160.0)),
         ];
-        let mut harness = create_test_harness(measurements);
+        let mut harness = create_test_harness(lines);
+        harness.run();
 
-        // 初回のupdate
+        harness.state_mut().measurement_mode = MeasurementMode::ParallelGap;
+        harness.state_mut().parallel_gap_references = [Some(1), Some(2)];
+        harness.state_mut().try_finish_parallel_gap();
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 3, "初期状態: 3つ");
 
-        // 1回Undo → 2つになる
-        harness.state_mut().pending_undo_count = 1;
+        assert_eq!(harness.state().parallel_gap_measurements.len(), 1);
+        let gap = &harness.state().parallel_gap_measurements[0];
+        assert!((gap.min_gap_px - 50.0 / 1.0025_f32.sqrt()).abs() < 0.01);
+        assert!((gap.max_gap_px - 60.0).abs() < 0.01);
+        assert!(gap.min_gap_px < gap.mean_gap_px && gap.mean_gap_px < gap.max_gap_px);
+        assert!(
+            (gap.angle_deviation_deg - 0.05_f32.atan().to_degrees()).abs() < 0.01,
+            "角度のずれは約2.86°であるべき（実際: {:.3}）",
+            gap.angle_deviation_deg
+        );
+
+        // 同じ線分を2本目に選ぶことはできない
+        harness.state_mut().parallel_gap_references = [Some(1), Some(1)];
+        harness.state_mut().try_finish_parallel_gap();
+        assert_eq!(harness.state().parallel_gap_measurements.len(), 1);
+        harness.run();
+        assert_eq!(harness.state().parallel_gap_references, [Some(1), None]);
+
+        // 2本とも描く（1本目を描いた時点ではまだ確定しない）
+        harness.state_mut().parallel_gap_references = [None, None];
+        click_image_pos(harness.state_mut(), egui::pos2(300.0, 0.0));
+        click_image_pos(harness.state_mut(), egui::pos2(300.0, 200.0));
+        assert_eq!(harness.state().parallel_gap_measurements.len(), 1);
+        click_image_pos(harness.state_mut(), egui::pos2(340.0, 0.0));
+        click_image_pos(harness.state_mut(), egui::pos2(340.0, 200.0));
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 2, "Undo 1回後: 2つ");
 
-        // スナップショット: 2つの寸法
-        harness.snapshot("multiple_undo_redo_step1_after_undo");
+        assert_eq!(harness.state().parallel_gap_measurements.len(), 2);
+        let drawn = &harness.state().parallel_gap_measurements[1];
+        assert!((drawn.mean_gap_px - 40.0).abs() < 0.01);
+        assert!(drawn.angle_deviation_deg.abs() < 0.01);
+        assert!(harness.state().parallel_gap_drawn_lines.is_empty());
+    }
 
-        // もう1回Undo → 1つになる
-        harness.state_mut().pending_undo_count = 1;
+    /// シナリオ: マーカーモードでカテゴリを切り替えながらクリック → カテゴリごとに番号付きで数えられる → Undoで1つ消える
+    #[test]
+    fn test_marker_counts() {
+        let mut harness = create_test_harness(vec![]);
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 1, "Undo 2回後: 1つ");
 
-        // 残った寸法は最初のもの（水平線 50px）
-        let remaining = &harness.state().measurements[0];
-        let expected_dist = 50.0;
+        harness.state_mut().measurement_mode = MeasurementMode::Marker;
+        harness.state_mut().marker_categories = vec![
+            MarkerCategory {
+                name: "defect".to_string(),
+                color: MARKER_CATEGORY_COLORS[0],
+            },
+            MarkerCategory {
+                name: "pore, open".to_string(),
+                color: MARKER_CATEGORY_COLORS[1],
+            },
+        ];
+        click_image_pos(harness.state_mut(), egui::pos2(10.0, 10.0));
+        harness.state_mut().active_marker_category = 1;
+        click_image_pos(harness.state_mut(), egui::pos2(20.0, 20.0));
+        harness.state_mut().active_marker_category = 0;
+        click_image_pos(harness.state_mut(), egui::pos2(30.0, 30.0));
+        harness.run();
+
+        assert_eq!(harness.state().markers.len(), 3);
+        assert_eq!(
+            harness.state().marker_counts(),
+            vec![("defect".to_string(), 2), ("pore, open".to_string(), 1)]
+        );
+        assert_eq!(harness.state().marker_numbers(), vec![1, 1, 2]);
+        let csv = harness.state().export_csv();
+        assert!(csv.contains("# Marker Counts"));
+        assert!(csv.contains("defect,2"));
+        // カンマを含むカテゴリ名は引用符で囲む
         assert!(
-            (remaining.distance_px - expected_dist).abs() < 0.1,
-            "残った寸法は50pxであるべき（実際: {:.1}）",
-            remaining.distance_px
+            csv.contains("\n2,\"pore, open\",1,20.00,20.00,既定\n"),
+            "CSV: {}",
+            csv
         );
+        assert!(csv.contains("\n\"pore, open\",1\n"));
 
-        // スナップショット: 1つの寸法
-        harness.snapshot("multiple_undo_redo_step2_after_undo");
-
-        // Redo 1回 → 2つに戻る
-        harness.state_mut().pending_redo_count = 1;
+        harness.state_mut().pending_undo_count = 1;
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 2, "Redo 1回後: 2つ");
+        assert_eq!(
+            harness.state().marker_counts(),
+            vec![("defect".to_string(), 1), ("pore, open".to_string(), 1)]
+        );
+    }
 
-        // 2番目の寸法が復活（垂直線 80px）
-        let second = &harness.state().measurements[1];
-        let expected_dist = 80.0;
+    /// シナリオ: 線分の輝度プロファイルを表示 → 双線形補間した値が線分に沿って得られ、CSVに出力できる
+    #[test]
+    fn test_intensity_profile() {
+        // 左半分が黒、右半分が白の 4x1 画像では、中央の境界で値が線形に変化する
+        let image = image::RgbaImage::from_fn(4, 1, |x, _| {
+            let v = if x < 2 { 0 } else { 255 };
+            image::Rgba([v, v, v, 255])
+        });
+        assert_eq!(
+            sample_bilinear(&image, egui::pos2(1.0, 0.5)),
+            [0.0, 0.0, 0.0, 255.0]
+        );
+        assert_eq!(sample_bilinear(&image, egui::pos2(2.0, 0.5))[0], 127.5);
+        let profile = intensity_profile(
+            &image,
+            egui::pos2(0.5, 0.5),
+            egui::pos2(3.5, 0.5),
+            ImageChannel::Red,
+        );
+        assert_eq!(
+            profile,
+            vec![(0.0, 0.0), (1.0, 0.0), (2.0, 255.0), (3.0, 255.0)]
+        );
+        // 画素のない画像でもパニックしない
+        let empty = image::RgbaImage::new(0, 0);
+        assert_eq!(sample_bilinear(&empty, egui::pos2(0.5, 0.5)), [0.0; 4]);
         assert!(
-            (second.distance_px - expected_dist).abs() < 0.1,
-            "復活した寸法は80pxであるべき（実際: {:.1}）",
-            second.distance_px
+            intensity_profile(
+                &empty,
+                egui::pos2(0.0, 0.0),
+                egui::pos2(1.0, 0.0),
+                ImageChannel::Red
+            )
+            .is_empty()
         );
 
-        // Redo もう1回 → 3つに戻る
-        harness.state_mut().pending_redo_count = 1;
+        let line = (egui::pos2(10.0, 20.0), egui::pos2(110.0, 20.0));
+        let mut harness = create_test_harness(vec![line]);
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 3, "Redo 2回後: 3つ");
+        assert!(
+            harness.state().image_rgba.is_some(),
+            "デコード済みの画像が保持されているべき"
+        );
 
-        // スナップショット: 全て復活
-        harness.snapshot("multiple_undo_redo_step3_all_restored");
+        harness.state_mut().profile_line = Some(1);
+        harness.run();
+        let (_, profile) = harness
+            .state()
+            .current_profile()
+            .expect("プロファイルが得られるべき");
+        assert_eq!(profile.len(), 101, "100pxの線分は1px間隔で101点");
+        let csv = harness
+            .state()
+            .export_profile_csv()
+            .expect("CSVを出力できるべき");
+        assert!(csv.starts_with("distance_px,distance_calibrated,luminance,unit"));
+        assert_eq!(csv.lines().count(), 102);
+
+        // 線分を削除するとプロファイルは閉じる
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert!(harness.state().profile_line.is_none());
     }
 
-    /// シナリオ: Undo後に新しい操作 → Redoできなくなる
+    /// シナリオ: 矩形測定を追加 → 矩形内の画素の平均・標準偏差・最小・最大・ヒストグラムが求まり、CSVに出力される
     #[test]
-    fn test_undo_then_new_action_clears_redo() {
-        // 2つの寸法を追加
-        let measurements = vec![
-            (egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0)), // 100px
-            (egui::pos2(100.0, 200.0), egui::pos2(200.0, 200.0)), // 100px
-        ];
-        let mut harness = create_test_harness(measurements);
+    fn test_rectangle_region_statistics() {
+        // 上段が 0、下段が 100 の 4x2 画像（R のみ）
+        let image = image::RgbaImage::from_fn(4, 2, |_, y| {
+            image::Rgba([if y == 0 { 0 } else { 100 }, 0, 0, 255])
+        });
+        let stats = region_statistics(&image, egui::pos2(0.0, 0.0), egui::pos2(2.0, 2.0))
+            .expect("画素を含むべき");
+        assert_eq!(stats.pixel_count, 4);
+        let red = &stats.channels[0];
+        assert_eq!(red.mean, 50.0);
+        assert_eq!(red.std_dev, 50.0);
+        assert_eq!((red.min, red.max), (0.0, 100.0));
+        assert_eq!((red.histogram[0], red.histogram[100]), (2, 2));
+        // 画素の中心を含まない矩形は統計なし
+        assert!(region_statistics(&image, egui::pos2(0.6, 0.6), egui::pos2(1.4, 1.4)).is_none());
+
+        let mut harness = create_test_harness(vec![]);
+        harness.run();
+        harness.state_mut().measurement_mode = MeasurementMode::Rectangle;
+        click_image_pos(harness.state_mut(), egui::pos2(10.0, 10.0));
+        click_image_pos(harness.state_mut(), egui::pos2(30.0, 20.0));
+        harness.run();
 
-        // 初回のupdate
+        let state = harness.state();
+        let stats = state
+            .rect_statistics(&state.rectangle_measurements[0])
+            .expect("読み込んだ画像から統計が求まるべき");
+        assert_eq!(stats.pixel_count, 200, "20x10 の矩形は200画素");
+        for c in &stats.channels {
+            assert_eq!(c.histogram.iter().sum::<u32>(), 200);
+            assert!(c.min <= c.mean && c.mean <= c.max);
+        }
+        let csv = harness.state().export_csv();
+        assert!(csv.contains("# Rectangle Region Statistics"));
+        assert!(csv.contains("# Rectangle Region Histograms"));
+
+        // 範囲を変えた矩形だけ求め直す
+        let moved = harness.state().rectangle_measurements[0]
+            .with_corners(egui::pos2(10.0, 10.0), egui::pos2(20.0, 20.0));
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::ReplaceRect(moved));
+        harness.state_mut().rebuild_from_history();
+        let state = harness.state();
+        let stats = state
+            .rect_statistics(&state.rectangle_measurements[0])
+            .unwrap();
+        assert_eq!(stats.pixel_count, 100);
+
+        // ドラッグで変形している間も今の範囲の統計を返す
+        let (w, h) = harness.state().image_dimensions.unwrap();
+        let image_rect =
+            egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(w as f32, h as f32));
+        harness.state_mut().measurement_mode = MeasurementMode::Select;
+        harness.state_mut().selection = vec![Selection::Rect(1)];
+        harness
+            .state_mut()
+            .begin_drag_edit(egui::pos2(20.0, 20.0), image_rect);
+        harness
+            .state_mut()
+            .update_drag_edit(egui::pos2(20.0, 15.0), image_rect);
+        let state = harness.state();
+        let stats = state
+            .rect_statistics(&state.rectangle_measurements[0])
+            .unwrap();
+        assert_eq!(stats.pixel_count, 50);
+        harness.state_mut().finish_drag_edit();
+
+        harness.state_mut().pending_undo_count = 3;
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 2, "初期状態: 2つ");
+        assert!(harness.state().rectangle_statistics.is_empty());
+    }
 
-        // Undo → 1つになる
-        harness.state_mut().pending_undo_count = 1;
+    /// シナリオ: 画像上にカーソルを置く → ステータスバー用に座標・RGBA・16進・HSVが読み取れる（N×N平均も可）
+    #[test]
+    fn test_pixel_inspector() {
+        assert_eq!(rgb_to_hsv(255.0, 0.0, 0.0), (0.0, 1.0, 1.0));
+        assert_eq!(rgb_to_hsv(0.0, 0.0, 255.0), (240.0, 1.0, 1.0));
+        assert_eq!(rgb_to_hsv(128.0, 128.0, 128.0).1, 0.0);
+
+        // 左上だけ白の 3x3 画像
+        let image = image::RgbaImage::from_fn(3, 3, |x, y| {
+            let v = if (x, y) == (0, 0) { 255 } else { 0 };
+            image::Rgba([v, v, v, 255])
+        });
+        assert_eq!(average_pixel(&image, 0, 0, 1), [255.0, 255.0, 255.0, 255.0]);
+        // 端では画像内の画素（2x2）だけで平均する
+        assert_eq!(average_pixel(&image, 0, 0, 3)[0], 255.0 / 4.0);
+        assert_eq!(average_pixel(&image, 1, 1, 3)[0], 255.0 / 9.0);
+
+        let mut harness = create_test_harness(vec![]);
+        harness.state_mut().debug_mouse_position = Some(egui::pos2(12.5, 34.5));
         harness.run();
-        assert_eq!(harness.state().measurements.len(), 1, "Undo後: 1つ");
 
-        // Redoできるか確認
-        assert!(harness.state().history.can_redo(), "Redo可能であるべき");
+        let reading = harness
+            .state()
+            .pixel_reading()
+            .expect("カーソル位置の画素値が読み取れるべき");
+        assert_eq!(reading.pixel, (12, 34));
+        let summary = reading.summary();
+        assert!(summary.contains("(12.5, 34.5)"));
+        assert!(summary.contains(&reading.hex()));
+        assert!(summary.contains("HSV("));
+
+        // カーソルが画像の外に出たら古い画素値を表示し続けない
+        harness.state_mut().debug_mouse_position = None;
+        harness.run();
+        assert!(harness.state().pixel_reading().is_none());
+    }
 
-        // 新しい寸法を追加（これによりRedo履歴がクリアされる）
-        harness.state_mut().pending_measurements =
-            vec![(egui::pos2(300.0, 300.0), egui::pos2(400.0, 300.0))];
+    /// シナリオ: 自動選択モードで領域内をクリック → 許容差内の色の領域の面積・周長・外接矩形・重心・輪郭が得られる
+    #[test]
+    fn test_magic_wand_region() {
+        let mut harness = create_test_harness(vec![]);
         harness.run();
 
-        // 2つになる（Undo前の1つ + 新しい1つ）
+        // 白地に、黒の 20x10 の矩形と、それに接する少し明るい 10x10 の矩形
+        let image = image::RgbaImage::from_fn(100, 100, |x, y| {
+            if (30..50).contains(&x) && (40..50).contains(&y) {
+                image::Rgba([0, 0, 0, 255])
+            } else if (50..60).contains(&x) && (40..50).contains(&y) {
+                image::Rgba([40, 40, 40, 255])
+            } else {
+                image::Rgba([255, 255, 255, 255])
+            }
+        });
+        harness.state_mut().set_image_rgba(Some(image));
+        harness.state_mut().measurement_mode = MeasurementMode::MagicWand;
+        harness.state_mut().magic_wand_tolerance = 10.0;
+        click_image_pos(harness.state_mut(), egui::pos2(35.5, 45.5));
+        harness.run();
+
+        assert_eq!(harness.state().region_measurements.len(), 1);
+        let region = &harness.state().region_measurements[0];
+        assert_eq!(region.area_px, 200.0);
+        assert_eq!(region.perimeter_px, 60.0);
         assert_eq!(
-            harness.state().measurements.len(),
-            2,
-            "新しい操作後: 2つ"
+            (region.bbox_min, region.bbox_max),
+            ((30.0, 40.0), (50.0, 50.0))
         );
+        assert_eq!(region.centroid, (40.0, 45.0));
+        assert_eq!(region.contours.len(), 1);
+        assert_eq!(region.contours[0].len(), 4, "矩形の輪郭は4頂点");
 
-        // Redoできなくなっている
-        assert!(
-            !harness.state().history.can_redo(),
-            "新しい操作後はRedoできないべき"
-        );
+        // 許容差を広げると隣の矩形も含まれる
+        harness.state_mut().magic_wand_tolerance = 50.0;
+        click_image_pos(harness.state_mut(), egui::pos2(35.5, 45.5));
+        harness.run();
+        let region = &harness.state().region_measurements[1];
+        assert_eq!(region.area_px, 300.0);
+        assert_eq!(region.bbox_size_px(), (30.0, 10.0));
 
-        // スナップショット
-        harness.snapshot("undo_then_new_action_clears_redo");
+        // 穴のある領域（白地）は外側と穴の輪郭を持つ
+        click_image_pos(harness.state_mut(), egui::pos2(5.0, 5.0));
+        harness.run();
+        let background = &harness.state().region_measurements[2];
+        assert_eq!(background.area_px, 10000.0 - 300.0);
+        assert_eq!(background.contours.len(), 2);
     }
 
-    // ========================================
-    // スナップモードのシナリオテスト
-    // ========================================
+    /// シナリオ: エッジスナップを有効にして境界の近くをクリック → 端点が境界上（小数画素）にスナップされる
+    #[test]
+    fn test_edge_snap() {
+        let mut harness = create_test_harness(vec![]);
+        harness.run();
 
-    /// スナップ対応テスト用ハーネスを作成するヘルパー関数
-    fn create_test_harness_with_snap(
-        measurements: Vec<(egui::Pos2, egui::Pos2)>,
-        length_snap_multiple: f32,
-    ) -> Harness<'static, SampoApp> {
-        let image_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets/lenna.png");
-        Harness::builder()
-            .with_size(egui::vec2(1024.0, 768.0))
-            .build_eframe(|cc| {
-                SampoApp::new_for_test_with_snap(cc, Some(image_path), measurements, length_snap_multiple)
-            })
+        // x = 20 を境に暗部と明部が分かれる縦のエッジ
+        let image = image::RgbaImage::from_fn(100, 100, |x, _| {
+            let v = if x < 20 { 0 } else { 200 };
+            image::Rgba([v, v, v, 255])
+        });
+        harness.state_mut().set_image_rgba(Some(image));
+        harness.state_mut().measurement_mode = MeasurementMode::Line;
+
+        // 無効ならスナップしない
+        click_image_pos(harness.state_mut(), egui::pos2(17.3, 30.2));
+        assert!(matches!(
+            harness.state().measurement_state,
+            MeasurementState::FirstPointSelected(p) if p == egui::pos2(17.3, 30.2)
+        ));
+        harness.state_mut().measurement_state = MeasurementState::Idle;
+
+        harness.state_mut().edge_snap_enabled = true;
+        click_image_pos(harness.state_mut(), egui::pos2(17.3, 30.2));
+        click_image_pos(harness.state_mut(), egui::pos2(60.0, 30.2));
+        harness.run();
+
+        let line = &harness.state().measurements[0];
+        assert!(
+            (line.start.0 - 20.0).abs() < 0.01,
+            "始点はエッジ上にスナップされるべき（実際: {}）",
+            line.start.0
+        );
+        assert!(
+            (line.start.1 - 30.2).abs() < 0.01,
+            "エッジに沿った位置は変わらない"
+        );
+        assert_eq!(line.end, (60.0, 30.2), "範囲内にエッジがなければそのまま");
     }
 
-    /// シナリオ: 角度スナップのプレビュー表示
-    /// - 始点(100, 100)を選択
-    /// - マウスを(200, 103)に移動（ほぼ水平）
-    /// - Ctrlを押した状態でプレビュー表示
-    /// - 青い十字で真のマウス位置、黄色いプレビュー線で水平にスナップされた線を表示
+    /// シナリオ: 既存の線分・矩形の近くをクリック → 端点・中点・交点・角にスナップする
+    /// 種類ごとに無効にできる
     #[test]
-    fn test_angle_snap_preview() {
-        let mut harness = create_test_harness_with_snap(vec![], 0.0);
-
-        // 初期化
+    fn test_object_snap() {
+        // 交差する2本の線分
+        let mut harness = create_test_harness(vec![
+            (egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0)),
+            (egui::pos2(180.0, 60.0), egui::pos2(180.0, 160.0)),
+        ]);
         harness.run();
+        let rect = RectangleMeasurement::new(egui::pos2(300.0, 300.0), egui::pos2(350.0, 340.0));
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::AddRect(rect));
+        harness.state_mut().rebuild_from_history();
 
-        // 始点を選択状態にする
-        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
-        // マウス位置を設定（ほぼ水平だが、少しずれている）
-        harness.state_mut().debug_mouse_position = Some(egui::pos2(200.0, 103.0));
-        // Ctrlを押した状態
-        harness.state_mut().debug_ctrl_pressed = true;
+        let app = harness.state();
+        assert_eq!(
+            app.snap_point_with_kind(egui::pos2(104.0, 97.0)),
+            (egui::pos2(100.0, 100.0), Some(SnapKind::Endpoint))
+        );
+        assert_eq!(
+            app.snap_point_with_kind(egui::pos2(152.0, 103.0)),
+            (egui::pos2(150.0, 100.0), Some(SnapKind::Midpoint))
+        );
+        assert_eq!(
+            app.snap_point_with_kind(egui::pos2(183.0, 98.0)),
+            (egui::pos2(180.0, 100.0), Some(SnapKind::Intersection))
+        );
+        assert_eq!(
+            app.snap_point_with_kind(egui::pos2(348.0, 302.0)),
+            (egui::pos2(350.0, 300.0), Some(SnapKind::Corner))
+        );
+        assert_eq!(
+            app.snap_point_with_kind(egui::pos2(250.0, 250.0)),
+            (egui::pos2(250.0, 250.0), None),
+            "離れた位置はスナップしない"
+        );
 
+        // 端点から線分を描くと、始点が既存の端点と一致する
+        harness.state_mut().measurement_mode = MeasurementMode::Line;
+        click_image_pos(harness.state_mut(), egui::pos2(197.0, 103.0));
+        click_image_pos(harness.state_mut(), egui::pos2(260.0, 160.0));
         harness.run();
+        assert_eq!(harness.state().measurements[2].start, (200.0, 100.0));
 
-        // 始点が選択されていることを確認
-        assert!(
-            matches!(
-                harness.state().measurement_state,
-                MeasurementState::FirstPointSelected(_)
-            ),
-            "始点選択状態であるべき"
+        // 端点にスナップした終点には角度・長さのスナップを重ねない
+        harness.state_mut().length_snap_multiple = 1.0;
+        harness.state_mut().is_ctrl_pressed = true;
+        click_image_pos(harness.state_mut(), egui::pos2(260.3, 171.7));
+        click_image_pos(harness.state_mut(), egui::pos2(202.0, 101.0));
+        harness.run();
+        assert_eq!(harness.state().measurements[3].end, (200.0, 100.0));
+        // 矩形の角も同様
+        harness.state_mut().measurement_mode = MeasurementMode::Rectangle;
+        click_image_pos(harness.state_mut(), egui::pos2(400.3, 400.6));
+        click_image_pos(harness.state_mut(), egui::pos2(352.0, 342.0));
+        harness.run();
+        assert_eq!(
+            harness.state().rectangle_measurements[1].corner2,
+            (350.0, 340.0)
         );
+        harness.state_mut().is_ctrl_pressed = false;
 
-        // Ctrlが押されていることを確認
-        assert!(
-            harness.state().is_ctrl_pressed,
-            "Ctrl押下状態であるべき"
+        // 無効にした種類にはスナップしない
+        harness.state_mut().snap_to_endpoints = false;
+        assert_eq!(
+            harness
+                .state()
+                .snap_point_with_kind(egui::pos2(104.0, 97.0)),
+            (egui::pos2(104.0, 97.0), None)
         );
-
-        // スナップショット: 角度スナップのプレビュー
-        // - 青い十字: 真のマウス位置(200, 103)
-        // - 黄色いプレビュー線: (100, 100)から水平にスナップされた線
-        harness.snapshot("angle_snap_preview");
     }
 
-    /// シナリオ: 角度スナップなしのプレビュー表示（比較用）
-    /// - Ctrl押下なしで斜めの線のプレビュー
+    /// シナリオ: 角度スナップの刻み・許容差・任意の角度・基準線を変える → Ctrl押下時の終点が設定どおりにスナップする
     #[test]
-    fn test_no_angle_snap_preview() {
-        let mut harness = create_test_harness_with_snap(vec![], 0.0);
+    fn test_configurable_angle_snap() {
+        // 基準線に使う線分（右下がり 10°）
+        let reference_end = egui::pos2(
+            100.0 + 100.0 * 10_f32.to_radians().cos(),
+            100.0 + 100.0 * 10_f32.to_radians().sin(),
+        );
+        let mut harness = create_test_harness(vec![(egui::pos2(100.0, 100.0), reference_end)]);
+        harness.run();
 
-        // 初期化
+        let start = egui::pos2(300.0, 300.0);
+        let end_at = |angle_deg: f32| {
+            let rad = angle_deg.to_radians();
+            start + egui::vec2(rad.cos(), rad.sin()) * 100.0
+        };
+        let snapped_angle = |app: &SampoApp, angle_deg: f32| {
+            let snapped = app.apply_angle_snap(start, end_at(angle_deg));
+            (snapped - start).angle().to_degrees()
+        };
+        harness.state_mut().is_ctrl_pressed = true;
+
+        // 既定は水平・垂直のみ
+        assert!((snapped_angle(harness.state(), 3.0) - 0.0).abs() < 0.01);
+        assert!((snapped_angle(harness.state(), -42.0) - -42.0).abs() < 0.01);
+
+        // 45°刻み（逆向きも同じ向きとみなす）
+        harness.state_mut().angle_snap_step = AngleSnapStep::Deg45;
+        assert!((snapped_angle(harness.state(), -42.0) - -45.0).abs() < 0.01);
+        assert!((snapped_angle(harness.state(), 138.0) - 135.0).abs() < 0.01);
+
+        // 30°刻みと許容差
+        harness.state_mut().angle_snap_step = AngleSnapStep::Deg30;
+        assert!((snapped_angle(harness.state(), 52.0) - 52.0).abs() < 0.01);
+        harness.state_mut().angle_snap_tolerance_deg = 10.0;
+        assert!((snapped_angle(harness.state(), 52.0) - 60.0).abs() < 0.01);
+
+        // 任意の角度
+        harness.state_mut().angle_snap_step = AngleSnapStep::Custom;
+        harness.state_mut().angle_snap_custom_angles = "0, 72°, abc".to_string();
+        assert!((snapped_angle(harness.state(), 68.0) - 72.0).abs() < 0.01);
+
+        // 基準線からの角度（基準線 10° + 90° = 100°）
+        harness.state_mut().angle_snap_step = AngleSnapStep::Deg90;
+        harness.state_mut().angle_snap_reference = Some(1);
+        assert!((snapped_angle(harness.state(), 95.0) - 100.0).abs() < 0.01);
+        assert!((snapped_angle(harness.state(), 2.0) - 10.0).abs() < 0.01);
+    }
+
+    /// シナリオ: キャリブレーション後に長さスナップを実寸の単位で指定 → 線分・矩形が実寸の倍数にスナップされる
+    #[test]
+    fn test_length_snap_in_calibrated_units() {
+        let mut harness = create_test_harness(vec![]);
         harness.run();
 
-        // 始点を選択状態にする
-        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
-        // マウス位置を設定（斜め）
-        harness.state_mut().debug_mouse_position = Some(egui::pos2(200.0, 130.0));
-        // Ctrlは押していない
-        harness.state_mut().debug_ctrl_pressed = false;
+        // 10 px = 1 mm
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::SetCalibration(Some(Calibration {
+                pixels_per_unit: 10.0,
+                unit_name: "mm".to_string(),
+            })));
+        harness.state_mut().rebuild_from_history();
+        harness.state_mut().length_snap_multiple = 0.5;
+        assert_eq!(
+            harness.state().length_snap_multiple_px(),
+            0.5,
+            "既定はピクセル"
+        );
 
+        harness.state_mut().length_snap_calibrated = true;
+        assert_eq!(harness.state().length_snap_unit(), "mm");
+        assert_eq!(harness.state().length_snap_multiple_px(), 5.0);
+
+        // 線分: 長さ 23 px → 25 px (2.5 mm)
+        harness.state_mut().measurement_mode = MeasurementMode::Line;
+        click_image_pos(harness.state_mut(), egui::pos2(100.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(123.0, 100.0));
+        // 矩形: 12 x 18 px → 10 x 20 px
+        harness.state_mut().measurement_mode = MeasurementMode::Rectangle;
+        click_image_pos(harness.state_mut(), egui::pos2(200.0, 200.0));
+        click_image_pos(harness.state_mut(), egui::pos2(212.0, 218.0));
         harness.run();
 
-        // スナップショット: 角度スナップなしのプレビュー
-        // - 青い十字: 真のマウス位置(200, 130)
-        // - 黄色いプレビュー線: 斜めのまま
-        harness.snapshot("no_angle_snap_preview");
+        let line = &harness.state().measurements[0];
+        assert!((line.distance_px - 25.0).abs() < 0.001);
+        let rect = &harness.state().rectangle_measurements[0];
+        assert_eq!((rect.width_px, rect.height_px), (10.0, 20.0));
+
+        // キャリブレーションを解除するとピクセル単位に戻る
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::SetCalibration(None));
+        harness.state_mut().rebuild_from_history();
+        assert_eq!(harness.state().length_snap_unit(), "px");
+        assert_eq!(harness.state().length_snap_multiple_px(), 0.5);
     }
 
-    /// シナリオ: 長さスナップが有効な状態で寸法を追加
-    /// - length_snap_multiple = 10.0 に設定
-    /// - (100, 100)から(143, 100)の線を追加（長さ43px）
-    /// - 長さは40pxにスナップされる
+    /// シナリオ: Shift押下または常に固定で矩形を描く → 正方形・指定した比になり、長さスナップとも両立する
     #[test]
-    fn test_length_snap() {
-        // 長さスナップを10pxに設定
-        let mut harness = create_test_harness_with_snap(vec![], 10.0);
-
-        // 初期化
+    fn test_aspect_locked_rectangle() {
+        let mut harness = create_test_harness(vec![]);
         harness.run();
+        harness.state_mut().measurement_mode = MeasurementMode::Rectangle;
+
+        // Shift押下で正方形（カーソルを覆う長い方の辺に合わせる）
+        harness.state_mut().is_shift_pressed = true;
+        click_image_pos(harness.state_mut(), egui::pos2(100.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(140.0, 125.0));
+        let rect = &harness.state().rectangle_measurements[0];
+        assert_eq!((rect.width_px, rect.height_px), (40.0, 40.0));
+
+        // 左上方向へ描いても正方形
+        click_image_pos(harness.state_mut(), egui::pos2(300.0, 300.0));
+        click_image_pos(harness.state_mut(), egui::pos2(290.0, 270.0));
+        let rect = &harness.state().rectangle_measurements[1];
+        assert_eq!(rect.min_corner(), egui::pos2(270.0, 270.0));
+        assert_eq!((rect.width_px, rect.height_px), (30.0, 30.0));
+
+        // 常に 16:9 に固定し、長さスナップ 10px と組み合わせる
+        harness.state_mut().is_shift_pressed = false;
+        harness.state_mut().rect_aspect_always = true;
+        harness.state_mut().rect_aspect = RectAspect::Ratio;
+        harness.state_mut().rect_aspect_ratio = (16.0, 9.0);
+        harness.state_mut().length_snap_multiple = 10.0;
+        click_image_pos(harness.state_mut(), egui::pos2(100.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(258.0, 120.0));
+        let rect = &harness.state().rectangle_measurements[2];
+        assert_eq!(rect.width_px, 160.0, "幅が倍数にスナップされる");
+        assert!((rect.height_px - 90.0).abs() < 0.001, "高さは比から決まる");
+
+        // 固定しなければ従来どおり
+        harness.state_mut().rect_aspect_always = false;
+        click_image_pos(harness.state_mut(), egui::pos2(100.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(158.0, 121.0));
+        let rect = &harness.state().rectangle_measurements[3];
+        assert_eq!((rect.width_px, rect.height_px), (60.0, 20.0));
+    }
 
-        // 始点を選択状態にする
-        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
-        // マウス位置（43px離れている）
-        harness.state_mut().debug_mouse_position = Some(egui::pos2(143.0, 100.0));
-
+    /// シナリオ: 選択モードで線分・矩形を選択し、端点・角・全体をドラッグで編集 → Undoで元に戻る
+    #[test]
+    fn test_select_and_drag_edit() {
+        let mut harness =
+            create_test_harness(vec![(egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0))]);
         harness.run();
+        let rect = RectangleMeasurement::new(egui::pos2(300.0, 300.0), egui::pos2(350.0, 340.0));
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::AddRect(rect));
+        harness.state_mut().rebuild_from_history();
+        harness.state_mut().measurement_mode = MeasurementMode::Select;
+
+        let (w, h) = harness.state().image_dimensions.unwrap();
+        let image_rect =
+            egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(w as f32, h as f32));
+        let drag = |app: &mut SampoApp, from: egui::Pos2, to: egui::Pos2| {
+            app.begin_drag_edit(from, image_rect);
+            app.update_drag_edit(to, image_rect);
+            app.finish_drag_edit();
+        };
 
-        // スナップショット: 長さスナップのプレビュー
-        // プレビュー線は40pxにスナップされているはず
-        harness.snapshot("length_snap_preview");
+        // クリックで選択、何もない所で解除
+        click_image_pos(harness.state_mut(), egui::pos2(150.0, 102.0));
+        assert_eq!(harness.state().selection, vec![Selection::Line(1)]);
+        click_image_pos(harness.state_mut(), egui::pos2(320.0, 320.0));
+        assert_eq!(harness.state().selection, vec![Selection::Rect(2)]);
+        click_image_pos(harness.state_mut(), egui::pos2(10.0, 10.0));
+        assert!(harness.state().selection.is_empty());
+
+        // 選択した線分の終点をドラッグ（長さは整数にスナップ）
+        harness.state_mut().length_snap_multiple = 1.0;
+        click_image_pos(harness.state_mut(), egui::pos2(150.0, 100.0));
+        drag(
+            harness.state_mut(),
+            egui::pos2(200.0, 100.0),
+            egui::pos2(230.3, 100.0),
+        );
+        assert_eq!(harness.state().measurements[0].end, (230.0, 100.0));
+        assert_eq!(harness.state().measurements[0].distance_px, 130.0);
+
+        // 線分全体を移動
+        drag(
+            harness.state_mut(),
+            egui::pos2(150.0, 100.0),
+            egui::pos2(160.0, 120.0),
+        );
+        let line = &harness.state().measurements[0];
+        assert_eq!((line.start, line.end), ((110.0, 120.0), (240.0, 120.0)));
+
+        // 矩形の右下の角をドラッグ、続けて全体を移動
+        click_image_pos(harness.state_mut(), egui::pos2(320.0, 320.0));
+        drag(
+            harness.state_mut(),
+            egui::pos2(350.0, 340.0),
+            egui::pos2(370.0, 360.0),
+        );
+        let rect = &harness.state().rectangle_measurements[0];
+        assert_eq!((rect.width_px, rect.height_px), (70.0, 60.0));
+        drag(
+            harness.state_mut(),
+            egui::pos2(320.0, 320.0),
+            egui::pos2(300.0, 330.0),
+        );
+        let rect = &harness.state().rectangle_measurements[0];
+        assert_eq!(rect.min_corner(), egui::pos2(280.0, 310.0));
+        assert_eq!(rect.area_px, 70.0 * 60.0);
+
+        // 動かさずに離しても履歴には記録しない
+        let actions = harness.state().history.actions.len();
+        drag(
+            harness.state_mut(),
+            egui::pos2(300.0, 330.0),
+            egui::pos2(300.0, 330.0),
+        );
+        assert_eq!(harness.state().history.actions.len(), actions);
 
-        // 実際に寸法を追加（pending_measurementsを使うとスナップが適用されないので、
-        // handle_canvas_clickをシミュレートするために直接追加）
-        // まずは状態をリセット
-        let start = egui::pos2(100.0, 100.0);
-        let end = egui::pos2(143.0, 100.0);
-        // 長さスナップを適用
-        let snapped_end = snap_line_length(start, end, 10.0);
-        let measurement = Measurement::new(start, snapped_end);
+        // 編集は1回ずつUndoできる
+        harness.state_mut().pending_undo_count = 2;
+        harness.run();
+        let rect = &harness.state().rectangle_measurements[0];
+        assert_eq!((rect.width_px, rect.height_px), (50.0, 40.0));
+        harness.state_mut().pending_undo_count = 2;
+        harness.run();
+        assert_eq!(harness.state().measurements[0].end, (200.0, 100.0));
+
+        // 端点のドラッグも矩形の角にスナップし、長さスナップは重ねない
+        click_image_pos(harness.state_mut(), egui::pos2(150.0, 100.0));
+        drag(
+            harness.state_mut(),
+            egui::pos2(200.0, 100.0),
+            egui::pos2(297.0, 302.0),
+        );
+        assert_eq!(harness.state().measurements[0].end, (300.0, 300.0));
+    }
 
-        // 寸法を追加
-        harness.state_mut().history.push_action(Action::AddLine(measurement.clone()));
+    /// シナリオ: 範囲選択とShift+クリックで複数の線分・矩形を選択 → まとめてスタイル変更・移動・削除
+    /// それぞれ1回のUndoで元に戻る
+    #[test]
+    fn test_multi_selection() {
+        let mut harness = create_test_harness(vec![
+            (egui::pos2(100.0, 100.0), egui::pos2(150.0, 100.0)),
+            (egui::pos2(100.0, 120.0), egui::pos2(150.0, 120.0)),
+            (egui::pos2(300.0, 100.0), egui::pos2(350.0, 100.0)),
+        ]);
+        harness.run();
+        let rect = RectangleMeasurement::new(egui::pos2(110.0, 140.0), egui::pos2(140.0, 160.0));
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::AddRect(rect));
         harness.state_mut().rebuild_from_history();
-        harness.state_mut().measurement_state = MeasurementState::Idle;
-        harness.state_mut().debug_mouse_position = None;
+        harness.state_mut().measurement_mode = MeasurementMode::Select;
+
+        let (w, h) = harness.state().image_dimensions.unwrap();
+        let image_rect =
+            egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(w as f32, h as f32));
+        let drag = |app: &mut SampoApp, from: egui::Pos2, to: egui::Pos2| {
+            app.begin_drag_edit(from, image_rect);
+            app.update_drag_edit(to, image_rect);
+            app.finish_drag_edit();
+        };
 
-        harness.run();
+        // 範囲選択: 左側の線分2本と矩形が入る
+        drag(
+            harness.state_mut(),
+            egui::pos2(90.0, 90.0),
+            egui::pos2(200.0, 170.0),
+        );
+        assert_eq!(
+            harness.state().selection,
+            vec![Selection::Line(1), Selection::Line(2), Selection::Rect(4)]
+        );
 
-        // 追加された寸法の検証
+        // Shift+クリックで追加・解除
+        harness.state_mut().is_shift_pressed = true;
+        click_image_pos(harness.state_mut(), egui::pos2(320.0, 100.0));
+        assert_eq!(harness.state().selection.len(), 4);
+        click_image_pos(harness.state_mut(), egui::pos2(120.0, 120.0));
+        assert_eq!(harness.state().selection.len(), 3);
+        assert!(!harness.state().selection.contains(&Selection::Line(2)));
+        harness.state_mut().is_shift_pressed = false;
+
+        // まとめて移動（1つの操作）
+        let actions_before = harness.state().history.actions.len();
+        drag(
+            harness.state_mut(),
+            egui::pos2(120.0, 100.0),
+            egui::pos2(130.0, 105.0),
+        );
+        assert_eq!(harness.state().history.actions.len(), actions_before + 1);
+        assert_eq!(harness.state().measurements[0].start, (110.0, 105.0));
         assert_eq!(
-            harness.state().measurements.len(),
-            1,
-            "寸法が1つ追加されているべき"
+            harness.state().measurements[1].start,
+            (100.0, 120.0),
+            "選択外は動かない"
+        );
+        assert_eq!(harness.state().measurements[2].start, (310.0, 105.0));
+        assert_eq!(
+            harness.state().rectangle_measurements[0].min_corner(),
+            egui::pos2(120.0, 145.0)
+        );
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements[0].start, (100.0, 100.0));
+        assert_eq!(harness.state().measurements[2].start, (300.0, 100.0));
+
+        // まとめて削除（1つの操作）
+        harness.state_mut().delete_selection();
+        assert_eq!(harness.state().measurements.len(), 1);
+        assert_eq!(harness.state().measurements[0].start, (100.0, 120.0));
+        assert!(harness.state().rectangle_measurements.is_empty());
+        assert!(harness.state().selection.is_empty());
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert_eq!(harness.state().measurements.len(), 3);
+        assert_eq!(harness.state().rectangle_measurements.len(), 1);
+
+        // 範囲選択した測定のスタイルをまとめて変更（1つの操作）
+        drag(
+            harness.state_mut(),
+            egui::pos2(90.0, 90.0),
+            egui::pos2(360.0, 170.0),
         );
+        let cursor_before = harness.state().history.cursor;
+        harness
+            .state_mut()
+            .apply_style_to_selection(|s| s.stroke_color = Some([255, 255, 0, 255]));
+        harness.state_mut().record_style_edit();
+        harness.run();
+        assert_eq!(harness.state().history.cursor, cursor_before + 1);
+        let restyled = |app: &SampoApp| {
+            app.measurement_items()
+                .filter(|item| item.style().stroke_color.is_some())
+                .map(|item| item.selection())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            restyled(harness.state()),
+            vec![
+                Selection::Line(1),
+                Selection::Line(2),
+                Selection::Line(3),
+                Selection::Rect(4)
+            ]
+        );
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert!(restyled(harness.state()).is_empty());
+    }
 
-        // スナップされた距離を検証（40pxになるはず）
-        let added_measurement = &harness.state().measurements[0];
-        assert!(
-            (added_measurement.distance_px - 40.0).abs() < 0.1,
-            "長さは40pxにスナップされるべき（実際: {:.1}）",
-            added_measurement.distance_px
+    /// シナリオ: 線分・矩形に名前とメモを付ける → 置き換えの操作として記録され、Undoで戻る
+    /// 名前・メモはエクスポートに含まれ、移動しても保たれる
+    #[test]
+    fn test_named_measurements_with_notes() {
+        let mut harness =
+            create_test_harness(vec![(egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0))]);
+        harness.run();
+        let rect = RectangleMeasurement::new(egui::pos2(300.0, 300.0), egui::pos2(350.0, 340.0));
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::AddRect(rect));
+        harness.state_mut().rebuild_from_history();
+
+        // 一覧で入力するたびに記録し、同じ入力欄での続く入力は1つの操作にまとめる
+        let actions = harness.state().history.actions.len();
+        let state = harness.state_mut();
+        state.record_text_edit(Selection::Line(1), TextField::Name, "bore".to_string());
+        state.record_text_edit(Selection::Line(1), TextField::Name, "bore A".to_string());
+        assert_eq!(state.history.actions.len(), actions + 1);
+        // 入力中に履歴から作り直しても（Undo・削除・ドラッグなど）入力は失われない
+        state.rebuild_from_history();
+        assert_eq!(state.measurements[0].name, "bore A");
+        // 別の入力欄での入力は別の操作になり、先の入力も失われない
+        state.record_text_edit(
+            Selection::Line(1),
+            TextField::Note,
+            "基準穴, 要再測定".to_string(),
         );
+        assert_eq!(state.measurements[0].name, "bore A");
+        state.record_text_edit(Selection::Rect(2), TextField::Name, "flange".to_string());
+        assert_eq!(state.history.actions.len(), actions + 3);
+        // 変更がなければ記録しない
+        state.text_edit_action = None;
+        state.record_text_edit(Selection::Rect(2), TextField::Name, "flange".to_string());
+        assert_eq!(state.history.actions.len(), actions + 3);
+        harness.run();
 
-        // 終点X座標を検証（100 + 40 = 140）
+        assert_eq!(harness.state().measurements[0].display_name(), "bore A");
+        let csv = harness.state().export_csv();
         assert!(
-            (added_measurement.end.0 - 140.0).abs() < 0.1,
-            "終点Xは140であるべき（実際: {:.1}）",
-            added_measurement.end.0
+            csv.contains(",bore A,\"基準穴, 要再測定\",既定\n"),
+            "CSV: {}",
+            csv
         );
-
-        harness.snapshot("length_snap_result");
+        assert!(csv.contains(",flange,,既定\n"));
+        let json: serde_json::Value = serde_json::from_str(&harness.state().export_json()).unwrap();
+        assert_eq!(json["measurements"][0]["name"], "bore A");
+        assert_eq!(json["rectangle_measurements"][0]["name"], "flange");
+
+        // 選択モードで移動しても名前は保たれる
+        harness.state_mut().measurement_mode = MeasurementMode::Select;
+        let (w, h) = harness.state().image_dimensions.unwrap();
+        let image_rect =
+            egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(w as f32, h as f32));
+        harness
+            .state_mut()
+            .begin_drag_edit(egui::pos2(150.0, 100.0), image_rect);
+        harness
+            .state_mut()
+            .update_drag_edit(egui::pos2(150.0, 110.0), image_rect);
+        harness.state_mut().finish_drag_edit();
+        assert_eq!(harness.state().measurements[0].name, "bore A");
+
+        // 名前の変更はUndoできる
+        harness.state_mut().pending_undo_count = 4;
+        harness.run();
+        assert_eq!(harness.state().measurements[0].display_name(), "線#1");
+        assert!(harness.state().rectangle_measurements[0].name.is_empty());
     }
 
-    /// シナリオ: 角度スナップ + 長さスナップの複合
-    /// - Ctrl押下で水平にスナップ
-    /// - 長さも10pxの倍数にスナップ
+    /// シナリオ: レイヤーを追加 → 新しい線分はそのレイヤーに入り、選択した矩形も移せる（Undo可）
+    /// 非表示のレイヤーの測定は選択・スナップの対象外で、エクスポートにはレイヤー名が入る
     #[test]
-    fn test_angle_and_length_combined_snap() {
-        // 長さスナップを10pxに設定
-        let mut harness = create_test_harness_with_snap(vec![], 10.0);
-
-        // 初期化
+    fn test_layers() {
+        let mut harness = create_test_harness(vec![]);
         harness.run();
+        let rect = RectangleMeasurement::new(egui::pos2(300.0, 300.0), egui::pos2(350.0, 340.0));
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::AddRect(rect));
+        harness.state_mut().rebuild_from_history();
 
-        // 始点を選択状態にする
-        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
-        // マウス位置（斜め、長さも半端）
-        harness.state_mut().debug_mouse_position = Some(egui::pos2(153.0, 104.0));
-        // Ctrl押下で角度スナップ
-        harness.state_mut().debug_ctrl_pressed = true;
+        // 既定のレイヤーは種類ごとの色（矩形は青）と、表示設定の寸法文字色を使う
+        assert_eq!(harness.state().layer_line_color(0, RECT_COLOR), RECT_COLOR);
+        harness.state_mut().text_color = egui::Color32::WHITE;
+        assert_eq!(harness.state().layer_label_color(0), egui::Color32::WHITE);
+
+        harness.state_mut().layers.push(Layer {
+            id: 1,
+            name: "寸法".to_string(),
+            line_color: Some(egui::Color32::YELLOW),
+            label_color: None,
+            visible: true,
+        });
+        harness.state_mut().active_layer = 1;
+        harness.state_mut().measurement_mode = MeasurementMode::Line;
+        click_image_pos(harness.state_mut(), egui::pos2(100.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(200.0, 100.0));
+        assert_eq!(harness.state().measurements.len(), 1);
+        assert_eq!(harness.state().measurements[0].layer, 1);
+        assert_eq!(harness.state().layer_counts(), vec![1, 1]);
+
+        // 選択した矩形をレイヤーに移す
+        harness.state_mut().selection = vec![Selection::Rect(1)];
+        harness.state_mut().move_selection_to_layer(1);
+        assert_eq!(harness.state().rectangle_measurements[0].layer, 1);
+        assert_eq!(harness.state().layer_counts(), vec![0, 2]);
+
+        let csv = harness.state().export_csv();
+        assert!(csv.contains(",,,寸法\n"), "CSV: {}", csv);
+        let json: serde_json::Value = serde_json::from_str(&harness.state().export_json()).unwrap();
+        assert_eq!(json["measurements"][0]["layer"], "寸法");
+        assert_eq!(json["rectangle_measurements"][0]["layer"], "寸法");
+
+        // 非表示にすると選択できず、端点にもスナップしない
+        harness.state_mut().layers[1].visible = false;
+        harness.run();
+        assert!(harness.state().selection.is_empty());
+        let (w, h) = harness.state().image_dimensions.unwrap();
+        let image_rect =
+            egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(w as f32, h as f32));
+        assert_eq!(
+            harness
+                .state()
+                .hit_test_measurement(egui::pos2(150.0, 100.0), image_rect),
+            None
+        );
+        assert!(
+            harness
+                .state()
+                .object_snap_target(egui::pos2(101.0, 101.0))
+                .is_none()
+        );
+        harness.state_mut().layers[1].visible = true;
+        assert_eq!(
+            harness
+                .state()
+                .hit_test_measurement(egui::pos2(150.0, 100.0), image_rect),
+            Some(Selection::Line(2))
+        );
 
+        // レイヤーの移動はUndoできる
+        harness.state_mut().pending_undo_count = 1;
         harness.run();
+        assert_eq!(harness.state().rectangle_measurements[0].layer, 0);
+        assert_eq!(harness.state().layer_counts(), vec![1, 1]);
+
+        // 線分・矩形以外の測定も選択中のレイヤーに入り、エクスポートにレイヤー名が入る
+        harness.state_mut().measurement_mode = MeasurementMode::Marker;
+        click_image_pos(harness.state_mut(), egui::pos2(20.0, 20.0));
+        harness.state_mut().measurement_mode = MeasurementMode::Angle;
+        click_image_pos(harness.state_mut(), egui::pos2(400.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(450.0, 100.0));
+        click_image_pos(harness.state_mut(), egui::pos2(400.0, 50.0));
+        assert_eq!(harness.state().markers[0].layer, 1);
+        assert_eq!(harness.state().angle_measurements[0].layer, 1);
+        assert_eq!(harness.state().layer_counts(), vec![1, 3]);
+        assert_eq!(
+            harness.state().layer_line_color(1, egui::Color32::RED),
+            egui::Color32::YELLOW
+        );
+        let csv = harness.state().export_csv();
+        assert!(csv.contains(",20.00,20.00,寸法\n"), "CSV: {}", csv);
+        assert!(csv.contains(",90.00,寸法\n"), "CSV: {}", csv);
+        let json: serde_json::Value = serde_json::from_str(&harness.state().export_json()).unwrap();
+        assert_eq!(json["markers"][0]["layer"], "寸法");
+        assert_eq!(json["angle_measurements"][0]["layer"], "寸法");
+
+        // 非表示のレイヤーの測定は描かない
+        harness.state_mut().layers[1].visible = false;
+        let state = harness.state();
+        assert_eq!(
+            state
+                .visible_in_layers(&state.angle_measurements, |a| a.layer)
+                .count(),
+            0
+        );
 
-        // スナップショット: 角度+長さスナップのプレビュー
-        // - 青い十字: 真のマウス位置(153, 104)
-        // - 黄色いプレビュー線: 水平にスナップ、長さも50pxにスナップ
-        harness.snapshot("angle_and_length_snap_preview");
+        // 測定はIDでレイヤーを参照するので、名前を変えても所属は変わらない
+        harness.state_mut().layers[1].name = "注記".to_string();
+        let json: serde_json::Value = serde_json::from_str(&harness.state().export_json()).unwrap();
+        assert_eq!(json["markers"][0]["layer"], "注記");
+        assert_eq!(harness.state().layer_counts(), vec![1, 3]);
+    }
 
-        // 実際に角度+長さスナップを適用した寸法を追加
-        let start = egui::pos2(100.0, 100.0);
-        let raw_end = egui::pos2(153.0, 104.0);
-        // 角度スナップを適用
-        let angle_snapped = snap_to_angle(start, raw_end);
-        // 長さスナップを適用
-        let snapped_end = snap_line_length(start, angle_snapped, 10.0);
-        let measurement = Measurement::new(start, snapped_end);
+    /// シナリオ: 線分と矩形を選択してスタイルを変更 → 選択中のすべてに適用され、1つの操作として記録される
+    /// スタイルはJSONに出力され、移動しても保たれ、Undoで既定に戻る
+    #[test]
+    fn test_measurement_style_overrides() {
+        let mut harness =
+            create_test_harness(vec![(egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0))]);
+        harness.run();
+        let rect = RectangleMeasurement::new(egui::pos2(300.0, 300.0), egui::pos2(350.0, 340.0));
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::AddRect(rect));
+        harness.state_mut().rebuild_from_history();
+        let actions = harness.state().history.actions.len();
+
+        harness.state_mut().measurement_mode = MeasurementMode::Select;
+        harness.state_mut().selection = vec![Selection::Line(1), Selection::Rect(2)];
+        let style = MeasurementStyle {
+            stroke_color: Some([0, 0, 255, 255]),
+            stroke_width: Some(4.0),
+            point_radius: Some(2.0),
+            label_font_size: Some(24.0),
+            label_offset: (0.0, -10.0),
+        };
+        // パネルでの操作と同じ手順（変更するたびに記録し、同じ操作中の変更は1つにまとめる）
+        harness
+            .state_mut()
+            .apply_style_to_selection(|s| s.stroke_width = Some(3.0));
+        harness.state_mut().record_style_edit();
+        harness
+            .state_mut()
+            .apply_style_to_selection(|s| *s = style.clone());
+        harness.state_mut().record_style_edit();
+        assert_eq!(harness.state().history.actions.len(), actions + 1);
+        // 記録済みなので履歴から作り直しても失われない
+        harness.state_mut().rebuild_from_history();
+        // 操作が終わったフレームで区切られる
+        harness.run();
+        assert!(harness.state().style_edit_action.is_none());
+        assert!(harness.state().measurements[0].style == style);
+        assert!(harness.state().rectangle_measurements[0].style == style);
+        let stroke = harness.state().measurements[0]
+            .style
+            .stroke(egui::Color32::RED);
+        assert_eq!(stroke, egui::Stroke::new(4.0, egui::Color32::BLUE));
+
+        let json: serde_json::Value = serde_json::from_str(&harness.state().export_json()).unwrap();
+        assert_eq!(json["measurements"][0]["style"]["stroke_width"], 4.0);
+        assert_eq!(
+            json["rectangle_measurements"][0]["style"]["stroke_color"],
+            serde_json::json!([0, 0, 255, 255])
+        );
+        // 個別設定のない項目は null
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&MeasurementStyle::default()).unwrap())
+                .unwrap();
+        assert!(json["stroke_width"].is_null());
+
+        // 移動してもスタイルは保たれる
+        let moved = harness.state().measurements[0]
+            .with_points(egui::pos2(100.0, 120.0), egui::pos2(200.0, 120.0));
+        assert!(moved.style == style);
+
+        // 1回のUndoで両方とも既定に戻る
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert!(harness.state().measurements[0].style == MeasurementStyle::default());
+        assert!(harness.state().rectangle_measurements[0].style == MeasurementStyle::default());
+
+        // 変更した項目だけを適用し、ほかの項目は測定ごとの値を保つ
+        harness.state_mut().selection = vec![Selection::Rect(2)];
+        harness
+            .state_mut()
+            .apply_style_to_selection(|s| s.stroke_width = Some(6.0));
+        harness.state_mut().record_style_edit();
+        harness.run();
+        harness.state_mut().selection = vec![Selection::Line(1), Selection::Rect(2)];
+        let before = MeasurementStyle::default();
+        let after = MeasurementStyle {
+            stroke_color: Some([255, 0, 0, 255]),
+            ..before.clone()
+        };
+        harness
+            .state_mut()
+            .apply_style_to_selection(|s| s.apply_changes(&before, &after));
+        harness.state_mut().record_style_edit();
+        harness.run();
+        let line_style = &harness.state().measurements[0].style;
+        assert_eq!(line_style.stroke_color, Some([255, 0, 0, 255]));
+        assert_eq!(line_style.stroke_width, None);
+        let rect_style = &harness.state().rectangle_measurements[0].style;
+        assert_eq!(rect_style.stroke_color, Some([255, 0, 0, 255]));
+        assert_eq!(rect_style.stroke_width, Some(6.0));
+        // 別々の操作として記録される（Undo した変更は取り除かれる）
+        assert_eq!(harness.state().history.actions.len(), actions + 2);
+
+        // 同じ操作中に元の値へ戻すと、その操作の記録は残らない
+        harness
+            .state_mut()
+            .apply_style_to_selection(|s| s.point_radius = Some(5.0));
+        harness.state_mut().record_style_edit();
+        assert_eq!(harness.state().history.actions.len(), actions + 3);
+        harness
+            .state_mut()
+            .apply_style_to_selection(|s| s.point_radius = None);
+        harness.state_mut().record_style_edit();
+        assert_eq!(harness.state().history.actions.len(), actions + 2);
+        assert!(harness.state().style_edit_action.is_none());
+    }
 
-        harness.state_mut().history.push_action(Action::AddLine(measurement.clone()));
+    /// シナリオ: 角度・円・マーカーをクリックと範囲選択で選ぶ → スタイルの変更・レイヤーの移動・削除がそれぞれ1つの操作になる
+    #[test]
+    fn test_select_and_style_other_measurements() {
+        let mut harness = create_test_harness(vec![]);
+        harness.run();
+        for action in [
+            Action::AddAngle(AngleMeasurement::new(
+                egui::pos2(100.0, 100.0),
+                egui::pos2(150.0, 100.0),
+                egui::pos2(100.0, 150.0),
+            )),
+            Action::AddCircle(CircleMeasurement::new(egui::pos2(300.0, 300.0), 40.0)),
+            Action::AddMarker(Marker::new(egui::pos2(200.0, 50.0), "A")),
+        ] {
+            harness.state_mut().history.push_action(action);
+        }
         harness.state_mut().rebuild_from_history();
-        harness.state_mut().measurement_state = MeasurementState::Idle;
-        harness.state_mut().debug_mouse_position = None;
-        harness.state_mut().debug_ctrl_pressed = false;
+        harness.state_mut().measurement_mode = MeasurementMode::Select;
+
+        // 円は輪郭の近く、マーカーは点の近くをクリックして選ぶ（円の内側では選ばない）
+        click_image_pos(harness.state_mut(), egui::pos2(300.0, 300.0));
+        assert!(harness.state().selection.is_empty());
+        click_image_pos(harness.state_mut(), egui::pos2(341.0, 302.0));
+        assert_eq!(harness.state().selection, vec![Selection::Circle(2)]);
+        harness.state_mut().is_shift_pressed = true;
+        click_image_pos(harness.state_mut(), egui::pos2(202.0, 51.0));
+        // 範囲選択は角度全体が枠に入れば選ぶ
+        let (w, h) = harness.state().image_dimensions.unwrap();
+        let image_rect =
+            egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(w as f32, h as f32));
+        harness
+            .state_mut()
+            .begin_drag_edit(egui::pos2(90.0, 90.0), image_rect);
+        harness
+            .state_mut()
+            .update_drag_edit(egui::pos2(160.0, 160.0), image_rect);
+        harness.state_mut().finish_drag_edit();
+        harness.state_mut().is_shift_pressed = false;
+        assert_eq!(
+            harness.state().selection,
+            vec![
+                Selection::Circle(2),
+                Selection::Marker(3),
+                Selection::Angle(1)
+            ]
+        );
 
+        let actions = harness.state().history.actions.len();
+        harness.state_mut().apply_style_to_selection(|s| {
+            s.stroke_color = Some([0, 0, 255, 255]);
+            s.label_font_size = Some(20.0);
+        });
+        harness.state_mut().record_style_edit();
         harness.run();
+        assert_eq!(harness.state().history.actions.len(), actions + 1);
+        let state = harness.state();
+        assert_eq!(
+            state.angle_measurements[0].style.stroke(ANGLE_COLOR).color,
+            egui::Color32::BLUE
+        );
+        assert_eq!(
+            state.circle_measurements[0].style.label_font_size,
+            Some(20.0)
+        );
+        assert_eq!(
+            state.markers[0].style.color_or(egui::Color32::RED),
+            egui::Color32::BLUE
+        );
+        let json: serde_json::Value = serde_json::from_str(&state.export_json()).unwrap();
+        assert_eq!(
+            json["angle_measurements"][0]["style"]["stroke_color"],
+            serde_json::json!([0, 0, 255, 255])
+        );
+        assert_eq!(json["markers"][0]["style"]["label_font_size"], 20.0);
+
+        // まとめて別のレイヤーに移す
+        harness.state_mut().layers.push(Layer {
+            id: 1,
+            name: "計数".to_string(),
+            line_color: None,
+            label_color: None,
+            visible: true,
+        });
+        harness.state_mut().move_selection_to_layer(1);
+        assert_eq!(harness.state().history.actions.len(), actions + 2);
+        assert_eq!(harness.state().layer_counts(), vec![0, 3]);
 
-        // 検証
-        assert_eq!(harness.state().measurements.len(), 1, "寸法が1つ追加されているべき");
+        // まとめて削除し、1回のUndoでスタイル・レイヤーごと戻る
+        harness.state_mut().delete_selection();
+        assert_eq!(harness.state().layer_counts(), vec![0, 0]);
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        let state = harness.state();
+        assert_eq!(state.layer_counts(), vec![0, 3]);
+        assert_eq!(
+            state.circle_measurements[0].style.label_font_size,
+            Some(20.0)
+        );
 
-        let added = &harness.state().measurements[0];
-        // 角度スナップで水平になっているはず（Y座標が同じ）
+        // 2回目のUndoでレイヤー、3回目でスタイルが戻る
+        harness.state_mut().pending_undo_count = 2;
+        harness.run();
+        assert_eq!(harness.state().layer_counts(), vec![3, 0]);
+        assert!(harness.state().markers[0].style == MeasurementStyle::default());
+    }
+
+    /// シナリオ: 名前・メモ・レイヤー・スタイルを付けた測定をプロジェクトとして保存 → 別の画面で開くと同じ状態に戻る
+    /// 開いた測定は1つの操作として元に戻せ、新しい測定には保存した測定と重ならないIDが付く
+    #[test]
+    fn test_project_round_trip() {
+        let mut harness =
+            create_test_harness(vec![(egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0))]);
+        harness.run();
+        let state = harness.state_mut();
+        state
+            .history
+            .push_action(Action::AddRect(RectangleMeasurement::new(
+                egui::pos2(300.0, 300.0),
+                egui::pos2(350.0, 340.0),
+            )));
+        state
+            .history
+            .push_action(Action::AddMarker(Marker::new(egui::pos2(50.0, 60.0), "核")));
+        state
+            .history
+            .push_action(Action::SetCalibration(Some(Calibration {
+                pixels_per_unit: 10.0,
+                unit_name: "mm".to_string(),
+            })));
+        state.rebuild_from_history();
+        state.marker_categories.push(MarkerCategory {
+            name: "核".to_string(),
+            color: egui::Color32::from_rgb(10, 20, 30),
+        });
+        state.layers.push(Layer {
+            id: 1,
+            name: "外形".to_string(),
+            line_color: Some(egui::Color32::YELLOW),
+            label_color: None,
+            visible: false,
+        });
+        state.record_text_edit(Selection::Line(1), TextField::Name, "全長".to_string());
+        state.record_text_edit(Selection::Line(1), TextField::Note, "要確認".to_string());
+        state.selection = vec![Selection::Rect(2), Selection::Marker(3)];
+        state.apply_style_to_selection(|s| s.stroke_width = Some(5.0));
+        state.record_style_edit();
+        state.move_selection_to_layer(1);
+        let json = state.project_json();
+
+        let mut opened = create_test_harness(vec![]);
+        opened.run();
+        let project: ProjectData = serde_json::from_str(&json).unwrap();
         assert!(
-            (added.end.1 - added.start.1).abs() < 0.1,
-            "角度スナップで水平になるべき（Y差: {:.1}）",
-            (added.end.1 - added.start.1).abs()
+            project
+                .image_path
+                .as_ref()
+                .is_some_and(|path| path.ends_with("lenna.png"))
         );
-        // 長さスナップで10の倍数になっているはず
-        let distance_mod_10 = added.distance_px % 10.0;
-        assert!(
-            distance_mod_10 < 0.1 || (10.0 - distance_mod_10) < 0.1,
-            "長さは10の倍数であるべき（実際: {:.1}）",
-            added.distance_px
+        opened.state_mut().apply_project(project);
+        let state = opened.state();
+        assert_eq!(state.measurements[0].id, 1);
+        assert_eq!(state.measurements[0].name, "全長");
+        assert_eq!(state.measurements[0].note, "要確認");
+        assert_eq!(state.rectangle_measurements[0].id, 2);
+        assert_eq!(state.rectangle_measurements[0].layer, 1);
+        assert_eq!(
+            state.rectangle_measurements[0].style.stroke_width,
+            Some(5.0)
+        );
+        assert_eq!(state.markers[0].category, "核");
+        assert_eq!(state.markers[0].style.stroke_width, Some(5.0));
+        assert_eq!(state.layers.len(), 2);
+        assert_eq!(state.layers[1].name, "外形");
+        assert_eq!(state.layers[1].line_color, Some(egui::Color32::YELLOW));
+        assert!(!state.layers[1].visible);
+        assert_eq!(
+            state.marker_categories[1].color,
+            egui::Color32::from_rgb(10, 20, 30)
+        );
+        assert_eq!(
+            state.calibration.as_ref().map(|cal| cal.unit_name.as_str()),
+            Some("mm")
         );
+        assert_eq!(state.history.actions.len(), 1);
+
+        // 新しい測定・レイヤーは保存したものとIDが重ならない
+        opened
+            .state_mut()
+            .history
+            .push_action(Action::AddLine(Measurement::new(
+                egui::pos2(0.0, 0.0),
+                egui::pos2(10.0, 0.0),
+            )));
+        opened.state_mut().rebuild_from_history();
+        assert_eq!(opened.state().measurements[1].id, 4);
+        assert_eq!(opened.state().last_layer_id, 1);
+
+        // 開いた測定は1回のUndoで取り除ける
+        opened.state_mut().pending_undo_count = 2;
+        opened.run();
+        assert!(!opened.state().has_measurements());
+        assert!(opened.state().calibration.is_none());
+    }
 
-        harness.snapshot("angle_and_length_snap_result");
+    /// シナリオ: 線分を3本追加 → 2本目を削除しても他の測定IDは変わらない
+    /// 削除のUndoで同じIDのまま戻り、新しい測定には未使用のIDが割り当てられる
+    #[test]
+    fn test_stable_measurement_ids() {
+        let mut harness = create_test_harness(vec![
+            (egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0)),
+            (egui::pos2(100.0, 150.0), egui::pos2(200.0, 150.0)),
+            (egui::pos2(100.0, 200.0), egui::pos2(200.0, 200.0)),
+        ]);
+        harness.run();
+        let ids = |harness: &Harness<'static, SampoApp>| -> Vec<u64> {
+            harness.state().measurements.iter().map(|m| m.id).collect()
+        };
+        assert_eq!(ids(&harness), vec![1, 2, 3]);
+
+        let id = harness.state().measurements[1].id;
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::RemoveLine(id));
+        harness.state_mut().rebuild_from_history();
+        assert_eq!(ids(&harness), vec![1, 3]);
+        assert_eq!(harness.state().measurements[1].display_name(), "線#3");
+
+        // エクスポートのIDも変わらない
+        let csv = harness.state().export_csv();
+        assert!(csv.contains("\n3,100.00,200.00,"), "CSV: {}", csv);
+        let json: serde_json::Value = serde_json::from_str(&harness.state().export_json()).unwrap();
+        assert_eq!(json["measurements"][1]["id"], 3);
+
+        // 移動してもIDは保たれる
+        harness.state_mut().selection = vec![Selection::Line(3)];
+        harness.state_mut().measurement_mode = MeasurementMode::Select;
+        let (w, h) = harness.state().image_dimensions.unwrap();
+        let image_rect =
+            egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(w as f32, h as f32));
+        harness
+            .state_mut()
+            .begin_drag_edit(egui::pos2(150.0, 200.0), image_rect);
+        harness
+            .state_mut()
+            .update_drag_edit(egui::pos2(150.0, 210.0), image_rect);
+        harness.state_mut().finish_drag_edit();
+        assert_eq!(harness.state().measurements[1].start, (100.0, 210.0));
+        assert_eq!(ids(&harness), vec![1, 3]);
+
+        // 移動と削除をUndoすると同じIDで元の位置に戻る
+        harness.state_mut().pending_undo_count = 2;
+        harness.run();
+        assert_eq!(ids(&harness), vec![1, 2, 3]);
+        assert_eq!(harness.state().measurements[2].start, (100.0, 200.0));
+
+        // Undo後に追加した測定にも使用済みのIDは割り当てない
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::AddLine(Measurement::new(
+                egui::pos2(300.0, 100.0),
+                egui::pos2(300.0, 200.0),
+            )));
+        harness.state_mut().rebuild_from_history();
+        assert_eq!(ids(&harness), vec![1, 2, 3, 4]);
     }
 
-    /// シナリオ: 垂直方向への角度スナップ
-    /// - 始点(100, 100)を選択
-    /// - マウスを(103, 200)に移動（ほぼ垂直）
-    /// - Ctrlを押した状態でプレビュー表示
+    /// シナリオ: 線#3を選択・プロファイル表示・基準線にしたまま線#1を削除 → どれも線#3を指したまま
+    /// 削除をUndoしても同じ線分を指す
     #[test]
-    fn test_angle_snap_vertical() {
-        let mut harness = create_test_harness_with_snap(vec![], 0.0);
+    fn test_selection_follows_measurement_id() {
+        let mut harness = create_test_harness(vec![
+            (egui::pos2(100.0, 100.0), egui::pos2(200.0, 100.0)),
+            (egui::pos2(100.0, 150.0), egui::pos2(200.0, 150.0)),
+            (egui::pos2(100.0, 200.0), egui::pos2(200.0, 200.0)),
+        ]);
+        harness.run();
+        harness.state_mut().measurement_mode = MeasurementMode::Select;
+        click_image_pos(harness.state_mut(), egui::pos2(150.0, 200.0));
+        assert_eq!(harness.state().selection, vec![Selection::Line(3)]);
+        harness.state_mut().profile_line = Some(3);
+        harness.state_mut().point_line_reference = Some(3);
+
+        let selected_start = |harness: &Harness<'static, SampoApp>| {
+            let state = harness.state();
+            let [Selection::Line(id)] = state.selection[..] else {
+                panic!("線分が1本選択されているべき: {:?}", state.selection);
+            };
+            state.line_by_id(id).map(|line| line.start)
+        };
+        // 一覧の「x」と同じ操作
+        harness
+            .state_mut()
+            .history
+            .push_action(Action::RemoveLine(1));
+        harness.state_mut().rebuild_from_history();
+        harness.run();
+        assert_eq!(selected_start(&harness), Some((100.0, 200.0)));
+        let (profile_line, _) = harness.state().current_profile().unwrap();
+        assert_eq!(profile_line.id, 3);
+        assert_eq!(harness.state().point_line_reference_line().unwrap().id, 3);
 
-        // 初期化
+        harness.state_mut().pending_undo_count = 1;
         harness.run();
+        assert_eq!(harness.state().measurements.len(), 3);
+        assert_eq!(selected_start(&harness), Some((100.0, 200.0)));
+        assert_eq!(harness.state().current_profile().unwrap().0.id, 3);
+    }
 
-        // 始点を選択状態にする
-        harness.state_mut().pending_first_point = Some(egui::pos2(100.0, 100.0));
-        // マウス位置を設定（ほぼ垂直）
-        harness.state_mut().debug_mouse_position = Some(egui::pos2(103.0, 200.0));
-        // Ctrlを押した状態
-        harness.state_mut().debug_ctrl_pressed = true;
+    /// シナリオ: 粒子解析を実行 → 大津の方法のしきい値で粒子が検出され、最小面積未満は除かれる
+    /// 検出結果は1回のUndoでまとめて消える
+    #[test]
+    fn test_blob_detection() {
+        let mut harness = create_test_harness(vec![]);
+        harness.run();
+
+        // 白地に黒の粒子: 5x5, 10x10（斜めにつながった1画素を含む）, 2x2
+        let image = image::RgbaImage::from_fn(100, 100, |x, y| {
+            let dark = ((10..15).contains(&x) && (10..15).contains(&y))
+                || ((40..50).contains(&x) && (40..50).contains(&y))
+                || (x, y) == (50, 50)
+                || ((80..82).contains(&x) && (80..82).contains(&y));
+            let v = if dark { 20 } else { 230 };
+            image::Rgba([v, v, v, 255])
+        });
+        harness.state_mut().set_image_rgba(Some(image));
+        harness.state_mut().blob_min_area = 10;
+        let threshold = harness
+            .state()
+            .effective_blob_threshold()
+            .expect("しきい値が求まるべき");
+        assert!(
+            (20..230).contains(&threshold),
+            "しきい値は粒子と背景の間であるべき（実際: {}）",
+            threshold
+        );
 
+        let count = harness.state_mut().run_blob_detection();
         harness.run();
+        assert_eq!(count, 2, "2x2 の粒子は最小面積未満で除かれる");
+        let blobs = &harness.state().blob_measurements;
+        assert_eq!(blobs[0].region.area_px, 25.0);
+        assert_eq!(blobs[0].region.centroid, (12.5, 12.5));
+        assert_eq!(blobs[1].region.area_px, 101.0);
+        assert_eq!(blobs[1].bounding_rect.width_px, 11.0);
+        let expected_diameter = 2.0 * (25.0 / std::f32::consts::PI).sqrt();
+        assert!((blobs[0].equivalent_diameter_px - expected_diameter).abs() < 0.001);
+        assert!(harness.state().export_csv().contains("# Blob Measurements"));
 
-        // スナップショット: 垂直方向への角度スナップ
-        harness.snapshot("angle_snap_vertical");
+        harness.state_mut().pending_undo_count = 1;
+        harness.run();
+        assert!(
+            harness.state().blob_measurements.is_empty(),
+            "Undo1回で検出結果がすべて消えるべき"
+        );
     }
 }